@@ -0,0 +1,117 @@
+//! Microphone capture through ALSA.
+//!
+//! [`AlsaSource`] reads interleaved 16-bit samples from a capture device and
+//! timestamps them with the wall clock the camera actor stamps video frames
+//! with, corrected for the samples still sitting in the ALSA buffer, so audio
+//! and video from the same moment carry the same time.
+//!
+//! Without a microphone, `streaming_core::ToneSource` and
+//! `streaming_core::wav::WavSource` stand in through the same
+//! [`AudioSource`] trait.
+
+use std::io;
+use std::time::SystemTime;
+
+use alsa::pcm::{Access, Format, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+use streaming_core::audio::samples_duration;
+use streaming_core::{AudioFrame, AudioSource};
+
+fn alsa_error(context: &str, err: alsa::Error) -> io::Error {
+    io::Error::other(format!("{}: {}", context, err))
+}
+
+/// Audio captured from an ALSA device.
+///
+/// # Examples
+///
+/// ```no_run
+/// use streaming_capture::AlsaSource;
+/// use streaming_core::AudioSource;
+///
+/// let mut microphone = AlsaSource::open("default", 48_000, 1)?;
+/// while let Some(frame) = microphone.read_frame()? {
+///     println!("{} samples at {:?}", frame.frames(), frame.timestamp);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct AlsaSource {
+    pcm: PCM,
+    sample_rate: u32,
+    channels: u16,
+    buffer: Vec<i16>,
+    sequence: usize,
+}
+
+impl AlsaSource {
+    /// Open `device` (e.g. `"default"` or `"hw:1,0"`) for `channels` at about
+    /// `sample_rate`, delivering 20 ms frames.
+    ///
+    /// The device may pick a nearby rate; [`AudioSource::sample_rate`] tells
+    /// which.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the device cannot be opened or does not support 16-bit
+    /// interleaved capture with that many channels.
+    pub fn open(device: &str, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let pcm = PCM::new(device, Direction::Capture, false).map_err(|e| alsa_error(device, e))?;
+        let (sample_rate, period) = {
+            let params = HwParams::any(&pcm).map_err(|e| alsa_error("hardware parameters", e))?;
+            params.set_access(Access::RWInterleaved).map_err(|e| alsa_error("interleaved access", e))?;
+            params.set_format(Format::s16()).map_err(|e| alsa_error("16-bit samples", e))?;
+            params.set_channels(channels as u32).map_err(|e| alsa_error("channel count", e))?;
+            let rate = params
+                .set_rate_near(sample_rate, ValueOr::Nearest)
+                .map_err(|e| alsa_error("sample rate", e))?;
+            let period = params
+                .set_period_size_near((rate / 50) as alsa::pcm::Frames, ValueOr::Nearest)
+                .map_err(|e| alsa_error("period size", e))?;
+            pcm.hw_params(&params).map_err(|e| alsa_error("hardware parameters", e))?;
+            (rate, period.max(1) as usize)
+        };
+        pcm.start().map_err(|e| alsa_error("start", e))?;
+
+        Ok(Self {
+            pcm,
+            sample_rate,
+            channels,
+            buffer: vec![0; period * channels as usize],
+            sequence: 0,
+        })
+    }
+}
+
+impl AudioSource for AlsaSource {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<AudioFrame>> {
+        let frames = loop {
+            match self.pcm.io_i16().and_then(|io| io.readi(&mut self.buffer)) {
+                Ok(frames) => break frames,
+                // An overrun loses samples; restart, and the timestamps of
+                // what follows show the gap
+                Err(err) => self.pcm.try_recover(err, true).map_err(|e| alsa_error("capture", e))?,
+            }
+        };
+        let now = SystemTime::now();
+
+        // The last sample read arrived `delay` samples ago, at the latest
+        let delay = self.pcm.delay().unwrap_or(0).max(0) as u64;
+        let age = samples_duration(delay + frames as u64, self.sample_rate);
+        self.sequence += 1;
+        Ok(Some(AudioFrame {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp: now.checked_sub(age).unwrap_or(now),
+            sequence: self.sequence,
+            samples: self.buffer[..frames * self.channels as usize].to_vec(),
+        }))
+    }
+}
@@ -1,15 +1,21 @@
 //! Camera capture library for video streaming.
 //!
 //! Provides camera discovery, capability querying, and frame capture functionality
-//! using V4L2 on Linux.
+//! using V4L2 on Linux, and microphone capture through ALSA in `audio` with the
+//! `alsa` feature.
 
 use std::path::Path;
 use std::time::SystemTime;
 use rscam::{Camera};
-use streaming_core::{CameraCapabilities, FormatCapability, PixelFormat, Resolution, Frame};
+use streaming_core::{CameraCapabilities, FormatCapability, FrameError, Metadata, PixelFormat, Pipeline, PipelineWorker, PrivacyMask, PrivacyMasker, Resolution, Frame, StageError, StageStats};
 use thiserror::Error;
 use tokio::sync::mpsc;
 
+#[cfg(feature = "alsa")]
+pub mod audio;
+#[cfg(feature = "alsa")]
+pub use audio::AlsaSource;
+
 #[derive(Debug, Error)]
 pub enum CameraError {
     #[error("Interface not found")]
@@ -35,6 +41,12 @@ pub enum CameraError {
 
     #[error("IO error: {0}")]
     IoError(String),
+
+    #[error("Frame processing failed: {0}")]
+    Pipeline(#[from] StageError),
+
+    #[error("Privacy masking failed, frame withheld: {0}")]
+    Masking(FrameError),
 }
 
 /// Commands that can be sent to the camera actor to control its behavior.
@@ -42,9 +54,15 @@ pub enum CameraError {
 /// These commands are sent through the `CameraHandle` to the actor thread,
 /// which processes them and sends back corresponding events.
 pub enum CameraCommand {
-    /// Change the camera device (e.g., from /dev/video0 to /dev/video1)
+    /// Change the camera device (e.g., from /dev/video0 to /dev/video1).
+    ///
+    /// The new device starts with an empty profile; privacy masks of the
+    /// previous device are not carried over.
     SetInterface(String),
 
+    /// Apply a capture profile, switching to its device first if needed
+    SetProfile(CaptureProfile),
+
     /// Discover the formats and resolutions supported by the camera
     DiscoverCapabilities,
 
@@ -60,6 +78,21 @@ pub enum CameraCommand {
     /// Stop capturing frames
     StopStreaming,
 
+    /// Run captured frames through a processing pipeline on a worker thread
+    /// before they are sent out, or deliver them untouched with `None`
+    SetPipeline(Option<Pipeline>),
+
+    /// Query the per-stage timing of the attached pipeline
+    GetPipelineStats,
+
+    /// Ask a camera streaming H.264 to make its next frame an IDR
+    RequestKeyframe,
+
+    /// Have a camera configured for H.264 refresh the picture with intra
+    /// macroblocks over this many frames instead of sending periodic IDRs,
+    /// or turn that off with `None`
+    SetIntraRefresh(Option<u32>),
+
     /// Shutdown the actor thread gracefully
     Shutdown
 }
@@ -72,6 +105,9 @@ pub enum CameraEvent {
     /// Camera device was successfully changed
     InterfaceChanged,
 
+    /// Capture profile was applied
+    ProfileApplied,
+
     /// Camera capabilities have been discovered
     CapabilitiesDiscovered(CameraCapabilities),
 
@@ -90,6 +126,15 @@ pub enum CameraEvent {
     /// Frame capture has stopped
     StreamingStopped,
 
+    /// Per-stage statistics of the attached pipeline (empty if none)
+    PipelineStats(Vec<StageStats>),
+
+    /// The camera accepted a keyframe request
+    KeyframeRequested,
+
+    /// The camera applied an intra-refresh period
+    IntraRefreshSet(Option<u32>),
+
     /// Actor thread has shut down
     ShutdownComplete,
 
@@ -97,6 +142,15 @@ pub enum CameraEvent {
     Error(CameraError),
 }
 
+/// Frames queued for the pipeline worker before new ones are dropped
+const PIPELINE_QUEUE_DEPTH: usize = 4;
+
+/// `V4L2_CID_MPEG_VIDEO_FORCE_KEY_FRAME` from `linux/v4l2-controls.h`
+const V4L2_CID_FORCE_KEY_FRAME: u32 = 0x0099_09e5;
+
+/// `V4L2_CID_MPEG_VIDEO_INTRA_REFRESH_PERIOD` from `linux/v4l2-controls.h`
+const V4L2_CID_INTRA_REFRESH_PERIOD: u32 = 0x0099_09ec;
+
 #[derive(PartialEq, Debug)]
 enum CameraState {
     Idle,
@@ -117,6 +171,49 @@ pub struct CaptureConfig {
     pub fps: u32,
 }
 
+/// Per-device capture settings enforced by the camera actor.
+///
+/// Privacy masks are applied inside the actor immediately after a frame is
+/// captured, before it reaches a pipeline, an encoder or the network. If a
+/// frame cannot be masked (e.g. MJPEG or H.264 output) it is withheld and a
+/// [`CameraError::Masking`] error is reported instead.
+///
+/// # Examples
+///
+/// ```no_run
+/// use streaming_capture::{spawn_camera_actor_with_profile, CaptureProfile};
+/// use streaming_core::{Color, MaskShape, MaskStyle, PrivacyMask};
+///
+/// let profile = CaptureProfile::new("/dev/video0").with_mask(PrivacyMask::new(
+///     MaskShape::Rect { x: 0.7, y: 0.0, width: 0.3, height: 0.4 },
+///     MaskStyle::Fill(Color::BLACK),
+/// ));
+/// let (handle, mut events) = spawn_camera_actor_with_profile(profile)?;
+/// # Ok::<(), streaming_capture::CameraError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct CaptureProfile {
+    /// Camera device the profile belongs to (e.g., "/dev/video0")
+    pub device: String,
+
+    /// Regions obscured in every frame from this device
+    pub privacy_masks: Vec<PrivacyMask>,
+}
+
+impl CaptureProfile {
+    pub fn new(device: &str) -> Self {
+        Self {
+            device: device.to_string(),
+            privacy_masks: Vec::new(),
+        }
+    }
+
+    pub fn with_mask(mut self, mask: PrivacyMask) -> Self {
+        self.privacy_masks.push(mask);
+        self
+    }
+}
+
 struct CameraActor {
     camera: Camera,
     name : String,
@@ -124,6 +221,8 @@ struct CameraActor {
     capabilities: Option<CameraCapabilities>,
     config: Option<CaptureConfig>,
     frame_sequence: usize,
+    masker: PrivacyMasker,
+    pipeline: Option<PipelineWorker>,
 }
 
 /// Handle for controlling a camera actor.
@@ -174,17 +273,19 @@ impl CaptureConfig {
 }
 
 impl CameraActor {
-    fn new(device_path: &str) -> Result<Self, CameraError> {
-        let camera = Camera::new(&device_path)
+    fn new(profile: CaptureProfile) -> Result<Self, CameraError> {
+        let camera = Camera::new(&profile.device)
             .map_err(|e| CameraError::IoError(format!("Failed to open: {}", e)))?;
 
         Ok(Self {
             camera,
-            name: device_path.to_string(),
+            name: profile.device,
             state: CameraState::Idle,
             capabilities: None,
             config: None,
             frame_sequence: 0,
+            masker: PrivacyMasker::new(profile.privacy_masks),
+            pipeline: None,
         })
     }
 
@@ -199,16 +300,28 @@ impl CameraActor {
         self.state = CameraState::Idle;
         self.capabilities = None;
         self.frame_sequence = 0;
+        self.masker = PrivacyMasker::default();
 
         Ok(())
     }
 
+    fn set_profile(&mut self, profile: CaptureProfile) -> Result<(), CameraError> {
+        if profile.device != self.name {
+            self.set_interface(&profile.device)?;
+        }
+        self.masker = PrivacyMasker::new(profile.privacy_masks);
+        Ok(())
+    }
+
     fn discover_capabilities(&mut self) {
         let mut formats = Vec::new();
 
         for format in self.camera.formats() {
             if let Ok(format) = format {
-                let pixel_format = PixelFormat::from_fourcc(&format.format);
+                // Skip formats we cannot handle rather than reporting them as something else
+                let Some(pixel_format) = PixelFormat::try_from_fourcc(&format.format) else {
+                    continue;
+                };
                 if let Ok(resolution_info) = self.camera.resolutions(&format.format) {
                     let resolutions = match resolution_info {
                         rscam::ResolutionInfo::Discretes(sizes) => {
@@ -272,15 +385,17 @@ impl CameraActor {
 
         self.frame_sequence += 1;
 
-        let frame = Frame {
+        let mut frame = Frame {
             format: PixelFormat::from_fourcc(&captured_frame.format),
             width: captured_frame.resolution.0,
             height: captured_frame.resolution.1,
             timestamp: SystemTime::now(),
             sequence: self.frame_sequence,
-            data: captured_frame.to_vec()
+            data: captured_frame.to_vec(),
+            metadata: Metadata::default(),
         };
 
+        self.masker.apply(&mut frame).map_err(CameraError::Masking)?;
         Ok(frame)
     }
 
@@ -304,6 +419,54 @@ impl CameraActor {
         return Err(CameraError::NotConfigured);
     }
 
+    fn set_pipeline(&mut self, pipeline: Option<Pipeline>, event_tx: &mpsc::Sender<CameraEvent>) {
+        // The old worker drains the frames it has accepted on its own: it may be
+        // blocked on a full event channel that is only read once this returns
+        if let Some(worker) = self.pipeline.take() {
+            worker.detach();
+        }
+
+        if let Some(pipeline) = pipeline {
+            let event_tx = event_tx.clone();
+            self.pipeline = Some(pipeline.spawn(PIPELINE_QUEUE_DEPTH, move |result| {
+                let event = match result {
+                    Ok(frame) => CameraEvent::FrameCaptured(frame),
+                    Err(e) => CameraEvent::Error(CameraError::Pipeline(e)),
+                };
+                let _ = event_tx.blocking_send(event);
+            }));
+        }
+    }
+
+    fn request_keyframe(&self) -> Result<(), CameraError> {
+        if self.state != CameraState::Streaming {
+            return Err(CameraError::NotStreaming);
+        }
+        let format = self.config.as_ref().map(|config| config.format).ok_or(CameraError::NotConfigured)?;
+        if format != PixelFormat::H264 {
+            return Err(CameraError::UnsupportedFormat(format));
+        }
+        self.camera
+            .set_control(V4L2_CID_FORCE_KEY_FRAME, &1)
+            .map_err(|e| CameraError::IoError(format!("Failed to request keyframe: {}", e)))
+    }
+
+    fn set_intra_refresh(&self, period: Option<u32>) -> Result<(), CameraError> {
+        let format = self.config.as_ref().map(|config| config.format).ok_or(CameraError::NotConfigured)?;
+        if format != PixelFormat::H264 {
+            return Err(CameraError::UnsupportedFormat(format));
+        }
+        // A period of 0 turns intra refresh off
+        let period = period.unwrap_or(0) as i32;
+        self.camera
+            .set_control(V4L2_CID_INTRA_REFRESH_PERIOD, &period)
+            .map_err(|e| CameraError::IoError(format!("Failed to set intra refresh: {}", e)))
+    }
+
+    fn pipeline_stats(&self) -> Vec<StageStats> {
+        self.pipeline.as_ref().map(|worker| worker.stats()).unwrap_or_default()
+    }
+
     fn stop_streaming(&mut self)  -> Result<(), CameraError> {
         if self.state == CameraState::Streaming {
             self.camera.stop().map_err(|e| CameraError::IoError(format!("Failed to stop camera: {}", e)))?;
@@ -336,6 +499,18 @@ impl CameraHandle{
         return self.command_tx.blocking_send(command).map_err(|_| CameraError::IoError("Failed to send command".to_string()));
     }
 
+    /// Callback sending [`CameraCommand::RequestKeyframe`] without blocking,
+    /// for wiring into an encoder such as `streaming_codec::H264Passthrough`.
+    ///
+    /// Requests made while the command queue is full are dropped; the camera
+    /// will produce a keyframe for one of the pending requests anyway.
+    pub fn keyframe_requester(&self) -> impl FnMut() + Send + 'static {
+        let command_tx = self.command_tx.clone();
+        move || {
+            let _ = command_tx.try_send(CameraCommand::RequestKeyframe);
+        }
+    }
+
     /// Gracefully shut down the camera actor and wait for the thread to exit.
     ///
     /// This sends a `Shutdown` command to the actor, which will stop any ongoing
@@ -417,7 +592,19 @@ impl CameraHandle{
 /// # Ok::<(), streaming_capture::CameraError>(())
 /// ```
 pub fn spawn_camera_actor(device_path: &str) -> Result<(CameraHandle, mpsc::Receiver<CameraEvent>), CameraError> {
-    let actor = CameraActor::new(device_path)?;
+    spawn_camera_actor_with_profile(CaptureProfile::new(device_path))
+}
+
+/// Spawn a camera actor thread for the device and settings in `profile`.
+///
+/// Behaves like [`spawn_camera_actor`], but privacy masks from the profile are
+/// enforced from the very first captured frame.
+///
+/// # Errors
+///
+/// Returns an error if the camera device cannot be opened.
+pub fn spawn_camera_actor_with_profile(profile: CaptureProfile) -> Result<(CameraHandle, mpsc::Receiver<CameraEvent>), CameraError> {
+    let actor = CameraActor::new(profile)?;
 
     let (command_tx, command_rx) = mpsc::channel(10);
     let (event_tx, event_rx) = mpsc::channel(100);
@@ -450,6 +637,16 @@ fn camera_actor_loop(mut actor: CameraActor, mut command_rx: mpsc::Receiver<Came
                             }
                         }
                     }
+                    CameraCommand::SetProfile(profile) => {
+                        match actor.set_profile(profile) {
+                            Ok(()) => {
+                                let _ = event_tx.blocking_send(CameraEvent::ProfileApplied);
+                            }
+                            Err(e) => {
+                                let _ = event_tx.blocking_send(CameraEvent::Error(e));
+                            }
+                        }
+                    }
                     CameraCommand::DiscoverCapabilities => {
                         actor.discover_capabilities();
                         if let Some(caps) = actor.capabilities.clone() {
@@ -496,6 +693,32 @@ fn camera_actor_loop(mut actor: CameraActor, mut command_rx: mpsc::Receiver<Came
                             }
                         }
                     }
+                    CameraCommand::SetPipeline(pipeline) => {
+                        actor.set_pipeline(pipeline, &event_tx);
+                    }
+                    CameraCommand::GetPipelineStats => {
+                        let _ = event_tx.blocking_send(CameraEvent::PipelineStats(actor.pipeline_stats()));
+                    }
+                    CameraCommand::RequestKeyframe => {
+                        match actor.request_keyframe() {
+                            Ok(()) => {
+                                let _ = event_tx.blocking_send(CameraEvent::KeyframeRequested);
+                            }
+                            Err(e) => {
+                                let _ = event_tx.blocking_send(CameraEvent::Error(e));
+                            }
+                        }
+                    }
+                    CameraCommand::SetIntraRefresh(period) => {
+                        match actor.set_intra_refresh(period) {
+                            Ok(()) => {
+                                let _ = event_tx.blocking_send(CameraEvent::IntraRefreshSet(period));
+                            }
+                            Err(e) => {
+                                let _ = event_tx.blocking_send(CameraEvent::Error(e));
+                            }
+                        }
+                    }
                     CameraCommand::Shutdown => {
                         // Stop streaming if active
                         if actor.state == CameraState::Streaming {
@@ -522,11 +745,28 @@ fn camera_actor_loop(mut actor: CameraActor, mut command_rx: mpsc::Receiver<Came
 
         // If streaming, capture and send frame
         if actor.state == CameraState::Streaming {
-            if let Ok(frame) = actor.capture_frame() {
-                let _ = event_tx.blocking_send(CameraEvent::FrameCaptured(frame));
+            match actor.capture_frame() {
+                Ok(frame) => match &actor.pipeline {
+                    // A busy pipeline drops the frame rather than stalling capture
+                    Some(worker) => {
+                        let _ = worker.submit(frame);
+                    }
+                    None => {
+                        let _ = event_tx.blocking_send(CameraEvent::FrameCaptured(frame));
+                    }
+                },
+                Err(e @ CameraError::Masking(_)) => {
+                    let _ = event_tx.blocking_send(CameraEvent::Error(e));
+                }
+                Err(_) => {}
             }
         }
     }
+
+    // Shutdown waits for this thread, so the pipeline must not wait on events
+    if let Some(worker) = actor.pipeline.take() {
+        worker.detach();
+    }
 }
 
 /// Discover all camera devices available in /dev/
@@ -0,0 +1,301 @@
+//! Audio encoding alongside video.
+//!
+//! Audio encoders turn [`AudioFrame`]s into [`EncodedFrame`]s like video
+//! encoders do, with zero width and height and every packet a keyframe.
+//! Packets keep the capture timestamp of their first sample, the same wall
+//! clock video frames are stamped with, and that is what lines the tracks
+//! up: muxers and players place each track by `timestamp - pts`, so audio
+//! and video from one moment land at one point of the shared timeline even
+//! though each encoder counts `pts` from its own first input.
+//!
+//! Opus needs fixed frame sizes; [`Rechunker`] cuts whatever runs of
+//! samples a source delivers into those. The Opus codec itself is in the
+//! `opus` module, behind the `opus` feature as it builds libopus from
+//! source, which needs cmake.
+//!
+//! Muxers write their headers when the first video keyframe arrives, so an
+//! audio track is declared to them up front as an [`AudioTrack`].
+
+use std::time::{Duration, SystemTime};
+
+use bytes::{BufMut, Bytes};
+use streaming_core::audio::samples_duration;
+use streaming_core::AudioFrame;
+
+use crate::{CodecError, CodecId, EncodedFrame};
+
+/// Compresses audio frames into [`EncodedFrame`]s.
+pub trait AudioEncoder: Send {
+    fn codec(&self) -> CodecId;
+
+    /// Encode one run of samples.
+    ///
+    /// Encoders working on fixed frame sizes buffer samples, returning no
+    /// packet for some inputs and several for others.
+    fn encode(&mut self, frame: &AudioFrame) -> Result<Vec<EncodedFrame>, CodecError>;
+
+    /// Encode buffered samples, padded with silence to a whole frame
+    fn flush(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        Ok(Vec::new())
+    }
+
+    /// Out-of-band decoder configuration
+    fn extradata(&self) -> Option<Bytes> {
+        None
+    }
+}
+
+/// Turns audio [`EncodedFrame`]s back into samples.
+pub trait AudioDecoder: Send {
+    fn codec(&self) -> CodecId;
+
+    fn decode(&mut self, packet: &EncodedFrame) -> Result<AudioFrame, CodecError>;
+}
+
+/// Cuts audio into frames of a fixed number of samples per channel.
+///
+/// Output frames are timestamped from the input: a frame starting inside an
+/// input frame gets that frame's timestamp plus the offset of its first
+/// sample, so gaps in the input show up as jumps rather than drift.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use streaming_codec::audio::Rechunker;
+/// use streaming_core::ToneSource;
+///
+/// // 10 ms from the source, 20 ms out
+/// let mut rechunker = Rechunker::new(48_000, 1, 960);
+/// let mut source = ToneSource::new(48_000, 1).frame_duration(Duration::from_millis(10));
+/// assert!(rechunker.push(&source.next_frame())?.is_empty());
+/// assert_eq!(rechunker.push(&source.next_frame())?[0].frames(), 960);
+/// # Ok::<(), streaming_codec::CodecError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Rechunker {
+    sample_rate: u32,
+    channels: u16,
+    frame_size: usize,
+    buffer: Vec<i16>,
+    /// Capture time of the first buffered sample
+    start: Option<SystemTime>,
+    sequence: usize,
+}
+
+impl Rechunker {
+    /// Frames of `frame_size` samples per channel, for input at
+    /// `sample_rate` with `channels`
+    pub fn new(sample_rate: u32, channels: u16, frame_size: usize) -> Self {
+        Self {
+            sample_rate,
+            channels: channels.max(1),
+            frame_size: frame_size.max(1),
+            buffer: Vec::new(),
+            start: None,
+            sequence: 0,
+        }
+    }
+
+    /// Length of one output frame
+    pub fn frame_duration(&self) -> Duration {
+        samples_duration(self.frame_size as u64, self.sample_rate)
+    }
+
+    /// Samples per channel waiting for a full frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() / self.channels as usize
+    }
+
+    /// Add `frame`, returning every frame completed by it.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the sample rate or channel count
+    /// differs from the one given at construction.
+    pub fn push(&mut self, frame: &AudioFrame) -> Result<Vec<AudioFrame>, CodecError> {
+        if (frame.sample_rate, frame.channels) != (self.sample_rate, self.channels) {
+            return Err(CodecError::InvalidData(format!(
+                "{} Hz audio with {} channels, expected {} Hz with {}",
+                frame.sample_rate, frame.channels, self.sample_rate, self.channels
+            )));
+        }
+        if self.buffer.is_empty() {
+            self.start = Some(frame.timestamp);
+        }
+        self.buffer.extend_from_slice(&frame.samples);
+
+        let chunk = self.frame_size * self.channels as usize;
+        let mut frames = Vec::new();
+        while self.buffer.len() >= chunk {
+            let samples: Vec<i16> = self.buffer.drain(..chunk).collect();
+            frames.push(self.frame(samples));
+        }
+        // Leftovers all from this input restart from its clock
+        if !self.buffer.is_empty() && self.buffer.len() <= frame.samples.len() {
+            let offset = (frame.samples.len() - self.buffer.len()) / self.channels as usize;
+            self.start = Some(frame.timestamp + samples_duration(offset as u64, self.sample_rate));
+        }
+        Ok(frames)
+    }
+
+    /// The buffered samples padded with silence to a whole frame, if any
+    pub fn flush(&mut self) -> Option<AudioFrame> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let mut samples = std::mem::take(&mut self.buffer);
+        samples.resize(self.frame_size * self.channels as usize, 0);
+        Some(self.frame(samples))
+    }
+
+    fn frame(&mut self, samples: Vec<i16>) -> AudioFrame {
+        let timestamp = self.start.unwrap_or(SystemTime::UNIX_EPOCH);
+        self.start = Some(timestamp + self.frame_duration());
+        self.sequence += 1;
+        AudioFrame {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp,
+            sequence: self.sequence,
+            samples,
+        }
+    }
+}
+
+/// Rate Opus always decodes at, whatever the input rate was
+pub const OPUS_SAMPLE_RATE: u32 = 48_000;
+
+/// Parameters of an audio track, declared to a muxer before writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioTrack {
+    pub codec: CodecId,
+    pub channels: u16,
+
+    /// Rate the audio was captured at
+    pub input_sample_rate: u32,
+
+    /// Samples per channel at 48 kHz the decoder discards at the start
+    pub pre_skip: u16,
+}
+
+impl AudioTrack {
+    /// Opus track described by an `OpusHead`, as found in the extradata of
+    /// Opus packets and returned by [`AudioEncoder::extradata`]
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if `head` is not an `OpusHead`, and
+    /// [`CodecError::Unsupported`] for channel mappings beyond mono and
+    /// stereo.
+    pub fn from_opus_head(head: &[u8]) -> Result<Self, CodecError> {
+        if head.len() < 19 || &head[..8] != b"OpusHead" || head[8] & 0xF0 != 0 {
+            return Err(CodecError::InvalidData("not an OpusHead header".to_string()));
+        }
+        if head[18] != 0 {
+            return Err(CodecError::Unsupported("Opus channel mapping families other than 0"));
+        }
+        Ok(Self {
+            codec: CodecId::Opus,
+            channels: head[9] as u16,
+            input_sample_rate: u32::from_le_bytes([head[12], head[13], head[14], head[15]]),
+            pre_skip: u16::from_le_bytes([head[10], head[11]]),
+        })
+    }
+
+    /// Rate of the decoded samples and of the track's timestamps
+    pub fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
+    /// Decoder configuration as stored in containers
+    pub fn extradata(&self) -> Bytes {
+        opus_head(self.channels, self.input_sample_rate, self.pre_skip)
+    }
+}
+
+/// Length of an Opus packet, read from its table-of-contents byte
+/// (RFC 6716, section 3.1), or `None` if the packet is malformed
+pub fn opus_packet_duration(packet: &[u8]) -> Option<Duration> {
+    let toc = *packet.first()?;
+    let config = (toc >> 3) as usize;
+    // Samples per frame at 48 kHz for SILK, hybrid and CELT configurations
+    let frame = match config {
+        0..=11 => [480, 960, 1920, 2880][config % 4],
+        12..=15 => [480, 960][config % 2],
+        _ => [120, 240, 480, 960][config % 4],
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3F) as u64,
+    };
+    Some(samples_duration(frame * frames, OPUS_SAMPLE_RATE))
+}
+
+/// `OpusHead` identification header (RFC 7845), the decoder configuration
+/// Matroska and MP4 store for Opus tracks
+pub fn opus_head(channels: u16, input_sample_rate: u32, pre_skip: u16) -> Bytes {
+    let mut out = Vec::with_capacity(19);
+    out.put_slice(b"OpusHead");
+    out.put_u8(1);
+    out.put_u8(channels as u8);
+    out.put_u16_le(pre_skip);
+    out.put_u32_le(input_sample_rate);
+    // Output gain, and channel mapping family 0 (mono or stereo)
+    out.put_i16_le(0);
+    out.put_u8(0);
+    Bytes::from(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_core::ToneSource;
+
+    #[test]
+    fn rechunks_with_input_timestamps() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut source = ToneSource::new(16_000, 2).frame_duration(Duration::from_millis(15)).start_time(start);
+        let mut rechunker = Rechunker::new(16_000, 2, 320);
+
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            frames.extend(rechunker.push(&source.next_frame()).unwrap());
+        }
+        // 60 ms in, three 20 ms frames out, each starting where it should
+        let starts: Vec<_> = frames.iter().map(|frame| frame.timestamp.duration_since(start).unwrap()).collect();
+        assert_eq!(starts, [0, 20, 40].map(Duration::from_millis));
+        assert!(frames.iter().all(|frame| frame.samples.len() == 640));
+        let input: Vec<i16> = ToneSource::new(16_000, 2).take(3).flat_map(|frame| frame.samples).collect();
+        assert_eq!(frames.iter().flat_map(|frame| frame.samples.clone()).collect::<Vec<_>>(), input);
+
+        // After a 100 ms gap the next frame starts at the new input's time
+        let late = AudioFrame { timestamp: start + Duration::from_millis(160), ..source.next_frame() };
+        assert!(rechunker.push(&late).unwrap().is_empty());
+        let tail = rechunker.flush().unwrap();
+        assert_eq!(tail.timestamp, late.timestamp);
+        assert_eq!(tail.samples[..480], late.samples[..]);
+        assert!(tail.samples[480..].iter().all(|&sample| sample == 0));
+        assert!(rechunker.flush().is_none());
+
+        let mono = ToneSource::new(16_000, 1).next_frame();
+        assert!(matches!(rechunker.push(&mono), Err(CodecError::InvalidData(_))));
+    }
+
+    #[test]
+    fn writes_opus_head() {
+        let head = opus_head(2, 48_000, 312);
+        assert_eq!(&head[..], b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00");
+
+        let track = AudioTrack::from_opus_head(&head).unwrap();
+        assert_eq!((track.channels, track.input_sample_rate, track.pre_skip), (2, 48_000, 312));
+        assert_eq!(track.extradata(), head);
+        assert!(AudioTrack::from_opus_head(&head[..12]).is_err());
+
+        // 20 ms CELT fullband, one frame and then three
+        assert_eq!(opus_packet_duration(&[0xF8, 0]), Some(Duration::from_millis(20)));
+        assert_eq!(opus_packet_duration(&[0xFB, 0x03]), Some(Duration::from_millis(60)));
+        assert_eq!(opus_packet_duration(&[]), None);
+    }
+}
@@ -0,0 +1,973 @@
+//! H.264 bitstream tooling: NAL unit framing, parameter sets and SPS parsing.
+//!
+//! Encoders and cameras produce Annex-B byte streams, where NAL units are
+//! separated by `00 00 01` start codes. MP4 and Matroska store AVCC instead:
+//! each NAL unit is prefixed with its big-endian length, and the SPS and PPS
+//! travel separately in an [`AvcConfig`] record. Everything here treats its
+//! input as untrusted and reports malformed data as
+//! [`CodecError::InvalidData`] rather than panicking.
+
+use bytes::Bytes;
+use streaming_core::Metadata;
+
+use crate::CodecError;
+
+/// Type of a NAL unit, from the low five bits of its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NalType {
+    Slice,
+    PartitionA,
+    PartitionB,
+    PartitionC,
+    IdrSlice,
+    Sei,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    EndOfSequence,
+    EndOfStream,
+    Filler,
+    Other(u8),
+}
+
+impl NalType {
+    pub fn from_header(header: u8) -> Self {
+        match header & 0x1F {
+            1 => NalType::Slice,
+            2 => NalType::PartitionA,
+            3 => NalType::PartitionB,
+            4 => NalType::PartitionC,
+            5 => NalType::IdrSlice,
+            6 => NalType::Sei,
+            7 => NalType::Sps,
+            8 => NalType::Pps,
+            9 => NalType::AccessUnitDelimiter,
+            10 => NalType::EndOfSequence,
+            11 => NalType::EndOfStream,
+            12 => NalType::Filler,
+            other => NalType::Other(other),
+        }
+    }
+
+    /// Whether the unit carries coded picture data
+    pub fn is_vcl(&self) -> bool {
+        matches!(
+            self,
+            NalType::Slice | NalType::PartitionA | NalType::PartitionB | NalType::PartitionC | NalType::IdrSlice
+        )
+    }
+}
+
+/// One NAL unit, header byte included, without start code or length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalUnit<'a>(&'a [u8]);
+
+impl<'a> NalUnit<'a> {
+    /// Wrap `data`, which must at least hold the header byte
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if data.is_empty() {
+            None
+        } else {
+            Some(Self(data))
+        }
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn nal_type(&self) -> NalType {
+        NalType::from_header(self.0[0])
+    }
+
+    /// `nal_ref_idc`; zero for units no other picture refers to
+    pub fn ref_idc(&self) -> u8 {
+        (self.0[0] >> 5) & 0x03
+    }
+}
+
+/// Split an Annex-B byte stream into NAL units.
+///
+/// Bytes before the first start code and trailing zero padding are dropped.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_codec::bitstream::{split_annex_b, NalType};
+///
+/// let stream = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE];
+/// let types: Vec<_> = split_annex_b(&stream).iter().map(|nal| nal.nal_type()).collect();
+/// assert_eq!(types, [NalType::Sps, NalType::Pps]);
+/// ```
+pub fn split_annex_b(data: &[u8]) -> Vec<NalUnit<'_>> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .filter_map(|(n, &start)| {
+            // The next unit's start code ends three bytes before its payload
+            let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+            let mut unit = &data[start..end];
+            while let [rest @ .., 0] = unit {
+                unit = rest;
+            }
+            NalUnit::new(unit)
+        })
+        .collect()
+}
+
+/// Split an AVCC buffer whose NAL units are prefixed with `length_size`-byte
+/// big-endian lengths.
+///
+/// # Errors
+///
+/// [`CodecError::InvalidData`] for an unsupported length size, a length
+/// running past the end of the buffer or an empty unit.
+pub fn split_avcc(data: &[u8], length_size: usize) -> Result<Vec<NalUnit<'_>>, CodecError> {
+    check_length_size(length_size)?;
+
+    let mut units = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < length_size {
+            return Err(CodecError::InvalidData("truncated AVCC length prefix".to_string()));
+        }
+        let (prefix, tail) = rest.split_at(length_size);
+        let length = prefix.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        if length > tail.len() {
+            return Err(CodecError::InvalidData(format!(
+                "AVCC NAL unit of {} bytes overruns buffer of {}",
+                length,
+                tail.len()
+            )));
+        }
+        let (unit, tail) = tail.split_at(length);
+        units.push(NalUnit::new(unit).ok_or_else(|| CodecError::InvalidData("empty AVCC NAL unit".to_string()))?);
+        rest = tail;
+    }
+    Ok(units)
+}
+
+/// Convert an Annex-B stream to AVCC with `length_size`-byte prefixes.
+///
+/// # Errors
+///
+/// [`CodecError::InvalidData`] for an unsupported length size or a NAL unit
+/// too long for it.
+pub fn annex_b_to_avcc(data: &[u8], length_size: usize) -> Result<Vec<u8>, CodecError> {
+    write_avcc(&split_annex_b(data), length_size)
+}
+
+/// Convert an Annex-B access unit to a sample as MP4 and Matroska store it:
+/// length-prefixed, without the parameter sets and delimiters that the
+/// container keeps in its decoder configuration.
+///
+/// # Errors
+///
+/// As [`annex_b_to_avcc`].
+pub fn annex_b_to_sample(data: &[u8], length_size: usize) -> Result<Vec<u8>, CodecError> {
+    let units: Vec<_> = split_annex_b(data)
+        .into_iter()
+        .filter(|unit| !matches!(unit.nal_type(), NalType::Sps | NalType::Pps | NalType::AccessUnitDelimiter))
+        .collect();
+    write_avcc(&units, length_size)
+}
+
+/// Convert an AVCC buffer to an Annex-B stream with four-byte start codes.
+///
+/// # Errors
+///
+/// See [`split_avcc`].
+pub fn avcc_to_annex_b(data: &[u8], length_size: usize) -> Result<Vec<u8>, CodecError> {
+    Ok(write_annex_b(&split_avcc(data, length_size)?))
+}
+
+/// Join NAL units into an Annex-B stream with four-byte start codes
+pub fn write_annex_b(units: &[NalUnit<'_>]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(units.iter().map(|unit| unit.data().len() + 4).sum());
+    for unit in units {
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(unit.data());
+    }
+    out
+}
+
+/// Join NAL units into an AVCC buffer with `length_size`-byte prefixes.
+///
+/// # Errors
+///
+/// [`CodecError::InvalidData`] for an unsupported length size or a NAL unit
+/// too long for it.
+pub fn write_avcc(units: &[NalUnit<'_>], length_size: usize) -> Result<Vec<u8>, CodecError> {
+    check_length_size(length_size)?;
+
+    let max_length = (1u64 << (8 * length_size)) - 1;
+    let mut out = Vec::with_capacity(units.iter().map(|unit| unit.data().len() + length_size).sum());
+    for unit in units {
+        let length = unit.data().len() as u64;
+        if length > max_length {
+            return Err(CodecError::InvalidData(format!(
+                "NAL unit of {} bytes does not fit a {}-byte length",
+                length, length_size
+            )));
+        }
+        out.extend_from_slice(&length.to_be_bytes()[8 - length_size..]);
+        out.extend_from_slice(unit.data());
+    }
+    Ok(out)
+}
+
+/// Group the NAL units of an Annex-B stream into access units (pictures).
+///
+/// A new access unit starts at a delimiter, parameter set or SEI following
+/// picture data, or at a slice whose `first_mb_in_slice` is zero.
+pub fn split_access_units(data: &[u8]) -> Vec<Vec<NalUnit<'_>>> {
+    let mut access_units: Vec<Vec<NalUnit<'_>>> = Vec::new();
+    let mut current = Vec::new();
+    let mut has_picture = false;
+
+    for unit in split_annex_b(data) {
+        let nal_type = unit.nal_type();
+        let starts_picture = match nal_type {
+            NalType::AccessUnitDelimiter | NalType::Sps | NalType::Pps | NalType::Sei => true,
+            // first_mb_in_slice is ue(v), so a leading 1 bit means zero
+            t if t.is_vcl() => unit.data().get(1).is_some_and(|byte| byte & 0x80 != 0),
+            _ => false,
+        };
+        if has_picture && starts_picture {
+            access_units.push(std::mem::take(&mut current));
+            has_picture = false;
+        }
+        has_picture |= nal_type.is_vcl();
+        current.push(unit);
+    }
+    if !current.is_empty() {
+        access_units.push(current);
+    }
+    access_units
+}
+
+/// Whether an Annex-B access unit holds an IDR slice
+pub fn is_idr(data: &[u8]) -> bool {
+    split_annex_b(data).iter().any(|unit| unit.nal_type() == NalType::IdrSlice)
+}
+
+/// SEI payload type of a recovery point, which marks where decoding can start
+/// in a stream refreshed with intra macroblocks instead of IDRs
+const SEI_RECOVERY_POINT: u32 = 6;
+
+/// Whether an Annex-B access unit carries a recovery point SEI message
+pub fn is_recovery_point(data: &[u8]) -> bool {
+    split_annex_b(data)
+        .iter()
+        .filter(|unit| unit.nal_type() == NalType::Sei)
+        .any(|unit| sei_messages(unit.data()).iter().any(|(kind, _)| *kind == SEI_RECOVERY_POINT))
+}
+
+/// Whether decoding can start at an Annex-B access unit: it holds an IDR
+/// slice or a recovery point
+pub fn is_random_access(data: &[u8]) -> bool {
+    is_idr(data) || is_recovery_point(data)
+}
+
+/// SEI payload type of unregistered user data: a UUID and opaque bytes
+const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+
+/// UUID identifying frame metadata among user data SEI messages
+pub const METADATA_UUID: [u8; 16] = [
+    0x6d, 0x65, 0x74, 0x61, 0x2e, 0x73, 0x74, 0x72, 0x65, 0x61, 0x6d, 0x69, 0x6e, 0x67, 0x00, 0x01,
+];
+
+/// Build an SEI NAL unit, without start code, carrying `metadata` as
+/// unregistered user data under [`METADATA_UUID`]
+pub fn metadata_sei(metadata: &Metadata) -> Vec<u8> {
+    let mut payload = METADATA_UUID.to_vec();
+    payload.extend_from_slice(&metadata.to_bytes());
+
+    let mut rbsp = Vec::with_capacity(payload.len() + payload.len() / 255 + 4);
+    for value in [SEI_USER_DATA_UNREGISTERED as usize, payload.len()] {
+        rbsp.extend(std::iter::repeat_n(0xFF, value / 255));
+        rbsp.push((value % 255) as u8);
+    }
+    rbsp.extend_from_slice(&payload);
+    rbsp.push(0x80);
+
+    let mut unit = vec![0x06];
+    unit.extend_from_slice(&escape(&rbsp));
+    unit
+}
+
+/// Insert an SEI NAL unit into an Annex-B access unit, ahead of its first
+/// slice as the standard requires
+pub fn insert_sei(access_unit: &[u8], sei: &[u8]) -> Vec<u8> {
+    let units = split_annex_b(access_unit);
+    let at = units.iter().position(|unit| unit.nal_type().is_vcl()).unwrap_or(units.len());
+    let mut out = Vec::with_capacity(access_unit.len() + sei.len() + 4);
+    for (index, unit) in units.iter().enumerate() {
+        if index == at {
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(sei);
+        }
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(unit.data());
+    }
+    if at == units.len() {
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(sei);
+    }
+    out
+}
+
+/// Embed `metadata` in an Annex-B access unit, replacing metadata embedded
+/// earlier, e.g. by the camera
+pub fn embed_metadata(access_unit: &[u8], metadata: &Metadata) -> Vec<u8> {
+    let units: Vec<_> = split_annex_b(access_unit)
+        .into_iter()
+        .filter(|unit| !is_metadata_sei(unit))
+        .collect();
+    insert_sei(&write_annex_b(&units), &metadata_sei(metadata))
+}
+
+fn is_metadata_sei(unit: &NalUnit<'_>) -> bool {
+    unit.nal_type() == NalType::Sei
+        && sei_messages(unit.data())
+            .iter()
+            .any(|(kind, payload)| *kind == SEI_USER_DATA_UNREGISTERED && payload.starts_with(&METADATA_UUID))
+}
+
+/// Frame metadata embedded in an Annex-B access unit by [`metadata_sei`],
+/// if any
+///
+/// # Errors
+///
+/// [`CodecError::InvalidData`] if a metadata message is present but cannot
+/// be decoded.
+pub fn extract_metadata(data: &[u8]) -> Result<Option<Metadata>, CodecError> {
+    let messages = split_annex_b(data)
+        .into_iter()
+        .filter(is_metadata_sei)
+        .flat_map(|unit| sei_messages(unit.data()));
+    for (kind, payload) in messages {
+        if kind == SEI_USER_DATA_UNREGISTERED && payload.starts_with(&METADATA_UUID) {
+            return Metadata::from_bytes(&payload[METADATA_UUID.len()..])
+                .map(Some)
+                .map_err(|e| CodecError::InvalidData(e.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Payload type and payload of the messages in an SEI NAL unit
+fn sei_messages(unit: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let payload = unescape(unit.get(1..).unwrap_or_default());
+    let mut messages = Vec::new();
+    let mut pos = 0;
+    // Type and size are each coded as a run of 0xFF bytes plus a final byte
+    let read = |pos: &mut usize| -> Option<u32> {
+        let mut value = 0u32;
+        loop {
+            let byte = *payload.get(*pos)?;
+            *pos += 1;
+            value = value.saturating_add(byte as u32);
+            if byte != 0xFF {
+                return Some(value);
+            }
+        }
+    };
+    // Messages run up to the RBSP trailing bits
+    while payload.get(pos).is_some_and(|&byte| byte != 0x80) {
+        let (Some(kind), Some(size)) = (read(&mut pos), read(&mut pos)) else {
+            break;
+        };
+        let Some(body) = payload.get(pos..).and_then(|rest| rest.get(..size as usize)) else {
+            break;
+        };
+        messages.push((kind, body.to_vec()));
+        pos += size as usize;
+    }
+    messages
+}
+
+fn check_length_size(length_size: usize) -> Result<(), CodecError> {
+    match length_size {
+        1 | 2 | 4 => Ok(()),
+        _ => Err(CodecError::InvalidData(format!("unsupported AVCC length size {}", length_size))),
+    }
+}
+
+/// Decoder configuration record (`avcC`), as stored by MP4 and Matroska.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcConfig {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+
+    /// Size of the NAL length prefixes in samples, see
+    /// [`AvcConfig::length_size`]
+    length_size: usize,
+    pub sps: Vec<Bytes>,
+    pub pps: Vec<Bytes>,
+}
+
+impl AvcConfig {
+    /// Collect the parameter sets of an Annex-B stream (e.g. encoder
+    /// extradata or a keyframe), using four-byte lengths.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the stream lacks an SPS or PPS.
+    pub fn from_annex_b(data: &[u8]) -> Result<Self, CodecError> {
+        let units = split_annex_b(data);
+        let collect = |nal_type| -> Vec<Bytes> {
+            units
+                .iter()
+                .filter(|unit| unit.nal_type() == nal_type)
+                .map(|unit| Bytes::copy_from_slice(unit.data()))
+                .collect()
+        };
+        let (sps, pps) = (collect(NalType::Sps), collect(NalType::Pps));
+
+        let first = sps.first().ok_or_else(|| CodecError::InvalidData("no SPS in stream".to_string()))?;
+        if first.len() < 4 {
+            return Err(CodecError::InvalidData("SPS too short".to_string()));
+        }
+        if pps.is_empty() {
+            return Err(CodecError::InvalidData("no PPS in stream".to_string()));
+        }
+
+        Ok(Self {
+            profile_idc: first[1],
+            constraint_flags: first[2],
+            level_idc: first[3],
+            length_size: 4,
+            sps,
+            pps,
+        })
+    }
+
+    /// Size of the NAL length prefixes in samples: 1, 2 or 4
+    pub fn length_size(&self) -> usize {
+        self.length_size
+    }
+
+    /// Prefix NAL units with `length_size`-byte lengths.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] unless `length_size` is 1, 2 or 4.
+    pub fn with_length_size(mut self, length_size: usize) -> Result<Self, CodecError> {
+        check_length_size(length_size)?;
+        self.length_size = length_size;
+        Ok(self)
+    }
+
+    /// Parse an `avcC` record.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the record is truncated, has an
+    /// unknown version or a length size other than 1, 2 or 4.
+    pub fn parse(data: &[u8]) -> Result<Self, CodecError> {
+        let truncated = || CodecError::InvalidData("truncated avcC record".to_string());
+        let header = data.get(..6).ok_or_else(truncated)?;
+        if header[0] != 1 {
+            return Err(CodecError::InvalidData(format!("unknown avcC version {}", header[0])));
+        }
+        let length_size = (header[4] & 0x03) as usize + 1;
+        check_length_size(length_size)?;
+
+        let mut pos = 5;
+        let read_sets = |count: usize, pos: &mut usize| -> Result<Vec<Bytes>, CodecError> {
+            let mut sets = Vec::with_capacity(count);
+            for _ in 0..count {
+                let length = data.get(*pos..*pos + 2).ok_or_else(truncated)?;
+                let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+                let set = data.get(*pos + 2..*pos + 2 + length).ok_or_else(truncated)?;
+                sets.push(Bytes::copy_from_slice(set));
+                *pos += 2 + length;
+            }
+            Ok(sets)
+        };
+
+        let sps_count = (data[pos] & 0x1F) as usize;
+        pos += 1;
+        let sps = read_sets(sps_count, &mut pos)?;
+        let pps_count = *data.get(pos).ok_or_else(truncated)? as usize;
+        pos += 1;
+        let pps = read_sets(pps_count, &mut pos)?;
+
+        Ok(Self {
+            profile_idc: header[1],
+            constraint_flags: header[2],
+            level_idc: header[3],
+            length_size,
+            sps,
+            pps,
+        })
+    }
+
+    /// Serialise as an `avcC` record
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![
+            1,
+            self.profile_idc,
+            self.constraint_flags,
+            self.level_idc,
+            0xFC | (self.length_size as u8 - 1),
+            0xE0 | self.sps.len() as u8,
+        ];
+        for set in &self.sps {
+            out.extend_from_slice(&(set.len() as u16).to_be_bytes());
+            out.extend_from_slice(set);
+        }
+        out.push(self.pps.len() as u8);
+        for set in &self.pps {
+            out.extend_from_slice(&(set.len() as u16).to_be_bytes());
+            out.extend_from_slice(set);
+        }
+        out
+    }
+
+    /// Parameter sets as an Annex-B stream, SPS first
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        let units: Vec<_> = self.sps.iter().chain(&self.pps).filter_map(|set| NalUnit::new(set)).collect();
+        write_annex_b(&units)
+    }
+
+    /// RFC 6381 codec string, e.g. `avc1.42C01E`
+    pub fn codec_string(&self) -> String {
+        format!("avc1.{:02X}{:02X}{:02X}", self.profile_idc, self.constraint_flags, self.level_idc)
+    }
+}
+
+/// Fields of a sequence parameter set the pipeline cares about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+
+    /// Level times ten, e.g. 31 for level 3.1
+    pub level_idc: u8,
+    pub id: u32,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+
+    /// Displayed size, after frame cropping
+    pub width: u32,
+    pub height: u32,
+
+    /// False for interlaced (field or MBAFF) streams
+    pub frame_mbs_only: bool,
+}
+
+impl Sps {
+    /// Parse an SPS NAL unit.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if `unit` is not an SPS, is truncated or
+    /// holds out-of-range values.
+    pub fn parse(unit: NalUnit<'_>) -> Result<Self, CodecError> {
+        if unit.nal_type() != NalType::Sps {
+            return Err(CodecError::InvalidData(format!("expected SPS, got {:?}", unit.nal_type())));
+        }
+        let mut r = BitReader::new(unescape(&unit.data()[1..]));
+
+        let profile_idc = r.bits(8)? as u8;
+        let constraint_flags = r.bits(8)? as u8;
+        let level_idc = r.bits(8)? as u8;
+        let id = r.ue_max(31)?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let (mut bit_depth_luma, mut bit_depth_chroma) = (8, 8);
+        if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+            chroma_format_idc = r.ue_max(3)?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.flag()?;
+            }
+            bit_depth_luma = r.ue_max(6)? + 8;
+            bit_depth_chroma = r.ue_max(6)? + 8;
+            r.flag()?; // qpprime_y_zero_transform_bypass_flag
+            if r.flag()? {
+                let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..lists {
+                    if r.flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        r.ue_max(12)?; // log2_max_frame_num_minus4
+        match r.ue_max(2)? {
+            0 => {
+                r.ue_max(12)?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                r.flag()?; // delta_pic_order_always_zero_flag
+                r.se()?; // offset_for_non_ref_pic
+                r.se()?; // offset_for_top_to_bottom_field
+                for _ in 0..r.ue_max(255)? {
+                    r.se()?; // offset_for_ref_frame
+                }
+            }
+            _ => {}
+        }
+        r.ue()?; // max_num_ref_frames
+        r.flag()?; // gaps_in_frame_num_value_allowed_flag
+
+        let width_mbs = r.ue_max(1023)? as u64 + 1;
+        let height_map_units = r.ue_max(1023)? as u64 + 1;
+        let frame_mbs_only = r.flag()?;
+        if !frame_mbs_only {
+            r.flag()?; // mb_adaptive_frame_field_flag
+        }
+        r.flag()?; // direct_8x8_inference_flag
+
+        let mut width = width_mbs * 16;
+        let mut height = height_map_units * 16 * if frame_mbs_only { 1 } else { 2 };
+        if r.flag()? {
+            let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+            let (sub_width, sub_height) = match chroma_array_type {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let crop_x = sub_width;
+            let crop_y = sub_height * if frame_mbs_only { 1 } else { 2 };
+            let (left, right, top, bottom) = (r.ue()? as u64, r.ue()? as u64, r.ue()? as u64, r.ue()? as u64);
+
+            let crop_width = (left + right) * crop_x;
+            let crop_height = (top + bottom) * crop_y;
+            if crop_width >= width || crop_height >= height {
+                return Err(CodecError::InvalidData("SPS cropping exceeds the coded size".to_string()));
+            }
+            width -= crop_width;
+            height -= crop_height;
+        }
+
+        Ok(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            id,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            width: width as u32,
+            height: height as u32,
+            frame_mbs_only,
+        })
+    }
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Result<(), CodecError> {
+    let (mut last, mut next) = (8i64, 8i64);
+    for _ in 0..size {
+        if next != 0 {
+            let delta = r.se()?;
+            if !(-128..=127).contains(&delta) {
+                return Err(CodecError::InvalidData("scaling list delta out of range".to_string()));
+            }
+            next = (last + delta + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Ok(())
+}
+
+/// Insert emulation prevention bytes so no `00 00 0x` with x <= 3 remains
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 64);
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+/// Remove emulation prevention bytes (`00 00 03` -> `00 00`)
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+/// MSB-first reader with Exp-Golomb decoding
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(data: Vec<u8>) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u32, CodecError> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or_else(|| CodecError::InvalidData("SPS truncated".to_string()))?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u32)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, CodecError> {
+        (0..count).try_fold(0, |acc, _| Ok((acc << 1) | self.bit()?))
+    }
+
+    fn flag(&mut self) -> Result<bool, CodecError> {
+        Ok(self.bit()? == 1)
+    }
+
+    fn ue(&mut self) -> Result<u32, CodecError> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return Err(CodecError::InvalidData("Exp-Golomb code too long".to_string()));
+            }
+        }
+        let value = (1u64 << zeros) - 1 + self.bits(zeros)? as u64;
+        u32::try_from(value).map_err(|_| CodecError::InvalidData("Exp-Golomb value out of range".to_string()))
+    }
+
+    fn ue_max(&mut self, max: u32) -> Result<u32, CodecError> {
+        let value = self.ue()?;
+        if value > max {
+            return Err(CodecError::InvalidData(format!("SPS field {} exceeds {}", value, max)));
+        }
+        Ok(value)
+    }
+
+    fn se(&mut self) -> Result<i64, CodecError> {
+        let value = self.ue()? as i64;
+        Ok(if value % 2 == 1 { (value + 1) / 2 } else { -(value / 2) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encoder, EncoderConfig, H264Encoder};
+    use streaming_core::{PixelFormat, SyntheticSource};
+
+    fn encoded_keyframe(width: u32, height: u32, low_latency: bool) -> Vec<u8> {
+        let mut config = EncoderConfig::new(width, height, 30);
+        config.low_latency = low_latency;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let frame = SyntheticSource::new(PixelFormat::YU12, width, height).next_frame();
+        encoder.encode(&frame).unwrap().remove(0).data.to_vec()
+    }
+
+    /// Deterministic xorshift noise for the malformed-input tests
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_annex_b_with_mixed_start_codes() {
+        let stream = [0xAA, 0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 0, 1, 0x65, 4, 0, 0];
+        let units = split_annex_b(&stream);
+        let data: Vec<_> = units.iter().map(|unit| unit.data()).collect();
+        assert_eq!(data, [&[0x67, 1, 2][..], &[0x68, 3], &[0x65, 4]]);
+        assert!(is_idr(&stream));
+        assert_eq!(units[2].ref_idc(), 3);
+    }
+
+    #[test]
+    fn groups_access_units() {
+        let mut config = EncoderConfig::new(64, 48, 30);
+        config.keyframe_interval = 3;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let mut stream = Vec::new();
+        let mut packets = Vec::new();
+        for frame in SyntheticSource::new(PixelFormat::YU12, 64, 48).velocity(2, 2).take(5) {
+            let packet = encoder.encode(&frame).unwrap().remove(0);
+            stream.extend_from_slice(&packet.data);
+            packets.push(packet);
+        }
+
+        let access_units = split_access_units(&stream);
+        assert_eq!(access_units.len(), packets.len());
+        for (units, packet) in access_units.iter().zip(&packets) {
+            assert_eq!(write_annex_b(units), annex_b_to_avcc(&packet.data, 4).and_then(|avcc| avcc_to_annex_b(&avcc, 4)).unwrap());
+            assert_eq!(units.iter().any(|unit| unit.nal_type() == NalType::IdrSlice), packet.keyframe);
+        }
+    }
+
+    #[test]
+    fn converts_between_annex_b_and_avcc() {
+        let annex_b = encoded_keyframe(64, 48, true);
+        for length_size in [2, 4] {
+            let avcc = annex_b_to_avcc(&annex_b, length_size).unwrap();
+            let units = split_avcc(&avcc, length_size).unwrap();
+            assert_eq!(units, split_annex_b(&annex_b));
+            assert_eq!(split_annex_b(&avcc_to_annex_b(&avcc, length_size).unwrap()), units);
+        }
+
+        let long = [0u8, 0, 1, 0x65].iter().copied().chain([0x88; 300]).collect::<Vec<_>>();
+        assert!(annex_b_to_avcc(&long, 1).is_err());
+        assert!(annex_b_to_avcc(&long, 3).is_err());
+    }
+
+    #[test]
+    fn parses_encoder_sps() {
+        for (width, height, low_latency, profile) in [(320, 240, true, 66), (100, 62, true, 66), (64, 48, false, 77)] {
+            let keyframe = encoded_keyframe(width, height, low_latency);
+            let sps_unit = split_annex_b(&keyframe)
+                .into_iter()
+                .find(|unit| unit.nal_type() == NalType::Sps)
+                .unwrap();
+            let sps = Sps::parse(sps_unit).unwrap();
+            assert_eq!((sps.width, sps.height), (width, height));
+            assert_eq!(sps.profile_idc, profile);
+            assert!(sps.frame_mbs_only);
+        }
+    }
+
+    #[test]
+    fn avc_config_round_trips() {
+        let keyframe = encoded_keyframe(64, 48, true);
+        let config = AvcConfig::from_annex_b(&keyframe).unwrap();
+        assert_eq!((config.sps.len(), config.pps.len()), (1, 1));
+        assert!(config.codec_string().starts_with("avc1.42"));
+
+        let parsed = AvcConfig::parse(&config.to_bytes()).unwrap();
+        assert_eq!(parsed, config);
+        let short = config.clone().with_length_size(2).unwrap();
+        assert_eq!(AvcConfig::parse(&short.to_bytes()).unwrap().length_size(), 2);
+        assert!(config.clone().with_length_size(3).is_err());
+        assert!(config.clone().with_length_size(0).is_err());
+        let mut record = config.to_bytes();
+        record[4] = 0xFE;
+        assert!(AvcConfig::parse(&record).is_err());
+        let annex_b = parsed.to_annex_b();
+        let sets = split_annex_b(&annex_b);
+        assert_eq!(sets.iter().map(|unit| unit.nal_type()).collect::<Vec<_>>(), [NalType::Sps, NalType::Pps]);
+    }
+
+    #[test]
+    fn unescapes_emulation_prevention() {
+        assert_eq!(unescape(&[0, 0, 3, 1, 0, 0, 3, 0, 0, 3]), [0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn finds_recovery_points() {
+        // SEI with a 300-byte user data message (type 5) then a recovery
+        // point (type 6), ahead of a non-IDR slice
+        let mut sei = vec![0, 0, 0, 1, 0x06, 5, 0xFF, 45];
+        sei.extend([0x11; 300]);
+        sei.extend([6, 1, 0x84, 0x80]);
+        let slice = [0, 0, 0, 1, 0x41, 0x9A, 0x02];
+        let access_unit = [sei.as_slice(), &slice].concat();
+        assert!(is_recovery_point(&access_unit));
+        assert!(is_random_access(&access_unit) && !is_idr(&access_unit));
+
+        assert!(!is_random_access(&slice));
+        assert!(is_random_access(&encoded_keyframe(64, 48, true)));
+        // Truncated messages are not mistaken for anything
+        assert!(!is_recovery_point(&[0, 0, 0, 1, 0x06, 0xFF]));
+    }
+
+    #[test]
+    fn embeds_metadata_in_sei() {
+        let mut metadata = Metadata::new();
+        metadata.insert("note", "x".repeat(300));
+        // Zero runs in the payload need emulation prevention
+        metadata.insert("raw", vec![0u8, 0, 0, 1, 0, 0, 2]);
+
+        let sei = metadata_sei(&metadata);
+        assert!(!sei.windows(3).any(|w| w[0] == 0 && w[1] == 0 && w[2] <= 2));
+        let keyframe = encoded_keyframe(64, 48, true);
+        let access_unit = insert_sei(&keyframe, &sei);
+        let types: Vec<_> = split_annex_b(&access_unit).iter().map(|unit| unit.nal_type()).collect();
+        assert_eq!(types, [NalType::Sps, NalType::Pps, NalType::Sei, NalType::IdrSlice]);
+        assert_eq!(extract_metadata(&access_unit).unwrap(), Some(metadata));
+
+        assert_eq!(extract_metadata(&keyframe).unwrap(), None);
+        // A message cut short is not read, one with a bad body is an error
+        let truncated = [&sei[..sei.len() - 4], &[0x80]].concat();
+        assert_eq!(extract_metadata(&insert_sei(&keyframe, &truncated)).unwrap(), None);
+        let unknown_version = [&[0x06, 5, 17][..], &METADATA_UUID, &[9, 0x80]].concat();
+        assert!(extract_metadata(&insert_sei(&keyframe, &unknown_version)).is_err());
+    }
+
+    #[test]
+    fn survives_malformed_input() {
+        let keyframe = encoded_keyframe(64, 48, true);
+        let sps = split_annex_b(&keyframe)
+            .into_iter()
+            .find(|unit| unit.nal_type() == NalType::Sps)
+            .unwrap()
+            .data()
+            .to_vec();
+        let record = AvcConfig::from_annex_b(&keyframe).unwrap().to_bytes();
+
+        // Every truncation must fail cleanly, never panic
+        for len in 1..sps.len() - 1 {
+            let _ = Sps::parse(NalUnit::new(&sps[..len]).unwrap());
+        }
+        for len in 0..record.len() {
+            assert!(AvcConfig::parse(&record[..len]).is_err());
+        }
+
+        for seed in 0..500 {
+            let mut data = noise(seed, (seed as usize * 7) % 200 + 1);
+            let _ = split_annex_b(&data);
+            let _ = split_access_units(&data);
+            let _ = is_idr(&data);
+            for length_size in [1, 2, 4] {
+                let _ = split_avcc(&data, length_size);
+            }
+            let _ = AvcConfig::parse(&data);
+            let _ = AvcConfig::from_annex_b(&data);
+
+            data[0] = 0x67;
+            let _ = Sps::parse(NalUnit::new(&data).unwrap());
+
+            // Random bit flips in a real SPS
+            let mut flipped = sps.clone();
+            let bit = seed as usize % ((flipped.len() - 1) * 8) + 8;
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            let _ = Sps::parse(NalUnit::new(&flipped).unwrap());
+        }
+    }
+}
@@ -0,0 +1,689 @@
+//! H.264 encoding and decoding in software through OpenH264.
+//!
+//! Packets carry Annex-B byte streams. OpenH264 never reorders frames, so
+//! decode order equals presentation order and every input frame produces at
+//! most one packet, whatever [`EncoderConfig::low_latency`] says.
+//!
+//! OpenH264 refreshes the picture only with keyframes. Intra refresh, with
+//! recovery points instead of periodic IDRs, is left to cameras encoding in
+//! hardware, whose streams go through [`H264Passthrough`].
+
+use std::ffi::c_void;
+
+use bytes::Bytes;
+use openh264::decoder::Decoder as OpenH264Decoder;
+use openh264::encoder::{
+    BitRate, Encoder as OpenH264Encoder, EncoderConfig as OpenH264Config, FrameRate, FrameType,
+    IntraFramePeriod, Profile, QpRange, RateControlMode as OpenH264RateControl, UsageType,
+    VuiConfig,
+};
+use openh264::formats::{YUVBuffer, YUVSource};
+use openh264::{OpenH264API, Timestamp};
+use openh264_sys2::{
+    SBitrateInfo, ENCODER_OPTION, ENCODER_OPTION_BITRATE, ENCODER_OPTION_FRAME_RATE,
+    ENCODER_OPTION_MAX_BITRATE, LAYER_NUM, SPATIAL_LAYER_0, SPATIAL_LAYER_ALL,
+};
+use streaming_core::convert::convert;
+use streaming_core::{Frame, PixelFormat};
+
+use crate::bitstream::{
+    embed_metadata, extract_metadata, is_recovery_point, split_annex_b, write_annex_b, NalType,
+};
+use crate::mjpeg::{check_resolution, decode_jpeg};
+use crate::{
+    BitrateMeter, BitrateReport, CodecError, CodecId, Decoder, EncodedFrame, Encoder,
+    EncoderConfig, RateControlMode, StreamClock,
+};
+
+/// Formats [`H264Encoder`] accepts; everything but I420 is converted first
+pub const INPUTS: [PixelFormat; 6] = [
+    PixelFormat::YU12,
+    PixelFormat::YV12,
+    PixelFormat::YUYV,
+    PixelFormat::RGB3,
+    PixelFormat::BGR3,
+    PixelFormat::MJPG,
+];
+
+/// Software H.264 encoder.
+///
+/// The rate controller may skip frames to hold the target bitrate; those
+/// produce no packet. Low-latency configurations use the constrained baseline
+/// profile, which every decoder handles; otherwise the main profile is used
+/// for its better entropy coding.
+///
+/// [`RateControlMode::Cbr`] caps the peak at the target, [`RateControlMode::Vbr`]
+/// lets it reach [`EncoderConfig::max_bitrate`], and [`RateControlMode::Crf`]
+/// fixes the quantiser and never skips frames. Bitrate and frame rate changes
+/// are applied to the running encoder without a keyframe; switching mode
+/// restarts it.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_codec::{Encoder, EncoderConfig, H264Encoder};
+/// use streaming_core::{PixelFormat, SyntheticSource};
+///
+/// let mut encoder = H264Encoder::new(EncoderConfig::new(320, 240, 30)).unwrap();
+/// let frame = SyntheticSource::new(PixelFormat::YUYV, 320, 240).next_frame();
+/// let packets = encoder.encode(&frame).unwrap();
+/// assert!(packets[0].keyframe);
+/// assert!(encoder.extradata().is_some());
+/// ```
+pub struct H264Encoder {
+    config: EncoderConfig,
+    encoder: OpenH264Encoder,
+    clock: StreamClock,
+    meter: BitrateMeter,
+    extradata: Option<Bytes>,
+    /// OpenH264 sets itself up on the first frame; options can only be
+    /// changed on a running encoder after that
+    initialized: bool,
+}
+
+impl H264Encoder {
+    /// Create an encoder for `config`.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::Backend`] if OpenH264 fails to initialise.
+    pub fn new(config: EncoderConfig) -> Result<Self, CodecError> {
+        Ok(Self {
+            encoder: Self::open(&config)?,
+            meter: BitrateMeter::new(config.bitrate_window),
+            config,
+            clock: StreamClock::default(),
+            extradata: None,
+            initialized: false,
+        })
+    }
+
+    fn open(config: &EncoderConfig) -> Result<OpenH264Encoder, CodecError> {
+        let profile = if config.low_latency {
+            Profile::Baseline
+        } else {
+            Profile::Main
+        };
+        let settings = OpenH264Config::new()
+            .bitrate(BitRate::from_bps(config.bitrate))
+            .max_frame_rate(FrameRate::from_hz(config.fps.max(1) as f32))
+            .intra_frame_period(IntraFramePeriod::from_num_frames(config.keyframe_interval))
+            .usage_type(UsageType::CameraVideoRealTime)
+            .profile(profile)
+            .vui(VuiConfig::bt601());
+        let settings = match config.rate_control {
+            RateControlMode::Cbr | RateControlMode::Vbr => settings
+                .rate_control_mode(OpenH264RateControl::Bitrate)
+                .skip_frames(true),
+            RateControlMode::Crf(crf) => {
+                let qp = crf.min(51);
+                settings
+                    .rate_control_mode(OpenH264RateControl::Off)
+                    .qp(QpRange::new(qp, qp))
+                    .skip_frames(false)
+            }
+        };
+        OpenH264Encoder::with_api_config(OpenH264API::from_source(), settings).map_err(backend)
+    }
+
+    /// Start a fresh OpenH264 instance; its first frame is a keyframe
+    fn reopen(&mut self) -> Result<(), CodecError> {
+        self.encoder = Self::open(&self.config)?;
+        self.initialized = false;
+        Ok(())
+    }
+
+    /// Push the configured target and peak to the running encoder
+    fn apply_bitrate(&mut self) -> Result<(), CodecError> {
+        let target = self.config.bitrate;
+        let max = match self.config.rate_control {
+            RateControlMode::Cbr => target,
+            RateControlMode::Vbr => self.config.max_bitrate.max(target),
+            RateControlMode::Crf(_) => return Ok(()),
+        };
+
+        // OpenH264 rejects a peak below the target, so order the two
+        // changes so that neither is ever set against a stale value
+        let mut current = SBitrateInfo {
+            iLayer: SPATIAL_LAYER_0,
+            iBitrate: 0,
+        };
+        // SAFETY: reading the option writes only into `current`
+        unsafe {
+            self.encoder
+                .raw_api()
+                .get_option(ENCODER_OPTION_BITRATE, (&raw mut current).cast());
+        }
+        if max as i32 >= current.iBitrate {
+            self.set_option_bitrate(ENCODER_OPTION_MAX_BITRATE, SPATIAL_LAYER_0, max)?;
+            self.set_option_bitrate(ENCODER_OPTION_BITRATE, SPATIAL_LAYER_ALL, target)
+        } else {
+            self.set_option_bitrate(ENCODER_OPTION_BITRATE, SPATIAL_LAYER_ALL, target)?;
+            self.set_option_bitrate(ENCODER_OPTION_MAX_BITRATE, SPATIAL_LAYER_0, max)
+        }
+    }
+
+    fn set_option_bitrate(
+        &mut self,
+        option: ENCODER_OPTION,
+        layer: LAYER_NUM,
+        bitrate: u32,
+    ) -> Result<(), CodecError> {
+        let mut info = SBitrateInfo {
+            iLayer: layer,
+            iBitrate: bitrate.min(i32::MAX as u32) as i32,
+        };
+        // SAFETY: the encoder is initialised and only reads `info` while
+        // applying the option
+        let status = unsafe {
+            self.encoder
+                .raw_api()
+                .set_option(option, (&raw mut info).cast::<c_void>())
+        };
+        option_result(option, status)
+    }
+
+    fn to_i420(frame: &Frame) -> Result<Frame, CodecError> {
+        if frame.format == PixelFormat::MJPG {
+            let rgb = decode_jpeg(&frame.data)?;
+            Ok(convert(&rgb, PixelFormat::YU12)?)
+        } else {
+            Ok(convert(frame, PixelFormat::YU12)?)
+        }
+    }
+}
+
+impl Encoder for H264Encoder {
+    fn codec(&self) -> CodecId {
+        CodecId::H264
+    }
+
+    fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+        check_resolution(&self.config, frame)?;
+        let i420 = Self::to_i420(frame)?;
+        let yuv = YUVBuffer::from_vec(i420.data, i420.width as usize, i420.height as usize);
+
+        let pts = self.clock.pts(frame.timestamp);
+        let bitstream = self
+            .encoder
+            .encode_at(&yuv, Timestamp::from_millis(pts.as_millis() as u64))
+            .map_err(backend)?;
+
+        let keyframe = match bitstream.frame_type() {
+            FrameType::IDR => true,
+            FrameType::I | FrameType::P | FrameType::IPMixed => false,
+            FrameType::Skip | FrameType::Invalid => return Ok(Vec::new()),
+        };
+
+        let mut data = bitstream.to_vec();
+        if !frame.metadata.is_empty() {
+            data = embed_metadata(&data, &frame.metadata);
+        }
+        if !self.initialized {
+            self.initialized = true;
+            self.apply_bitrate()?;
+        }
+
+        let parameter_sets: Vec<_> = split_annex_b(&data)
+            .into_iter()
+            .filter(|unit| matches!(unit.nal_type(), NalType::Sps | NalType::Pps))
+            .collect();
+        if !parameter_sets.is_empty() {
+            self.extradata = Some(Bytes::from(write_annex_b(&parameter_sets)));
+        }
+
+        let packet = EncodedFrame {
+            codec: CodecId::H264,
+            width: frame.width,
+            height: frame.height,
+            keyframe,
+            pts,
+            dts: pts,
+            duration: self.config.frame_duration(),
+            timestamp: frame.timestamp,
+            sequence: frame.sequence,
+            data: Bytes::from(data),
+            extradata: if keyframe { self.extradata.clone() } else { None },
+            metadata: frame.metadata.clone(),
+        };
+        self.meter.record(&packet);
+        Ok(vec![packet])
+    }
+
+    fn request_keyframe(&mut self) {
+        self.encoder.force_intra_frame();
+    }
+
+    fn set_bitrate(&mut self, target: u32, max: u32) -> Result<(), CodecError> {
+        self.config.bitrate = target;
+        self.config.max_bitrate = max.max(target);
+        if self.initialized {
+            self.apply_bitrate()
+        } else {
+            self.reopen()
+        }
+    }
+
+    fn set_rate_control(&mut self, mode: RateControlMode) -> Result<(), CodecError> {
+        if mode != self.config.rate_control {
+            self.config.rate_control = mode;
+            self.reopen()?;
+        }
+        Ok(())
+    }
+
+    fn set_frame_rate(&mut self, fps: u32) -> Result<(), CodecError> {
+        self.config.fps = fps.max(1);
+        if !self.initialized {
+            return self.reopen();
+        }
+        let mut rate = self.config.fps as f32;
+        // SAFETY: the encoder is initialised and only reads `rate`
+        let status = unsafe {
+            self.encoder
+                .raw_api()
+                .set_option(ENCODER_OPTION_FRAME_RATE, (&raw mut rate).cast())
+        };
+        option_result(ENCODER_OPTION_FRAME_RATE, status)
+    }
+
+    fn output_bitrate(&self) -> Option<BitrateReport> {
+        Some(self.meter.report())
+    }
+
+    fn extradata(&self) -> Option<Bytes> {
+        self.extradata.clone()
+    }
+}
+
+/// Wraps H.264 access units from a camera that encodes in hardware.
+///
+/// Keyframes are detected from the IDR slices in each access unit. Cameras
+/// often send SPS and PPS only once, so the latest parameter sets are
+/// remembered and prepended to keyframes lacking them, keeping every keyframe
+/// independently decodable. Frames before the first keyframe are dropped, as
+/// nothing downstream could decode them, and a keyframe is requested once.
+/// Cameras in intra-refresh mode, switched on with the capture crate's
+/// `SetIntraRefresh` command, rarely send IDRs, so a recovery point SEI
+/// starts the stream as well, and gets the parameter sets too.
+///
+/// The encoder cannot make the camera emit an IDR by itself;
+/// [`H264Passthrough::with_keyframe_requester`] connects
+/// [`Encoder::request_keyframe`] to whatever can, such as a capture command.
+/// Likewise the bitrate is the camera's to set; only the output is measured.
+pub struct H264Passthrough {
+    config: EncoderConfig,
+    clock: StreamClock,
+    meter: BitrateMeter,
+    extradata: Option<Bytes>,
+    keyframe_requester: Option<Box<dyn FnMut() + Send>>,
+    seen_keyframe: bool,
+    requested_first_keyframe: bool,
+}
+
+impl H264Passthrough {
+    pub fn new(config: EncoderConfig) -> Self {
+        Self {
+            meter: BitrateMeter::new(config.bitrate_window),
+            config,
+            clock: StreamClock::default(),
+            extradata: None,
+            keyframe_requester: None,
+            seen_keyframe: false,
+            requested_first_keyframe: false,
+        }
+    }
+
+    /// Call `requester` whenever a keyframe is requested from this encoder
+    pub fn with_keyframe_requester<F>(mut self, requester: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.keyframe_requester = Some(Box::new(requester));
+        self
+    }
+}
+
+impl Encoder for H264Passthrough {
+    fn codec(&self) -> CodecId {
+        CodecId::H264
+    }
+
+    fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+        if frame.format != PixelFormat::H264 {
+            return Err(CodecError::UnsupportedInput {
+                codec: CodecId::H264,
+                format: frame.format,
+            });
+        }
+        check_resolution(&self.config, frame)?;
+
+        let units = split_annex_b(&frame.data);
+        if units.is_empty() {
+            return Err(CodecError::InvalidData("no NAL units in H.264 frame".to_string()));
+        }
+
+        let parameter_sets: Vec<_> = units
+            .iter()
+            .copied()
+            .filter(|unit| matches!(unit.nal_type(), NalType::Sps | NalType::Pps))
+            .collect();
+        if !parameter_sets.is_empty() {
+            self.extradata = Some(Bytes::from(write_annex_b(&parameter_sets)));
+        }
+
+        let keyframe = units.iter().any(|unit| unit.nal_type() == NalType::IdrSlice);
+        let random_access = keyframe || is_recovery_point(&frame.data);
+        if !random_access && !self.seen_keyframe {
+            if !self.requested_first_keyframe {
+                self.requested_first_keyframe = true;
+                self.request_keyframe();
+            }
+            return Ok(Vec::new());
+        }
+        self.seen_keyframe = true;
+
+        let mut data = match (&self.extradata, random_access && parameter_sets.is_empty()) {
+            (Some(sets), true) => [sets.as_ref(), &frame.data].concat(),
+            _ => frame.data.clone(),
+        };
+        if !frame.metadata.is_empty() {
+            data = embed_metadata(&data, &frame.metadata);
+        }
+
+        let pts = self.clock.pts(frame.timestamp);
+        let packet = EncodedFrame {
+            codec: CodecId::H264,
+            width: frame.width,
+            height: frame.height,
+            keyframe,
+            pts,
+            dts: pts,
+            duration: self.config.frame_duration(),
+            timestamp: frame.timestamp,
+            sequence: frame.sequence,
+            data: Bytes::from(data),
+            extradata: if keyframe { self.extradata.clone() } else { None },
+            metadata: frame.metadata.clone(),
+        };
+        self.meter.record(&packet);
+        Ok(vec![packet])
+    }
+
+    fn request_keyframe(&mut self) {
+        if let Some(requester) = &mut self.keyframe_requester {
+            requester();
+        }
+    }
+
+    fn set_frame_rate(&mut self, fps: u32) -> Result<(), CodecError> {
+        self.config.fps = fps.max(1);
+        Ok(())
+    }
+
+    fn output_bitrate(&self) -> Option<BitrateReport> {
+        Some(self.meter.report())
+    }
+
+    fn extradata(&self) -> Option<Bytes> {
+        self.extradata.clone()
+    }
+}
+
+/// Software H.264 decoder producing raw frames in a chosen format.
+pub struct H264Decoder {
+    decoder: OpenH264Decoder,
+    output: PixelFormat,
+}
+
+impl H264Decoder {
+    /// Create a decoder converting its output to `output`.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::Backend`] if OpenH264 fails to initialise.
+    pub fn new(output: PixelFormat) -> Result<Self, CodecError> {
+        Ok(Self {
+            decoder: OpenH264Decoder::new().map_err(backend)?,
+            output,
+        })
+    }
+}
+
+impl Decoder for H264Decoder {
+    fn codec(&self) -> CodecId {
+        CodecId::H264
+    }
+
+    fn decode(&mut self, packet: &EncodedFrame) -> Result<Vec<Frame>, CodecError> {
+        if packet.codec != CodecId::H264 {
+            return Err(CodecError::UnsupportedCodec(packet.codec));
+        }
+
+        let Some(yuv) = self
+            .decoder
+            .decode(&packet.data)
+            .map_err(|e| CodecError::InvalidData(e.to_string()))?
+        else {
+            return Ok(Vec::new());
+        };
+
+        // Packets read back from a file or the network may only have the
+        // metadata in the bitstream
+        let metadata = if packet.metadata.is_empty() {
+            extract_metadata(&packet.data).ok().flatten().unwrap_or_default()
+        } else {
+            packet.metadata.clone()
+        };
+
+        // Copy the planes out of OpenH264's padded buffers
+        let (width, height) = yuv.dimensions();
+        let (y_stride, u_stride, v_stride) = yuv.strides();
+        let mut data = Vec::with_capacity(width * height * 3 / 2);
+        for row in 0..height {
+            data.extend_from_slice(&yuv.y()[row * y_stride..][..width]);
+        }
+        for (plane, stride) in [(yuv.u(), u_stride), (yuv.v(), v_stride)] {
+            for row in 0..height / 2 {
+                data.extend_from_slice(&plane[row * stride..][..width / 2]);
+            }
+        }
+
+        let frame = Frame {
+            format: PixelFormat::YU12,
+            width: width as u32,
+            height: height as u32,
+            timestamp: packet.timestamp,
+            sequence: packet.sequence,
+            data,
+            metadata,
+        };
+        if self.output == PixelFormat::YU12 {
+            Ok(vec![frame])
+        } else {
+            Ok(vec![convert(&frame, self.output)?])
+        }
+    }
+}
+
+fn backend(error: openh264::Error) -> CodecError {
+    CodecError::Backend(error.to_string())
+}
+
+fn option_result(option: ENCODER_OPTION, status: i32) -> Result<(), CodecError> {
+    match status {
+        0 => Ok(()),
+        _ => Err(CodecError::Backend(format!(
+            "OpenH264 rejected option {option} (status {status})"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use streaming_core::metadata::keys;
+    use streaming_core::{Metadata, SyntheticSource};
+
+    #[test]
+    fn round_trip_keeps_quality() {
+        let mut config = EncoderConfig::new(128, 96, 30);
+        config.bitrate = 1_000_000;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let mut decoder = H264Decoder::new(PixelFormat::YU12).unwrap();
+
+        let source = SyntheticSource::new(PixelFormat::YUYV, 128, 96).velocity(3, 2);
+        for frame in source.take(10) {
+            let original = convert(&frame, PixelFormat::YU12).unwrap();
+            for packet in encoder.encode(&frame).unwrap() {
+                assert_eq!(packet.dts, packet.pts);
+                let decoded = decoder.decode(&packet).unwrap().remove(0);
+                assert_eq!(decoded.sequence, frame.sequence);
+                let quality = crate::quality::compare(&original, &decoded).unwrap().psnr_y;
+                assert!(quality > 30.0, "frame {} decoded at {:.1} dB", frame.sequence, quality);
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_survives_the_bitstream() {
+        let mut encoder = H264Encoder::new(EncoderConfig::new(64, 48, 30)).unwrap();
+        let mut decoder = H264Decoder::new(PixelFormat::YU12).unwrap();
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 64, 48);
+
+        let mut frame = source.next_frame();
+        frame.metadata.insert(keys::GPS, vec![52.52, 13.405]);
+        frame.metadata.insert(keys::EXPOSURE_US, 8000i64);
+        let packet = encoder.encode(&frame).unwrap().remove(0);
+        assert_eq!(packet.metadata, frame.metadata);
+        assert!(packet.keyframe);
+
+        // Without the side channel, the decoder finds the metadata in the SEI
+        let bare = EncodedFrame { metadata: Metadata::default(), ..packet.clone() };
+        assert_eq!(decoder.decode(&bare).unwrap().remove(0).metadata, frame.metadata);
+
+        // Passing the stream through keeps the camera's metadata too
+        let mut passthrough = H264Passthrough::new(EncoderConfig::new(64, 48, 30));
+        let camera_frame = Frame {
+            format: PixelFormat::H264,
+            data: packet.data.to_vec(),
+            metadata: Metadata::from_iter([(keys::GAIN_DB, 6.0)]),
+            ..source.next_frame()
+        };
+        let forwarded = passthrough.encode(&camera_frame).unwrap().remove(0);
+        assert_eq!(extract_metadata(&forwarded.data).unwrap(), Some(camera_frame.metadata));
+
+        let plain = encoder.encode(&source.next_frame()).unwrap().remove(0);
+        assert!(plain.metadata.is_empty());
+        assert!(decoder.decode(&plain).unwrap().remove(0).metadata.is_empty());
+    }
+
+    #[test]
+    fn keyframes_follow_gop_and_requests() {
+        let mut config = EncoderConfig::new(64, 48, 30);
+        config.keyframe_interval = 4;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 64, 48).velocity(2, 1);
+
+        let mut keyframes = Vec::new();
+        for i in 0..10 {
+            if i == 6 {
+                encoder.request_keyframe();
+            }
+            let packet = encoder.encode(&source.next_frame()).unwrap().remove(0);
+            assert_eq!(packet.extradata.is_some(), packet.keyframe);
+            if packet.keyframe {
+                keyframes.push(i);
+            }
+        }
+        assert_eq!(&keyframes[..3], &[0, 4, 6]);
+        let extradata = encoder.extradata().unwrap();
+        let sets: Vec<_> = split_annex_b(&extradata).iter().map(|unit| unit.nal_type()).collect();
+        assert_eq!(sets, [NalType::Sps, NalType::Pps]);
+    }
+
+    #[test]
+    fn bitrate_changes_apply_without_keyframe() {
+        let mut config = EncoderConfig::new(128, 96, 30);
+        config.bitrate = 1_000_000;
+        config.keyframe_interval = 300;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 128, 96).fps(30).velocity(3, 2).noise(4);
+
+        for frame in source.by_ref().take(60) {
+            encoder.encode(&frame).unwrap();
+        }
+        let high = encoder.output_bitrate().unwrap();
+
+        encoder.set_bitrate(100_000, 150_000).unwrap();
+        encoder.set_frame_rate(30).unwrap();
+        let mut keyframes = 0;
+        for frame in source.by_ref().take(60) {
+            keyframes += encoder.encode(&frame).unwrap().iter().filter(|packet| packet.keyframe).count();
+        }
+        let low = encoder.output_bitrate().unwrap();
+        assert_eq!(keyframes, 0);
+        assert!(low.bitrate < high.bitrate / 2, "{} bps after lowering from {} bps", low.bitrate, high.bitrate);
+        assert!(low.bitrate < 150_000, "{} bps for a 100 kbps target", low.bitrate);
+
+        // Changing mode restarts the encoder
+        encoder.set_rate_control(RateControlMode::Crf(20)).unwrap();
+        assert!(encoder.encode(&source.next_frame()).unwrap()[0].keyframe);
+    }
+
+    #[test]
+    fn accepts_camera_jpeg_and_times_from_first_frame() {
+        let config = EncoderConfig::new(64, 48, 25);
+        let mut jpeg = crate::MjpegEncoder::new(config.clone());
+        let mut encoder = H264Encoder::new(config).unwrap();
+
+        let mut source = SyntheticSource::new(PixelFormat::RGB3, 64, 48).fps(25);
+        source.next_frame();
+        let frame = source.next_frame();
+        let camera_frame = Frame {
+            format: PixelFormat::MJPG,
+            data: jpeg.encode(&frame).unwrap().remove(0).data.to_vec(),
+            ..frame
+        };
+        let first = encoder.encode(&camera_frame).unwrap().remove(0);
+        let second = encoder.encode(&source.next_frame()).unwrap().remove(0);
+        assert_eq!(first.pts, Duration::ZERO);
+        assert_eq!(second.pts, Duration::from_millis(40));
+
+        let wrong_size = SyntheticSource::new(PixelFormat::YU12, 32, 32).next_frame();
+        assert!(matches!(encoder.encode(&wrong_size), Err(CodecError::ResolutionMismatch { .. })));
+    }
+
+    #[test]
+    fn passthrough_starts_at_recovery_points() {
+        let sets = [0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80];
+        let recovery_sei = [0, 0, 0, 1, 0x06, 6, 1, 0x84, 0x80];
+        let slice = [0, 0, 0, 1, 0x41, 0x9A, 0x02];
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let mut passthrough = H264Passthrough::new(EncoderConfig::new(64, 48, 30)).with_keyframe_requester(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 64, 48);
+        let mut camera = |data: Vec<u8>| {
+            let frame = Frame { format: PixelFormat::H264, data, ..source.next_frame() };
+            passthrough.encode(&frame).unwrap()
+        };
+
+        // Joined mid-refresh: nothing until the next recovery point
+        assert!(camera(slice.to_vec()).is_empty());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        let first = camera([&sets[..], &recovery_sei, &slice].concat()).remove(0);
+        assert!(!first.keyframe && is_recovery_point(&first.data));
+        assert_eq!(camera(slice.to_vec()).len(), 1);
+
+        // Later recovery points get the remembered parameter sets
+        let later = camera([&recovery_sei[..], &slice].concat()).remove(0);
+        assert_eq!(later.data[..], [&sets[..], &recovery_sei, &slice].concat());
+        assert!(crate::bitstream::is_random_access(&later.data));
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+}
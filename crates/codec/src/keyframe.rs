@@ -0,0 +1,268 @@
+//! Keyframe requests from stream consumers back to the encoder.
+//!
+//! A viewer that joins mid-stream, loses packets or fails to decode cannot
+//! show anything until the next keyframe. Consumers signal this through a
+//! [`KeyframeRequester`], obtained from
+//! [`EncodePool::keyframe_requester`](crate::stage::EncodePool::keyframe_requester)
+//! or wrapped around any other path to the encoder, such as a camera
+//! command for hardware H.264. [`RecoveringDecoder`] does this on the
+//! decoding side by itself.
+//!
+//! Many viewers losing the same packet would otherwise turn the stream into
+//! a run of keyframes, so the encode stage passes requests through a
+//! [`KeyframeLimiter`]: at most one forced keyframe per interval, with
+//! requests in between folded into one that is served when the interval
+//! ends, or by a scheduled keyframe if that comes first.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use streaming_core::Frame;
+
+use crate::bitstream::is_random_access;
+use crate::{CodecError, CodecId, Decoder, EncodedFrame};
+
+/// Why a consumer wants a keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyframeReason {
+    /// A viewer started receiving the stream
+    Join,
+
+    /// Packets were lost in transit
+    PacketLoss,
+
+    /// The decoder rejected a packet
+    DecodeError,
+
+    /// Asked for explicitly, e.g. by an operator
+    Manual,
+}
+
+/// Cloneable handle asking an encoder for a keyframe.
+#[derive(Clone)]
+pub struct KeyframeRequester {
+    request: Arc<dyn Fn(KeyframeReason) + Send + Sync>,
+}
+
+impl KeyframeRequester {
+    /// Call `request` for every keyframe request
+    pub fn new<F>(request: F) -> Self
+    where
+        F: Fn(KeyframeReason) + Send + Sync + 'static,
+    {
+        Self { request: Arc::new(request) }
+    }
+
+    pub fn request(&self, reason: KeyframeReason) {
+        (self.request)(reason);
+    }
+}
+
+impl fmt::Debug for KeyframeRequester {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyframeRequester").finish_non_exhaustive()
+    }
+}
+
+/// Rate limit for forced keyframes.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use streaming_codec::keyframe::KeyframeLimiter;
+///
+/// let mut limiter = KeyframeLimiter::new(Duration::from_secs(1));
+/// let start = Instant::now();
+/// assert!(limiter.request(start));
+/// // A second request within the interval waits for it to end
+/// assert!(!limiter.request(start + Duration::from_millis(200)));
+/// assert!(!limiter.poll(start + Duration::from_millis(900)));
+/// assert!(limiter.poll(start + Duration::from_secs(1)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeyframeLimiter {
+    min_interval: Duration,
+    last: Option<Instant>,
+    pending: bool,
+}
+
+impl KeyframeLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        Self { min_interval, last: None, pending: false }
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    /// Register a request at `now`, returning whether to force a keyframe
+    /// right away; otherwise the request is deferred
+    pub fn request(&mut self, now: Instant) -> bool {
+        self.pending = true;
+        self.poll(now)
+    }
+
+    /// Whether a deferred request is due at `now`; it then counts as served
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let elapsed = |last: Instant| now.saturating_duration_since(last) >= self.min_interval;
+        let due = self.pending && self.last.is_none_or(elapsed);
+        if due {
+            self.pending = false;
+            self.last = Some(now);
+        }
+        due
+    }
+
+    /// A keyframe went out at `now`, serving any deferred request
+    pub fn keyframe_sent(&mut self, now: Instant) {
+        self.pending = false;
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+    }
+
+    /// Whether a request is waiting for the interval to end
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+}
+
+/// Wraps a [`Decoder`] so that it only starts, and restarts after a
+/// problem, at packets it can decode from, asking for a keyframe meanwhile.
+///
+/// Packets are discarded from the start until the first keyframe, after a
+/// decode error until the next, and likewise after [`RecoveringDecoder::lost`]
+/// reports missing packets. For H.264, a recovery point also ends the wait,
+/// so streams using intra refresh instead of periodic IDRs can be joined.
+pub struct RecoveringDecoder<D: Decoder> {
+    inner: D,
+    requester: KeyframeRequester,
+    waiting: Option<KeyframeReason>,
+    requested: bool,
+    discarded: u64,
+}
+
+impl<D: Decoder> RecoveringDecoder<D> {
+    pub fn new(inner: D, requester: KeyframeRequester) -> Self {
+        Self { inner, requester, waiting: Some(KeyframeReason::Join), requested: false, discarded: 0 }
+    }
+
+    /// Report that packets were lost before the next one to decode
+    pub fn lost(&mut self) {
+        self.resync(KeyframeReason::PacketLoss);
+    }
+
+    /// Whether packets are being discarded until a keyframe
+    pub fn is_waiting(&self) -> bool {
+        self.waiting.is_some()
+    }
+
+    /// Packets discarded while waiting for a keyframe
+    pub fn discarded(&self) -> u64 {
+        self.discarded
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn resync(&mut self, reason: KeyframeReason) {
+        self.waiting = Some(reason);
+        self.requested = false;
+    }
+
+    fn can_start(packet: &EncodedFrame) -> bool {
+        packet.keyframe || (packet.codec == CodecId::H264 && is_random_access(&packet.data))
+    }
+}
+
+impl<D: Decoder> Decoder for RecoveringDecoder<D> {
+    fn codec(&self) -> CodecId {
+        self.inner.codec()
+    }
+
+    fn decode(&mut self, packet: &EncodedFrame) -> Result<Vec<Frame>, CodecError> {
+        if let Some(reason) = self.waiting {
+            if !Self::can_start(packet) {
+                if !self.requested {
+                    self.requested = true;
+                    self.requester.request(reason);
+                }
+                self.discarded += 1;
+                return Ok(Vec::new());
+            }
+            self.waiting = None;
+        }
+        self.inner.decode(packet).inspect_err(|_| {
+            self.resync(KeyframeReason::DecodeError);
+            self.requested = true;
+            self.requester.request(KeyframeReason::DecodeError);
+        })
+    }
+
+    fn flush(&mut self) -> Result<Vec<Frame>, CodecError> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use streaming_core::{PixelFormat, SyntheticSource};
+
+    use crate::{Encoder, EncoderConfig, H264Decoder, H264Encoder};
+
+    #[test]
+    fn limiter_folds_requests_and_counts_scheduled_keyframes() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut limiter = KeyframeLimiter::new(Duration::from_millis(500));
+
+        assert!(limiter.request(at(0)));
+        assert!(!limiter.request(at(100)) && !limiter.request(at(200)));
+        assert!(limiter.is_pending());
+        assert!(limiter.poll(at(500)));
+        assert!(!limiter.poll(at(2000)));
+
+        // A scheduled keyframe serves the deferred request and restarts the
+        // interval
+        assert!(!limiter.request(at(600)));
+        limiter.keyframe_sent(at(700));
+        assert!(!limiter.is_pending() && !limiter.poll(at(1500)));
+        assert!(!limiter.request(at(1100)));
+        assert!(limiter.request(at(1200)));
+    }
+
+    #[test]
+    fn recovering_decoder_waits_for_keyframes() {
+        let mut config = EncoderConfig::new(64, 48, 30);
+        config.keyframe_interval = 4;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let packets: Vec<_> = SyntheticSource::new(PixelFormat::YU12, 64, 48)
+            .velocity(2, 1)
+            .take(12)
+            .flat_map(|frame| encoder.encode(&frame).unwrap())
+            .collect();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        let requester = KeyframeRequester::new(move |reason| log.lock().unwrap().push(reason));
+        let mut decoder = RecoveringDecoder::new(H264Decoder::new(PixelFormat::YU12).unwrap(), requester);
+
+        // Joining at frame 1 means waiting for the keyframe at 4
+        let mut decoded = Vec::new();
+        for packet in &packets[1..6] {
+            decoded.extend(decoder.decode(packet).unwrap().iter().map(|frame| frame.sequence));
+        }
+        decoder.lost();
+        for packet in &packets[7..] {
+            decoded.extend(decoder.decode(packet).unwrap().iter().map(|frame| frame.sequence));
+        }
+
+        let sequence = |index: usize| packets[index].sequence;
+        assert_eq!(decoded, [4, 5, 8, 9, 10, 11].map(sequence));
+        assert_eq!(decoder.discarded(), 3 + 1);
+        assert_eq!(*requests.lock().unwrap(), [KeyframeReason::Join, KeyframeReason::PacketLoss]);
+    }
+}
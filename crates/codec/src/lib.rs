@@ -1 +1,280 @@
-// Codec library placeholder
+//! Video encoding and decoding for the streaming pipeline.
+//!
+//! Defines the [`Encoder`] and [`Decoder`] traits that sit between raw
+//! [`Frame`]s from capture and the compressed [`EncodedFrame`]s handed to
+//! muxers and the network, plus a [`CodecRegistry`] that picks an
+//! implementation for a codec and input pixel format.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use streaming_core::{Frame, FrameError, Metadata, PixelFormat};
+use thiserror::Error;
+
+pub mod audio;
+pub mod bitstream;
+pub mod h264;
+pub mod keyframe;
+pub mod mjpeg;
+pub mod mkv;
+pub mod mp4;
+#[cfg(feature = "opus")]
+pub mod opus;
+pub mod quality;
+pub mod rate;
+pub mod registry;
+pub mod replay;
+pub mod simulcast;
+pub mod stage;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod y4m;
+
+pub use audio::{AudioDecoder, AudioEncoder, AudioTrack, Rechunker};
+pub use h264::{H264Decoder, H264Encoder, H264Passthrough};
+pub use keyframe::{KeyframeLimiter, KeyframeReason, KeyframeRequester, RecoveringDecoder};
+pub use mjpeg::{MjpegDecoder, MjpegEncoder, MjpegPassthrough};
+#[cfg(feature = "opus")]
+pub use opus::{OpusDecoder, OpusEncoder};
+pub use rate::{BitrateMeter, BitrateReport, RateControlMode};
+pub use registry::{CodecRegistry, DecoderInfo, EncoderInfo};
+pub use simulcast::{LayerId, Simulcast};
+pub use stage::{DropPolicy, EncodePool, EncodeStage, StreamId, StreamInput, StreamStats, SubmitError};
+
+/// Compressed formats known to the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodecId {
+    H264,
+    Mjpeg,
+
+    /// Opus audio, see [`audio`]
+    Opus,
+}
+
+impl CodecId {
+    /// Short lowercase name, as used in file extensions and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            CodecId::H264 => "h264",
+            CodecId::Mjpeg => "mjpeg",
+            CodecId::Opus => "opus",
+        }
+    }
+
+    pub fn is_audio(&self) -> bool {
+        matches!(self, CodecId::Opus)
+    }
+}
+
+impl fmt::Display for CodecId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("No implementation registered for codec {0}")]
+    UnsupportedCodec(CodecId),
+
+    #[error("Codec {codec} cannot take {format:?} input")]
+    UnsupportedInput { codec: CodecId, format: PixelFormat },
+
+    #[error("Frame is {got_width}x{got_height}, encoder was configured for {width}x{height}")]
+    ResolutionMismatch { width: u32, height: u32, got_width: u32, got_height: u32 },
+
+    #[error("Invalid bitstream: {0}")]
+    InvalidData(String),
+
+    #[error("Frame error: {0}")]
+    Frame(#[from] FrameError),
+
+    #[error("Encoder does not support {0}")]
+    Unsupported(&'static str),
+
+    #[error("{0} belongs to another encode stage")]
+    UnknownStream(StreamId),
+
+    #[error("{0} belongs to another simulcast")]
+    UnknownLayer(LayerId),
+
+    #[error("Codec backend error: {0}")]
+    Backend(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// One compressed access unit (a video frame, or a run of audio samples) and
+/// its timing.
+///
+/// `pts` and `dts` are measured from the first frame the encoder saw, so
+/// streams always start at zero. `timestamp` keeps the wall-clock capture time
+/// of the source frame for recording and overlay purposes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedFrame {
+    pub codec: CodecId,
+    pub width: u32,
+    pub height: u32,
+
+    /// Whether the frame can be decoded without any earlier frame
+    pub keyframe: bool,
+
+    /// Presentation time
+    pub pts: Duration,
+
+    /// Decode time; equal to `pts` for streams without frame reordering
+    pub dts: Duration,
+
+    /// Display duration, usually `1 / fps`
+    pub duration: Duration,
+
+    /// Capture time of the source frame
+    pub timestamp: SystemTime,
+
+    /// Sequence number of the source frame
+    pub sequence: usize,
+
+    /// Compressed payload
+    pub data: Bytes,
+
+    /// Decoder configuration (e.g. H.264 SPS/PPS) when it is carried out of band
+    pub extradata: Option<Bytes>,
+
+    /// Metadata of the source frame; H.264 encoders also embed it in the
+    /// bitstream, see [`bitstream::embed_metadata`]
+    pub metadata: Metadata,
+}
+
+/// Settings shared by all encoders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncoderConfig {
+    pub width: u32,
+    pub height: u32,
+
+    /// Nominal frame rate, used for durations and rate control
+    pub fps: u32,
+
+    /// Target bitrate in bits per second
+    pub bitrate: u32,
+
+    /// Peak bitrate in bits per second, used by [`RateControlMode::Vbr`]
+    pub max_bitrate: u32,
+
+    pub rate_control: RateControlMode,
+
+    /// Frames between keyframes (1 = every frame is a keyframe)
+    pub keyframe_interval: u32,
+
+    /// Forbid frame reordering (B-frames) and lookahead, so every input frame
+    /// comes out as soon as it is encoded
+    pub low_latency: bool,
+
+    /// Window over which [`Encoder::output_bitrate`] is measured
+    pub bitrate_window: Duration,
+}
+
+impl EncoderConfig {
+    pub fn new(width: u32, height: u32, fps: u32) -> Self {
+        Self {
+            width,
+            height,
+            fps,
+            bitrate: 2_000_000,
+            max_bitrate: 4_000_000,
+            rate_control: RateControlMode::default(),
+            keyframe_interval: fps.max(1) * 2,
+            low_latency: true,
+            bitrate_window: Duration::from_secs(1),
+        }
+    }
+
+    /// Duration of one frame at the configured rate
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.fps.max(1)
+    }
+}
+
+/// Maps capture timestamps onto stream time starting at the first frame.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StreamClock {
+    origin: Option<SystemTime>,
+}
+
+impl StreamClock {
+    /// Time of `timestamp` since the first timestamp seen; never negative
+    pub(crate) fn pts(&mut self, timestamp: SystemTime) -> Duration {
+        let origin = *self.origin.get_or_insert(timestamp);
+        timestamp.duration_since(origin).unwrap_or_default()
+    }
+}
+
+/// Compresses raw frames into [`EncodedFrame`]s.
+pub trait Encoder: Send {
+    fn codec(&self) -> CodecId;
+
+    /// Encode one frame.
+    ///
+    /// Encoders with internal delay may return no packet for some inputs and
+    /// several for later ones; [`Encoder::flush`] drains whatever is left.
+    fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError>;
+
+    /// Drain frames still buffered inside the encoder
+    fn flush(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        Ok(Vec::new())
+    }
+
+    /// Make the next encoded frame a keyframe; a no-op for intra-only codecs
+    fn request_keyframe(&mut self) {}
+
+    /// Change the target and peak bitrate, in bits per second, without
+    /// restarting the stream
+    fn set_bitrate(&mut self, _target: u32, _max: u32) -> Result<(), CodecError> {
+        Err(CodecError::Unsupported("bitrate control"))
+    }
+
+    /// Switch rate control mode; encoders may restart with a keyframe
+    fn set_rate_control(&mut self, _mode: RateControlMode) -> Result<(), CodecError> {
+        Err(CodecError::Unsupported("rate control modes"))
+    }
+
+    /// Change the nominal frame rate without restarting the stream
+    fn set_frame_rate(&mut self, _fps: u32) -> Result<(), CodecError> {
+        Err(CodecError::Unsupported("frame rate changes"))
+    }
+
+    /// Bitrate actually produced over the configured window
+    fn output_bitrate(&self) -> Option<BitrateReport> {
+        None
+    }
+
+    /// Out-of-band decoder configuration, once known
+    fn extradata(&self) -> Option<Bytes> {
+        None
+    }
+}
+
+/// Turns [`EncodedFrame`]s back into raw frames.
+pub trait Decoder: Send {
+    fn codec(&self) -> CodecId;
+
+    /// Decode one access unit into zero or more frames
+    fn decode(&mut self, packet: &EncodedFrame) -> Result<Vec<Frame>, CodecError>;
+
+    /// Drain frames still buffered inside the decoder
+    fn flush(&mut self) -> Result<Vec<Frame>, CodecError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Writes [`EncodedFrame`]s into a container file.
+///
+/// Lets recorders switch between [`mp4::Mp4Writer`] and [`mkv::MkvWriter`]
+/// at runtime.
+pub trait Muxer: Send {
+    fn write_packet(&mut self, packet: &EncodedFrame) -> Result<(), CodecError>;
+
+    /// Write indexes and trailers; the file is complete once this returns
+    fn finish(self: Box<Self>) -> Result<(), CodecError>;
+}
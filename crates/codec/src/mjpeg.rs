@@ -0,0 +1,585 @@
+//! Motion JPEG: every frame is an independent JPEG image.
+//!
+//! Cameras delivering [`PixelFormat::MJPG`] already produce MJPEG, so
+//! [`MjpegPassthrough`] only wraps their output; [`MjpegEncoder`] compresses raw
+//! frames in software. Many UVC cameras leave the Huffman tables out of every
+//! frame and rely on the defaults from the JPEG specification (annex K.3), which
+//! [`insert_default_huffman_tables`] puts back so the images decode anywhere.
+
+use std::borrow::Cow;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ExtendedColorType, ImageFormat};
+use streaming_core::convert::convert;
+use streaming_core::{Frame, Metadata, PixelFormat};
+
+use crate::{
+    BitrateMeter, BitrateReport, CodecError, CodecId, Decoder, EncodedFrame, Encoder,
+    EncoderConfig, RateControlMode, StreamClock,
+};
+
+/// Raw formats [`MjpegEncoder`] accepts
+pub const RAW_INPUTS: [PixelFormat; 5] = [
+    PixelFormat::YUYV,
+    PixelFormat::RGB3,
+    PixelFormat::BGR3,
+    PixelFormat::YU12,
+    PixelFormat::YV12,
+];
+
+const SOI: u8 = 0xD8;
+const DHT: u8 = 0xC4;
+const SOS: u8 = 0xDA;
+
+/// Wraps JPEG frames from the camera without re-encoding them.
+///
+/// Frames missing their Huffman tables get the default ones inserted; all
+/// other bytes are passed through unchanged.
+///
+/// The camera decides the bitrate, so only the frame rate can be changed.
+pub struct MjpegPassthrough {
+    config: EncoderConfig,
+    clock: StreamClock,
+    meter: BitrateMeter,
+}
+
+impl MjpegPassthrough {
+    pub fn new(config: EncoderConfig) -> Self {
+        Self {
+            meter: BitrateMeter::new(config.bitrate_window),
+            config,
+            clock: StreamClock::default(),
+        }
+    }
+}
+
+impl Encoder for MjpegPassthrough {
+    fn codec(&self) -> CodecId {
+        CodecId::Mjpeg
+    }
+
+    fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+        if frame.format != PixelFormat::MJPG {
+            return Err(CodecError::UnsupportedInput {
+                codec: CodecId::Mjpeg,
+                format: frame.format,
+            });
+        }
+        check_resolution(&self.config, frame)?;
+
+        let data = insert_default_huffman_tables(&frame.data)?.into_owned();
+        let packet = packet(&self.config, &mut self.clock, frame, data);
+        self.meter.record(&packet);
+        Ok(vec![packet])
+    }
+
+    fn set_frame_rate(&mut self, fps: u32) -> Result<(), CodecError> {
+        self.config.fps = fps.max(1);
+        Ok(())
+    }
+
+    fn output_bitrate(&self) -> Option<BitrateReport> {
+        Some(self.meter.report())
+    }
+}
+
+/// Software JPEG encoder for raw frames.
+///
+/// JPEG has no rate control of its own, so in [`RateControlMode::Cbr`] and
+/// [`RateControlMode::Vbr`] the quality is nudged after every frame: CBR
+/// steers each frame towards the per-frame budget, VBR steers the windowed
+/// average towards the target and only reacts to single frames above the
+/// peak. [`RateControlMode::Crf`] maps to a fixed quality.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_codec::{Encoder, EncoderConfig, MjpegEncoder};
+/// use streaming_core::{PixelFormat, SyntheticSource};
+///
+/// let mut encoder = MjpegEncoder::new(EncoderConfig::new(320, 240, 30)).quality(75);
+/// let frame = SyntheticSource::new(PixelFormat::YUYV, 320, 240).next_frame();
+/// let packets = encoder.encode(&frame).unwrap();
+/// assert!(packets[0].keyframe);
+/// ```
+pub struct MjpegEncoder {
+    config: EncoderConfig,
+    clock: StreamClock,
+    meter: BitrateMeter,
+    quality: u8,
+}
+
+impl MjpegEncoder {
+    /// Create an encoder starting at quality 85, or at the quality matching
+    /// a CRF configuration
+    pub fn new(config: EncoderConfig) -> Self {
+        let quality = match config.rate_control {
+            RateControlMode::Crf(crf) => crf_quality(crf),
+            _ => 85,
+        };
+        Self {
+            meter: BitrateMeter::new(config.bitrate_window),
+            config,
+            clock: StreamClock::default(),
+            quality,
+        }
+    }
+
+    /// JPEG quality from 1 (smallest) to 100 (best)
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.set_quality(quality);
+        self
+    }
+
+    /// Change the quality for subsequent frames; in CBR and VBR modes rate
+    /// control takes over from there
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.clamp(1, 100);
+    }
+
+    pub fn current_quality(&self) -> u8 {
+        self.quality
+    }
+
+    fn adapt_quality(&mut self, frame_bytes: usize) {
+        let frame_bitrate = frame_bytes as f64 * 8.0 * self.config.fps.max(1) as f64;
+        let (measured, target) = match self.config.rate_control {
+            RateControlMode::Crf(_) => return,
+            RateControlMode::Cbr => (frame_bitrate, self.config.bitrate),
+            RateControlMode::Vbr if frame_bitrate > self.config.max_bitrate as f64 => {
+                (frame_bitrate, self.config.max_bitrate)
+            }
+            RateControlMode::Vbr => (self.meter.report().bitrate as f64, self.config.bitrate),
+        };
+
+        // Back off quickly when over budget, creep back up slowly
+        let ratio = measured / target.max(1) as f64;
+        let step = if ratio > 1.05 {
+            -(((ratio - 1.0) * 10.0).ceil().min(10.0) as i32)
+        } else if ratio < 0.95 {
+            1
+        } else {
+            0
+        };
+        self.quality = (self.quality as i32 + step).clamp(1, 100) as u8;
+    }
+}
+
+/// JPEG quality for a CRF value: 0 is 100, 51 is 1
+fn crf_quality(crf: u8) -> u8 {
+    100 - (crf.min(51) as u32 * 99 / 51) as u8
+}
+
+impl Encoder for MjpegEncoder {
+    fn codec(&self) -> CodecId {
+        CodecId::Mjpeg
+    }
+
+    fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+        if frame.format.is_compressed() {
+            return Err(CodecError::UnsupportedInput {
+                codec: CodecId::Mjpeg,
+                format: frame.format,
+            });
+        }
+        check_resolution(&self.config, frame)?;
+
+        let rgb = convert(frame, PixelFormat::RGB3)?;
+        let mut data = Vec::new();
+        JpegEncoder::new_with_quality(&mut data, self.quality)
+            .encode(&rgb.data, rgb.width, rgb.height, ExtendedColorType::Rgb8)
+            .map_err(|e| CodecError::Backend(e.to_string()))?;
+
+        let packet = packet(&self.config, &mut self.clock, frame, data);
+        self.meter.record(&packet);
+        self.adapt_quality(packet.data.len());
+        Ok(vec![packet])
+    }
+
+    fn set_bitrate(&mut self, target: u32, max: u32) -> Result<(), CodecError> {
+        self.config.bitrate = target;
+        self.config.max_bitrate = max.max(target);
+        Ok(())
+    }
+
+    fn set_rate_control(&mut self, mode: RateControlMode) -> Result<(), CodecError> {
+        self.config.rate_control = mode;
+        if let RateControlMode::Crf(crf) = mode {
+            self.quality = crf_quality(crf);
+        }
+        Ok(())
+    }
+
+    fn set_frame_rate(&mut self, fps: u32) -> Result<(), CodecError> {
+        self.config.fps = fps.max(1);
+        Ok(())
+    }
+
+    fn output_bitrate(&self) -> Option<BitrateReport> {
+        Some(self.meter.report())
+    }
+}
+
+/// Software JPEG decoder producing raw frames in a chosen format.
+#[derive(Debug, Clone)]
+pub struct MjpegDecoder {
+    output: PixelFormat,
+}
+
+impl MjpegDecoder {
+    /// Create a decoder converting its output to `output`
+    pub fn new(output: PixelFormat) -> Self {
+        Self { output }
+    }
+}
+
+impl Default for MjpegDecoder {
+    /// Decode to [`PixelFormat::RGB3`], which needs no conversion
+    fn default() -> Self {
+        Self::new(PixelFormat::RGB3)
+    }
+}
+
+impl Decoder for MjpegDecoder {
+    fn codec(&self) -> CodecId {
+        CodecId::Mjpeg
+    }
+
+    fn decode(&mut self, packet: &EncodedFrame) -> Result<Vec<Frame>, CodecError> {
+        if packet.codec != CodecId::Mjpeg {
+            return Err(CodecError::UnsupportedCodec(packet.codec));
+        }
+
+        let mut frame = decode_jpeg(&packet.data)?;
+        frame.timestamp = packet.timestamp;
+        frame.sequence = packet.sequence;
+        frame.metadata = packet.metadata.clone();
+        if self.output == PixelFormat::RGB3 {
+            Ok(vec![frame])
+        } else {
+            Ok(vec![convert(&frame, self.output)?])
+        }
+    }
+}
+
+/// Insert the default Huffman tables into a JPEG image that has none.
+///
+/// Images that already carry a DHT segment are returned unchanged.
+///
+/// # Errors
+///
+/// [`CodecError::InvalidData`] if `data` does not start with a well-formed
+/// JPEG header.
+pub fn insert_default_huffman_tables(data: &[u8]) -> Result<Cow<'_, [u8]>, CodecError> {
+    if !data.starts_with(&[0xFF, SOI]) {
+        return Err(CodecError::InvalidData("missing JPEG start-of-image marker".to_string()));
+    }
+
+    let mut pos = 2;
+    loop {
+        // Markers may be preceded by any number of 0xFF fill bytes
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = match (data.get(pos), data.get(pos + 1)) {
+            (Some(0xFF), Some(&marker)) => marker,
+            (Some(_), Some(_)) => {
+                return Err(CodecError::InvalidData(format!("expected JPEG marker at offset {}", pos)));
+            }
+            _ => return Err(CodecError::InvalidData("truncated JPEG header".to_string())),
+        };
+
+        match marker {
+            DHT => return Ok(Cow::Borrowed(data)),
+            SOS => {
+                let tables = default_huffman_segment();
+                let mut repaired = Vec::with_capacity(data.len() + tables.len());
+                repaired.extend_from_slice(&data[..pos]);
+                repaired.extend_from_slice(&tables);
+                repaired.extend_from_slice(&data[pos..]);
+                return Ok(Cow::Owned(repaired));
+            }
+            _ => {
+                let length = match data.get(pos + 2..pos + 4) {
+                    Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]) as usize,
+                    None => return Err(CodecError::InvalidData("truncated JPEG header".to_string())),
+                };
+                pos += 2 + length;
+            }
+        }
+    }
+}
+
+/// Decode one JPEG image to an RGB frame with the timestamp and sequence
+/// number left at their defaults
+pub(crate) fn decode_jpeg(data: &[u8]) -> Result<Frame, CodecError> {
+    let data = insert_default_huffman_tables(data)?;
+    let image = image::load_from_memory_with_format(&data, ImageFormat::Jpeg)
+        .map_err(|e| CodecError::InvalidData(e.to_string()))?
+        .into_rgb8();
+
+    Ok(Frame {
+        format: PixelFormat::RGB3,
+        width: image.width(),
+        height: image.height(),
+        timestamp: SystemTime::UNIX_EPOCH,
+        sequence: 0,
+        data: image.into_raw(),
+        metadata: Metadata::default(),
+    })
+}
+
+pub(crate) fn check_resolution(config: &EncoderConfig, frame: &Frame) -> Result<(), CodecError> {
+    if frame.width != config.width || frame.height != config.height {
+        return Err(CodecError::ResolutionMismatch {
+            width: config.width,
+            height: config.height,
+            got_width: frame.width,
+            got_height: frame.height,
+        });
+    }
+    Ok(())
+}
+
+fn packet(config: &EncoderConfig, clock: &mut StreamClock, frame: &Frame, data: Vec<u8>) -> EncodedFrame {
+    let pts = clock.pts(frame.timestamp);
+    EncodedFrame {
+        codec: CodecId::Mjpeg,
+        width: frame.width,
+        height: frame.height,
+        keyframe: true,
+        pts,
+        dts: pts,
+        duration: config.frame_duration(),
+        timestamp: frame.timestamp,
+        sequence: frame.sequence,
+        data: Bytes::from(data),
+        extradata: None,
+        metadata: frame.metadata.clone(),
+    }
+}
+
+/// DHT segment holding the four tables of JPEG annex K.3
+fn default_huffman_segment() -> Vec<u8> {
+    let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+        (0x00, &LUMA_DC_LENGTHS, &DC_VALUES),
+        (0x10, &LUMA_AC_LENGTHS, &LUMA_AC_VALUES),
+        (0x01, &CHROMA_DC_LENGTHS, &DC_VALUES),
+        (0x11, &CHROMA_AC_LENGTHS, &CHROMA_AC_VALUES),
+    ];
+    let length = 2 + tables.iter().map(|(_, lengths, values)| 1 + lengths.len() + values.len()).sum::<usize>();
+
+    let mut segment = vec![0xFF, DHT];
+    segment.extend_from_slice(&(length as u16).to_be_bytes());
+    for (class_and_id, lengths, values) in tables {
+        segment.push(class_and_id);
+        segment.extend_from_slice(lengths);
+        segment.extend_from_slice(values);
+    }
+    segment
+}
+
+const DC_VALUES: [u8; 12] = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B];
+
+const LUMA_DC_LENGTHS: [u8; 16] = [
+    0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+const CHROMA_DC_LENGTHS: [u8; 16] = [
+    0x00, 0x03, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+const LUMA_AC_LENGTHS: [u8; 16] = [
+    0x00, 0x02, 0x01, 0x03, 0x03, 0x02, 0x04, 0x03, 0x05, 0x05, 0x04, 0x04, 0x00, 0x00, 0x01, 0x7D,
+];
+
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+const CHROMA_AC_LENGTHS: [u8; 16] = [
+    0x00, 0x02, 0x01, 0x02, 0x04, 0x04, 0x03, 0x04, 0x07, 0x05, 0x04, 0x04, 0x00, 0x01, 0x02, 0x77,
+];
+
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use streaming_core::SyntheticSource;
+
+    fn mean_abs_diff(a: &[u8], b: &[u8]) -> f64 {
+        assert_eq!(a.len(), b.len());
+        let total: u64 = a.iter().zip(b).map(|(&x, &y)| (x as i32 - y as i32).unsigned_abs() as u64).sum();
+        total as f64 / a.len() as f64
+    }
+
+    /// Remove every DHT segment, as webcams emitting bare MJPEG do
+    fn strip_huffman_tables(data: &[u8]) -> Vec<u8> {
+        let mut out = data[..2].to_vec();
+        let mut pos = 2;
+        while data[pos + 1] != SOS {
+            let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            if data[pos + 1] != DHT {
+                out.extend_from_slice(&data[pos..pos + 2 + length]);
+            }
+            pos += 2 + length;
+        }
+        out.extend_from_slice(&data[pos..]);
+        out
+    }
+
+    #[test]
+    fn software_round_trip_is_close() {
+        let mut source = SyntheticSource::new(PixelFormat::YUYV, 64, 48);
+        let frame = source.next_frame();
+        let mut encoder = MjpegEncoder::new(EncoderConfig::new(64, 48, 30)).quality(90);
+        let packet = encoder.encode(&frame).unwrap().remove(0);
+        assert!(packet.keyframe);
+        assert_eq!(packet.pts, Duration::ZERO);
+
+        let decoded = MjpegDecoder::new(PixelFormat::YUYV).decode(&packet).unwrap().remove(0);
+        assert_eq!((decoded.format, decoded.width, decoded.height), (PixelFormat::YUYV, 64, 48));
+        assert_eq!(decoded.sequence, frame.sequence);
+        assert!(mean_abs_diff(&decoded.data, &frame.data) < 4.0);
+    }
+
+    #[test]
+    fn quality_trades_size() {
+        let frame = SyntheticSource::new(PixelFormat::RGB3, 64, 48).next_frame();
+        let config = EncoderConfig::new(64, 48, 30);
+        let low = MjpegEncoder::new(config.clone()).quality(10).encode(&frame).unwrap().remove(0);
+        let high = MjpegEncoder::new(config).quality(95).encode(&frame).unwrap().remove(0);
+        assert!(low.data.len() < high.data.len());
+    }
+
+    #[test]
+    fn cbr_converges_on_target() {
+        let mut config = EncoderConfig::new(64, 48, 10);
+        config.rate_control = RateControlMode::Cbr;
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 64, 48)
+            .fps(10)
+            .noise(20);
+
+        // Budget for what quality 50 produces, starting far above it
+        let reference = MjpegEncoder::new(config.clone())
+            .quality(50)
+            .encode(&source.next_frame())
+            .unwrap()
+            .remove(0);
+        config.bitrate = reference.data.len() as u32 * 8 * 10;
+        let mut encoder = MjpegEncoder::new(config.clone()).quality(100);
+        for frame in source.by_ref().take(40) {
+            encoder.encode(&frame).unwrap();
+        }
+        let report = encoder.output_bitrate().unwrap();
+        let error = report.bitrate as f64 / config.bitrate as f64;
+        assert!(
+            (0.75..1.25).contains(&error),
+            "measured {} for target {}",
+            report.bitrate,
+            config.bitrate
+        );
+
+        // Halving the target at runtime lowers the output
+        encoder
+            .set_bitrate(config.bitrate / 2, config.bitrate / 2)
+            .unwrap();
+        for frame in source.by_ref().take(40) {
+            encoder.encode(&frame).unwrap();
+        }
+        assert!(encoder.output_bitrate().unwrap().bitrate < report.bitrate * 3 / 4);
+
+        encoder.set_rate_control(RateControlMode::Crf(0)).unwrap();
+        assert_eq!(encoder.current_quality(), 100);
+    }
+
+    #[test]
+    fn passthrough_keeps_bytes_and_times_from_first_frame() {
+        let config = EncoderConfig::new(64, 48, 25);
+        let mut software = MjpegEncoder::new(config.clone());
+        let mut passthrough = MjpegPassthrough::new(config);
+
+        for (i, frame) in SyntheticSource::new(PixelFormat::RGB3, 64, 48).fps(25).take(3).enumerate() {
+            let jpeg = software.encode(&frame).unwrap().remove(0);
+            let camera_frame = Frame {
+                format: PixelFormat::MJPG,
+                data: jpeg.data.to_vec(),
+                ..frame
+            };
+            let packet = passthrough.encode(&camera_frame).unwrap().remove(0);
+            assert_eq!(packet.data, jpeg.data);
+            assert_eq!(packet.pts, Duration::from_millis(40) * i as u32);
+            assert_eq!(packet.duration, Duration::from_millis(40));
+        }
+        assert_eq!(passthrough.output_bitrate().unwrap().frames, 3);
+        assert!(matches!(
+            passthrough.set_bitrate(1_000_000, 1_000_000),
+            Err(CodecError::Unsupported(_))
+        ));
+        passthrough.set_frame_rate(50).unwrap();
+    }
+
+    #[test]
+    fn restores_missing_huffman_tables() {
+        let frame = SyntheticSource::new(PixelFormat::RGB3, 32, 32).next_frame();
+        let complete = MjpegEncoder::new(EncoderConfig::new(32, 32, 30)).encode(&frame).unwrap().remove(0);
+        let bare = strip_huffman_tables(&complete.data);
+        assert!(bare.len() < complete.data.len());
+
+        let repaired = insert_default_huffman_tables(&bare).unwrap();
+        assert!(matches!(repaired, Cow::Owned(_)));
+        assert!(matches!(insert_default_huffman_tables(&complete.data).unwrap(), Cow::Borrowed(_)));
+
+        let mut decoder = MjpegDecoder::default();
+        let expected = decoder.decode(&complete).unwrap().remove(0);
+        let bare_packet = EncodedFrame {
+            data: Bytes::from(bare),
+            ..complete
+        };
+        assert_eq!(decoder.decode(&bare_packet).unwrap().remove(0).data, expected.data);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let frame = SyntheticSource::new(PixelFormat::YUYV, 64, 48).next_frame();
+        let mut passthrough = MjpegPassthrough::new(EncoderConfig::new(64, 48, 30));
+        assert!(matches!(passthrough.encode(&frame), Err(CodecError::UnsupportedInput { .. })));
+
+        let garbage = Frame {
+            format: PixelFormat::MJPG,
+            data: vec![0x00; 16],
+            ..frame
+        };
+        assert!(matches!(passthrough.encode(&garbage), Err(CodecError::InvalidData(_))));
+
+        let mut encoder = MjpegEncoder::new(EncoderConfig::new(32, 32, 30));
+        let frame = SyntheticSource::new(PixelFormat::YUYV, 64, 48).next_frame();
+        assert!(matches!(encoder.encode(&frame), Err(CodecError::ResolutionMismatch { .. })));
+    }
+}
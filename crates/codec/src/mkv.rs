@@ -0,0 +1,1395 @@
+//! Matroska muxing and recovery of interrupted recordings.
+//!
+//! [`MkvWriter`] writes one video track in clusters that start at keyframes,
+//! plus optionally an Opus audio track placed by capture time.
+//! Each cluster is written with an unknown size and patched once the next
+//! one starts, so a file cut off by a crash or power loss still parses up
+//! to its last complete frame. [`MkvWriter::finish`] adds cue points for
+//! seeking and fills in the duration and seek head; [`recover`] does the
+//! same for a file that was never finished.
+//!
+//! The capture start time is stored both as the segment date and as a
+//! `DATE_RECORDED` tag, next to an optional device name tag, so archive
+//! tools can index recordings without decoding them.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io::Cursor;
+//! use streaming_codec::mkv::{MkvReader, MkvWriter, TAG_DEVICE_NAME};
+//! use streaming_codec::{Encoder, EncoderConfig, MjpegEncoder};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//!
+//! let mut encoder = MjpegEncoder::new(EncoderConfig::new(64, 48, 10));
+//! let mut writer = MkvWriter::new(Cursor::new(Vec::new())).device_name("front door");
+//! for frame in SyntheticSource::new(PixelFormat::RGB3, 64, 48).take(5) {
+//!     for packet in encoder.encode(&frame)? {
+//!         writer.write_packet(&packet)?;
+//!     }
+//! }
+//! let file = writer.finish()?.into_inner();
+//!
+//! let mut reader = MkvReader::new(Cursor::new(file))?;
+//! assert_eq!(reader.info().tag(TAG_DEVICE_NAME), Some("front door"));
+//! assert_eq!(reader.by_ref().count(), 5);
+//! # Ok::<(), streaming_codec::CodecError>(())
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use streaming_core::overlay::format_utc;
+use streaming_core::Metadata;
+
+use crate::audio::{opus_packet_duration, AudioTrack};
+use crate::bitstream::{annex_b_to_sample, avcc_to_annex_b, extract_metadata, AvcConfig};
+use crate::{CodecError, CodecId, EncodedFrame, Muxer};
+
+/// Tag holding the name of the capture device
+pub const TAG_DEVICE_NAME: &str = "DEVICE_NAME";
+
+/// Standard Matroska tag holding the capture start time, in UTC
+pub const TAG_DATE_RECORDED: &str = "DATE_RECORDED";
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const VOID: u32 = 0xEC;
+
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+
+const INFO: u32 = 0x1549_A966;
+const TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const DATE_UTC: u32 = 0x4461;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+const TAGS: u32 = 0x1254_C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TARGET_TYPE_VALUE: u32 = 0x68CA;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+
+const CODEC_ID_H264: &str = "V_MPEG4/ISO/AVC";
+const CODEC_ID_MJPEG: &str = "V_MJPEG";
+const CODEC_ID_OPUS: &str = "A_OPUS";
+
+/// Eight-byte size field meaning "until the parent ends"
+const UNKNOWN_SIZE: u64 = 0x01FF_FFFF_FFFF_FFFF;
+
+/// Nanoseconds per timecode unit; the Matroska default of a millisecond
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+
+/// Bytes kept free after the segment header for the seek head
+const SEEK_HEAD_RESERVE: usize = 128;
+
+/// Seconds from the Unix epoch to the Matroska epoch (2001-01-01)
+const MATROSKA_EPOCH_OFFSET: u64 = 978_307_200;
+
+const TRACK_NUMBER_VIDEO: u64 = 1;
+const TRACK_NUMBER_AUDIO: u64 = 2;
+
+/// Audio the decoder needs before a seek target to converge, as the Opus
+/// mapping recommends
+const OPUS_SEEK_PRE_ROLL: Duration = Duration::from_millis(80);
+
+const BLOCK_KEYFRAME: u8 = 0x80;
+const BLOCK_LACING: u8 = 0x06;
+
+/// Largest element body the reader buffers, to reject corrupt sizes before
+/// allocating
+const MAX_ELEMENT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// A seek target: a cluster starting with a keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CuePoint {
+    /// Stream time of the keyframe
+    pub time: Duration,
+
+    /// Offset of the cluster from the start of the segment's data
+    pub position: u64,
+}
+
+/// Track parameters, taken from the first keyframe.
+#[derive(Debug, Clone)]
+struct Track {
+    codec: CodecId,
+    width: u32,
+    height: u32,
+    avc: Option<AvcConfig>,
+    frame_duration: Duration,
+    /// Capture time of stream time zero
+    start: SystemTime,
+}
+
+impl Track {
+    fn from_keyframe(packet: &EncodedFrame) -> Result<Self, CodecError> {
+        let avc = match packet.codec {
+            CodecId::H264 => {
+                let sets = packet.extradata.as_ref().unwrap_or(&packet.data);
+                Some(AvcConfig::from_annex_b(sets)?)
+            }
+            CodecId::Mjpeg => None,
+            CodecId::Opus => return Err(CodecError::InvalidData("audio packet for the video track".to_string())),
+        };
+        Ok(Self {
+            codec: packet.codec,
+            width: packet.width,
+            height: packet.height,
+            avc,
+            frame_duration: packet.duration,
+            start: packet.timestamp.checked_sub(packet.pts).unwrap_or(packet.timestamp),
+        })
+    }
+}
+
+/// Offsets of the header fields patched by [`MkvWriter::finish`]
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// Absolute offset of the segment's data
+    segment: u64,
+    /// Absolute offset of the Duration value
+    duration: u64,
+    /// Offsets of top-level elements, relative to the segment's data
+    info: u64,
+    tracks: u64,
+    tags: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OpenCluster {
+    /// Absolute offset of the cluster's ID
+    start: u64,
+    /// Timecode in milliseconds
+    time: u64,
+}
+
+/// Writes one encoded video stream to a Matroska file.
+///
+/// Frames before the first keyframe are dropped. New clusters start at the
+/// first keyframe after the cluster duration (any frame for MJPEG), or
+/// earlier when a frame would not fit the 16-bit block timecode. Dropping
+/// the writer without [`MkvWriter::finish`] leaves a file players can read
+/// but not seek in.
+///
+/// Audio packets of a track declared with [`MkvWriter::audio`] are placed
+/// at their capture time relative to the first video keyframe, and dropped
+/// before it; they should arrive interleaved with video in capture order.
+pub struct MkvWriter<W: Write + Seek> {
+    inner: W,
+    cluster_duration: Duration,
+    tags: Vec<(String, String)>,
+    audio: Option<AudioTrack>,
+    track: Option<Track>,
+    layout: Option<Layout>,
+    cluster: Option<OpenCluster>,
+    cues: Vec<CuePoint>,
+    position: u64,
+    /// End of the last frame, in milliseconds
+    end: u64,
+}
+
+impl MkvWriter<BufWriter<File>> {
+    /// Create or truncate a file
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write + Seek> MkvWriter<W> {
+    /// Write to `inner`, starting at its current position
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            cluster_duration: Duration::from_secs(5),
+            tags: Vec::new(),
+            audio: None,
+            track: None,
+            layout: None,
+            cluster: None,
+            cues: Vec::new(),
+            position: 0,
+            end: 0,
+        }
+    }
+
+    /// Minimum length of a cluster, and so the seek granularity
+    pub fn cluster_duration(mut self, duration: Duration) -> Self {
+        self.cluster_duration = duration;
+        self
+    }
+
+    /// Record the name of the capture device in the file's tags
+    pub fn device_name(self, name: impl Into<String>) -> Self {
+        self.tag(TAG_DEVICE_NAME, name)
+    }
+
+    /// Add a tag describing the whole file
+    pub fn tag(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add an audio track next to the video one
+    pub fn audio(mut self, track: AudioTrack) -> Self {
+        self.audio = Some(track);
+        self
+    }
+
+    /// Cue points written so far
+    pub fn cues(&self) -> &[CuePoint] {
+        &self.cues
+    }
+
+    /// Append one packet.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the first keyframe lacks H.264
+    /// parameter sets, the codec changes mid-stream, frames are reordered
+    /// or an audio packet does not match the declared audio track, and
+    /// [`CodecError::Io`] if writing fails.
+    pub fn write_packet(&mut self, packet: &EncodedFrame) -> Result<(), CodecError> {
+        if packet.codec.is_audio() {
+            return self.write_audio(packet);
+        }
+        if self.track.is_none() {
+            if !packet.keyframe {
+                return Ok(());
+            }
+            let track = Track::from_keyframe(packet)?;
+            self.write_header(&track)?;
+            self.track = Some(track);
+        }
+        let Some(track) = &self.track else {
+            return Ok(());
+        };
+        if packet.codec != track.codec {
+            return Err(CodecError::InvalidData(format!(
+                "{} packet in a {} track",
+                packet.codec, track.codec
+            )));
+        }
+        if packet.pts != packet.dts {
+            return Err(CodecError::InvalidData("reordered frames are not supported".to_string()));
+        }
+        let data = match track.codec {
+            CodecId::H264 => annex_b_to_sample(&packet.data, 4)?,
+            CodecId::Mjpeg | CodecId::Opus => packet.data.to_vec(),
+        };
+
+        let time = millis(packet.pts);
+        let starts_cluster = packet.keyframe || track.codec == CodecId::Mjpeg;
+        let split = match self.cluster {
+            None => true,
+            Some(cluster) => {
+                let offset = time as i64 - cluster.time as i64;
+                offset > i16::MAX as i64
+                    || offset < i16::MIN as i64
+                    || (starts_cluster && offset >= millis(self.cluster_duration) as i64)
+            }
+        };
+        if split {
+            self.close_cluster()?;
+            self.open_cluster(time, packet.keyframe)?;
+        }
+        self.write_block(TRACK_NUMBER_VIDEO, time, packet.keyframe, &data)?;
+        self.end = self.end.max(time + millis(packet.duration));
+        Ok(())
+    }
+
+    fn write_audio(&mut self, packet: &EncodedFrame) -> Result<(), CodecError> {
+        if self.audio.is_none_or(|audio| audio.codec != packet.codec) {
+            return Err(CodecError::InvalidData(format!("{} packet without a matching audio track", packet.codec)));
+        }
+        let (Some(track), Some(cluster)) = (&self.track, self.cluster) else {
+            return Ok(());
+        };
+        let Ok(offset) = packet.timestamp.duration_since(track.start) else {
+            return Ok(());
+        };
+
+        let time = millis(offset);
+        if time as i64 - cluster.time as i64 > i16::MAX as i64 {
+            self.close_cluster()?;
+            self.open_cluster(time, false)?;
+        }
+        self.write_block(TRACK_NUMBER_AUDIO, time, true, &packet.data)?;
+        self.end = self.end.max(time + millis(packet.duration));
+        Ok(())
+    }
+
+    /// Append a simple block to the open cluster; `time` must fit its
+    /// 16-bit offset
+    fn write_block(&mut self, track_number: u64, time: u64, keyframe: bool, data: &[u8]) -> Result<(), CodecError> {
+        let Some(cluster) = self.cluster else {
+            return Ok(());
+        };
+        let offset = (time as i64 - cluster.time as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        let mut block = Vec::with_capacity(data.len() + 16);
+        put_id(&mut block, SIMPLE_BLOCK);
+        put_size(&mut block, data.len() as u64 + 4);
+        block.push(0x80 | track_number as u8);
+        block.extend_from_slice(&offset.to_be_bytes());
+        block.push(if keyframe { BLOCK_KEYFRAME } else { 0 });
+        block.extend_from_slice(data);
+        self.write(&block)
+    }
+
+    /// Close the last cluster, write cues and fill in the header, then
+    /// return the writer
+    pub fn finish(mut self) -> Result<W, CodecError> {
+        self.close_cluster()?;
+        if let Some(layout) = self.layout {
+            let cues = self.position - layout.segment;
+            let mut out = Vec::new();
+            element(&mut out, CUES, |out| {
+                for cue in &self.cues {
+                    element(out, CUE_POINT, |out| {
+                        uint(out, CUE_TIME, cue.time.as_millis() as u64);
+                        element(out, CUE_TRACK_POSITIONS, |out| {
+                            uint(out, CUE_TRACK, TRACK_NUMBER_VIDEO);
+                            uint(out, CUE_CLUSTER_POSITION, cue.position);
+                        });
+                    });
+                }
+            });
+            self.write(&out)?;
+            let end = self.position;
+
+            let mut seek_head = Vec::with_capacity(SEEK_HEAD_RESERVE);
+            element(&mut seek_head, SEEK_HEAD, |out| {
+                let targets = [(INFO, layout.info), (TRACKS, layout.tracks), (TAGS, layout.tags), (CUES, cues)];
+                for (id, position) in targets {
+                    element(out, SEEK, |out| {
+                        element(out, SEEK_ID, |out| put_id(out, id));
+                        uint(out, SEEK_POSITION, position);
+                    });
+                }
+            });
+            let padding = SEEK_HEAD_RESERVE - seek_head.len();
+            void(&mut seek_head, padding);
+
+            self.patch(layout.segment, &seek_head)?;
+            self.patch(layout.duration, &(self.end as f64).to_be_bytes())?;
+            self.patch(layout.segment - 8, &fixed_size(end - layout.segment))?;
+            self.inner.seek(SeekFrom::Start(end))?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_header(&mut self, track: &Track) -> Result<(), CodecError> {
+        self.position = self.inner.stream_position()?;
+
+        let mut out = Vec::new();
+        element(&mut out, EBML, |out| {
+            uint(out, EBML_VERSION, 1);
+            uint(out, EBML_READ_VERSION, 1);
+            uint(out, EBML_MAX_ID_LENGTH, 4);
+            uint(out, EBML_MAX_SIZE_LENGTH, 8);
+            string(out, DOC_TYPE, "matroska");
+            uint(out, DOC_TYPE_VERSION, 4);
+            uint(out, DOC_TYPE_READ_VERSION, 2);
+        });
+        put_id(&mut out, SEGMENT);
+        out.extend_from_slice(&UNKNOWN_SIZE.to_be_bytes());
+        let segment = out.len();
+        void(&mut out, SEEK_HEAD_RESERVE);
+
+        let info = out.len();
+        let date = track.start.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos() as i64
+            - (MATROSKA_EPOCH_OFFSET * 1_000_000_000) as i64;
+        element(&mut out, INFO, |out| {
+            uint(out, TIMECODE_SCALE, TIMECODE_SCALE_NS);
+            string(out, MUXING_APP, concat!("streaming-codec ", env!("CARGO_PKG_VERSION")));
+            string(out, WRITING_APP, concat!("streaming-codec ", env!("CARGO_PKG_VERSION")));
+            element(out, DATE_UTC, |out| out.extend_from_slice(&date.to_be_bytes()));
+            // Patched in finish(); must stay last in Info
+            element(out, DURATION, |out| out.extend_from_slice(&0f64.to_be_bytes()));
+        });
+        let duration = out.len() - 8;
+
+        let tracks = out.len();
+        element(&mut out, TRACKS, |out| {
+            element(out, TRACK_ENTRY, |out| {
+                uint(out, TRACK_NUMBER, TRACK_NUMBER_VIDEO);
+                uint(out, TRACK_UID, TRACK_NUMBER_VIDEO);
+                uint(out, TRACK_TYPE, 1);
+                uint(out, FLAG_LACING, 0);
+                if !track.frame_duration.is_zero() {
+                    uint(out, DEFAULT_DURATION, track.frame_duration.as_nanos() as u64);
+                }
+                match &track.avc {
+                    Some(avc) => {
+                        string(out, CODEC_ID, CODEC_ID_H264);
+                        element(out, CODEC_PRIVATE, |out| out.extend_from_slice(&avc.to_bytes()));
+                    }
+                    None => string(out, CODEC_ID, CODEC_ID_MJPEG),
+                }
+                element(out, VIDEO, |out| {
+                    uint(out, PIXEL_WIDTH, track.width as u64);
+                    uint(out, PIXEL_HEIGHT, track.height as u64);
+                });
+            });
+            if let Some(audio) = &self.audio {
+                element(out, TRACK_ENTRY, |out| {
+                    uint(out, TRACK_NUMBER, TRACK_NUMBER_AUDIO);
+                    uint(out, TRACK_UID, TRACK_NUMBER_AUDIO);
+                    uint(out, TRACK_TYPE, 2);
+                    uint(out, FLAG_LACING, 0);
+                    string(out, CODEC_ID, CODEC_ID_OPUS);
+                    element(out, CODEC_PRIVATE, |out| out.extend_from_slice(&audio.extradata()));
+                    let pre_skip = audio.pre_skip as u64 * 1_000_000_000 / audio.sample_rate() as u64;
+                    uint(out, CODEC_DELAY, pre_skip);
+                    uint(out, SEEK_PRE_ROLL, OPUS_SEEK_PRE_ROLL.as_nanos() as u64);
+                    element(out, AUDIO, |out| {
+                        let rate = audio.sample_rate() as f64;
+                        element(out, SAMPLING_FREQUENCY, |out| out.extend_from_slice(&rate.to_be_bytes()));
+                        uint(out, CHANNELS, audio.channels as u64);
+                    });
+                });
+            }
+        });
+
+        let tags = out.len();
+        let recorded = format_utc(track.start);
+        element(&mut out, TAGS, |out| {
+            element(out, TAG, |out| {
+                // Tags describe the whole file
+                element(out, TARGETS, |out| uint(out, TARGET_TYPE_VALUE, 50));
+                let date = (TAG_DATE_RECORDED, recorded.trim_end_matches('Z'));
+                let extra = self.tags.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+                for (name, value) in std::iter::once(date).chain(extra) {
+                    element(out, SIMPLE_TAG, |out| {
+                        string(out, TAG_NAME, name);
+                        string(out, TAG_STRING, value);
+                    });
+                }
+            });
+        });
+
+        let base = self.position + segment as u64;
+        self.layout = Some(Layout {
+            segment: base,
+            duration: self.position + duration as u64,
+            info: (info - segment) as u64,
+            tracks: (tracks - segment) as u64,
+            tags: (tags - segment) as u64,
+        });
+        self.write(&out)
+    }
+
+    fn open_cluster(&mut self, time: u64, keyframe: bool) -> Result<(), CodecError> {
+        let Some(layout) = self.layout else {
+            return Ok(());
+        };
+        let cluster = OpenCluster { start: self.position, time };
+        if keyframe {
+            self.cues.push(CuePoint {
+                time: Duration::from_millis(time),
+                position: cluster.start - layout.segment,
+            });
+        }
+        let mut out = Vec::new();
+        put_id(&mut out, CLUSTER);
+        out.extend_from_slice(&UNKNOWN_SIZE.to_be_bytes());
+        uint(&mut out, TIMECODE, time);
+        self.cluster = Some(cluster);
+        self.write(&out)
+    }
+
+    /// Replace the cluster's unknown size with its real one
+    fn close_cluster(&mut self) -> Result<(), CodecError> {
+        if let Some(cluster) = self.cluster.take() {
+            let size = self.position - (cluster.start + 12);
+            self.patch(cluster.start + 4, &fixed_size(size))?;
+            self.inner.seek(SeekFrom::Start(self.position))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), CodecError> {
+        self.inner.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    /// Overwrite bytes already written, leaving the stream at their end
+    fn patch(&mut self, offset: u64, data: &[u8]) -> Result<(), CodecError> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.write_all(data)?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek + Send> Muxer for MkvWriter<W> {
+    fn write_packet(&mut self, packet: &EncodedFrame) -> Result<(), CodecError> {
+        MkvWriter::write_packet(self, packet)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), CodecError> {
+        MkvWriter::finish(*self).map(drop)
+    }
+}
+
+/// Stream parameters read from a Matroska header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MkvInfo {
+    pub codec: CodecId,
+    pub width: u32,
+    pub height: u32,
+
+    /// Capture time of stream time zero
+    pub start: Option<SystemTime>,
+
+    /// Length of the stream, if the file was finished
+    pub duration: Option<Duration>,
+
+    /// Nominal frame duration
+    pub frame_duration: Option<Duration>,
+
+    /// File-level tags as name and value
+    pub tags: Vec<(String, String)>,
+
+    /// The first Opus track, if there is one
+    pub audio: Option<AudioTrack>,
+}
+
+impl MkvInfo {
+    /// Value of the first tag called `name`
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the video frames of a Matroska file written by [`MkvWriter`] or
+/// another muxer using simple blocks, and its audio on request.
+///
+/// A file that ends partway through a frame yields every frame before it;
+/// [`MkvReader::is_truncated`] then reports the damage.
+pub struct MkvReader<R: Read> {
+    inner: R,
+    position: u64,
+    segment: u64,
+    info: MkvInfo,
+    track_number: u64,
+    audio_track_number: Option<u64>,
+    read_audio: bool,
+    audio_sequence: usize,
+    timecode_scale: u64,
+    avc: Option<AvcConfig>,
+    cluster_time: u64,
+    sequence: usize,
+    cues: Vec<CuePoint>,
+    truncated: bool,
+    done: bool,
+}
+
+impl MkvReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> MkvReader<R> {
+    /// Read the header, up to the first cluster.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the stream is not Matroska, has no
+    /// video track in a supported codec or is cut off before its first
+    /// cluster.
+    pub fn new(inner: R) -> Result<Self, CodecError> {
+        let mut reader = Self {
+            inner,
+            position: 0,
+            segment: 0,
+            info: MkvInfo {
+                codec: CodecId::Mjpeg,
+                width: 0,
+                height: 0,
+                start: None,
+                duration: None,
+                frame_duration: None,
+                tags: Vec::new(),
+                audio: None,
+            },
+            track_number: 0,
+            audio_track_number: None,
+            read_audio: false,
+            audio_sequence: 0,
+            timecode_scale: TIMECODE_SCALE_NS,
+            avc: None,
+            cluster_time: 0,
+            sequence: 0,
+            cues: Vec::new(),
+            truncated: false,
+            done: false,
+        };
+        reader.read_header().map_err(|err| match err {
+            CodecError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => invalid("truncated Matroska header"),
+            err => err,
+        })?;
+        Ok(reader)
+    }
+
+    /// Also return the packets of the audio track in [`MkvInfo::audio`],
+    /// in file order with the video frames
+    pub fn with_audio(mut self) -> Self {
+        self.read_audio = true;
+        self
+    }
+
+    pub fn info(&self) -> &MkvInfo {
+        &self.info
+    }
+
+    /// Cue points, once the reader has passed them; [`MkvWriter`] writes
+    /// them after the last cluster
+    pub fn cues(&self) -> &[CuePoint] {
+        &self.cues
+    }
+
+    /// Offset of the segment's data in the file, the origin of cue positions
+    pub fn segment_offset(&self) -> u64 {
+        self.segment
+    }
+
+    /// Whether the stream ended partway through an element
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Read the next frame of the video track, as Annex-B with parameter
+    /// sets on keyframes for H.264, or `None` at the end of the stream;
+    /// audio packets come in between after [`MkvReader::with_audio`]
+    pub fn next_packet(&mut self) -> Result<Option<EncodedFrame>, CodecError> {
+        if self.done {
+            return Ok(None);
+        }
+        match self.read_packet() {
+            Ok(None) => {
+                self.done = true;
+                Ok(None)
+            }
+            Err(CodecError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                self.done = true;
+                self.truncated = true;
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    fn read_header(&mut self) -> Result<(), CodecError> {
+        if self.read_id()? != Some(EBML) {
+            return Err(invalid("not an EBML stream"));
+        }
+        let size = self.read_size()?.ok_or_else(|| invalid("EBML header of unknown size"))?;
+        let header = self.read_body(size)?;
+        let doc_type = children(&header)?.into_iter().find(|(id, _)| *id == DOC_TYPE).map(|(_, body)| text(body));
+        if !matches!(doc_type.as_deref(), Some("matroska" | "webm")) {
+            return Err(invalid(format!("unsupported document type {:?}", doc_type)));
+        }
+
+        if self.read_id()? != Some(SEGMENT) {
+            return Err(invalid("missing segment"));
+        }
+        self.read_size()?;
+        self.segment = self.position;
+
+        let mut found_track = false;
+        while let Some(id) = self.read_id()? {
+            let size = self.read_size()?;
+            if id == CLUSTER {
+                break;
+            }
+            let size = size.ok_or_else(|| invalid("element of unknown size before the first cluster"))?;
+            match id {
+                INFO => {
+                    let body = self.read_body(size)?;
+                    self.parse_info(&body)?;
+                }
+                TRACKS => {
+                    let body = self.read_body(size)?;
+                    found_track |= self.parse_tracks(&body)?;
+                }
+                TAGS => {
+                    let body = self.read_body(size)?;
+                    self.parse_tags(&body)?;
+                }
+                _ => self.skip(size)?,
+            }
+        }
+        if !found_track {
+            return Err(invalid("no video track"));
+        }
+        Ok(())
+    }
+
+    fn parse_info(&mut self, body: &[u8]) -> Result<(), CodecError> {
+        let mut duration = None;
+        for (id, value) in children(body)? {
+            match id {
+                TIMECODE_SCALE => self.timecode_scale = unsigned(value).max(1),
+                DURATION => duration = float(value),
+                DATE_UTC if value.len() == 8 => {
+                    let date = i64::from_be_bytes(value.try_into().unwrap_or_default());
+                    let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(MATROSKA_EPOCH_OFFSET);
+                    self.info.start = if date >= 0 {
+                        epoch.checked_add(Duration::from_nanos(date as u64))
+                    } else {
+                        epoch.checked_sub(Duration::from_nanos(date.unsigned_abs()))
+                    };
+                }
+                _ => {}
+            }
+        }
+        // Duration is in timecode units; zero means the file was never finished
+        self.info.duration = duration
+            .filter(|duration| *duration > 0.0)
+            .map(|duration| Duration::from_nanos((duration * self.timecode_scale as f64) as u64));
+        Ok(())
+    }
+
+    /// Take the first video track and the first Opus track, returning
+    /// whether there was a video track
+    fn parse_tracks(&mut self, body: &[u8]) -> Result<bool, CodecError> {
+        let mut found_video = false;
+        for (id, entry) in children(body)? {
+            if id != TRACK_ENTRY {
+                continue;
+            }
+            let fields = children(entry)?;
+            let field = |wanted| fields.iter().find(|(id, _)| *id == wanted).map(|(_, value)| *value);
+            let codec_id = field(CODEC_ID).map(text).unwrap_or_default();
+            match field(TRACK_TYPE).map(unsigned) {
+                Some(1) if !found_video => found_video = true,
+                Some(2) if self.info.audio.is_none() && codec_id == CODEC_ID_OPUS => {
+                    let private = field(CODEC_PRIVATE).ok_or_else(|| invalid("Opus track without OpusHead"))?;
+                    self.info.audio = Some(AudioTrack::from_opus_head(private)?);
+                    self.audio_track_number = field(TRACK_NUMBER).map(unsigned);
+                    continue;
+                }
+                _ => continue,
+            }
+            self.info.codec = match codec_id.as_str() {
+                CODEC_ID_H264 => {
+                    let private = field(CODEC_PRIVATE).ok_or_else(|| invalid("H.264 track without avcC"))?;
+                    self.avc = Some(AvcConfig::parse(private)?);
+                    CodecId::H264
+                }
+                CODEC_ID_MJPEG => CodecId::Mjpeg,
+                _ => return Err(invalid(format!("unsupported codec {}", codec_id))),
+            };
+            self.track_number = field(TRACK_NUMBER).map(unsigned).unwrap_or(TRACK_NUMBER_VIDEO);
+            self.info.frame_duration = field(DEFAULT_DURATION).map(|value| Duration::from_nanos(unsigned(value)));
+            if let Some(video) = field(VIDEO) {
+                for (id, value) in children(video)? {
+                    match id {
+                        PIXEL_WIDTH => self.info.width = unsigned(value) as u32,
+                        PIXEL_HEIGHT => self.info.height = unsigned(value) as u32,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(found_video)
+    }
+
+    fn parse_tags(&mut self, body: &[u8]) -> Result<(), CodecError> {
+        for (id, tag) in children(body)? {
+            if id != TAG {
+                continue;
+            }
+            for (id, simple) in children(tag)? {
+                if id != SIMPLE_TAG {
+                    continue;
+                }
+                let fields = children(simple)?;
+                let name = fields.iter().find(|(id, _)| *id == TAG_NAME).map(|(_, value)| text(value));
+                let value = fields.iter().find(|(id, _)| *id == TAG_STRING).map(|(_, value)| text(value));
+                if let (Some(name), Some(value)) = (name, value) {
+                    self.info.tags.push((name, value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_cues(&mut self, body: &[u8]) -> Result<(), CodecError> {
+        for (id, point) in children(body)? {
+            if id != CUE_POINT {
+                continue;
+            }
+            let mut time = None;
+            let mut position = None;
+            for (id, value) in children(point)? {
+                match id {
+                    CUE_TIME => time = Some(unsigned(value)),
+                    CUE_TRACK_POSITIONS => {
+                        position = children(value)?
+                            .into_iter()
+                            .find(|(id, _)| *id == CUE_CLUSTER_POSITION)
+                            .map(|(_, value)| unsigned(value));
+                    }
+                    _ => {}
+                }
+            }
+            if let (Some(time), Some(position)) = (time, position) {
+                self.cues.push(CuePoint { time: self.scaled(time)?, position });
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk clusters without regard to their sizes, so clusters of unknown
+    /// size and a missing end are handled alike
+    fn read_packet(&mut self) -> Result<Option<EncodedFrame>, CodecError> {
+        loop {
+            let Some(id) = self.read_id()? else {
+                return Ok(None);
+            };
+            let size = self.read_size()?;
+            if id == CLUSTER {
+                continue;
+            }
+            let size = size.ok_or_else(|| invalid(format!("element {:X} of unknown size", id)))?;
+            match id {
+                TIMECODE => self.cluster_time = unsigned(&self.read_body(size)?),
+                SIMPLE_BLOCK => {
+                    let body = self.read_body(size)?;
+                    if let Some(packet) = self.parse_block(&body)? {
+                        return Ok(Some(packet));
+                    }
+                }
+                CUES => {
+                    let body = self.read_body(size)?;
+                    self.parse_cues(&body)?;
+                }
+                _ => self.skip(size)?,
+            }
+        }
+    }
+
+    fn parse_block(&mut self, body: &[u8]) -> Result<Option<EncodedFrame>, CodecError> {
+        let (track, length) = parse_vint(body, false)?;
+        let audio = self.read_audio && track.is_some() && track == self.audio_track_number;
+        if track != Some(self.track_number) && !audio {
+            return Ok(None);
+        }
+        let header = body.get(length..length + 3).ok_or_else(|| invalid("short block"))?;
+        let offset = i16::from_be_bytes([header[0], header[1]]);
+        let flags = header[2];
+        if flags & BLOCK_LACING != 0 {
+            return Err(CodecError::Unsupported("laced blocks"));
+        }
+        let payload = &body[length + 3..];
+        let keyframe = flags & BLOCK_KEYFRAME != 0;
+
+        let time = match self.cluster_time.checked_add_signed(offset as i64) {
+            Some(time) => time,
+            None if offset < 0 => 0,
+            None => return Err(invalid("block timecode out of range")),
+        };
+        let pts = self.scaled(time)?;
+        let timestamp = self
+            .info
+            .start
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .checked_add(pts)
+            .ok_or_else(|| invalid("block time out of range"))?;
+        if let (true, Some(track)) = (audio, self.info.audio) {
+            let sequence = self.audio_sequence;
+            self.audio_sequence += 1;
+            return Ok(Some(EncodedFrame {
+                codec: track.codec,
+                width: 0,
+                height: 0,
+                keyframe: true,
+                pts,
+                dts: pts,
+                duration: opus_packet_duration(payload).unwrap_or_default(),
+                timestamp,
+                sequence,
+                data: Bytes::copy_from_slice(payload),
+                extradata: Some(track.extradata()),
+                metadata: Metadata::default(),
+            }));
+        }
+        let (data, extradata, metadata) = match &self.avc {
+            Some(avc) => {
+                let sets = avc.to_annex_b();
+                let mut data = if keyframe { sets.clone() } else { Vec::new() };
+                data.extend(avcc_to_annex_b(payload, avc.length_size())?);
+                let metadata = extract_metadata(&data)?.unwrap_or_default();
+                (Bytes::from(data), if keyframe { Some(Bytes::from(sets)) } else { None }, metadata)
+            }
+            None => (Bytes::copy_from_slice(payload), None, Metadata::default()),
+        };
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+        Ok(Some(EncodedFrame {
+            codec: self.info.codec,
+            width: self.info.width,
+            height: self.info.height,
+            keyframe,
+            pts,
+            dts: pts,
+            duration: self.info.frame_duration.unwrap_or_default(),
+            timestamp,
+            sequence,
+            data,
+            extradata,
+            metadata,
+        }))
+    }
+
+    /// Stream time of `time` timecode units
+    fn scaled(&self, time: u64) -> Result<Duration, CodecError> {
+        time.checked_mul(self.timecode_scale)
+            .map(Duration::from_nanos)
+            .ok_or_else(|| invalid(format!("timecode {} out of range", time)))
+    }
+
+    /// Read an element ID, or `None` at a clean end of stream
+    fn read_id(&mut self) -> Result<Option<u32>, CodecError> {
+        let mut first = [0u8];
+        if self.inner.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        self.position += 1;
+        let length = first[0].leading_zeros() as usize + 1;
+        if length > 4 {
+            return Err(invalid(format!("invalid element ID at offset {}", self.position - 1)));
+        }
+        let mut bytes = [0u8; 4];
+        bytes[4 - length] = first[0];
+        self.read_exact(&mut bytes[5 - length..])?;
+        Ok(Some(u32::from_be_bytes(bytes)))
+    }
+
+    /// Read an element size, `None` meaning unknown
+    fn read_size(&mut self) -> Result<Option<u64>, CodecError> {
+        let mut bytes = [0u8; 8];
+        self.read_exact(&mut bytes[..1])?;
+        let length = bytes[0].leading_zeros() as usize + 1;
+        if length > 8 {
+            return Err(invalid(format!("invalid element size at offset {}", self.position - 1)));
+        }
+        self.read_exact(&mut bytes[1..length])?;
+        parse_vint(&bytes[..length], false).map(|(size, _)| size)
+    }
+
+    fn read_body(&mut self, size: u64) -> Result<Vec<u8>, CodecError> {
+        if size > MAX_ELEMENT_SIZE {
+            return Err(invalid(format!("element of {} bytes", size)));
+        }
+        let mut body = vec![0; size as usize];
+        self.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    fn skip(&mut self, size: u64) -> Result<(), CodecError> {
+        let skipped = io::copy(&mut self.inner.by_ref().take(size), &mut io::sink())?;
+        self.position += skipped;
+        if skipped < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), CodecError> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for MkvReader<R> {
+    type Item = Result<EncodedFrame, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Outcome of [`recover`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recovery {
+    /// Video frames copied to the new file
+    pub frames: usize,
+
+    /// Length of the recovered stream
+    pub duration: Duration,
+
+    /// Whether the input was cut off partway through an element
+    pub truncated: bool,
+}
+
+/// Copy every complete frame of a Matroska file, typically one left
+/// unfinished by a crash, into a new finished file with cue points and a
+/// duration, along with its Opus audio. Tags other than the recording
+/// date, which is derived again from the frames, are carried over.
+///
+/// # Errors
+///
+/// As [`MkvReader::new`] if the header is unreadable, or any error writing
+/// the output.
+pub fn recover<R: Read, W: Write + Seek>(input: R, output: W) -> Result<Recovery, CodecError> {
+    let mut reader = MkvReader::new(input)?.with_audio();
+    let mut writer = MkvWriter::new(output);
+    for (name, value) in &reader.info().tags {
+        if name != TAG_DATE_RECORDED {
+            writer = writer.tag(name.clone(), value.clone());
+        }
+    }
+    if let Some(audio) = reader.info().audio {
+        writer = writer.audio(audio);
+    }
+
+    let mut frames = 0;
+    let mut end = Duration::ZERO;
+    while let Some(packet) = reader.next_packet()? {
+        writer.write_packet(&packet)?;
+        end = end.max(packet.pts + packet.duration);
+        if !packet.codec.is_audio() {
+            frames += 1;
+        }
+    }
+    writer.finish()?;
+    Ok(Recovery {
+        frames,
+        duration: end,
+        truncated: reader.is_truncated(),
+    })
+}
+
+fn millis(time: Duration) -> u64 {
+    ((time.as_nanos() + TIMECODE_SCALE_NS as u128 / 2) / TIMECODE_SCALE_NS as u128) as u64
+}
+
+fn invalid(message: impl Into<String>) -> CodecError {
+    CodecError::InvalidData(message.into())
+}
+
+/// Write an element ID; IDs carry their own length marker
+fn put_id(out: &mut Vec<u8>, id: u32) {
+    let skip = (id.leading_zeros() / 8) as usize;
+    out.extend_from_slice(&id.to_be_bytes()[skip.min(3)..]);
+}
+
+/// Write a size in the shortest variable-length form
+fn put_size(out: &mut Vec<u8>, size: u64) {
+    // All ones is reserved for unknown sizes
+    let length = (1..8).find(|length| size < (1 << (7 * length)) - 1).unwrap_or(8);
+    out.extend_from_slice(&(size | 1 << (7 * length)).to_be_bytes()[8 - length..]);
+}
+
+fn element(out: &mut Vec<u8>, id: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let mut content = Vec::new();
+    body(&mut content);
+    put_id(out, id);
+    put_size(out, content.len() as u64);
+    out.extend_from_slice(&content);
+}
+
+fn uint(out: &mut Vec<u8>, id: u32, value: u64) {
+    let skip = ((value.leading_zeros() / 8) as usize).min(7);
+    element(out, id, |out| out.extend_from_slice(&value.to_be_bytes()[skip..]));
+}
+
+fn string(out: &mut Vec<u8>, id: u32, value: &str) {
+    element(out, id, |out| out.extend_from_slice(value.as_bytes()));
+}
+
+/// A size in the eight-byte form, so it can be patched in place
+fn fixed_size(size: u64) -> [u8; 8] {
+    (size | (1 << 56)).to_be_bytes()
+}
+
+/// Padding of exactly `length` bytes, at least 9
+fn void(out: &mut Vec<u8>, length: usize) {
+    put_id(out, VOID);
+    out.extend_from_slice(&fixed_size((length - 9) as u64));
+    out.resize(out.len() + length - 9, 0);
+}
+
+/// Decode a variable-length integer, returning it and its length; the value
+/// is `None` for the reserved all-ones pattern
+fn parse_vint(data: &[u8], keep_marker: bool) -> Result<(Option<u64>, usize), CodecError> {
+    let first = *data.first().ok_or_else(|| invalid("missing variable-length integer"))?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 || data.len() < length {
+        return Err(invalid("malformed variable-length integer"));
+    }
+    // Value bits of the first byte; none for an eight-byte integer
+    let bits = (0xFFu16 >> length) as u8;
+    let mask = if keep_marker { 0xFF } else { bits };
+    let value = data[1..length].iter().fold((first & mask) as u64, |value, &byte| value << 8 | byte as u64);
+    let all_ones = first & bits == bits && data[1..length].iter().all(|&byte| byte == 0xFF);
+    Ok((if all_ones && !keep_marker { None } else { Some(value) }, length))
+}
+
+/// Split an element body into its children
+fn children(mut data: &[u8]) -> Result<Vec<(u32, &[u8])>, CodecError> {
+    let mut found = Vec::new();
+    while !data.is_empty() {
+        let (id, id_length) = parse_vint(data, true)?;
+        if id_length > 4 {
+            return Err(invalid("invalid element ID"));
+        }
+        let (size, size_length) = parse_vint(&data[id_length..], false)?;
+        let start = id_length + size_length;
+        let size = size.ok_or_else(|| invalid("nested element of unknown size"))?;
+        if size > (data.len() - start) as u64 {
+            return Err(invalid("element overruns its parent"));
+        }
+        let end = start + size as usize;
+        found.push((id.unwrap_or_default() as u32, &data[start..end]));
+        data = &data[end..];
+    }
+    Ok(found)
+}
+
+fn unsigned(data: &[u8]) -> u64 {
+    data.iter().take(8).fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).trim_end_matches('\0').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{at, mjpeg_packets, opus_packets};
+    use crate::{Decoder, Encoder, EncoderConfig, H264Decoder, H264Encoder};
+    use std::io::Cursor;
+    use streaming_core::{PixelFormat, SyntheticSource};
+
+    #[test]
+    fn h264_round_trip_with_cues_and_tags() {
+        let mut config = EncoderConfig::new(96, 64, 30);
+        config.keyframe_interval = 10;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let packets: Vec<_> = SyntheticSource::new(PixelFormat::YU12, 96, 64)
+            .velocity(3, 2)
+            .take(30)
+            .flat_map(|frame| encoder.encode(&frame).unwrap())
+            .collect();
+
+        let mut writer = MkvWriter::new(Cursor::new(Vec::new()))
+            .cluster_duration(Duration::from_millis(300))
+            .device_name("cam0");
+        for packet in &packets {
+            writer.write_packet(packet).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        let mut reader = MkvReader::new(Cursor::new(file.clone())).unwrap();
+        let info = reader.info().clone();
+        assert_eq!((info.codec, info.width, info.height), (CodecId::H264, 96, 64));
+        assert_eq!(info.start, Some(packets[0].timestamp));
+        assert_eq!(info.duration, Some(Duration::from_secs(1)));
+        assert_eq!(info.tag(TAG_DEVICE_NAME), Some("cam0"));
+        let recorded = format_utc(packets[0].timestamp);
+        assert_eq!(info.tag(TAG_DATE_RECORDED), Some(recorded.trim_end_matches('Z')));
+
+        let mut decoder = H264Decoder::new(PixelFormat::YU12).unwrap();
+        let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 30);
+        for (read, original) in read.iter().zip(&packets) {
+            assert_eq!(read.keyframe, original.keyframe);
+            assert!(read.pts.abs_diff(original.pts) <= Duration::from_millis(1));
+            assert_eq!(decoder.decode(read).unwrap().len(), 1);
+        }
+        assert!(!reader.is_truncated());
+
+        // One cluster per GOP, each found where its cue says
+        let times: Vec<_> = reader.cues().iter().map(|cue| cue.time.as_millis()).collect();
+        assert_eq!(times, [0, 333, 667]);
+        for cue in reader.cues() {
+            let at = (reader.segment_offset() + cue.position) as usize;
+            assert_eq!(file[at..at + 4], CLUSTER.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn truncated_file_is_readable_and_recoverable() {
+        let packets = mjpeg_packets(at(0), 25);
+        let mut file = Vec::new();
+        let mut writer = MkvWriter::new(Cursor::new(&mut file))
+            .cluster_duration(Duration::from_secs(1))
+            .device_name("cam1");
+        for packet in &packets {
+            writer.write_packet(packet).unwrap();
+        }
+        // Power lost mid-frame, before finish()
+        drop(writer);
+        file.truncate(file.len() - 50);
+
+        let mut reader = MkvReader::new(Cursor::new(&file)).unwrap();
+        assert_eq!(reader.info().duration, None);
+        let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert!(reader.is_truncated());
+        assert_eq!(read.len(), 24);
+        for (read, original) in read.iter().zip(&packets) {
+            assert_eq!(read.data, original.data);
+            assert_eq!(read.timestamp, original.timestamp);
+        }
+
+        let mut recovered = Cursor::new(Vec::new());
+        let recovery = recover(Cursor::new(&file), &mut recovered).unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                frames: 24,
+                duration: Duration::from_millis(2400),
+                truncated: true
+            }
+        );
+
+        let mut reader = MkvReader::new(Cursor::new(recovered.into_inner())).unwrap();
+        assert_eq!(reader.info().duration, Some(Duration::from_millis(2400)));
+        assert_eq!(reader.info().tag(TAG_DEVICE_NAME), Some("cam1"));
+        assert_eq!(reader.info().start, Some(packets[0].timestamp));
+        assert_eq!(reader.by_ref().count(), 24);
+        assert!(!reader.is_truncated());
+        // Rewritten with the default five-second clusters
+        assert_eq!(reader.cues().len(), 1);
+    }
+
+    #[test]
+    fn opus_track_alongside_video() {
+        let video = mjpeg_packets(at(0), 10);
+        let start = video[0].timestamp;
+        // 20 ms packets from 30 ms before the first frame to the last one
+        let audio = opus_packets(start - Duration::from_millis(30), 50);
+        let mut packets: Vec<_> = video.iter().chain(&audio).cloned().collect();
+        packets.sort_by_key(|packet| packet.timestamp);
+
+        let track = AudioTrack::from_opus_head(audio[0].extradata.as_ref().unwrap()).unwrap();
+        let mut file = Vec::new();
+        let mut writer = MkvWriter::new(Cursor::new(&mut file)).audio(track);
+        for packet in &packets {
+            writer.write_packet(packet).unwrap();
+        }
+        drop(writer);
+
+        // Audio before the first keyframe has nowhere to go
+        let mut reader = MkvReader::new(Cursor::new(&file)).unwrap().with_audio();
+        assert_eq!(reader.info().audio, Some(track));
+        let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        let (read_audio, read_video): (Vec<_>, Vec<_>) = read.iter().partition(|packet| packet.codec.is_audio());
+        assert_eq!(read_video.len(), 10);
+        assert_eq!(read_audio.len(), 48);
+        for (read, original) in read_audio.iter().zip(&audio[2..]) {
+            assert_eq!(read.data, original.data);
+            assert_eq!(read.duration, Duration::from_millis(20));
+            assert!(read.timestamp.duration_since(original.timestamp).unwrap() <= Duration::from_millis(1));
+        }
+        assert!(read.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert_eq!(MkvReader::new(Cursor::new(&file)).unwrap().count(), 10);
+
+        // Recovery keeps the audio track
+        let mut recovered = Cursor::new(Vec::new());
+        assert_eq!(recover(Cursor::new(&file), &mut recovered).unwrap().frames, 10);
+        let reader = MkvReader::new(Cursor::new(recovered.into_inner())).unwrap().with_audio();
+        assert_eq!(reader.info().audio, Some(track));
+        assert_eq!(reader.count(), 58);
+    }
+
+    #[test]
+    fn long_gaps_start_new_clusters() {
+        let mut packets = mjpeg_packets(at(0), 3);
+        for (i, packet) in packets.iter_mut().enumerate() {
+            packet.pts = Duration::from_secs(20 * i as u64);
+            packet.dts = packet.pts;
+        }
+
+        let mut file = Vec::new();
+        let mut muxer: Box<dyn Muxer> =
+            Box::new(MkvWriter::new(Cursor::new(&mut file)).cluster_duration(Duration::from_secs(60)));
+        for packet in &packets {
+            muxer.write_packet(packet).unwrap();
+        }
+        muxer.finish().unwrap();
+
+        // 40 s does not fit a block's 16-bit millisecond offset
+        let mut reader = MkvReader::new(Cursor::new(&file)).unwrap();
+        let times: Vec<_> = reader.by_ref().map(|packet| packet.unwrap().pts.as_secs()).collect();
+        assert_eq!(times, [0, 20, 40]);
+        let cues: Vec<_> = reader.cues().iter().map(|cue| cue.time.as_secs()).collect();
+        assert_eq!(cues, [0, 40]);
+
+        // A corrupt cluster timecode fails the read instead of overflowing
+        let second = file.windows(4).position(|bytes| bytes == [0xE7, 0x82, 0x9C, 0x40]).unwrap();
+        let mut corrupt = file[..second].to_vec();
+        corrupt.extend_from_slice(&[0xE7, 0x88, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        corrupt.extend_from_slice(&file[second + 4..]);
+        let mut reader = MkvReader::new(Cursor::new(corrupt)).unwrap();
+        assert_eq!(reader.by_ref().take(2).map(|packet| packet.unwrap().pts.as_secs()).collect::<Vec<_>>(), [0, 20]);
+        assert!(matches!(reader.next(), Some(Err(CodecError::InvalidData(_)))));
+
+        let mut size = Vec::new();
+        put_size(&mut size, 126);
+        put_size(&mut size, 127);
+        assert_eq!(size, [0xFE, 0x40, 0x7F]);
+        assert_eq!(parse_vint(&UNKNOWN_SIZE.to_be_bytes(), false).unwrap(), (None, 8));
+    }
+}
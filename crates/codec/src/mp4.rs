@@ -0,0 +1,1774 @@
+//! ISO-BMFF (MP4) muxing of encoded streams.
+//!
+//! [`Mp4Fragmenter`] turns [`EncodedFrame`]s into an init segment followed by
+//! `moof`/`mdat` fragments, the form HLS and DASH serve and that survives a
+//! recording being cut short. [`Mp4Writer`] writes either that fragmented
+//! layout or, for maximum player compatibility, a plain MP4 whose sample
+//! tables are written when the file is finished.
+//!
+//! H.264 is stored as `avc1` with length-prefixed samples and the parameter
+//! sets in `avcC`; MJPEG as `mp4v` with the JPEG object type, as ffmpeg does.
+//! Sample times come from packet timestamps, so gaps left by dropped frames
+//! are kept, and each fragment carries a producer reference time (`prft`)
+//! with the capture time of its first frame. [`Mp4Reader`] reads the video
+//! of fragmented files back, capture times included.
+//!
+//! An Opus track declared up front is stored as `Opus` with a `dOps` box
+//! (the Opus in ISO-BMFF mapping) next to the video. Audio packets are placed
+//! by capture time relative to the first video keyframe and should arrive
+//! interleaved with video in capture order; fragments are still cut on
+//! video keyframes and carry the audio received since the previous cut.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io::Cursor;
+//! use streaming_codec::mp4::{Mp4Layout, Mp4Writer};
+//! use streaming_codec::{Encoder, EncoderConfig, H264Encoder};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//!
+//! let mut encoder = H264Encoder::new(EncoderConfig::new(160, 120, 30))?;
+//! let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Layout::Finalized);
+//! for frame in SyntheticSource::new(PixelFormat::YU12, 160, 120).take(10) {
+//!     for packet in encoder.encode(&frame)? {
+//!         writer.write_packet(&packet)?;
+//!     }
+//! }
+//! let file = writer.finish()?.into_inner();
+//! assert_eq!(&file[4..8], b"ftyp");
+//! # Ok::<(), streaming_codec::CodecError>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use bytes::{BufMut, Bytes};
+use streaming_core::Metadata;
+
+use crate::audio::{AudioTrack, OPUS_SAMPLE_RATE};
+use crate::bitstream::{annex_b_to_sample, avcc_to_annex_b, extract_metadata, AvcConfig};
+use crate::{CodecError, CodecId, EncodedFrame, Muxer};
+
+/// Media timescale of the video track, in ticks per second
+pub const TIMESCALE: u32 = 90_000;
+
+/// Media timescale of the audio track, the rate Opus decodes at
+const AUDIO_TIMESCALE: u32 = OPUS_SAMPLE_RATE;
+
+/// Movie header timescale
+const MOVIE_TIMESCALE: u32 = 1000;
+
+/// Seconds from the MP4 epoch (1904) to the Unix epoch
+const MP4_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+const TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+
+/// MPEG-4 object type for JPEG in `esds`
+const OBJECT_TYPE_JPEG: u8 = 0x6C;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// `sample_is_non_sync_sample` in sample flags
+const SAMPLE_NON_SYNC: u32 = 0x0001_0000;
+
+const TFHD_BASE_DATA_OFFSET: u32 = 0x01;
+const TFHD_SAMPLE_DESCRIPTION: u32 = 0x02;
+const TFHD_DEFAULT_DURATION: u32 = 0x08;
+const TFHD_DEFAULT_SIZE: u32 = 0x10;
+const TFHD_DEFAULT_FLAGS: u32 = 0x20;
+
+const TRUN_DATA_OFFSET: u32 = 0x001;
+const TRUN_FIRST_FLAGS: u32 = 0x004;
+const TRUN_DURATION: u32 = 0x100;
+const TRUN_SIZE: u32 = 0x200;
+const TRUN_FLAGS: u32 = 0x400;
+const TRUN_COMPOSITION_OFFSET: u32 = 0x800;
+
+/// Largest box read into memory, to reject corrupt sizes before allocating
+const MAX_BOX_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Most samples accepted in one `trun`
+const MAX_TRUN_SAMPLES: u32 = 1 << 20;
+
+/// Track parameters, taken from the first keyframe.
+#[derive(Debug, Clone)]
+struct Track {
+    codec: CodecId,
+    width: u32,
+    height: u32,
+    avc: Option<AvcConfig>,
+    /// Capture time of stream time zero
+    start: SystemTime,
+}
+
+impl Track {
+    fn from_keyframe(packet: &EncodedFrame) -> Result<Self, CodecError> {
+        let avc = match packet.codec {
+            CodecId::H264 => {
+                let sets = packet.extradata.as_ref().unwrap_or(&packet.data);
+                Some(AvcConfig::from_annex_b(sets)?)
+            }
+            CodecId::Mjpeg => None,
+            CodecId::Opus => return Err(CodecError::InvalidData("audio packet for the video track".to_string())),
+        };
+        Ok(Self {
+            codec: packet.codec,
+            width: packet.width,
+            height: packet.height,
+            avc,
+            start: packet.timestamp.checked_sub(packet.pts).unwrap_or(packet.timestamp),
+        })
+    }
+
+    /// Payload as stored in a sample: length-prefixed NAL units without
+    /// parameter sets for H.264, unchanged for MJPEG
+    fn sample_data(&self, packet: &EncodedFrame) -> Result<Vec<u8>, CodecError> {
+        if packet.codec != self.codec {
+            return Err(CodecError::InvalidData(format!(
+                "{} packet in a {} track",
+                packet.codec, self.codec
+            )));
+        }
+        if packet.pts != packet.dts {
+            return Err(CodecError::InvalidData("reordered frames are not supported".to_string()));
+        }
+        match self.codec {
+            CodecId::H264 => annex_b_to_sample(&packet.data, 4),
+            CodecId::Mjpeg | CodecId::Opus => Ok(packet.data.to_vec()),
+        }
+    }
+}
+
+/// The track a `trak` or `traf` describes
+#[derive(Debug, Clone, Copy)]
+enum Media<'a> {
+    Video(&'a Track),
+    Audio(&'a AudioTrack),
+}
+
+impl Media<'_> {
+    fn id(self) -> u32 {
+        match self {
+            Media::Video(_) => TRACK_ID,
+            Media::Audio(_) => AUDIO_TRACK_ID,
+        }
+    }
+
+    fn timescale(self) -> u32 {
+        match self {
+            Media::Video(_) => TIMESCALE,
+            Media::Audio(_) => AUDIO_TIMESCALE,
+        }
+    }
+}
+
+/// One sample waiting to be written, with its position in the stream
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    time: u64,
+    duration: u32,
+    size: u32,
+    keyframe: bool,
+}
+
+/// Samples of a finalized file's track and their offsets in the file
+type Table<'a> = (&'a [Sample], &'a [u64]);
+
+/// Check an audio packet against the declared track, returning its time
+/// in audio ticks from the video track's start, or `None` if it comes
+/// before the video and cannot be placed
+fn audio_time(
+    audio: Option<&AudioTrack>,
+    track: Option<&Track>,
+    packet: &EncodedFrame,
+) -> Result<Option<u64>, CodecError> {
+    if audio.is_none_or(|audio| audio.codec != packet.codec) {
+        return Err(CodecError::InvalidData(format!("{} packet without a matching audio track", packet.codec)));
+    }
+    let offset = track.and_then(|track| packet.timestamp.duration_since(track.start).ok());
+    Ok(offset.map(|offset| ticks(offset, AUDIO_TIMESCALE)))
+}
+
+/// A `moof`/`mdat` pair ready to be served or appended to a file.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    /// Fragment number, from 1
+    pub sequence: u32,
+
+    /// Stream time of the first sample
+    pub start: Duration,
+
+    pub duration: Duration,
+
+    /// Capture time of the first sample
+    pub timestamp: SystemTime,
+
+    /// Number of frames in the fragment
+    pub samples: usize,
+
+    /// Whether the fragment starts with a keyframe, so playback can begin
+    /// with it
+    pub keyframe: bool,
+
+    pub data: Bytes,
+}
+
+/// Splits a stream into an init segment and fragments.
+///
+/// Frames before the first keyframe are dropped, since nothing could decode
+/// them. Fragments are cut at the first keyframe after the fragment
+/// duration is reached, so each one starts with a keyframe.
+pub struct Mp4Fragmenter {
+    fragment_duration: Duration,
+    audio: Option<AudioTrack>,
+    track: Option<Track>,
+    pending: Vec<(Sample, Vec<u8>, SystemTime)>,
+    last_duration: u32,
+    audio_pending: Vec<(Sample, Vec<u8>)>,
+    audio_last_duration: u32,
+    sequence: u32,
+}
+
+impl Default for Mp4Fragmenter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mp4Fragmenter {
+    /// Create a fragmenter cutting roughly every two seconds
+    pub fn new() -> Self {
+        Self {
+            fragment_duration: Duration::from_secs(2),
+            audio: None,
+            track: None,
+            pending: Vec::new(),
+            last_duration: 0,
+            audio_pending: Vec::new(),
+            audio_last_duration: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Minimum length of a fragment
+    pub fn fragment_duration(mut self, duration: Duration) -> Self {
+        self.fragment_duration = duration;
+        self
+    }
+
+    /// Add an audio track next to the video one
+    pub fn audio(mut self, track: AudioTrack) -> Self {
+        self.audio = Some(track);
+        self
+    }
+
+    /// `ftyp` and `moov` describing the tracks, once the first keyframe
+    /// has been pushed
+    pub fn init_segment(&self) -> Option<Bytes> {
+        let track = self.track.as_ref()?;
+        let mut out = Vec::new();
+        write_ftyp(&mut out, true);
+        write_moov(&mut out, track, self.audio.as_ref(), None);
+        Some(Bytes::from(out))
+    }
+
+    /// Add a packet, returning the previous fragment if this packet starts a
+    /// new one.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the first keyframe lacks H.264
+    /// parameter sets, the codec changes mid-stream, frames are reordered
+    /// or an audio packet does not match the declared audio track.
+    pub fn push(&mut self, packet: &EncodedFrame) -> Result<Option<Fragment>, CodecError> {
+        if packet.codec.is_audio() {
+            if let Some(time) = audio_time(self.audio.as_ref(), self.track.as_ref(), packet)? {
+                self.audio_last_duration = ticks(packet.duration, AUDIO_TIMESCALE).max(1) as u32;
+                let sample = Sample { time, duration: 0, size: packet.data.len() as u32, keyframe: true };
+                self.audio_pending.push((sample, packet.data.to_vec()));
+            }
+            return Ok(None);
+        }
+        if self.track.is_none() {
+            if !packet.keyframe {
+                return Ok(None);
+            }
+            self.track = Some(Track::from_keyframe(packet)?);
+        }
+        let Some(track) = &self.track else {
+            return Ok(None);
+        };
+        let data = track.sample_data(packet)?;
+        let time = ticks(packet.pts, TIMESCALE);
+
+        let mut completed = None;
+        if let Some((first, ..)) = self.pending.first() {
+            let starts_fragment = packet.keyframe || track.codec == CodecId::Mjpeg;
+            if starts_fragment && time.saturating_sub(first.time) >= ticks(self.fragment_duration, TIMESCALE) {
+                completed = Some(self.cut(time));
+            }
+        }
+
+        self.last_duration = ticks(packet.duration, TIMESCALE).max(1) as u32;
+        let sample = Sample {
+            time,
+            duration: 0,
+            size: data.len() as u32,
+            keyframe: packet.keyframe,
+        };
+        self.pending.push((sample, data, packet.timestamp));
+        Ok(completed)
+    }
+
+    /// Emit whatever is buffered as a final fragment
+    pub fn flush(&mut self) -> Option<Fragment> {
+        let (last, ..) = self.pending.last()?;
+        let end = last.time + self.last_duration as u64;
+        Some(self.cut(end))
+    }
+
+    /// Build a fragment from the pending samples, the last video sample
+    /// ending at `end`
+    fn cut(&mut self, end: u64) -> Fragment {
+        let pending = std::mem::take(&mut self.pending);
+        let mut samples: Vec<Sample> = pending.iter().map(|(sample, ..)| *sample).collect();
+        set_durations(&mut samples, end);
+        let audio_pending = std::mem::take(&mut self.audio_pending);
+        let mut audio_samples: Vec<Sample> = audio_pending.iter().map(|(sample, _)| *sample).collect();
+        if let Some(last) = audio_samples.last() {
+            let audio_end = last.time + self.audio_last_duration as u64;
+            set_durations(&mut audio_samples, audio_end);
+        }
+        self.sequence += 1;
+
+        let (first, _, timestamp) = &pending[0];
+        let mut out = Vec::new();
+        write_prft(&mut out, *timestamp, first.time);
+        let moof_start = out.len();
+        let mut trafs = vec![(TRACK_ID, samples.as_slice())];
+        if !audio_samples.is_empty() {
+            trafs.push((AUDIO_TRACK_ID, audio_samples.as_slice()));
+        }
+        let offsets_at = write_moof(&mut out, self.sequence, &trafs);
+        let moof_size = out.len() - moof_start;
+
+        // trun data offsets count from the start of moof to the track's
+        // first sample; video comes first, just past the mdat header
+        let video_payload: usize = pending.iter().map(|(_, data, _)| data.len()).sum();
+        let audio_payload: usize = audio_pending.iter().map(|(_, data)| data.len()).sum();
+        let mut data_offset = moof_size + 8;
+        for (offset_at, payload) in offsets_at.into_iter().zip([video_payload, audio_payload]) {
+            out[offset_at..offset_at + 4].copy_from_slice(&(data_offset as u32).to_be_bytes());
+            data_offset += payload;
+        }
+
+        out.put_u32((video_payload + audio_payload + 8) as u32);
+        out.put_slice(b"mdat");
+        for (_, data, _) in &pending {
+            out.put_slice(data);
+        }
+        for (_, data) in &audio_pending {
+            out.put_slice(data);
+        }
+
+        Fragment {
+            sequence: self.sequence,
+            start: duration(first.time, TIMESCALE),
+            duration: duration(end - first.time, TIMESCALE),
+            timestamp: *timestamp,
+            samples: samples.len(),
+            keyframe: first.keyframe,
+            data: Bytes::from(out),
+        }
+    }
+}
+
+/// How [`Mp4Writer`] lays out a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mp4Layout {
+    /// Init segment and fragments; everything up to the last complete
+    /// fragment stays playable if writing stops abruptly
+    Fragmented { fragment_duration: Duration },
+
+    /// A single `mdat` with the sample tables in `moov` at the end, written
+    /// by [`Mp4Writer::finish`]; readable by every player but unplayable
+    /// until finished
+    Finalized,
+}
+
+/// Writes a stream to an MP4 file.
+///
+/// Needs [`Seek`] to patch the `mdat` size in [`Mp4Layout::Finalized`];
+/// for output that cannot seek, use [`Mp4Fragmenter`] directly.
+pub struct Mp4Writer<W: Write + Seek> {
+    inner: W,
+    layout: Mp4Layout,
+    fragmenter: Mp4Fragmenter,
+    init_written: bool,
+    /// Finalized layout: file offset of the `mdat` box and the samples in it
+    mdat_start: Option<u64>,
+    position: u64,
+    samples: Vec<(Sample, u64)>,
+    last_duration: u32,
+    audio_samples: Vec<(Sample, u64)>,
+    audio_last_duration: u32,
+}
+
+impl Mp4Writer<BufWriter<File>> {
+    /// Create or truncate a file
+    pub fn create(path: impl AsRef<Path>, layout: Mp4Layout) -> Result<Self, CodecError> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), layout))
+    }
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    pub fn new(inner: W, layout: Mp4Layout) -> Self {
+        let fragment_duration = match layout {
+            Mp4Layout::Fragmented { fragment_duration } => fragment_duration,
+            Mp4Layout::Finalized => Duration::ZERO,
+        };
+        Self {
+            inner,
+            layout,
+            fragmenter: Mp4Fragmenter::new().fragment_duration(fragment_duration),
+            init_written: false,
+            mdat_start: None,
+            position: 0,
+            samples: Vec::new(),
+            last_duration: 0,
+            audio_samples: Vec::new(),
+            audio_last_duration: 0,
+        }
+    }
+
+    /// Add an audio track next to the video one
+    pub fn audio(mut self, track: AudioTrack) -> Self {
+        self.fragmenter = self.fragmenter.audio(track);
+        self
+    }
+
+    /// Append one packet.
+    ///
+    /// # Errors
+    ///
+    /// As [`Mp4Fragmenter::push`], or [`CodecError::Io`] if writing fails.
+    pub fn write_packet(&mut self, packet: &EncodedFrame) -> Result<(), CodecError> {
+        match self.layout {
+            Mp4Layout::Fragmented { .. } => {
+                let fragment = self.fragmenter.push(packet)?;
+                if !self.init_written {
+                    if let Some(init) = self.fragmenter.init_segment() {
+                        self.inner.write_all(&init)?;
+                        self.init_written = true;
+                    }
+                }
+                if let Some(fragment) = fragment {
+                    self.inner.write_all(&fragment.data)?;
+                }
+            }
+            Mp4Layout::Finalized if packet.codec.is_audio() => {
+                let fragmenter = &self.fragmenter;
+                let time = audio_time(fragmenter.audio.as_ref(), fragmenter.track.as_ref(), packet)?;
+                // Nothing is placed before the video's first keyframe opens mdat
+                let Some(time) = time.filter(|_| self.mdat_start.is_some()) else {
+                    return Ok(());
+                };
+                let sample = Sample { time, duration: 0, size: packet.data.len() as u32, keyframe: true };
+                self.audio_samples.push((sample, self.position));
+                self.audio_last_duration = ticks(packet.duration, AUDIO_TIMESCALE).max(1) as u32;
+                self.inner.write_all(&packet.data)?;
+                self.position += packet.data.len() as u64;
+            }
+            Mp4Layout::Finalized => {
+                if self.fragmenter.track.is_none() {
+                    if !packet.keyframe {
+                        return Ok(());
+                    }
+                    self.fragmenter.track = Some(Track::from_keyframe(packet)?);
+                }
+                let Some(track) = &self.fragmenter.track else {
+                    return Ok(());
+                };
+                let data = track.sample_data(packet)?;
+
+                if self.mdat_start.is_none() {
+                    let mut header = Vec::new();
+                    write_ftyp(&mut header, false);
+                    self.mdat_start = Some(header.len() as u64);
+                    // 64-bit size, patched in finish()
+                    header.put_u32(1);
+                    header.put_slice(b"mdat");
+                    header.put_u64(0);
+                    self.inner.write_all(&header)?;
+                    self.position = header.len() as u64;
+                }
+
+                let sample = Sample {
+                    time: ticks(packet.pts, TIMESCALE),
+                    duration: 0,
+                    size: data.len() as u32,
+                    keyframe: packet.keyframe,
+                };
+                self.samples.push((sample, self.position));
+                self.last_duration = ticks(packet.duration, TIMESCALE).max(1) as u32;
+                self.inner.write_all(&data)?;
+                self.position += data.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the last fragment or the sample tables and return the writer
+    pub fn finish(mut self) -> Result<W, CodecError> {
+        match self.layout {
+            Mp4Layout::Fragmented { .. } => {
+                if let Some(fragment) = self.fragmenter.flush() {
+                    self.inner.write_all(&fragment.data)?;
+                }
+            }
+            Mp4Layout::Finalized => {
+                if let (Some(mdat_start), Some(track)) = (self.mdat_start, &self.fragmenter.track) {
+                    self.inner.seek(SeekFrom::Start(mdat_start + 8))?;
+                    self.inner.write_all(&(self.position - mdat_start).to_be_bytes())?;
+                    self.inner.seek(SeekFrom::Start(self.position))?;
+
+                    let (samples, offsets) = finish_table(&self.samples, self.last_duration);
+                    let (audio_samples, audio_offsets) = finish_table(&self.audio_samples, self.audio_last_duration);
+                    let tables = [(&samples[..], &offsets[..]), (&audio_samples[..], &audio_offsets[..])];
+
+                    let mut moov = Vec::new();
+                    write_moov(&mut moov, track, self.fragmenter.audio.as_ref(), Some(tables));
+                    self.inner.write_all(&moov)?;
+                }
+            }
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek + Send> Muxer for Mp4Writer<W> {
+    fn write_packet(&mut self, packet: &EncodedFrame) -> Result<(), CodecError> {
+        Mp4Writer::write_packet(self, packet)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), CodecError> {
+        Mp4Writer::finish(*self).map(drop)
+    }
+}
+
+/// Box type and body size, `None` meaning up to the end of the stream
+type BoxHeader = ([u8; 4], Option<u64>);
+
+/// Box type and body of a box inside another
+type Child<'a> = ([u8; 4], &'a [u8]);
+
+/// Stream parameters read from the `moov` of an MP4 file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mp4Info {
+    pub codec: CodecId,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Reads the video frames of a fragmented MP4 file, as written by
+/// [`Mp4Writer`] with [`Mp4Layout::Fragmented`].
+///
+/// Capture times come from the `prft` box of each fragment; frames before
+/// the first one are timed from the Unix epoch. A file that ends partway
+/// through a fragment yields every frame before it;
+/// [`Mp4Reader::is_truncated`] then reports the damage.
+pub struct Mp4Reader<R: Read> {
+    inner: R,
+    position: u64,
+    info: Mp4Info,
+    track_id: u32,
+    timescale: u32,
+    avc: Option<AvcConfig>,
+    /// Capture time of a media time, from the latest `prft`
+    reference: Option<(SystemTime, u64)>,
+    /// Samples of the last `moof` and their file offsets, until its `mdat`
+    located: Vec<(Sample, u64)>,
+    ready: VecDeque<EncodedFrame>,
+    sequence: usize,
+    truncated: bool,
+    done: bool,
+}
+
+impl Mp4Reader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Mp4Reader<R> {
+    /// Read the header, up to the end of `moov`.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the stream is not MP4, has no video
+    /// track in a supported codec or is cut off before the end of `moov`,
+    /// and [`CodecError::Unsupported`] if it is not fragmented.
+    pub fn new(inner: R) -> Result<Self, CodecError> {
+        let mut reader = Self {
+            inner,
+            position: 0,
+            info: Mp4Info { codec: CodecId::Mjpeg, width: 0, height: 0 },
+            track_id: TRACK_ID,
+            timescale: TIMESCALE,
+            avc: None,
+            reference: None,
+            located: Vec::new(),
+            ready: VecDeque::new(),
+            sequence: 0,
+            truncated: false,
+            done: false,
+        };
+        reader.read_header().map_err(|err| match err {
+            CodecError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => invalid("truncated MP4 header"),
+            err => err,
+        })?;
+        Ok(reader)
+    }
+
+    pub fn info(&self) -> &Mp4Info {
+        &self.info
+    }
+
+    /// Whether the stream ended partway through a fragment
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Read the next frame of the video track, as Annex-B with parameter
+    /// sets on keyframes for H.264, or `None` at the end of the stream
+    pub fn next_packet(&mut self) -> Result<Option<EncodedFrame>, CodecError> {
+        if let Some(packet) = self.ready.pop_front() {
+            return Ok(Some(packet));
+        }
+        if self.done {
+            return Ok(None);
+        }
+        match self.read_fragment() {
+            Ok(()) => self.next_packet(),
+            Err(CodecError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                self.done = true;
+                self.truncated = true;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_header(&mut self) -> Result<(), CodecError> {
+        loop {
+            let (kind, size) = self.read_box_header()?.ok_or_else(|| invalid("no moov box"))?;
+            match &kind {
+                b"moov" => {
+                    let body = self.read_body(size)?;
+                    return self.parse_moov(&body);
+                }
+                b"mdat" => return Err(CodecError::Unsupported("MP4 files without fragments")),
+                _ => self.skip(size)?,
+            }
+        }
+    }
+
+    /// Take the first video track
+    fn parse_moov(&mut self, body: &[u8]) -> Result<(), CodecError> {
+        let moov = boxes(body)?;
+        if !moov.iter().any(|(kind, _)| kind == b"mvex") {
+            return Err(CodecError::Unsupported("MP4 files without fragments"));
+        }
+        for (_, trak) in moov.iter().filter(|(kind, _)| kind == b"trak") {
+            let mdia = child(trak, b"mdia")?;
+            if child(mdia, b"hdlr")?.get(8..12) != Some(b"vide") {
+                continue;
+            }
+            // Version 1 headers have 64-bit times before the fields we want
+            let tkhd = child(trak, b"tkhd")?;
+            self.track_id = u32::from_be_bytes(field(tkhd, if tkhd.first() == Some(&1) { 20 } else { 12 })?);
+            let mdhd = child(mdia, b"mdhd")?;
+            let timescale = u32::from_be_bytes(field(mdhd, if mdhd.first() == Some(&1) { 20 } else { 12 })?);
+            self.timescale = timescale.max(1);
+
+            let stsd = child(child(child(mdia, b"minf")?, b"stbl")?, b"stsd")?;
+            let entries = boxes(stsd.get(8..).unwrap_or_default())?;
+            let (kind, entry) = entries.first().ok_or_else(|| invalid("empty sample description"))?;
+            self.info.width = u16::from_be_bytes(field(entry, 24)?) as u32;
+            self.info.height = u16::from_be_bytes(field(entry, 26)?) as u32;
+            let extensions = entry.get(78..).unwrap_or_default();
+            self.info.codec = match kind {
+                b"avc1" => {
+                    self.avc = Some(AvcConfig::parse(child(extensions, b"avcC")?)?);
+                    CodecId::H264
+                }
+                b"mp4v" if esds_object_type(child(extensions, b"esds")?) == Some(OBJECT_TYPE_JPEG) => CodecId::Mjpeg,
+                _ => return Err(invalid(format!("unsupported sample entry {}", String::from_utf8_lossy(kind)))),
+            };
+            return Ok(());
+        }
+        Err(invalid("no video track"))
+    }
+
+    /// Read boxes until the samples of a fragment are ready or the stream
+    /// ends
+    fn read_fragment(&mut self) -> Result<(), CodecError> {
+        while self.ready.is_empty() {
+            let start = self.position;
+            let Some((kind, size)) = self.read_box_header()? else {
+                // A moof without its mdat lost its samples
+                self.truncated = !self.located.is_empty();
+                self.done = true;
+                return Ok(());
+            };
+            match &kind {
+                b"prft" => {
+                    let body = self.read_body(size)?;
+                    self.parse_prft(&body)?;
+                }
+                b"moof" => {
+                    let body = self.read_body(size)?;
+                    self.parse_moof(&body, start)?;
+                }
+                b"mdat" => {
+                    let data_start = self.position;
+                    let body = self.read_body(size)?;
+                    self.take_samples(&body, data_start)?;
+                }
+                _ => self.skip(size)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_prft(&mut self, body: &[u8]) -> Result<(), CodecError> {
+        if u32::from_be_bytes(field(body, 4)?) != self.track_id {
+            return Ok(());
+        }
+        let ntp = u64::from_be_bytes(field(body, 8)?);
+        let media_time = match body.first() {
+            Some(1) => u64::from_be_bytes(field(body, 16)?),
+            _ => u32::from_be_bytes(field(body, 16)?) as u64,
+        };
+        let Some(seconds) = (ntp >> 32).checked_sub(NTP_EPOCH_OFFSET) else {
+            return Ok(());
+        };
+        // Rounded, so whole nanoseconds survive the 32-bit fraction
+        let nanos = ((ntp & 0xFFFF_FFFF) * 1_000_000_000 + (1 << 31)) >> 32;
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_nanos(nanos);
+        self.reference = Some((time, media_time));
+        Ok(())
+    }
+
+    /// Locate the video samples of a fragment; `moof_start` is the file
+    /// offset data offsets count from
+    fn parse_moof(&mut self, body: &[u8], moof_start: u64) -> Result<(), CodecError> {
+        self.located.clear();
+        for (_, traf) in boxes(body)?.into_iter().filter(|(kind, _)| kind == b"traf") {
+            let tfhd = child(traf, b"tfhd")?;
+            let flags = u32::from_be_bytes(field(tfhd, 0)?) & 0xFF_FFFF;
+            if u32::from_be_bytes(field(tfhd, 4)?) != self.track_id {
+                continue;
+            }
+            // Optional fields follow the track ID in flag order
+            let mut pos = 8;
+            let mut next_offset = moof_start;
+            if flags & TFHD_BASE_DATA_OFFSET != 0 {
+                next_offset = u64::from_be_bytes(field(tfhd, pos)?);
+                pos += 8;
+            }
+            if flags & TFHD_SAMPLE_DESCRIPTION != 0 {
+                pos += 4;
+            }
+            let default_duration = take_u32(tfhd, &mut pos, flags & TFHD_DEFAULT_DURATION != 0, 0)?;
+            let default_size = take_u32(tfhd, &mut pos, flags & TFHD_DEFAULT_SIZE != 0, 0)?;
+            let default_flags = take_u32(tfhd, &mut pos, flags & TFHD_DEFAULT_FLAGS != 0, 0)?;
+            let base = next_offset;
+
+            let tfdt = child(traf, b"tfdt")?;
+            let mut time = match tfdt.first() {
+                Some(1) => u64::from_be_bytes(field(tfdt, 4)?),
+                _ => u32::from_be_bytes(field(tfdt, 4)?) as u64,
+            };
+            for (_, trun) in boxes(traf)?.into_iter().filter(|(kind, _)| kind == b"trun") {
+                let flags = u32::from_be_bytes(field(trun, 0)?) & 0xFF_FFFF;
+                let count = u32::from_be_bytes(field(trun, 4)?);
+                if count > MAX_TRUN_SAMPLES {
+                    return Err(invalid(format!("run of {} samples", count)));
+                }
+                let mut pos = 8;
+                // Without a data offset a run continues where the last ended
+                if flags & TRUN_DATA_OFFSET != 0 {
+                    let offset = i32::from_be_bytes(field(trun, pos)?);
+                    let offset = base.checked_add_signed(offset as i64);
+                    next_offset = offset.ok_or_else(|| invalid("negative data offset"))?;
+                    pos += 4;
+                }
+                let first_flags = take_u32(trun, &mut pos, flags & TRUN_FIRST_FLAGS != 0, default_flags)?;
+                for i in 0..count {
+                    let duration = take_u32(trun, &mut pos, flags & TRUN_DURATION != 0, default_duration)?;
+                    let size = take_u32(trun, &mut pos, flags & TRUN_SIZE != 0, default_size)?;
+                    let default_flags = if i == 0 { first_flags } else { default_flags };
+                    let sample_flags = take_u32(trun, &mut pos, flags & TRUN_FLAGS != 0, default_flags)?;
+                    take_u32(trun, &mut pos, flags & TRUN_COMPOSITION_OFFSET != 0, 0)?;
+
+                    let keyframe = sample_flags & SAMPLE_NON_SYNC == 0;
+                    self.located.push((Sample { time, duration, size, keyframe }, next_offset));
+                    time += duration as u64;
+                    next_offset += size as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cut the samples located by the last `moof` out of an `mdat` whose
+    /// body starts at file offset `data_start`
+    fn take_samples(&mut self, mdat: &[u8], data_start: u64) -> Result<(), CodecError> {
+        for (sample, offset) in std::mem::take(&mut self.located) {
+            let payload = offset
+                .checked_sub(data_start)
+                .and_then(|start| mdat.get(start as usize..)?.get(..sample.size as usize))
+                .ok_or_else(|| invalid("sample outside its mdat"))?;
+            let packet = self.packet(sample, payload)?;
+            self.ready.push_back(packet);
+        }
+        Ok(())
+    }
+
+    fn packet(&mut self, sample: Sample, payload: &[u8]) -> Result<EncodedFrame, CodecError> {
+        let pts = duration(sample.time, self.timescale);
+        let timestamp = match self.reference {
+            Some((time, media_time)) => {
+                let reference = duration(media_time, self.timescale);
+                match pts.checked_sub(reference) {
+                    Some(after) => time + after,
+                    None => time.checked_sub(reference - pts).unwrap_or(time),
+                }
+            }
+            None => SystemTime::UNIX_EPOCH + pts,
+        };
+        let (data, extradata, metadata) = match &self.avc {
+            Some(avc) => {
+                let sets = avc.to_annex_b();
+                let mut data = if sample.keyframe { sets.clone() } else { Vec::new() };
+                data.extend(avcc_to_annex_b(payload, avc.length_size())?);
+                let metadata = extract_metadata(&data)?.unwrap_or_default();
+                (Bytes::from(data), if sample.keyframe { Some(Bytes::from(sets)) } else { None }, metadata)
+            }
+            None => (Bytes::copy_from_slice(payload), None, Metadata::default()),
+        };
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+        Ok(EncodedFrame {
+            codec: self.info.codec,
+            width: self.info.width,
+            height: self.info.height,
+            keyframe: sample.keyframe,
+            pts,
+            dts: pts,
+            duration: duration(sample.duration as u64, self.timescale),
+            timestamp,
+            sequence,
+            data,
+            extradata,
+            metadata,
+        })
+    }
+
+    /// Read a box header, or `None` at a clean end of stream
+    fn read_box_header(&mut self) -> Result<Option<BoxHeader>, CodecError> {
+        let mut header = [0u8; 8];
+        if self.inner.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+        self.position += 1;
+        self.read_exact(&mut header[1..])?;
+        let kind: [u8; 4] = header[4..].try_into().unwrap_or_default();
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap_or_default()) {
+            0 => return Ok(Some((kind, None))),
+            1 => {
+                let mut large = [0u8; 8];
+                self.read_exact(&mut large)?;
+                u64::from_be_bytes(large).checked_sub(16)
+            }
+            size => (size as u64).checked_sub(8),
+        };
+        let name = String::from_utf8_lossy(&kind);
+        let size = size.ok_or_else(|| invalid(format!("box {} smaller than its header", name)))?;
+        Ok(Some((kind, Some(size))))
+    }
+
+    fn read_body(&mut self, size: Option<u64>) -> Result<Vec<u8>, CodecError> {
+        let Some(size) = size else {
+            let mut body = Vec::new();
+            self.inner.by_ref().take(MAX_BOX_SIZE).read_to_end(&mut body)?;
+            self.position += body.len() as u64;
+            return Ok(body);
+        };
+        if size > MAX_BOX_SIZE {
+            return Err(invalid(format!("box of {} bytes", size)));
+        }
+        let mut body = vec![0; size as usize];
+        self.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    fn skip(&mut self, size: Option<u64>) -> Result<(), CodecError> {
+        let skipped = io::copy(&mut self.inner.by_ref().take(size.unwrap_or(u64::MAX)), &mut io::sink())?;
+        self.position += skipped;
+        if size.is_some_and(|size| skipped < size) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), CodecError> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Mp4Reader<R> {
+    type Item = Result<EncodedFrame, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+fn invalid(message: impl Into<String>) -> CodecError {
+    CodecError::InvalidData(message.into())
+}
+
+/// Boxes inside a container box's body
+fn boxes(mut data: &[u8]) -> Result<Vec<Child<'_>>, CodecError> {
+    let mut found = Vec::new();
+    while !data.is_empty() {
+        let kind: [u8; 4] = field(data, 4)?;
+        let (header, size) = match u32::from_be_bytes(field(data, 0)?) {
+            0 => (8, data.len()),
+            1 => (16, u64::from_be_bytes(field(data, 8)?).min(usize::MAX as u64) as usize),
+            size => (8, size as usize),
+        };
+        if size < header || size > data.len() {
+            return Err(invalid(format!("box {} overruns its parent", String::from_utf8_lossy(&kind))));
+        }
+        found.push((kind, &data[header..size]));
+        data = &data[size..];
+    }
+    Ok(found)
+}
+
+/// Body of the first `kind` box inside `data`
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<&'a [u8], CodecError> {
+    boxes(data)?
+        .into_iter()
+        .find(|(found, _)| found == kind)
+        .map(|(_, body)| body)
+        .ok_or_else(|| invalid(format!("missing {} box", String::from_utf8_lossy(kind))))
+}
+
+fn field<const N: usize>(data: &[u8], at: usize) -> Result<[u8; N], CodecError> {
+    data.get(at..at + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid("box too short"))
+}
+
+/// The u32 at `pos`, advancing past it, if `present`; otherwise `default`
+fn take_u32(data: &[u8], pos: &mut usize, present: bool, default: u32) -> Result<u32, CodecError> {
+    if !present {
+        return Ok(default);
+    }
+    let value = u32::from_be_bytes(field(data, *pos)?);
+    *pos += 4;
+    Ok(value)
+}
+
+/// Object type of the decoder config in an `esds` box
+fn esds_object_type(esds: &[u8]) -> Option<u8> {
+    // Version and flags, then the ES descriptor
+    if *esds.get(4)? != 0x03 {
+        return None;
+    }
+    let mut pos = descriptor_body(esds, 4)?;
+    let flags = *esds.get(pos + 2)?;
+    pos += 3;
+    // Stream dependence, URL and OCR stream fields, as announced
+    if flags & 0x80 != 0 {
+        pos += 2;
+    }
+    if flags & 0x40 != 0 {
+        pos += 1 + *esds.get(pos)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        pos += 2;
+    }
+    if *esds.get(pos)? != 0x04 {
+        return None;
+    }
+    esds.get(descriptor_body(esds, pos)?).copied()
+}
+
+/// Offset of a descriptor's body from that of its tag, past the
+/// variable-length size
+fn descriptor_body(data: &[u8], tag: usize) -> Option<usize> {
+    let mut pos = tag + 1;
+    for _ in 0..4 {
+        let byte = *data.get(pos)?;
+        pos += 1;
+        if byte & 0x80 == 0 {
+            return Some(pos);
+        }
+    }
+    None
+}
+
+/// Rounded to the nearest tick, since frame times are truncated to whole
+/// nanoseconds
+fn ticks(time: Duration, timescale: u32) -> u64 {
+    ((time.as_nanos() * timescale as u128 + 500_000_000) / 1_000_000_000) as u64
+}
+
+fn duration(ticks: u64, timescale: u32) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / timescale.max(1) as u128) as u64)
+}
+
+/// Each sample lasts until the next one starts; the last until `end`
+fn set_durations(samples: &mut [Sample], end: u64) {
+    let mut next = end;
+    for sample in samples.iter_mut().rev() {
+        sample.duration = next.saturating_sub(sample.time).max(1) as u32;
+        next = sample.time;
+    }
+}
+
+/// Samples of a finalized track with their durations set, and their offsets
+fn finish_table(written: &[(Sample, u64)], last_duration: u32) -> (Vec<Sample>, Vec<u64>) {
+    let mut samples: Vec<Sample> = written.iter().map(|(sample, _)| *sample).collect();
+    if let Some(last) = samples.last() {
+        let end = last.time + last_duration as u64;
+        set_durations(&mut samples, end);
+    }
+    (samples, written.iter().map(|(_, offset)| *offset).collect())
+}
+
+/// Convert between timescales, rounding down
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    (value as u128 * to as u128 / from as u128) as u64
+}
+
+fn mp4_time(time: SystemTime) -> u64 {
+    let unix = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    unix + MP4_EPOCH_OFFSET
+}
+
+fn atom(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.put_u32(0);
+    out.put_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn full_atom(out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    atom(out, kind, |out| {
+        out.put_u32((version as u32) << 24 | flags);
+        body(out);
+    });
+}
+
+/// Version of a header box able to hold `created` and `duration`: 1, with
+/// 64-bit times and durations, once either overflows 32 bits
+fn header_version(created: u64, duration: u64) -> u8 {
+    u8::from(created.max(duration) > u32::MAX as u64)
+}
+
+/// Creation and modification time of a header box of `version`
+fn put_times(out: &mut Vec<u8>, version: u8, created: u64) {
+    for _ in 0..2 {
+        if version == 1 {
+            out.put_u64(created);
+        } else {
+            out.put_u32(created as u32);
+        }
+    }
+}
+
+/// Duration field of a header box of `version`
+fn put_duration(out: &mut Vec<u8>, version: u8, duration: u64) {
+    if version == 1 {
+        out.put_u64(duration);
+    } else {
+        out.put_u32(duration as u32);
+    }
+}
+
+fn write_matrix(out: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000u32] {
+        out.put_u32(value);
+    }
+}
+
+fn write_ftyp(out: &mut Vec<u8>, fragmented: bool) {
+    atom(out, b"ftyp", |out| {
+        if fragmented {
+            out.put_slice(b"iso6");
+            out.put_u32(0);
+            out.put_slice(b"iso6iso5mp41");
+        } else {
+            out.put_slice(b"isom");
+            out.put_u32(0x200);
+            out.put_slice(b"isomiso2avc1mp41");
+        }
+    });
+}
+
+/// `moov` for a fragmented file when `tables` is `None`, otherwise with the
+/// sample tables of a finalized one, video first
+fn write_moov(out: &mut Vec<u8>, track: &Track, audio: Option<&AudioTrack>, tables: Option<[Table; 2]>) {
+    let created = mp4_time(track.start);
+    let [video_table, audio_table] = tables.map_or([None, None], |tables| tables.map(Some));
+    let mut traks = vec![(Media::Video(track), video_table, 0)];
+    if let Some(audio) = audio {
+        // A finalized file starts at the first frame; audio keeps its place
+        // relative to it through an empty edit
+        let first = |table: Option<Table>| table.and_then(|(samples, _)| samples.first().map(|sample| sample.time));
+        let video_start = first(video_table).map_or(0, |time| rescale(time, TIMESCALE, AUDIO_TIMESCALE));
+        let delay = first(audio_table).map_or(0, |time| time.saturating_sub(video_start));
+        traks.push((Media::Audio(audio), audio_table, delay));
+    }
+    let movie_duration = traks
+        .iter()
+        .map(|&(media, table, delay)| rescale(delay + media_duration(table), media.timescale(), MOVIE_TIMESCALE))
+        .max()
+        .unwrap_or(0);
+
+    atom(out, b"moov", |out| {
+        let version = header_version(created, movie_duration);
+        full_atom(out, b"mvhd", version, 0, |out| {
+            put_times(out, version, created);
+            out.put_u32(MOVIE_TIMESCALE);
+            put_duration(out, version, movie_duration);
+            out.put_u32(0x0001_0000);
+            out.put_u16(0x0100);
+            out.put_bytes(0, 10);
+            write_matrix(out);
+            out.put_bytes(0, 24);
+            out.put_u32(traks.len() as u32 + 1);
+        });
+
+        for &(media, table, delay) in &traks {
+            write_trak(out, media, created, table, delay);
+        }
+
+        if tables.is_none() {
+            atom(out, b"mvex", |out| {
+                for &(media, ..) in &traks {
+                    full_atom(out, b"trex", 0, 0, |out| {
+                        out.put_u32(media.id());
+                        out.put_u32(1);
+                        out.put_u32(0);
+                        out.put_u32(0);
+                        out.put_u32(0);
+                    });
+                }
+            });
+        }
+    });
+}
+
+/// Total duration of a track's samples, in its timescale
+fn media_duration(table: Option<Table>) -> u64 {
+    table.map_or(0, |(samples, _)| samples.iter().map(|sample| sample.duration as u64).sum())
+}
+
+/// `trak` for one track, starting `delay` media ticks into the movie
+fn write_trak(out: &mut Vec<u8>, media: Media, created: u64, table: Option<Table>, delay: u64) {
+    let media_duration = media_duration(table);
+    let to_movie = |ticks| rescale(ticks, media.timescale(), MOVIE_TIMESCALE);
+    let (width, height) = match media {
+        Media::Video(track) => (track.width, track.height),
+        Media::Audio(_) => (0, 0),
+    };
+
+    atom(out, b"trak", |out| {
+        let track_duration = to_movie(delay + media_duration);
+        let version = header_version(created, track_duration);
+        full_atom(out, b"tkhd", version, 0x3, |out| {
+            put_times(out, version, created);
+            out.put_u32(media.id());
+            out.put_u32(0);
+            put_duration(out, version, track_duration);
+            out.put_bytes(0, 8);
+            out.put_u16(0);
+            out.put_u16(0);
+            // Volume: full for audio, none for video
+            out.put_u16(if matches!(media, Media::Audio(_)) { 0x0100 } else { 0 });
+            out.put_u16(0);
+            write_matrix(out);
+            out.put_u32(width << 16);
+            out.put_u32(height << 16);
+        });
+
+        if delay > 0 {
+            atom(out, b"edts", |out| {
+                // Edit lists carry no times
+                let version = header_version(0, track_duration);
+                full_atom(out, b"elst", version, 0, |out| {
+                    out.put_u32(2);
+                    // Nothing for `delay`, then the media from its start
+                    for (duration, media_time) in [(to_movie(delay), -1), (to_movie(media_duration), 0)] {
+                        put_duration(out, version, duration);
+                        if version == 1 {
+                            out.put_i64(media_time);
+                        } else {
+                            out.put_i32(media_time as i32);
+                        }
+                        out.put_u32(0x0001_0000);
+                    }
+                });
+            });
+        }
+
+        atom(out, b"mdia", |out| {
+            let version = header_version(created, media_duration);
+            full_atom(out, b"mdhd", version, 0, |out| {
+                put_times(out, version, created);
+                out.put_u32(media.timescale());
+                put_duration(out, version, media_duration);
+                // "und", packed as three 5-bit letters
+                out.put_u16(0x55C4);
+                out.put_u16(0);
+            });
+            let (handler, name) = match media {
+                Media::Video(_) => (b"vide", &b"VideoHandler\0"[..]),
+                Media::Audio(_) => (b"soun", &b"SoundHandler\0"[..]),
+            };
+            full_atom(out, b"hdlr", 0, 0, |out| {
+                out.put_u32(0);
+                out.put_slice(handler);
+                out.put_bytes(0, 12);
+                out.put_slice(name);
+            });
+
+            atom(out, b"minf", |out| {
+                match media {
+                    Media::Video(_) => full_atom(out, b"vmhd", 0, 1, |out| out.put_bytes(0, 8)),
+                    Media::Audio(_) => full_atom(out, b"smhd", 0, 0, |out| out.put_bytes(0, 4)),
+                }
+                atom(out, b"dinf", |out| {
+                    full_atom(out, b"dref", 0, 0, |out| {
+                        out.put_u32(1);
+                        full_atom(out, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_stbl(out, media, table);
+            });
+        });
+    });
+}
+
+fn write_stbl(out: &mut Vec<u8>, media: Media, table: Option<Table>) {
+    let (samples, offsets) = table.unwrap_or((&[], &[]));
+    atom(out, b"stbl", |out| {
+        full_atom(out, b"stsd", 0, 0, |out| {
+            out.put_u32(1);
+            write_sample_entry(out, media);
+        });
+
+        // Run-length encoded sample durations
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for sample in samples {
+            match runs.last_mut() {
+                Some((count, duration)) if *duration == sample.duration => *count += 1,
+                _ => runs.push((1, sample.duration)),
+            }
+        }
+        full_atom(out, b"stts", 0, 0, |out| {
+            out.put_u32(runs.len() as u32);
+            for (count, duration) in &runs {
+                out.put_u32(*count);
+                out.put_u32(*duration);
+            }
+        });
+
+        // One sample per chunk, so chunk offsets are sample offsets
+        full_atom(out, b"stsc", 0, 0, |out| {
+            if samples.is_empty() {
+                out.put_u32(0);
+            } else {
+                out.put_u32(1);
+                out.put_u32(1);
+                out.put_u32(1);
+                out.put_u32(1);
+            }
+        });
+        full_atom(out, b"stsz", 0, 0, |out| {
+            out.put_u32(0);
+            out.put_u32(samples.len() as u32);
+            for sample in samples {
+                out.put_u32(sample.size);
+            }
+        });
+        if offsets.iter().all(|&offset| offset <= u32::MAX as u64) {
+            full_atom(out, b"stco", 0, 0, |out| {
+                out.put_u32(offsets.len() as u32);
+                for &offset in offsets {
+                    out.put_u32(offset as u32);
+                }
+            });
+        } else {
+            full_atom(out, b"co64", 0, 0, |out| {
+                out.put_u32(offsets.len() as u32);
+                for &offset in offsets {
+                    out.put_u64(offset);
+                }
+            });
+        }
+
+        // Without stss every sample is a sync sample
+        if samples.iter().any(|sample| !sample.keyframe) {
+            let sync: Vec<u32> = (1..).zip(samples).filter(|(_, s)| s.keyframe).map(|(i, _)| i).collect();
+            full_atom(out, b"stss", 0, 0, |out| {
+                out.put_u32(sync.len() as u32);
+                for index in sync {
+                    out.put_u32(index);
+                }
+            });
+        }
+    });
+}
+
+fn write_sample_entry(out: &mut Vec<u8>, media: Media) {
+    let track = match media {
+        Media::Video(track) => track,
+        Media::Audio(audio) => return write_audio_sample_entry(out, audio),
+    };
+    let kind = match track.codec {
+        CodecId::H264 => b"avc1",
+        CodecId::Mjpeg | CodecId::Opus => b"mp4v",
+    };
+    atom(out, kind, |out| {
+        out.put_bytes(0, 6);
+        out.put_u16(1);
+        out.put_bytes(0, 16);
+        out.put_u16(track.width as u16);
+        out.put_u16(track.height as u16);
+        out.put_u32(0x0048_0000);
+        out.put_u32(0x0048_0000);
+        out.put_u32(0);
+        out.put_u16(1);
+        out.put_bytes(0, 32);
+        out.put_u16(0x0018);
+        out.put_i16(-1);
+
+        match &track.avc {
+            Some(avc) => atom(out, b"avcC", |out| out.put_slice(&avc.to_bytes())),
+            None => full_atom(out, b"esds", 0, 0, |out| {
+                // ES descriptor holding a decoder config and SL config
+                out.put_slice(&[0x03, 3 + 15 + 3, 0x00, 0x01, 0x00]);
+                out.put_slice(&[0x04, 13, OBJECT_TYPE_JPEG, 0x11]);
+                out.put_bytes(0, 3 + 4 + 4);
+                out.put_slice(&[0x06, 1, 0x02]);
+            }),
+        }
+    });
+}
+
+/// `Opus` sample entry with its `dOps` box, which holds the fields of
+/// `OpusHead` big-endian
+fn write_audio_sample_entry(out: &mut Vec<u8>, audio: &AudioTrack) {
+    atom(out, b"Opus", |out| {
+        out.put_bytes(0, 6);
+        out.put_u16(1);
+        out.put_bytes(0, 8);
+        out.put_u16(audio.channels);
+        out.put_u16(16);
+        out.put_u32(0);
+        out.put_u32(audio.sample_rate() << 16);
+        atom(out, b"dOps", |out| {
+            out.put_u8(0);
+            out.put_u8(audio.channels as u8);
+            out.put_u16(audio.pre_skip);
+            out.put_u32(audio.input_sample_rate);
+            // Output gain and channel mapping family 0
+            out.put_i16(0);
+            out.put_u8(0);
+        });
+    });
+}
+
+/// Producer reference time: the capture time of a fragment's first sample
+fn write_prft(out: &mut Vec<u8>, timestamp: SystemTime, media_time: u64) {
+    let since_unix = timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_unix.as_secs() + NTP_EPOCH_OFFSET;
+    let fraction = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    full_atom(out, b"prft", 1, 0, |out| {
+        out.put_u32(TRACK_ID);
+        out.put_u64(seconds << 32 | fraction);
+        out.put_u64(media_time);
+    });
+}
+
+/// Write `moof` with a `traf` per track, leaving each trun's data offset
+/// zero and returning where those offsets are in `out`
+fn write_moof(out: &mut Vec<u8>, sequence: u32, trafs: &[(u32, &[Sample])]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(trafs.len());
+    atom(out, b"moof", |out| {
+        full_atom(out, b"mfhd", 0, 0, |out| out.put_u32(sequence));
+        for &(track_id, samples) in trafs {
+            atom(out, b"traf", |out| {
+                // default-base-is-moof
+                full_atom(out, b"tfhd", 0, 0x02_0000, |out| out.put_u32(track_id));
+                full_atom(out, b"tfdt", 1, 0, |out| out.put_u64(samples[0].time));
+                // data offset, then duration, size and flags per sample
+                offsets.push(out.len() + 16);
+                full_atom(out, b"trun", 0, 0x000_701, |out| {
+                    out.put_u32(samples.len() as u32);
+                    out.put_u32(0);
+                    for sample in samples {
+                        out.put_u32(sample.duration);
+                        out.put_u32(sample.size);
+                        out.put_u32(if sample.keyframe { SAMPLE_FLAGS_SYNC } else { SAMPLE_FLAGS_NON_SYNC });
+                    }
+                });
+            });
+        }
+    });
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{avcc_to_annex_b, split_annex_b, write_avcc};
+    use crate::test_util::{at, mjpeg_packets, opus_packets};
+    use crate::{Decoder, Encoder, EncoderConfig, H264Decoder, H264Encoder, MjpegEncoder};
+    use std::io::Cursor;
+    use streaming_core::{PixelFormat, SyntheticSource};
+
+    /// Minimal box walker checking that sizes nest exactly
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut found = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            assert!(data.len() - pos >= 8, "truncated box header at {}", pos);
+            let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+            let (header, size) = match u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) {
+                1 => (16, u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap()) as usize),
+                0 => (8, data.len() - pos),
+                size => (8, size as usize),
+            };
+            assert!(size >= header && pos + size <= data.len(), "bad size for {:?}", kind);
+            found.push((kind, &data[pos + header..pos + size]));
+            pos += size;
+        }
+        found
+    }
+
+    /// Body of the box at `path`, descending through containers
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        let (kind, rest) = path.split_first().unwrap();
+        let body = boxes(data).into_iter().find(|(k, _)| k == *kind).unwrap_or_else(|| panic!("no {:?}", kind)).1;
+        if rest.is_empty() {
+            body
+        } else {
+            find(body, rest)
+        }
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn h264_packets(count: usize, gop: u32) -> Vec<EncodedFrame> {
+        let mut config = EncoderConfig::new(96, 64, 30);
+        config.keyframe_interval = gop;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        SyntheticSource::new(PixelFormat::YU12, 96, 64)
+            .velocity(3, 2)
+            .take(count)
+            .flat_map(|frame| encoder.encode(&frame).unwrap())
+            .collect()
+    }
+
+    /// `avcC` from a sample entry, skipping the visual sample entry fields
+    fn avc_config(init: &[u8]) -> AvcConfig {
+        let stsd = find(init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+        let entry = find(&stsd[8..], &[b"avc1"]);
+        AvcConfig::parse(find(&entry[78..], &[b"avcC"])).unwrap()
+    }
+
+    #[test]
+    fn fragments_parse_and_decode() {
+        let packets = h264_packets(30, 10);
+        let mut fragmenter = Mp4Fragmenter::new().fragment_duration(Duration::from_millis(300));
+        let mut fragments: Vec<_> = packets.iter().filter_map(|packet| fragmenter.push(packet).unwrap()).collect();
+        fragments.extend(fragmenter.flush());
+
+        let init = fragmenter.init_segment().unwrap();
+        let kinds: Vec<_> = boxes(&init).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov"]);
+        find(&init, &[b"moov", b"mvex", b"trex"]);
+        let avc = avc_config(&init);
+        assert_eq!(avc.to_annex_b(), packets[0].extradata.as_ref().unwrap().to_vec());
+
+        // Keyframes every 10 frames at 30 fps: one fragment per GOP
+        assert_eq!(fragments.len(), 3);
+        let mut decoder = H264Decoder::new(PixelFormat::YU12).unwrap();
+        let mut next_time = 0;
+        let mut decoded = 0;
+        for fragment in &fragments {
+            assert!(fragment.keyframe);
+            let top = boxes(&fragment.data);
+            let kinds: Vec<_> = top.iter().map(|(kind, _)| *kind).collect();
+            assert_eq!(kinds, [*b"prft", *b"moof", *b"mdat"]);
+
+            let moof = top[1].1;
+            let tfdt = find(moof, &[b"traf", b"tfdt"]);
+            assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), next_time);
+            let trun = find(moof, &[b"traf", b"trun"]);
+            let count = u32_at(trun, 4) as usize;
+            assert_eq!(count, fragment.samples);
+            assert_eq!(u32_at(trun, 8) as usize, moof.len() + 8 + 8);
+
+            let mut pos = 0;
+            for i in 0..count {
+                let entry = 12 + i * 12;
+                let (duration, size, flags) = (u32_at(trun, entry), u32_at(trun, entry + 4), u32_at(trun, entry + 8));
+                assert_eq!(duration, TIMESCALE / 30);
+                assert_eq!(flags == SAMPLE_FLAGS_SYNC, i == 0);
+                next_time += duration as u64;
+
+                let sample = &top[2].1[pos..pos + size as usize];
+                pos += size as usize;
+                let mut annex_b = if i == 0 { avc.to_annex_b() } else { Vec::new() };
+                annex_b.extend(avcc_to_annex_b(sample, 4).unwrap());
+                let packet = EncodedFrame {
+                    data: Bytes::from(annex_b),
+                    ..packets[decoded].clone()
+                };
+                assert_eq!(decoder.decode(&packet).unwrap().len(), 1);
+                decoded += 1;
+            }
+            assert_eq!(pos, top[2].1.len());
+        }
+        assert_eq!(decoded, 30);
+
+        // The reference time is the capture time of the fragment's first frame
+        let prft = boxes(&fragments[1].data)[0].1;
+        let ntp = u64::from_be_bytes(prft[8..16].try_into().unwrap());
+        let capture = packets[10].timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(ntp >> 32, capture.as_secs() + NTP_EPOCH_OFFSET);
+    }
+
+    #[test]
+    fn finalized_file_has_sample_tables() {
+        let packets = h264_packets(12, 5);
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Layout::Finalized);
+        // Joining mid-GOP: frames before the keyframe are left out
+        for packet in &packets[3..] {
+            writer.write_packet(packet).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        let kinds: Vec<_> = boxes(&file).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"mdat", *b"moov"]);
+        let stbl = find(&file, &[b"moov", b"trak", b"mdia", b"minf", b"stbl"]);
+        let stsz = find(stbl, &[b"stsz"]);
+        let stco = find(stbl, &[b"stco"]);
+        assert_eq!((u32_at(stsz, 8), u32_at(stco, 4)), (7, 7));
+        let stss = find(stbl, &[b"stss"]);
+        assert_eq!((u32_at(stss, 4), u32_at(stss, 8), u32_at(stss, 12)), (2, 1, 6));
+        let stts = find(stbl, &[b"stts"]);
+        assert_eq!((u32_at(stts, 4), u32_at(stts, 8), u32_at(stts, 12)), (1, 7, TIMESCALE / 30));
+
+        for (i, packet) in packets[5..].iter().enumerate() {
+            let (offset, size) = (u32_at(stco, 8 + i * 4) as usize, u32_at(stsz, 12 + i * 4) as usize);
+            let sample = &file[offset..offset + size];
+            let expected: Vec<_> =
+                split_annex_b(&packet.data).into_iter().filter(|unit| unit.nal_type().is_vcl()).collect();
+            assert_eq!(sample, write_avcc(&expected, 4).unwrap());
+        }
+
+        let mvhd = find(&file, &[b"moov", b"mvhd"]);
+        assert_eq!(u32_at(mvhd, 16), 7 * 1000 / 30);
+
+        // Frames seven hours apart overflow 32-bit durations at 90 kHz
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Layout::Finalized);
+        for (i, packet) in mjpeg_packets(at(0), 3).into_iter().enumerate() {
+            let pts = Duration::from_secs(7 * 3600 * i as u64);
+            writer.write_packet(&EncodedFrame { pts, dts: pts, ..packet }).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+        let mdhd = find(&file, &[b"moov", b"trak", b"mdia", b"mdhd"]);
+        assert_eq!(mdhd[0], 1);
+        let ticks = 14 * 3600 * TIMESCALE as u64 + TIMESCALE as u64 / 10;
+        assert_eq!(u64::from_be_bytes(mdhd[24..32].try_into().unwrap()), ticks);
+        // Milliseconds still fit
+        let tkhd = find(&file, &[b"moov", b"trak", b"tkhd"]);
+        assert_eq!((tkhd[0], u32_at(tkhd, 20)), (0, 14 * 3600 * 1000 + 100));
+
+        // Capture times from 2040 overflow 32-bit creation times
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(2_240_000_000);
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Layout::Finalized);
+        for packet in mjpeg_packets(start, 2) {
+            writer.write_packet(&packet).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+        for path in [&[b"moov", b"mvhd"][..], &[b"moov", b"trak", b"tkhd"], &[b"moov", b"trak", b"mdia", b"mdhd"]] {
+            let header = find(&file, path);
+            assert_eq!(header[0], 1);
+            assert_eq!(u64::from_be_bytes(header[4..12].try_into().unwrap()), 2_240_000_000 + MP4_EPOCH_OFFSET);
+        }
+    }
+
+    #[test]
+    fn mjpeg_uses_mp4v_and_cuts_on_duration() {
+        let config = EncoderConfig::new(32, 32, 10);
+        let mut encoder = MjpegEncoder::new(config);
+        let mut fragmenter = Mp4Fragmenter::new().fragment_duration(Duration::from_millis(500));
+        let mut fragments = Vec::new();
+        for frame in SyntheticSource::new(PixelFormat::RGB3, 32, 32).fps(10).take(12) {
+            let packet = encoder.encode(&frame).unwrap().remove(0);
+            fragments.extend(fragmenter.push(&packet).unwrap());
+        }
+        fragments.extend(fragmenter.flush());
+        let counts: Vec<_> = fragments.iter().map(|fragment| fragment.samples).collect();
+        assert_eq!(counts, [5, 5, 2]);
+        assert_eq!(fragments[1].start, Duration::from_millis(500));
+
+        let init = fragmenter.init_segment().unwrap();
+        let stsd = find(&init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+        let esds = find(&find(&stsd[8..], &[b"mp4v"])[78..], &[b"esds"]);
+        assert_eq!(esds[4..7], [0x03, 21, 0x00]);
+        assert_eq!(esds[9..12], [0x04, 13, OBJECT_TYPE_JPEG]);
+    }
+
+    #[test]
+    fn opus_track_in_fragments_and_tables() {
+        let mut encoder = MjpegEncoder::new(EncoderConfig::new(32, 32, 10));
+        let video: Vec<_> = SyntheticSource::new(PixelFormat::RGB3, 32, 32)
+            .fps(10)
+            .take(10)
+            .flat_map(|frame| encoder.encode(&frame).unwrap())
+            .collect();
+        // Audio from 10 ms after the first frame, in capture order with it
+        let audio = opus_packets(video[0].timestamp + Duration::from_millis(10), 49);
+        let mut packets: Vec<_> = video.iter().chain(&audio).cloned().collect();
+        packets.sort_by_key(|packet| packet.timestamp);
+        let track = AudioTrack::from_opus_head(audio[0].extradata.as_ref().unwrap()).unwrap();
+
+        let mut fragmenter = Mp4Fragmenter::new().fragment_duration(Duration::from_millis(500)).audio(track);
+        let mut fragments: Vec<_> = packets.iter().filter_map(|packet| fragmenter.push(packet).unwrap()).collect();
+        fragments.extend(fragmenter.flush());
+        assert_eq!(fragments.len(), 2);
+
+        let init = fragmenter.init_segment().unwrap();
+        let moov = find(&init, &[b"moov"]);
+        let traks: Vec<_> = boxes(moov).into_iter().filter(|(kind, _)| kind == b"trak").collect();
+        assert_eq!(traks.len(), 2);
+        assert_eq!(&find(traks[1].1, &[b"mdia", b"hdlr"])[8..12], b"soun");
+        let stsd = find(traks[1].1, &[b"mdia", b"minf", b"stbl", b"stsd"]);
+        let dops = find(&find(&stsd[8..], &[b"Opus"])[28..], &[b"dOps"]);
+        assert_eq!(dops, [0, 1, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0, 0, 0]);
+        assert_eq!(boxes(find(moov, &[b"mvex"])).len(), 2);
+
+        // Each fragment carries the audio since the last cut, at capture time
+        let mut read = Vec::new();
+        for fragment in &fragments {
+            let top = boxes(&fragment.data);
+            let (moof, mdat) = (top[1].1, top[2].1);
+            let trafs: Vec<_> = boxes(moof).into_iter().filter(|(kind, _)| kind == b"traf").collect();
+            assert_eq!(u32_at(find(trafs[1].1, &[b"tfhd"]), 4), AUDIO_TRACK_ID);
+            let tfdt = find(trafs[1].1, &[b"tfdt"]);
+            let mut time = u64::from_be_bytes(tfdt[4..12].try_into().unwrap());
+            let trun = find(trafs[1].1, &[b"trun"]);
+            let mut pos = u32_at(trun, 8) as usize - (moof.len() + 8 + 8);
+            for i in 0..u32_at(trun, 4) as usize {
+                let (duration, size) = (u32_at(trun, 12 + i * 12), u32_at(trun, 16 + i * 12) as usize);
+                read.push((time, &mdat[pos..pos + size]));
+                time += duration as u64;
+                pos += size;
+            }
+        }
+        assert_eq!(read.len(), 49);
+        for (i, (time, data)) in read.into_iter().enumerate() {
+            assert_eq!(time, 480 + 960 * i as u64);
+            assert_eq!(data, &audio[i].data[..]);
+        }
+
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Layout::Finalized).audio(track);
+        for packet in &packets {
+            writer.write_packet(packet).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+        let moov = find(&file, &[b"moov"]);
+        assert_eq!(u32_at(find(moov, &[b"mvhd"]), 96), 3);
+        let trak = boxes(moov).into_iter().filter(|(kind, _)| kind == b"trak").nth(1).unwrap().1;
+        let stbl = find(trak, &[b"mdia", b"minf", b"stbl"]);
+        let (stsz, stco) = (find(stbl, &[b"stsz"]), find(stbl, &[b"stco"]));
+        assert_eq!(u32_at(stsz, 8), 49);
+        for (i, packet) in audio.iter().enumerate() {
+            let (offset, size) = (u32_at(stco, 8 + i * 4) as usize, u32_at(stsz, 12 + i * 4) as usize);
+            assert_eq!(file[offset..offset + size], packet.data[..]);
+        }
+        // An empty edit keeps the audio 10 ms behind the first frame
+        let elst = find(trak, &[b"edts", b"elst"]);
+        assert_eq!((u32_at(elst, 4), u32_at(elst, 8), u32_at(elst, 12)), (2, 10, u32::MAX));
+    }
+
+    #[test]
+    fn reader_reads_back_fragmented_files() {
+        let video = h264_packets(30, 10);
+        let audio = opus_packets(video[0].timestamp, 40);
+        let mut packets: Vec<_> = video.iter().chain(&audio).cloned().collect();
+        packets.sort_by_key(|packet| packet.timestamp);
+        let track = AudioTrack::from_opus_head(audio[0].extradata.as_ref().unwrap()).unwrap();
+        let layout = Mp4Layout::Fragmented { fragment_duration: Duration::from_millis(300) };
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), layout).audio(track);
+        for packet in &packets {
+            writer.write_packet(packet).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        let mut reader = Mp4Reader::new(Cursor::new(&file)).unwrap();
+        assert_eq!(*reader.info(), Mp4Info { codec: CodecId::H264, width: 96, height: 64 });
+        let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert!(!reader.is_truncated());
+        assert_eq!(read.len(), 30);
+        let mut decoder = H264Decoder::new(PixelFormat::YU12).unwrap();
+        let near = |a: SystemTime, b: SystemTime| a.duration_since(b).or(b.duration_since(a)).unwrap().as_micros() < 1;
+        for (read, packet) in read.iter().zip(&video) {
+            assert_eq!(read.keyframe, packet.keyframe);
+            assert_eq!(ticks(read.pts, TIMESCALE), ticks(packet.pts, TIMESCALE));
+            assert!(near(read.timestamp, packet.timestamp));
+            assert_eq!(decoder.decode(read).unwrap().len(), 1);
+        }
+
+        // Cut partway through the last fragment: the first two survive
+        let mut reader = Mp4Reader::new(Cursor::new(&file[..file.len() - 100])).unwrap();
+        assert_eq!(reader.by_ref().count(), 20);
+        assert!(reader.is_truncated());
+
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new()), Mp4Layout::Finalized);
+        for packet in &video {
+            writer.write_packet(packet).unwrap();
+        }
+        let finalized = writer.finish().unwrap().into_inner();
+        assert!(matches!(Mp4Reader::new(Cursor::new(finalized)), Err(CodecError::Unsupported(_))));
+    }
+}
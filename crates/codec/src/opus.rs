@@ -0,0 +1,183 @@
+//! Opus audio encoding and decoding through libopus.
+//!
+//! Packets hold one 20 ms Opus frame each. Their extradata is the
+//! `OpusHead` header containers need, with the encoder's lookahead as
+//! pre-skip.
+//!
+//! Behind the `opus` feature: the `opus` crate builds a bundled libopus
+//! with cmake, which has to be installed.
+
+use ::opus::{Application, Bitrate, Channels};
+use bytes::Bytes;
+use streaming_core::{AudioFrame, Metadata};
+
+use crate::audio::{opus_head, AudioDecoder, AudioEncoder, Rechunker};
+use crate::{CodecError, CodecId, EncodedFrame, StreamClock};
+
+/// Largest packet libopus is asked to produce, as its documentation
+/// recommends
+const MAX_PACKET: usize = 4000;
+
+/// Longest frame an Opus packet can decode to, 120 ms, in samples per
+/// channel at 48 kHz
+const MAX_FRAME: usize = 5760;
+
+fn backend(err: ::opus::Error) -> CodecError {
+    CodecError::Backend(err.to_string())
+}
+
+fn channel_layout(channels: u16) -> Result<Channels, CodecError> {
+    match channels {
+        1 => Ok(Channels::Mono),
+        2 => Ok(Channels::Stereo),
+        _ => Err(CodecError::InvalidData(format!("Opus takes 1 or 2 channels, not {}", channels))),
+    }
+}
+
+/// Opus encoder in 20 ms frames.
+///
+/// Input may arrive in runs of any length; samples are buffered until a
+/// frame is complete.
+pub struct OpusEncoder {
+    encoder: ::opus::Encoder,
+    rechunker: Rechunker,
+    clock: StreamClock,
+    extradata: Bytes,
+}
+
+impl OpusEncoder {
+    /// Encode `channels` at `sample_rate`, which must be one of 8, 12, 16,
+    /// 24 or 48 kHz, at `bitrate` bits per second
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] for more than two channels, and
+    /// [`CodecError::Backend`] if libopus rejects the settings.
+    pub fn new(sample_rate: u32, channels: u16, bitrate: u32) -> Result<Self, CodecError> {
+        let mut encoder =
+            ::opus::Encoder::new(sample_rate, channel_layout(channels)?, Application::Audio).map_err(backend)?;
+        encoder.set_bitrate(Bitrate::Bits(bitrate.min(i32::MAX as u32) as i32)).map_err(backend)?;
+        // OpusHead counts pre-skip at 48 kHz whatever the input rate
+        let lookahead = encoder.get_lookahead().map_err(backend)?.max(0) as u32;
+        let pre_skip = (lookahead * 48_000 / sample_rate) as u16;
+        Ok(Self {
+            encoder,
+            rechunker: Rechunker::new(sample_rate, channels, (sample_rate / 50) as usize),
+            clock: StreamClock::default(),
+            extradata: opus_head(channels, sample_rate, pre_skip),
+        })
+    }
+
+    /// Change the target bitrate without restarting the stream
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<(), CodecError> {
+        self.encoder.set_bitrate(Bitrate::Bits(bitrate.min(i32::MAX as u32) as i32)).map_err(backend)
+    }
+
+    fn packet(&mut self, frame: AudioFrame) -> Result<EncodedFrame, CodecError> {
+        let data = self.encoder.encode_vec(&frame.samples, MAX_PACKET).map_err(backend)?;
+        let pts = self.clock.pts(frame.timestamp);
+        Ok(EncodedFrame {
+            codec: CodecId::Opus,
+            width: 0,
+            height: 0,
+            keyframe: true,
+            pts,
+            dts: pts,
+            duration: frame.duration(),
+            timestamp: frame.timestamp,
+            sequence: frame.sequence,
+            data: Bytes::from(data),
+            extradata: Some(self.extradata.clone()),
+            metadata: Metadata::default(),
+        })
+    }
+}
+
+impl AudioEncoder for OpusEncoder {
+    fn codec(&self) -> CodecId {
+        CodecId::Opus
+    }
+
+    fn encode(&mut self, frame: &AudioFrame) -> Result<Vec<EncodedFrame>, CodecError> {
+        let frames = self.rechunker.push(frame)?;
+        frames.into_iter().map(|frame| self.packet(frame)).collect()
+    }
+
+    fn flush(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        self.rechunker.flush().map(|frame| self.packet(frame)).into_iter().collect()
+    }
+
+    fn extradata(&self) -> Option<Bytes> {
+        Some(self.extradata.clone())
+    }
+}
+
+/// Opus decoder producing interleaved 16-bit samples.
+pub struct OpusDecoder {
+    decoder: ::opus::Decoder,
+    sample_rate: u32,
+    channels: u16,
+    buffer: Vec<i16>,
+}
+
+impl OpusDecoder {
+    /// Decode to `channels` at `sample_rate`, whatever the stream was
+    /// encoded with
+    pub fn new(sample_rate: u32, channels: u16) -> Result<Self, CodecError> {
+        let decoder = ::opus::Decoder::new(sample_rate, channel_layout(channels)?).map_err(backend)?;
+        let frame = MAX_FRAME * sample_rate as usize / 48_000;
+        Ok(Self { decoder, sample_rate, channels, buffer: vec![0; frame * channels as usize] })
+    }
+}
+
+impl AudioDecoder for OpusDecoder {
+    fn codec(&self) -> CodecId {
+        CodecId::Opus
+    }
+
+    fn decode(&mut self, packet: &EncodedFrame) -> Result<AudioFrame, CodecError> {
+        if packet.codec != CodecId::Opus {
+            return Err(CodecError::InvalidData(format!("{} packet for the Opus decoder", packet.codec)));
+        }
+        let frames = self.decoder.decode(&packet.data, &mut self.buffer, false).map_err(backend)?;
+        Ok(AudioFrame {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp: packet.timestamp,
+            sequence: packet.sequence,
+            samples: self.buffer[..frames * self.channels as usize].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use streaming_core::ToneSource;
+
+    #[test]
+    fn round_trips_a_tone() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(10);
+        let source = ToneSource::new(48_000, 2).frame_duration(Duration::from_millis(30)).start_time(start);
+        let mut encoder = OpusEncoder::new(48_000, 2, 64_000).unwrap();
+        let mut packets = Vec::new();
+        for frame in source.take(20) {
+            packets.extend(encoder.encode(&frame).unwrap());
+        }
+        packets.extend(encoder.flush().unwrap());
+
+        // 600 ms of input is thirty 20 ms packets, stamped 20 ms apart
+        assert_eq!(packets.len(), 30);
+        assert_eq!(packets[29].pts, Duration::from_millis(580));
+        assert_eq!(packets[29].timestamp, start + Duration::from_millis(580));
+        assert_eq!(&packets[0].extradata.as_ref().unwrap()[..8], b"OpusHead");
+
+        let mut decoder = OpusDecoder::new(48_000, 2).unwrap();
+        let decoded: Vec<_> = packets.iter().map(|packet| decoder.decode(packet).unwrap()).collect();
+        assert!(decoded.iter().all(|frame| frame.frames() == 960));
+        // Past the start-up, the tone comes back at about the level it went in
+        let peak = decoded[10..].iter().flat_map(|frame| frame.samples.iter()).map(|s| s.unsigned_abs()).max();
+        assert!(peak.unwrap() > 12_000, "{:?}", peak);
+    }
+}
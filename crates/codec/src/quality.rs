@@ -0,0 +1,520 @@
+//! Objective video quality metrics and rate-distortion measurement.
+//!
+//! [`compare`] scores a decoded frame against its source with PSNR per
+//! plane and SSIM on luma. Frames are compared in YU12, so any raw format
+//! can be measured against any other; chroma is subsampled the same way the
+//! encoders see it. [`RdHarness`] encodes and decodes a synthetic sequence
+//! at several settings and tabulates size against quality, and
+//! [`Thresholds`] turns the results into pass/fail checks for regression
+//! tests.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_codec::quality::{RdHarness, Thresholds};
+//! use streaming_codec::CodecId;
+//!
+//! let harness = RdHarness::new(96, 64, 10).frames(5);
+//! let curve = harness.run_crf(CodecId::Mjpeg, &[40, 10])?;
+//! println!("{}", curve);
+//!
+//! // Better settings cost bits and buy quality
+//! assert!(curve.points[1].bytes > curve.points[0].bytes);
+//! assert!(curve.points[1].quality.mean.psnr() > curve.points[0].quality.mean.psnr());
+//! Thresholds::new(30.0, 0.8).check(&curve.points[1].quality)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::path::Path;
+
+use streaming_core::convert::convert;
+use streaming_core::{Frame, PixelFormat, SyntheticSource};
+
+use crate::y4m::Y4mReader;
+use crate::{CodecError, CodecId, CodecRegistry, EncoderConfig, RateControlMode};
+
+/// PSNR reported for identical planes, in place of infinity so averages
+/// stay finite
+pub const MAX_PSNR: f64 = 100.0;
+
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Quality of one frame against its reference.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quality {
+    /// PSNR of the luma plane in dB
+    pub psnr_y: f64,
+    pub psnr_u: f64,
+    pub psnr_v: f64,
+
+    /// Structural similarity of the luma plane, from 0 to 1
+    pub ssim: f64,
+}
+
+impl Quality {
+    /// Combined PSNR, weighting luma 6:1:1 against each chroma plane
+    pub fn psnr(&self) -> f64 {
+        (6.0 * self.psnr_y + self.psnr_u + self.psnr_v) / 8.0
+    }
+}
+
+/// Compare `distorted` against `reference`.
+///
+/// # Errors
+///
+/// [`CodecError::ResolutionMismatch`] if the frames differ in size, and
+/// frame errors if either cannot be converted to YU12.
+pub fn compare(reference: &Frame, distorted: &Frame) -> Result<Quality, CodecError> {
+    if (reference.width, reference.height) != (distorted.width, distorted.height) {
+        return Err(CodecError::ResolutionMismatch {
+            width: reference.width,
+            height: reference.height,
+            got_width: distorted.width,
+            got_height: distorted.height,
+        });
+    }
+    let reference = convert(reference, PixelFormat::YU12)?;
+    let distorted = convert(distorted, PixelFormat::YU12)?;
+
+    let (width, height) = (reference.width as usize, reference.height as usize);
+    let [reference_y, reference_u, reference_v] = planes(&reference);
+    let [distorted_y, distorted_u, distorted_v] = planes(&distorted);
+    Ok(Quality {
+        psnr_y: psnr(reference_y, distorted_y),
+        psnr_u: psnr(reference_u, distorted_u),
+        psnr_v: psnr(reference_v, distorted_v),
+        ssim: ssim(reference_y, distorted_y, width, height),
+    })
+}
+
+/// Y, U and V planes of a YU12 frame
+fn planes(frame: &Frame) -> [&[u8]; 3] {
+    let luma = frame.width as usize * frame.height as usize;
+    let (y, chroma) = frame.data.split_at(luma);
+    let (u, v) = chroma.split_at(luma / 4);
+    [y, u, v]
+}
+
+/// Compare two Y4M files frame by frame, up to the end of the shorter one
+pub fn compare_files(reference: impl AsRef<Path>, distorted: impl AsRef<Path>) -> Result<SequenceQuality, CodecError> {
+    let mut quality = SequenceQuality::default();
+    for (reference, distorted) in Y4mReader::open(reference)?.zip(Y4mReader::open(distorted)?) {
+        quality.push(compare(&reference?, &distorted?)?);
+    }
+    Ok(quality)
+}
+
+/// PSNR in dB between two sample planes of equal size
+pub fn psnr(reference: &[u8], distorted: &[u8]) -> f64 {
+    let count = reference.len().min(distorted.len()).max(1);
+    let error: u64 = reference.iter().zip(distorted).map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64).sum();
+    if error == 0 {
+        return MAX_PSNR;
+    }
+    let mse = error as f64 / count as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+}
+
+/// Mean SSIM between two `width` x `height` planes over 8x8 windows placed
+/// every 4 samples
+pub fn ssim(reference: &[u8], distorted: &[u8], width: usize, height: usize) -> f64 {
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=height - window_height).step_by(SSIM_STEP) {
+        for x in (0..=width - window_width).step_by(SSIM_STEP) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for row in y..y + window_height {
+                let start = row * width + x;
+                let end = start + window_width;
+                for (&a, &b) in reference[start..end].iter().zip(&distorted[start..end]) {
+                    let (a, b) = (a as f64, b as f64);
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+            let n = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        return 1.0;
+    }
+    total / windows as f64
+}
+
+/// Quality over a sequence of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SequenceQuality {
+    pub frames: usize,
+
+    /// Per-metric average over all frames
+    pub mean: Quality,
+
+    /// Lowest combined PSNR of any frame
+    pub min_psnr: f64,
+
+    /// Lowest SSIM of any frame
+    pub min_ssim: f64,
+}
+
+impl SequenceQuality {
+    pub fn push(&mut self, quality: Quality) {
+        let (min_psnr, min_ssim) = match self.frames {
+            0 => (quality.psnr(), quality.ssim),
+            _ => (self.min_psnr.min(quality.psnr()), self.min_ssim.min(quality.ssim)),
+        };
+        let frames = self.frames as f64;
+        let average = |mean: f64, value: f64| (mean * frames + value) / (frames + 1.0);
+        self.mean = Quality {
+            psnr_y: average(self.mean.psnr_y, quality.psnr_y),
+            psnr_u: average(self.mean.psnr_u, quality.psnr_u),
+            psnr_v: average(self.mean.psnr_v, quality.psnr_v),
+            ssim: average(self.mean.ssim, quality.ssim),
+        };
+        self.frames += 1;
+        self.min_psnr = min_psnr;
+        self.min_ssim = min_ssim;
+    }
+}
+
+/// Minimum quality a sequence must reach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Minimum mean combined PSNR in dB
+    pub min_psnr: f64,
+
+    /// Minimum mean SSIM
+    pub min_ssim: f64,
+
+    /// Minimum combined PSNR of the worst frame, if checked
+    pub min_frame_psnr: Option<f64>,
+}
+
+impl Thresholds {
+    pub fn new(min_psnr: f64, min_ssim: f64) -> Self {
+        Self { min_psnr, min_ssim, min_frame_psnr: None }
+    }
+
+    /// Also require every frame to reach `psnr`
+    pub fn min_frame_psnr(mut self, psnr: f64) -> Self {
+        self.min_frame_psnr = Some(psnr);
+        self
+    }
+
+    /// Check `quality`, reporting the first metric that falls short
+    pub fn check(&self, quality: &SequenceQuality) -> Result<(), ThresholdMiss> {
+        let checks = [
+            ("mean PSNR", quality.mean.psnr(), Some(self.min_psnr)),
+            ("mean SSIM", quality.mean.ssim, Some(self.min_ssim)),
+            ("worst frame PSNR", quality.min_psnr, self.min_frame_psnr),
+        ];
+        for (metric, value, threshold) in checks {
+            if let Some(threshold) = threshold.filter(|&threshold| value < threshold) {
+                return Err(ThresholdMiss { metric, value, threshold });
+            }
+        }
+        if quality.frames == 0 {
+            return Err(ThresholdMiss { metric: "frame count", value: 0.0, threshold: 1.0 });
+        }
+        Ok(())
+    }
+}
+
+/// A metric that fell below its [`Thresholds`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThresholdMiss {
+    pub metric: &'static str,
+    pub value: f64,
+    pub threshold: f64,
+}
+
+impl fmt::Display for ThresholdMiss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:.4} is below the threshold of {:.4}", self.metric, self.value, self.threshold)
+    }
+}
+
+impl std::error::Error for ThresholdMiss {}
+
+/// One encoder setting and what it produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RdPoint {
+    pub rate_control: RateControlMode,
+
+    /// Configured target bitrate; ignored in CRF mode
+    pub target_bitrate: u32,
+
+    /// Bitrate of the encoded sequence in bits per second
+    pub bitrate: f64,
+
+    /// Total encoded size
+    pub bytes: usize,
+
+    /// Source frames the encoder dropped
+    pub skipped: usize,
+
+    /// Quality of the frames that came out of the decoder
+    pub quality: SequenceQuality,
+}
+
+/// Rate-distortion measurements of one codec, in the order they were run.
+///
+/// Its [`Display`](fmt::Display) output is a plain-text table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RdCurve {
+    pub codec: CodecId,
+    pub points: Vec<RdPoint>,
+}
+
+impl fmt::Display for RdCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<6} {:<8} {:>12} {:>12} {:>8} {:>8} {:>7}",
+            "codec", "mode", "target kb/s", "actual kb/s", "PSNR-Y", "PSNR", "SSIM"
+        )?;
+        for point in &self.points {
+            let mode = match point.rate_control {
+                RateControlMode::Cbr => "cbr".to_string(),
+                RateControlMode::Vbr => "vbr".to_string(),
+                RateControlMode::Crf(crf) => format!("crf {}", crf),
+            };
+            let target = match point.rate_control {
+                RateControlMode::Crf(_) => "-".to_string(),
+                _ => format!("{:.1}", point.target_bitrate as f64 / 1000.0),
+            };
+            writeln!(
+                f,
+                "{:<6} {:<8} {:>12} {:>12.1} {:>8.2} {:>8.2} {:>7.4}",
+                self.codec.name(),
+                mode,
+                target,
+                point.bitrate / 1000.0,
+                point.quality.mean.psnr_y,
+                point.quality.mean.psnr(),
+                point.quality.mean.ssim
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes and decodes a synthetic sequence to measure rate against
+/// distortion.
+///
+/// The sequence is a moving box over a noisy background from
+/// [`SyntheticSource`], regenerated identically for every run. Encoders and
+/// decoders come from [`CodecRegistry::with_defaults`] unless replaced with
+/// [`RdHarness::registry`].
+pub struct RdHarness {
+    registry: CodecRegistry,
+    width: u32,
+    height: u32,
+    fps: u32,
+    frames: usize,
+    input: PixelFormat,
+    noise: u8,
+}
+
+impl RdHarness {
+    /// A one-second YU12 sequence at `fps`
+    pub fn new(width: u32, height: u32, fps: u32) -> Self {
+        Self {
+            registry: CodecRegistry::with_defaults(),
+            width,
+            height,
+            fps,
+            frames: fps.max(1) as usize,
+            input: PixelFormat::YU12,
+            noise: 8,
+        }
+    }
+
+    pub fn frames(mut self, frames: usize) -> Self {
+        self.frames = frames;
+        self
+    }
+
+    /// Pixel format fed to the encoder
+    pub fn input(mut self, format: PixelFormat) -> Self {
+        self.input = format;
+        self
+    }
+
+    /// Amplitude of the background noise; more noise is harder to compress
+    pub fn noise(mut self, amplitude: u8) -> Self {
+        self.noise = amplitude;
+        self
+    }
+
+    pub fn registry(mut self, registry: CodecRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Base configuration for every run, with keyframes once a second
+    pub fn config(&self) -> EncoderConfig {
+        let mut config = EncoderConfig::new(self.width, self.height, self.fps);
+        config.keyframe_interval = self.fps.max(1);
+        config
+    }
+
+    /// Measure CBR encoding at each target bitrate
+    pub fn run_bitrates(&self, codec: CodecId, bitrates: &[u32]) -> Result<RdCurve, CodecError> {
+        let configs = bitrates.iter().map(|&bitrate| EncoderConfig {
+            bitrate,
+            max_bitrate: bitrate,
+            rate_control: RateControlMode::Cbr,
+            ..self.config()
+        });
+        self.run(codec, configs)
+    }
+
+    /// Measure constant-quality encoding at each CRF value
+    pub fn run_crf(&self, codec: CodecId, crfs: &[u8]) -> Result<RdCurve, CodecError> {
+        let configs =
+            crfs.iter().map(|&crf| EncoderConfig { rate_control: RateControlMode::Crf(crf), ..self.config() });
+        self.run(codec, configs)
+    }
+
+    /// Measure one point per configuration
+    pub fn run(&self, codec: CodecId, configs: impl IntoIterator<Item = EncoderConfig>) -> Result<RdCurve, CodecError> {
+        let points = configs.into_iter().map(|config| self.measure(codec, &config)).collect::<Result<_, _>>()?;
+        Ok(RdCurve { codec, points })
+    }
+
+    fn measure(&self, codec: CodecId, config: &EncoderConfig) -> Result<RdPoint, CodecError> {
+        // Test frames are generated raw
+        if self.input.is_compressed() {
+            return Err(CodecError::UnsupportedInput { codec, format: self.input });
+        }
+        let mut encoder = self.registry.encoder(codec, self.input, config)?;
+        let mut decoder = self.registry.decoder(codec)?;
+        let source: Vec<Frame> = SyntheticSource::new(self.input, self.width, self.height)
+            .fps(self.fps)
+            .velocity(3, 2)
+            .noise(self.noise)
+            .take(self.frames)
+            .collect();
+
+        let mut packets = Vec::new();
+        for frame in &source {
+            packets.extend(encoder.encode(frame)?);
+        }
+        packets.extend(encoder.flush()?);
+
+        let mut decoded = Vec::new();
+        for packet in &packets {
+            decoded.extend(decoder.decode(packet)?);
+        }
+        decoded.extend(decoder.flush()?);
+
+        let mut quality = SequenceQuality::default();
+        for frame in &decoded {
+            if let Some(reference) = source.iter().find(|reference| reference.sequence == frame.sequence) {
+                quality.push(compare(reference, frame)?);
+            }
+        }
+
+        let bytes: usize = packets.iter().map(|packet| packet.data.len()).sum();
+        let seconds = self.frames as f64 / self.fps.max(1) as f64;
+        Ok(RdPoint {
+            rate_control: config.rate_control,
+            target_bitrate: config.bitrate,
+            bitrate: if seconds > 0.0 { bytes as f64 * 8.0 / seconds } else { 0.0 },
+            bytes,
+            skipped: self.frames.saturating_sub(quality.frames),
+            quality,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::y4m::{Y4mHeader, Y4mWriter};
+
+    fn source(format: PixelFormat, noise: u8) -> SyntheticSource {
+        SyntheticSource::new(format, 64, 48).velocity(3, 2).noise(noise)
+    }
+
+    #[test]
+    fn identical_and_degraded_frames() {
+        let frame = source(PixelFormat::YUYV, 4).next_frame();
+        let same = compare(&frame, &convert(&frame, PixelFormat::YU12).unwrap()).unwrap();
+        assert_eq!((same.psnr_y, same.ssim), (MAX_PSNR, 1.0));
+
+        // A uniform error of 4 is an MSE of 16, 36.1 dB
+        let mut brighter = convert(&frame, PixelFormat::YU12).unwrap();
+        brighter.data[..64 * 48].iter_mut().for_each(|sample| *sample = sample.saturating_add(4));
+        let quality = compare(&frame, &brighter).unwrap();
+        assert!((quality.psnr_y - 36.1).abs() < 0.2, "{:?}", quality);
+        assert_eq!(quality.psnr_u, MAX_PSNR);
+        assert!(quality.ssim > 0.95 && quality.ssim < 1.0);
+
+        let noisy = source(PixelFormat::YU12, 60).next_frame();
+        let worse = compare(&frame, &noisy).unwrap();
+        assert!(worse.psnr_y < quality.psnr_y && worse.ssim < quality.ssim);
+
+        let small = SyntheticSource::new(PixelFormat::YU12, 32, 32).next_frame();
+        assert!(matches!(compare(&frame, &small), Err(CodecError::ResolutionMismatch { .. })));
+    }
+
+    #[test]
+    fn sequences_and_thresholds() {
+        let dir = std::env::temp_dir().join(format!("quality-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (reference, distorted) = (dir.join("reference.y4m"), dir.join("distorted.y4m"));
+        let header = Y4mHeader::new(PixelFormat::YU12, 64, 48).unwrap();
+        let mut writer = Y4mWriter::create(&reference, header.clone()).unwrap();
+        for frame in source(PixelFormat::YU12, 0).take(4) {
+            writer.write_frame(&frame).unwrap();
+        }
+        writer.finish().unwrap();
+        let mut writer = Y4mWriter::create(&distorted, header).unwrap();
+        for frame in source(PixelFormat::YU12, 30).take(3) {
+            writer.write_frame(&frame).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let quality = compare_files(&reference, &distorted).unwrap();
+        assert_eq!(quality.frames, 3);
+        assert!(quality.min_psnr <= quality.mean.psnr() && quality.min_ssim <= quality.mean.ssim);
+        assert!(Thresholds::new(20.0, 0.1).check(&quality).is_ok());
+        let miss = Thresholds::new(20.0, 0.99).check(&quality).unwrap_err();
+        assert_eq!(miss.metric, "mean SSIM");
+        assert!(Thresholds::new(0.0, 0.0).check(&SequenceQuality::default()).is_err());
+
+        let identical = compare_files(&reference, &reference).unwrap();
+        assert!(Thresholds::new(MAX_PSNR, 1.0).min_frame_psnr(MAX_PSNR).check(&identical).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn h264_quality_rises_with_bitrate() {
+        let harness = RdHarness::new(128, 96, 15).frames(15);
+        let curve = harness.run_bitrates(CodecId::H264, &[50_000, 400_000, 1_500_000]).unwrap();
+        assert_eq!(curve.points.len(), 3);
+        for pair in curve.points.windows(2) {
+            assert!(pair[1].bytes > pair[0].bytes, "{}", curve);
+            assert!(pair[1].quality.mean.psnr() > pair[0].quality.mean.psnr(), "{}", curve);
+        }
+        Thresholds::new(32.0, 0.9).check(&curve.points[2].quality).unwrap();
+        assert_eq!(curve.to_string().lines().count(), 4);
+    }
+}
@@ -0,0 +1,162 @@
+//! Rate control settings and output bitrate measurement.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::EncodedFrame;
+
+/// How an encoder trades quality against size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateControlMode {
+    /// Hold the target bitrate closely, skipping frames if necessary
+    Cbr,
+
+    /// Average the target bitrate, letting complex scenes peak up to the
+    /// maximum bitrate
+    #[default]
+    Vbr,
+
+    /// Constant quality from 0 (best) to 51 (worst) on the H.264 quantiser
+    /// scale; bitrate targets are ignored
+    Crf(u8),
+}
+
+/// Output measured by a [`BitrateMeter`] over its window.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BitrateReport {
+    /// Stream time covered by the measured packets, at most the window
+    pub span: Duration,
+
+    /// Bits per second over `span`
+    pub bitrate: u64,
+    pub frames: usize,
+    pub keyframes: usize,
+
+    /// Packets per second over `span`
+    pub fps: f64,
+}
+
+/// Sliding-window measurement of an encoder's output.
+///
+/// The window is measured in stream time (packet `pts`), not wall-clock
+/// time, so results do not depend on how fast frames are encoded.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use streaming_codec::rate::BitrateMeter;
+///
+/// let meter = BitrateMeter::new(Duration::from_secs(1));
+/// assert_eq!(meter.report().bitrate, 0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BitrateMeter {
+    window: Duration,
+    packets: VecDeque<(Duration, Duration, usize, bool)>,
+}
+
+impl BitrateMeter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window.max(Duration::from_millis(1)),
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Account for one output packet
+    pub fn record(&mut self, packet: &EncodedFrame) {
+        self.packets.push_back((
+            packet.pts,
+            packet.duration,
+            packet.data.len(),
+            packet.keyframe,
+        ));
+
+        let end = packet.pts + packet.duration;
+        while let Some(&(pts, ..)) = self.packets.front() {
+            if end.saturating_sub(pts) > self.window {
+                self.packets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Output over the most recent window
+    pub fn report(&self) -> BitrateReport {
+        let (Some(first), Some(last)) = (self.packets.front(), self.packets.back()) else {
+            return BitrateReport::default();
+        };
+        let span = (last.0 + last.1).saturating_sub(first.0).min(self.window);
+        if span.is_zero() {
+            return BitrateReport::default();
+        }
+
+        let bytes: usize = self.packets.iter().map(|&(_, _, size, _)| size).sum();
+        let seconds = span.as_secs_f64();
+        BitrateReport {
+            span,
+            bitrate: (bytes as f64 * 8.0 / seconds).round() as u64,
+            frames: self.packets.len(),
+            keyframes: self
+                .packets
+                .iter()
+                .filter(|&&(.., keyframe)| keyframe)
+                .count(),
+            fps: self.packets.len() as f64 / seconds,
+        }
+    }
+
+    /// Forget everything measured so far
+    pub fn reset(&mut self) {
+        self.packets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CodecId;
+    use bytes::Bytes;
+    use std::time::SystemTime;
+
+    fn packet(index: u32, size: usize) -> EncodedFrame {
+        let duration = Duration::from_millis(100);
+        EncodedFrame {
+            codec: CodecId::H264,
+            width: 16,
+            height: 16,
+            keyframe: index.is_multiple_of(5),
+            pts: duration * index,
+            dts: duration * index,
+            duration,
+            timestamp: SystemTime::UNIX_EPOCH,
+            sequence: index as usize,
+            data: Bytes::from(vec![0; size]),
+            extradata: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn measures_over_sliding_window() {
+        let mut meter = BitrateMeter::new(Duration::from_secs(1));
+        for i in 0..5 {
+            meter.record(&packet(i, 1000));
+        }
+        // Half a window of 1000-byte packets at 10 fps
+        let report = meter.report();
+        assert_eq!(report.span, Duration::from_millis(500));
+        assert_eq!(report.bitrate, 80_000);
+        assert_eq!(report.keyframes, 1);
+
+        for i in 5..30 {
+            meter.record(&packet(i, if i < 20 { 1000 } else { 500 }));
+        }
+        let report = meter.report();
+        assert_eq!((report.span, report.frames), (Duration::from_secs(1), 10));
+        assert_eq!(report.bitrate, 40_000);
+        assert!((report.fps - 10.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,292 @@
+//! Lookup of encoder and decoder implementations.
+//!
+//! Implementations are registered with the codec they produce and the pixel
+//! formats they accept. When several match, the one registered first wins, so
+//! hardware or pass-through implementations should be registered before
+//! generic software ones.
+
+use streaming_core::PixelFormat;
+
+use crate::h264::{self, H264Decoder, H264Encoder, H264Passthrough};
+use crate::mjpeg::{self, MjpegDecoder, MjpegEncoder, MjpegPassthrough};
+use crate::{CodecError, CodecId, Decoder, Encoder, EncoderConfig};
+
+type EncoderFactory = Box<dyn Fn(&EncoderConfig) -> Result<Box<dyn Encoder>, CodecError> + Send + Sync>;
+type DecoderFactory = Box<dyn Fn() -> Result<Box<dyn Decoder>, CodecError> + Send + Sync>;
+
+/// Description of a registered encoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderInfo {
+    pub name: &'static str,
+    pub codec: CodecId,
+
+    /// Pixel formats the encoder takes without conversion
+    pub inputs: Vec<PixelFormat>,
+}
+
+/// Description of a registered decoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecoderInfo {
+    pub name: &'static str,
+    pub codec: CodecId,
+}
+
+struct EncoderEntry {
+    info: EncoderInfo,
+    factory: EncoderFactory,
+}
+
+struct DecoderEntry {
+    info: DecoderInfo,
+    factory: DecoderFactory,
+}
+
+/// Registry choosing codec implementations by codec and input format.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_codec::{CodecError, CodecId, CodecRegistry, EncoderConfig};
+/// use streaming_core::PixelFormat;
+///
+/// let registry = CodecRegistry::new();
+/// let config = EncoderConfig::new(1280, 720, 30);
+/// match registry.encoder(CodecId::H264, PixelFormat::YUYV, &config) {
+///     Ok(encoder) => println!("encoding with {:?}", encoder.codec()),
+///     Err(CodecError::UnsupportedCodec(codec)) => println!("no {} encoder", codec),
+///     Err(e) => println!("{}", e),
+/// }
+/// ```
+#[derive(Default)]
+pub struct CodecRegistry {
+    encoders: Vec<EncoderEntry>,
+    decoders: Vec<DecoderEntry>,
+}
+
+impl CodecRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry holding the implementations built into this crate
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register_encoder(
+            EncoderInfo {
+                name: "mjpeg_passthrough",
+                codec: CodecId::Mjpeg,
+                inputs: vec![PixelFormat::MJPG],
+            },
+            |config| Ok(Box::new(MjpegPassthrough::new(config.clone()))),
+        );
+        registry.register_encoder(
+            EncoderInfo {
+                name: "mjpeg_software",
+                codec: CodecId::Mjpeg,
+                inputs: mjpeg::RAW_INPUTS.to_vec(),
+            },
+            |config| Ok(Box::new(MjpegEncoder::new(config.clone()))),
+        );
+        registry.register_encoder(
+            EncoderInfo {
+                name: "h264_passthrough",
+                codec: CodecId::H264,
+                inputs: vec![PixelFormat::H264],
+            },
+            |config| Ok(Box::new(H264Passthrough::new(config.clone()))),
+        );
+        registry.register_encoder(
+            EncoderInfo {
+                name: "openh264",
+                codec: CodecId::H264,
+                inputs: h264::INPUTS.to_vec(),
+            },
+            |config| Ok(Box::new(H264Encoder::new(config.clone())?)),
+        );
+        registry.register_decoder(
+            DecoderInfo {
+                name: "mjpeg_software",
+                codec: CodecId::Mjpeg,
+            },
+            || Ok(Box::new(MjpegDecoder::default())),
+        );
+        registry.register_decoder(
+            DecoderInfo {
+                name: "openh264",
+                codec: CodecId::H264,
+            },
+            || Ok(Box::new(H264Decoder::new(PixelFormat::YU12)?)),
+        );
+        registry
+    }
+
+    /// Register an encoder; earlier registrations take precedence
+    pub fn register_encoder<F>(&mut self, info: EncoderInfo, factory: F)
+    where
+        F: Fn(&EncoderConfig) -> Result<Box<dyn Encoder>, CodecError> + Send + Sync + 'static,
+    {
+        self.encoders.push(EncoderEntry {
+            info,
+            factory: Box::new(factory),
+        });
+    }
+
+    /// Register a decoder; earlier registrations take precedence
+    pub fn register_decoder<F>(&mut self, info: DecoderInfo, factory: F)
+    where
+        F: Fn() -> Result<Box<dyn Decoder>, CodecError> + Send + Sync + 'static,
+    {
+        self.decoders.push(DecoderEntry {
+            info,
+            factory: Box::new(factory),
+        });
+    }
+
+    /// All registered encoders, in priority order
+    pub fn encoders(&self) -> Vec<&EncoderInfo> {
+        self.encoders.iter().map(|entry| &entry.info).collect()
+    }
+
+    /// All registered decoders, in priority order
+    pub fn decoders(&self) -> Vec<&DecoderInfo> {
+        self.decoders.iter().map(|entry| &entry.info).collect()
+    }
+
+    /// Find the first encoder for `codec` that accepts `input`
+    pub fn find_encoder(&self, codec: CodecId, input: PixelFormat) -> Option<&EncoderInfo> {
+        self.encoder_entry(codec, input).map(|entry| &entry.info)
+    }
+
+    /// Create an encoder for `codec` taking `input` frames.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::UnsupportedCodec`] if no encoder produces `codec`,
+    /// [`CodecError::UnsupportedInput`] if none of them accepts `input`, or the
+    /// error of the implementation's constructor.
+    pub fn encoder(&self, codec: CodecId, input: PixelFormat, config: &EncoderConfig) -> Result<Box<dyn Encoder>, CodecError> {
+        match self.encoder_entry(codec, input) {
+            Some(entry) => (entry.factory)(config),
+            None if self.encoders.iter().any(|entry| entry.info.codec == codec) => {
+                Err(CodecError::UnsupportedInput { codec, format: input })
+            }
+            None => Err(CodecError::UnsupportedCodec(codec)),
+        }
+    }
+
+    /// Create a decoder for `codec`.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::UnsupportedCodec`] if no decoder is registered for `codec`.
+    pub fn decoder(&self, codec: CodecId) -> Result<Box<dyn Decoder>, CodecError> {
+        let entry = self
+            .decoders
+            .iter()
+            .find(|entry| entry.info.codec == codec)
+            .ok_or(CodecError::UnsupportedCodec(codec))?;
+        (entry.factory)()
+    }
+
+    fn encoder_entry(&self, codec: CodecId, input: PixelFormat) -> Option<&EncoderEntry> {
+        self.encoders
+            .iter()
+            .find(|entry| entry.info.codec == codec && entry.info.inputs.contains(&input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncodedFrame;
+    use streaming_core::Frame;
+
+    struct NamedEncoder(&'static str);
+
+    impl Encoder for NamedEncoder {
+        fn codec(&self) -> CodecId {
+            CodecId::Mjpeg
+        }
+
+        fn encode(&mut self, _frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+            Err(CodecError::Backend(self.0.to_string()))
+        }
+    }
+
+    fn name_of(mut encoder: Box<dyn Encoder>) -> String {
+        let frame = Frame {
+            format: PixelFormat::MJPG,
+            width: 0,
+            height: 0,
+            timestamp: std::time::SystemTime::UNIX_EPOCH,
+            sequence: 0,
+            data: Vec::new(),
+            metadata: Default::default(),
+        };
+        match encoder.encode(&frame) {
+            Err(CodecError::Backend(name)) => name,
+            _ => unreachable!(),
+        }
+    }
+
+    fn registry() -> CodecRegistry {
+        let mut registry = CodecRegistry::new();
+        registry.register_encoder(
+            EncoderInfo {
+                name: "passthrough",
+                codec: CodecId::Mjpeg,
+                inputs: vec![PixelFormat::MJPG],
+            },
+            |_| Ok(Box::new(NamedEncoder("passthrough"))),
+        );
+        registry.register_encoder(
+            EncoderInfo {
+                name: "software",
+                codec: CodecId::Mjpeg,
+                inputs: vec![PixelFormat::MJPG, PixelFormat::YUYV],
+            },
+            |_| Ok(Box::new(NamedEncoder("software"))),
+        );
+        registry
+    }
+
+    #[test]
+    fn picks_first_matching_encoder_for_input() {
+        let registry = registry();
+        let config = EncoderConfig::new(640, 480, 30);
+
+        let mjpg = registry.encoder(CodecId::Mjpeg, PixelFormat::MJPG, &config).unwrap();
+        assert_eq!(name_of(mjpg), "passthrough");
+
+        let yuyv = registry.encoder(CodecId::Mjpeg, PixelFormat::YUYV, &config).unwrap();
+        assert_eq!(name_of(yuyv), "software");
+        assert_eq!(registry.find_encoder(CodecId::Mjpeg, PixelFormat::YUYV).unwrap().name, "software");
+    }
+
+    #[test]
+    fn reports_why_no_encoder_matched() {
+        let registry = registry();
+        let config = EncoderConfig::new(640, 480, 30);
+
+        assert!(matches!(
+            registry.encoder(CodecId::Mjpeg, PixelFormat::RGB3, &config),
+            Err(CodecError::UnsupportedInput { codec: CodecId::Mjpeg, format: PixelFormat::RGB3 })
+        ));
+        assert!(matches!(
+            registry.encoder(CodecId::H264, PixelFormat::YUYV, &config),
+            Err(CodecError::UnsupportedCodec(CodecId::H264))
+        ));
+        assert!(matches!(registry.decoder(CodecId::Mjpeg), Err(CodecError::UnsupportedCodec(_))));
+    }
+
+    #[test]
+    fn defaults_prefer_passthrough_for_camera_bitstreams() {
+        let registry = CodecRegistry::with_defaults();
+        assert_eq!(registry.find_encoder(CodecId::Mjpeg, PixelFormat::MJPG).unwrap().name, "mjpeg_passthrough");
+        assert_eq!(registry.find_encoder(CodecId::Mjpeg, PixelFormat::YUYV).unwrap().name, "mjpeg_software");
+        assert!(registry.decoder(CodecId::Mjpeg).is_ok());
+        assert_eq!(registry.find_encoder(CodecId::H264, PixelFormat::MJPG).unwrap().name, "openh264");
+        assert_eq!(registry.find_encoder(CodecId::H264, PixelFormat::H264).unwrap().name, "h264_passthrough");
+    }
+}
@@ -0,0 +1,275 @@
+//! File replay standing in for a camera that encodes H.264 itself.
+//!
+//! [`AnnexBReplay`] reads a raw `.h264` elementary stream and hands out one
+//! access unit per [`PixelFormat::H264`] frame, timed like a live capture.
+//! It lets the hardware pass-through path be exercised without the camera.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use streaming_core::{Frame, Metadata, PixelFormat};
+
+use crate::bitstream::{split_access_units, write_annex_b, NalType, Sps};
+use crate::CodecError;
+
+/// Cloneable handle asking an [`AnnexBReplay`] for a keyframe.
+#[derive(Debug, Clone, Default)]
+pub struct KeyframeTrigger(Arc<AtomicBool>);
+
+impl KeyframeTrigger {
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Replays an Annex-B H.264 stream as camera frames.
+///
+/// A recorded stream cannot produce new keyframes, so a keyframe request
+/// makes the replay skip ahead to the next IDR access unit instead, which is
+/// what a viewer of a live camera would observe.
+///
+/// # Examples
+///
+/// ```no_run
+/// use streaming_codec::replay::AnnexBReplay;
+///
+/// let replay = AnnexBReplay::open("capture.h264")?.fps(25);
+/// for frame in replay.take(100) {
+///     println!("{} bytes at {:?}", frame.data.len(), frame.timestamp);
+/// }
+/// # Ok::<(), streaming_codec::CodecError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct AnnexBReplay {
+    access_units: Vec<(Vec<u8>, bool)>,
+    width: u32,
+    height: u32,
+    fps: u32,
+    start: SystemTime,
+    looping: bool,
+    index: usize,
+    sequence: usize,
+    trigger: KeyframeTrigger,
+}
+
+impl AnnexBReplay {
+    /// Load a stream from a file.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::Io`] if the file cannot be read, otherwise as
+    /// [`AnnexBReplay::from_bytes`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Load a stream from memory, at 30 fps starting now.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the stream has no parseable SPS to
+    /// take the frame size from.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CodecError> {
+        let grouped = split_access_units(data);
+        let sps = grouped
+            .iter()
+            .flatten()
+            .find(|unit| unit.nal_type() == NalType::Sps)
+            .ok_or_else(|| CodecError::InvalidData("no SPS in stream".to_string()))?;
+        let sps = Sps::parse(*sps)?;
+
+        let access_units = grouped
+            .iter()
+            .map(|units| {
+                let keyframe = units.iter().any(|unit| unit.nal_type() == NalType::IdrSlice);
+                (write_annex_b(units), keyframe)
+            })
+            .collect();
+
+        Ok(Self {
+            access_units,
+            width: sps.width,
+            height: sps.height,
+            fps: 30,
+            start: SystemTime::now(),
+            looping: false,
+            index: 0,
+            sequence: 0,
+            trigger: KeyframeTrigger::default(),
+        })
+    }
+
+    pub fn fps(mut self, fps: u32) -> Self {
+        self.fps = fps.max(1);
+        self
+    }
+
+    /// Timestamp of the first frame
+    pub fn start_time(mut self, start: SystemTime) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Start over from the beginning when the stream ends
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Frame size from the stream's SPS
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Number of access units in the stream
+    pub fn len(&self) -> usize {
+        self.access_units.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.access_units.is_empty()
+    }
+
+    /// Handle for requesting a keyframe from another thread or an encoder
+    pub fn keyframe_trigger(&self) -> KeyframeTrigger {
+        self.trigger.clone()
+    }
+
+    /// Return the next access unit as a frame, or `None` at the end of a
+    /// non-looping stream
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        if self.trigger.take() {
+            self.skip_to_keyframe();
+        }
+        if self.index >= self.access_units.len() {
+            if !self.looping || self.access_units.is_empty() {
+                return None;
+            }
+            self.index = 0;
+        }
+
+        let (data, _) = &self.access_units[self.index];
+        let timestamp = self.start + Duration::from_secs(1) * self.sequence as u32 / self.fps;
+        self.index += 1;
+        self.sequence += 1;
+
+        Some(Frame {
+            format: PixelFormat::H264,
+            width: self.width,
+            height: self.height,
+            timestamp,
+            sequence: self.sequence,
+            data: data.clone(),
+            metadata: Metadata::default(),
+        })
+    }
+
+    fn skip_to_keyframe(&mut self) {
+        let ahead = (self.index..self.access_units.len()).find(|&i| self.access_units[i].1);
+        let wrapped = || (0..self.access_units.len()).find(|&i| self.access_units[i].1);
+        if let Some(index) = ahead.or_else(|| if self.looping { wrapped() } else { None }) {
+            self.index = index;
+        }
+    }
+}
+
+impl Iterator for AnnexBReplay {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        self.next_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encoder, EncoderConfig, H264Encoder, H264Passthrough};
+    use std::sync::atomic::AtomicUsize;
+    use streaming_core::SyntheticSource;
+
+    /// Stream of `count` frames with a keyframe every `gop` frames
+    fn recorded_stream(count: usize, gop: u32) -> Vec<u8> {
+        let mut config = EncoderConfig::new(96, 64, 30);
+        config.keyframe_interval = gop;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        SyntheticSource::new(PixelFormat::YU12, 96, 64)
+            .velocity(3, 1)
+            .take(count)
+            .flat_map(|frame| encoder.encode(&frame).unwrap())
+            .flat_map(|packet| packet.data.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn replays_file_through_passthrough() {
+        let path = std::env::temp_dir().join(format!("replay-{}.h264", std::process::id()));
+        std::fs::write(&path, recorded_stream(12, 5)).unwrap();
+        let replay = AnnexBReplay::open(&path).unwrap().fps(25).start_time(SystemTime::UNIX_EPOCH);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((replay.resolution(), replay.len()), ((96, 64), 12));
+
+        let mut passthrough = H264Passthrough::new(EncoderConfig::new(96, 64, 25));
+        let packets: Vec<_> = replay.flat_map(|frame| passthrough.encode(&frame).unwrap()).collect();
+        assert_eq!(packets.len(), 12);
+        let keyframes: Vec<_> = packets.iter().enumerate().filter(|(_, p)| p.keyframe).map(|(i, _)| i).collect();
+        assert_eq!(keyframes, [0, 5, 10]);
+        assert_eq!(packets[3].pts, Duration::from_millis(120));
+        assert!(passthrough.extradata().is_some());
+    }
+
+    #[test]
+    fn waits_for_keyframe_and_requests_one() {
+        let stream = recorded_stream(12, 5);
+        let mut replay = AnnexBReplay::from_bytes(&stream).unwrap();
+        let trigger = replay.keyframe_trigger();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let mut passthrough = H264Passthrough::new(EncoderConfig::new(96, 64, 30)).with_keyframe_requester(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            trigger.request();
+        });
+
+        // Join mid-GOP: the first frames are dropped until the replay answers
+        // the request by jumping to the next IDR
+        replay.next_frame();
+        let frame = replay.next_frame().unwrap();
+        assert!(passthrough.encode(&frame).unwrap().is_empty());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        let packet = passthrough.encode(&replay.next_frame().unwrap()).unwrap().remove(0);
+        assert!(packet.keyframe);
+        assert_eq!(packet.sequence, 3);
+    }
+
+    #[test]
+    fn prepends_parameter_sets_to_bare_keyframes() {
+        let stream = recorded_stream(6, 3);
+        let mut passthrough = H264Passthrough::new(EncoderConfig::new(96, 64, 30));
+        let mut replay = AnnexBReplay::from_bytes(&stream).unwrap();
+
+        let first = replay.next_frame().unwrap();
+        passthrough.encode(&first).unwrap();
+        replay.next_frame();
+        replay.next_frame();
+
+        // Strip SPS/PPS from the second keyframe as many cameras do
+        let mut keyframe = replay.next_frame().unwrap();
+        let units: Vec<_> = crate::bitstream::split_annex_b(&keyframe.data)
+            .into_iter()
+            .filter(|unit| unit.nal_type().is_vcl())
+            .map(|unit| unit.data().to_vec())
+            .collect();
+        keyframe.data = units.iter().flat_map(|unit| [&[0, 0, 0, 1][..], unit].concat()).collect();
+
+        let packet = passthrough.encode(&keyframe).unwrap().remove(0);
+        assert!(packet.keyframe);
+        assert!(packet.data.starts_with(&passthrough.extradata().unwrap()));
+    }
+}
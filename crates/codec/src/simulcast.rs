@@ -0,0 +1,439 @@
+//! Several renditions of one capture, for viewers on different links.
+//!
+//! A [`Simulcast`] scales every input frame to each layer's resolution,
+//! thins it to the layer's frame rate and encodes it with the layer's
+//! bitrate. Keyframes are scheduled by the simulcast rather than by each
+//! encoder: when one is due, every layer encodes that same source frame as
+//! a keyframe, so a viewer can switch layers at any keyframe without a gap.
+//! All packets of one source frame share its timestamp and `pts`.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_codec::simulcast::Simulcast;
+//! use streaming_codec::{CodecId, EncoderConfig};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//!
+//! let mut simulcast = Simulcast::new(CodecId::H264, PixelFormat::YU12);
+//! let mut high = EncoderConfig::new(128, 96, 30);
+//! high.bitrate = 1_000_000;
+//! let mut low = EncoderConfig::new(64, 48, 15);
+//! low.bitrate = 200_000;
+//! let high = simulcast.add_layer("high", high)?;
+//! let low = simulcast.add_layer("low", low)?;
+//!
+//! let mut packets = Vec::new();
+//! for frame in SyntheticSource::new(PixelFormat::YU12, 128, 96).take(4) {
+//!     packets.extend(simulcast.encode(&frame)?);
+//! }
+//! assert_eq!(packets.iter().filter(|(layer, _)| *layer == high).count(), 4);
+//! assert_eq!(packets.iter().filter(|(layer, _)| *layer == low).count(), 2);
+//! assert_eq!(simulcast.pick(500_000), Some(low));
+//! # Ok::<(), streaming_codec::CodecError>(())
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use streaming_core::{CropScale, Frame, PixelFormat, ScaleFilter};
+
+use crate::{CodecError, CodecId, CodecRegistry, EncodedFrame, Encoder, EncoderConfig, StreamClock};
+
+static NEXT_SIMULCAST: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies a layer within one [`Simulcast`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LayerId {
+    simulcast: usize,
+    index: usize,
+}
+
+impl fmt::Display for LayerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "layer {}", self.index)
+    }
+}
+
+struct Layer {
+    name: String,
+    config: EncoderConfig,
+    scale: Option<CropScale>,
+    encoder: Box<dyn Encoder>,
+    next_frame: Option<SystemTime>,
+    frames: u64,
+}
+
+impl Layer {
+    /// Whether the layer's frame rate calls for a frame at `timestamp`.
+    ///
+    /// Frames are due on a grid of the layer's frame duration; a quarter of
+    /// a frame of slack absorbs capture jitter without letting a faster
+    /// input through twice per slot.
+    fn due(&mut self, timestamp: SystemTime) -> bool {
+        let duration = self.config.frame_duration();
+        let due = self.next_frame.is_none_or(|next| timestamp + duration / 4 >= next);
+        if due {
+            self.schedule_after(timestamp);
+        }
+        due
+    }
+
+    fn schedule_after(&mut self, timestamp: SystemTime) {
+        let duration = self.config.frame_duration();
+        // Stay on the grid unless the input fell a whole slot behind it
+        let next = self.next_frame.map(|next| next + duration).filter(|&next| next > timestamp);
+        self.next_frame = Some(next.unwrap_or(timestamp + duration));
+    }
+}
+
+/// Encodes one stream of frames into several layers with aligned keyframes.
+///
+/// Layer configurations set resolution, frame rate, bitrate and rate
+/// control; their `keyframe_interval` is ignored in favour of
+/// [`Simulcast::keyframe_period`]. Layers may not be larger or faster than
+/// the input.
+pub struct Simulcast {
+    id: usize,
+    codec: CodecId,
+    input: PixelFormat,
+    registry: CodecRegistry,
+    keyframe_period: Duration,
+    filter: ScaleFilter,
+    layers: Vec<Layer>,
+    clock: StreamClock,
+    next_keyframe: Option<SystemTime>,
+    keyframe_requested: bool,
+}
+
+impl Simulcast {
+    /// Layers of `codec` encoding frames in `input` format, with the default
+    /// codecs, a keyframe every two seconds and area scaling
+    pub fn new(codec: CodecId, input: PixelFormat) -> Self {
+        Self {
+            id: NEXT_SIMULCAST.fetch_add(1, Ordering::Relaxed),
+            codec,
+            input,
+            registry: CodecRegistry::with_defaults(),
+            keyframe_period: Duration::from_secs(2),
+            filter: ScaleFilter::Area,
+            layers: Vec::new(),
+            clock: StreamClock::default(),
+            next_keyframe: None,
+            keyframe_requested: false,
+        }
+    }
+
+    /// Pick encoders for new layers from `registry`
+    pub fn registry(mut self, registry: CodecRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Time between the keyframes shared by all layers
+    pub fn keyframe_period(mut self, period: Duration) -> Self {
+        self.keyframe_period = period;
+        self
+    }
+
+    /// Interpolation used to scale frames down to each layer
+    pub fn filter(mut self, filter: ScaleFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Add a layer encoded with `config`.
+    ///
+    /// A layer added while streaming starts at the next shared keyframe.
+    ///
+    /// # Errors
+    ///
+    /// Whatever the registry returns when creating the encoder.
+    pub fn add_layer(&mut self, name: &str, mut config: EncoderConfig) -> Result<LayerId, CodecError> {
+        // Keyframes come from request_keyframe only; OpenH264 treats 0 as
+        // "choose for yourself"
+        config.keyframe_interval = u32::MAX;
+        let encoder = self.registry.encoder(self.codec, self.input, &config)?;
+        self.layers.push(Layer {
+            name: name.to_string(),
+            config,
+            scale: None,
+            encoder,
+            next_frame: None,
+            frames: 0,
+        });
+        Ok(LayerId { simulcast: self.id, index: self.layers.len() - 1 })
+    }
+
+    /// All layers, in the order they were added
+    pub fn layers(&self) -> impl Iterator<Item = LayerId> + '_ {
+        (0..self.layers.len()).map(|index| LayerId { simulcast: self.id, index })
+    }
+
+    pub fn name(&self, layer: LayerId) -> Result<&str, CodecError> {
+        Ok(&self.layer(layer)?.name)
+    }
+
+    /// Configuration of `layer`, with the bitrate as last set
+    pub fn config(&self, layer: LayerId) -> Result<&EncoderConfig, CodecError> {
+        Ok(&self.layer(layer)?.config)
+    }
+
+    /// Frames encoded for `layer` so far
+    pub fn frames(&self, layer: LayerId) -> Result<u64, CodecError> {
+        Ok(self.layer(layer)?.frames)
+    }
+
+    /// The layer with the highest bitrate that fits in `bitrate` bits per
+    /// second, or the lowest one if none fits; `None` without layers
+    pub fn pick(&self, bitrate: u32) -> Option<LayerId> {
+        let by_bitrate = || self.layers().zip(&self.layers).map(|(id, layer)| (layer.config.bitrate, id));
+        by_bitrate()
+            .filter(|&(layer_bitrate, _)| layer_bitrate <= bitrate)
+            .max()
+            .or_else(|| by_bitrate().min())
+            .map(|(_, layer)| layer)
+    }
+
+    /// Make the next input frame a keyframe in every layer
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    /// Change the target and peak bitrate of one layer
+    pub fn set_bitrate(&mut self, layer: LayerId, target: u32, max: u32) -> Result<(), CodecError> {
+        self.layer(layer)?;
+        let layer = &mut self.layers[layer.index];
+        layer.encoder.set_bitrate(target, max)?;
+        layer.config.bitrate = target;
+        layer.config.max_bitrate = max;
+        Ok(())
+    }
+
+    /// Encode `frame` into every layer whose frame rate calls for it.
+    ///
+    /// # Errors
+    ///
+    /// Fails on input in the wrong format, frames smaller than a layer, and
+    /// encoder errors; layers before the failing one have then already
+    /// consumed the frame.
+    pub fn encode(&mut self, frame: &Frame) -> Result<Vec<(LayerId, EncodedFrame)>, CodecError> {
+        if frame.format != self.input {
+            return Err(CodecError::UnsupportedInput {
+                codec: self.codec,
+                format: frame.format,
+            });
+        }
+
+        let keyframe = self.keyframe_requested || self.next_keyframe.is_none_or(|next| frame.timestamp >= next);
+        if keyframe {
+            self.keyframe_requested = false;
+            self.next_keyframe = Some(frame.timestamp + self.keyframe_period);
+        }
+        // The shared clock starts at the first input frame, whichever layers
+        // take it
+        self.clock.pts(frame.timestamp);
+
+        let id = self.id;
+        let mut packets = Vec::new();
+        for (index, layer) in self.layers.iter_mut().enumerate() {
+            if keyframe {
+                // Every layer takes the keyframe, whatever its frame rate
+                layer.next_frame = None;
+                layer.due(frame.timestamp);
+                layer.encoder.request_keyframe();
+            } else if layer.next_frame.is_none() || !layer.due(frame.timestamp) {
+                // Layers added since the last keyframe wait for the next
+                continue;
+            }
+
+            let (width, height) = (layer.config.width, layer.config.height);
+            if width > frame.width || height > frame.height {
+                return Err(CodecError::ResolutionMismatch {
+                    width,
+                    height,
+                    got_width: frame.width,
+                    got_height: frame.height,
+                });
+            }
+            let encoded = if (width, height) == (frame.width, frame.height) {
+                layer.encoder.encode(frame)?
+            } else {
+                let filter = self.filter;
+                let scale = layer.scale.get_or_insert_with(|| CropScale::new().resize(width, height).filter(filter));
+                layer.encoder.encode(&scale.apply(frame)?)?
+            };
+            layer.frames += 1;
+            packets.extend(retime(&mut self.clock, LayerId { simulcast: id, index }, encoded));
+        }
+        Ok(packets)
+    }
+
+    /// Drain frames still buffered inside the layer encoders
+    pub fn flush(&mut self) -> Result<Vec<(LayerId, EncodedFrame)>, CodecError> {
+        let id = self.id;
+        let mut packets = Vec::new();
+        for (index, layer) in self.layers.iter_mut().enumerate() {
+            packets.extend(retime(&mut self.clock, LayerId { simulcast: id, index }, layer.encoder.flush()?));
+        }
+        Ok(packets)
+    }
+
+    fn layer(&self, id: LayerId) -> Result<&Layer, CodecError> {
+        if id.simulcast != self.id {
+            return Err(CodecError::UnknownLayer(id));
+        }
+        Ok(&self.layers[id.index])
+    }
+}
+
+/// Time packets of `layer` on the shared clock by the capture time of their
+/// source frame, rather than from when the layer's encoder started
+fn retime(
+    clock: &mut StreamClock,
+    layer: LayerId,
+    packets: Vec<EncodedFrame>,
+) -> impl Iterator<Item = (LayerId, EncodedFrame)> + '_ {
+    packets.into_iter().map(move |mut packet| {
+        packet.pts = clock.pts(packet.timestamp);
+        packet.dts = packet.pts;
+        (layer, packet)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use streaming_core::SyntheticSource;
+
+    use crate::{Decoder, EncoderInfo, H264Decoder, MjpegEncoder};
+
+    /// MJPEG held back by one frame, like an encoder with lookahead
+    struct Delayed {
+        inner: MjpegEncoder,
+        held: Vec<EncodedFrame>,
+    }
+
+    impl Encoder for Delayed {
+        fn codec(&self) -> CodecId {
+            CodecId::Mjpeg
+        }
+
+        fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+            let encoded = self.inner.encode(frame)?;
+            Ok(std::mem::replace(&mut self.held, encoded))
+        }
+
+        fn flush(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+            Ok(std::mem::take(&mut self.held))
+        }
+    }
+
+    fn layers(simulcast: &mut Simulcast) -> [LayerId; 3] {
+        let config = |width, height, fps, bitrate| {
+            let mut config = EncoderConfig::new(width, height, fps);
+            config.bitrate = bitrate;
+            config
+        };
+        [
+            simulcast.add_layer("high", config(128, 96, 30, 800_000)).unwrap(),
+            simulcast.add_layer("medium", config(64, 48, 15, 300_000)).unwrap(),
+            simulcast.add_layer("low", config(32, 24, 10, 100_000)).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn layers_share_keyframes_and_timestamps() {
+        // A little under 12 frames, as frame timestamps are rounded down
+        let period = Duration::from_millis(390);
+        let mut simulcast = Simulcast::new(CodecId::H264, PixelFormat::YU12).keyframe_period(period);
+        let [high, medium, low] = layers(&mut simulcast);
+
+        let mut by_layer: BTreeMap<LayerId, Vec<EncodedFrame>> = BTreeMap::new();
+        for (index, frame) in SyntheticSource::new(PixelFormat::YU12, 128, 96).velocity(3, 2).take(60).enumerate() {
+            if index == 30 {
+                simulcast.request_keyframe();
+            }
+            for (layer, packet) in simulcast.encode(&frame).unwrap() {
+                by_layer.entry(layer).or_default().push(packet);
+            }
+        }
+
+        let counts: Vec<_> = [high, medium, low].map(|layer| by_layer[&layer].len()).to_vec();
+        assert_eq!(counts, [60, 30, 20]);
+
+        // Scheduled keyframes every 12 frames, restarted by the request at 30
+        let keyframes = |layer: LayerId| -> Vec<_> {
+            let packets = by_layer[&layer].iter().filter(|packet| packet.keyframe);
+            packets.map(|packet| (packet.sequence, packet.pts)).collect()
+        };
+        let expected: Vec<_> = [0, 12, 24, 30, 42, 54].iter().map(|&index| by_layer[&high][index].sequence).collect();
+        assert_eq!(keyframes(high).iter().map(|&(sequence, _)| sequence).collect::<Vec<_>>(), expected);
+        assert_eq!(keyframes(medium), keyframes(high));
+        assert_eq!(keyframes(low), keyframes(high));
+
+        // Every layer decodes to its own size from its first keyframe
+        let mut decoder = H264Decoder::new(PixelFormat::YU12).unwrap();
+        let decoded = decoder.decode(&by_layer[&low][0]).unwrap().remove(0);
+        assert_eq!((decoded.width, decoded.height), (32, 24));
+    }
+
+    #[test]
+    fn picks_layers_by_bitrate_and_starts_new_ones_at_keyframes() {
+        let mut simulcast = Simulcast::new(CodecId::Mjpeg, PixelFormat::YU12);
+        assert_eq!(simulcast.pick(1_000_000), None);
+        let [high, medium, low] = layers(&mut simulcast);
+        assert_eq!(simulcast.pick(1_000_000), Some(high));
+        assert_eq!(simulcast.pick(500_000), Some(medium));
+        assert_eq!(simulcast.pick(10_000), Some(low));
+
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 128, 96);
+        simulcast.encode(&source.next_frame()).unwrap();
+        let late = simulcast.add_layer("late", EncoderConfig::new(128, 96, 30)).unwrap();
+        assert!(simulcast.encode(&source.next_frame()).unwrap().iter().all(|(layer, _)| *layer != late));
+        simulcast.request_keyframe();
+        assert!(simulcast.encode(&source.next_frame()).unwrap().iter().any(|(layer, _)| *layer == late));
+        assert_eq!(simulcast.frames(late).unwrap(), 1);
+        assert_eq!(simulcast.name(late).unwrap(), "late");
+
+        // Ids of another simulcast are rejected rather than indexed
+        let mut other = Simulcast::new(CodecId::Mjpeg, PixelFormat::YU12);
+        let foreign = layers(&mut other)[0];
+        assert_eq!(foreign.to_string(), high.to_string());
+        assert!(matches!(simulcast.config(foreign), Err(CodecError::UnknownLayer(_))));
+        assert!(matches!(simulcast.set_bitrate(foreign, 1, 1), Err(CodecError::UnknownLayer(_))));
+
+        simulcast.request_keyframe();
+        let mut small = SyntheticSource::new(PixelFormat::YU12, 64, 48);
+        assert!(matches!(simulcast.encode(&small.next_frame()), Err(CodecError::ResolutionMismatch { .. })));
+    }
+
+    #[test]
+    fn buffered_packets_keep_their_source_timing() {
+        let mut registry = CodecRegistry::new();
+        let info = EncoderInfo { name: "delayed", codec: CodecId::Mjpeg, inputs: vec![PixelFormat::YU12] };
+        registry.register_encoder(info, |config| {
+            Ok(Box::new(Delayed { inner: MjpegEncoder::new(config.clone()), held: Vec::new() }))
+        });
+        let mut simulcast = Simulcast::new(CodecId::Mjpeg, PixelFormat::YU12).registry(registry);
+        simulcast.add_layer("high", EncoderConfig::new(64, 48, 30)).unwrap();
+
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 64, 48);
+        let first = source.next_frame();
+        let mut packets = simulcast.encode(&first).unwrap();
+        // A layer joining later is still timed from the first input frame
+        simulcast.add_layer("late", EncoderConfig::new(32, 24, 30)).unwrap();
+        simulcast.request_keyframe();
+        for frame in source.take(4) {
+            packets.extend(simulcast.encode(&frame).unwrap());
+        }
+        packets.extend(simulcast.flush().unwrap());
+
+        assert_eq!(packets.len(), 5 + 4);
+        for (_, packet) in &packets {
+            assert_eq!(packet.pts, packet.timestamp.duration_since(first.timestamp).unwrap());
+            assert_eq!(packet.dts, packet.pts);
+        }
+    }
+}
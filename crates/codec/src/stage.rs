@@ -0,0 +1,950 @@
+//! Multi-threaded encode stage.
+//!
+//! An [`EncodeStage`] holds one or more streams, each with its own encoder,
+//! and is moved onto a pool of worker threads with [`EncodeStage::spawn`].
+//! Frames are submitted per stream without blocking capture; packets come out
+//! of the sink in submission order for every stream, whichever worker
+//! encoded them.
+//!
+//! Inter-frame codecs keep state between frames, so a stream with a single
+//! encoder is only ever encoded on one worker at a time, while different
+//! streams run in parallel. Intra-only codecs can additionally spread one
+//! stream over several encoder instances with
+//! [`EncodeStage::add_parallel_stream`], which is what lets a single 1080p
+//! MJPEG stream use more than one core.
+//!
+//! Consumers ask for keyframes through [`EncodePool::keyframe_requester`];
+//! each stream forces at most one per [`EncodeStage::min_keyframe_interval`]
+//! and folds further requests into the next one.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_codec::stage::{DropPolicy, EncodeStage};
+//! use streaming_codec::{EncoderConfig, MjpegEncoder};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//!
+//! let config = EncoderConfig::new(320, 240, 30);
+//! let mut stage = EncodeStage::new().workers(2).drop_policy(DropPolicy::DropOldest);
+//! let stream = stage.add_stream("main", MjpegEncoder::new(config));
+//!
+//! let (tx, rx) = std::sync::mpsc::channel();
+//! let pool = stage.spawn(move |_, result| tx.send(result.unwrap()).unwrap());
+//! for frame in SyntheticSource::new(PixelFormat::YUYV, 320, 240).take(3) {
+//!     let _ = pool.submit(stream, frame);
+//! }
+//! pool.shutdown();
+//! assert_eq!(rx.iter().count(), 3);
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use streaming_core::{Frame, PixelFormat};
+use thiserror::Error;
+
+use crate::bitstream::is_random_access;
+use crate::keyframe::{KeyframeLimiter, KeyframeRequester};
+use crate::{CodecError, CodecId, EncodedFrame, Encoder, StreamClock};
+
+/// Source of [`EncodeStage`] identities, so a [`StreamId`] can be told
+/// apart from one of another stage
+static NEXT_STAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies a stream within one [`EncodeStage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamId {
+    stage: usize,
+    index: usize,
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream {}", self.index)
+    }
+}
+
+/// Why [`EncodePool::submit`] did not queue a frame.
+#[derive(Debug, Error)]
+pub enum SubmitError {
+    /// Rejected by the drop policy, discarded while a compressed stream
+    /// waits for its next keyframe, or the pool is shut down; the frame is
+    /// handed back
+    #[error("Frame {} was not queued", .0.sequence)]
+    Rejected(Frame),
+
+    /// The stream does not belong to the pool
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+}
+
+impl SubmitError {
+    /// The frame handed back, if it was rejected
+    pub fn into_frame(self) -> Option<Frame> {
+        match self {
+            SubmitError::Rejected(frame) => Some(frame),
+            SubmitError::Codec(_) => None,
+        }
+    }
+}
+
+/// What to do with a frame submitted to a stream whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Reject the new frame, keeping what is already queued
+    DropNewest,
+
+    /// Discard the oldest queued frame to make room, keeping latency low
+    #[default]
+    DropOldest,
+
+    /// Wait for room; capture is slowed down instead of losing frames
+    Block,
+}
+
+/// Queue and timing counters for one stream of an [`EncodePool`].
+#[derive(Debug, Clone, Default)]
+pub struct StreamStats {
+    pub name: String,
+
+    /// Frames accepted into the queue
+    pub frames: u64,
+
+    /// Frames that went through an encoder
+    pub encoded: u64,
+
+    /// Packets handed to the sink
+    pub packets: u64,
+
+    /// Frames discarded under overload or while waiting for a keyframe
+    pub dropped: u64,
+
+    /// Frames the encoder failed on
+    pub errors: u64,
+
+    /// Keyframe requests from consumers
+    pub keyframe_requests: u64,
+
+    /// Requests passed on to the encoder; the rate limit deferred and folded
+    /// together the others
+    pub forced_keyframes: u64,
+
+    /// Frames waiting for a worker right now
+    pub queue_depth: usize,
+
+    /// Deepest the queue has been
+    pub max_queue_depth: usize,
+
+    /// Time from submission to encoded, summed over all frames
+    pub total_latency: Duration,
+
+    /// Time from submission to encoded for the most recent frame
+    pub last_latency: Duration,
+
+    /// Slowest single frame from submission to encoded
+    pub max_latency: Duration,
+
+    /// Time spent inside the encoder across all frames
+    pub encode_time: Duration,
+}
+
+impl StreamStats {
+    /// Mean time from submission to encoded
+    pub fn average_latency(&self) -> Duration {
+        if self.encoded == 0 {
+            return Duration::ZERO;
+        }
+        self.total_latency / self.encoded as u32
+    }
+
+    /// Mean time spent inside the encoder per frame
+    pub fn average_encode_time(&self) -> Duration {
+        if self.encoded == 0 {
+            return Duration::ZERO;
+        }
+        self.encode_time / self.encoded as u32
+    }
+}
+
+struct Queued {
+    frame: Frame,
+    queued_at: Instant,
+}
+
+struct Stream {
+    /// Idle encoders; an encoder is taken out while a worker uses it
+    encoders: Vec<Box<dyn Encoder>>,
+    instances: usize,
+    queue: VecDeque<Queued>,
+    next_index: u64,
+    /// A compressed input frame was dropped, so later frames reference one
+    /// the encoder never saw; discard until the next keyframe
+    resync: bool,
+    keyframe_pending: bool,
+    limiter: KeyframeLimiter,
+    stats: StreamStats,
+}
+
+impl Stream {
+    fn idle(&self) -> bool {
+        self.encoders.len() == self.instances
+    }
+
+    fn request_keyframe(&mut self) {
+        if self.idle() {
+            self.encoders.iter_mut().for_each(|encoder| encoder.request_keyframe());
+        } else {
+            self.keyframe_pending = true;
+        }
+    }
+
+    /// A consumer asked for a keyframe; force one unless the limiter defers it
+    fn consumer_request(&mut self) {
+        self.stats.keyframe_requests += 1;
+        if self.limiter.request(Instant::now()) {
+            self.stats.forced_keyframes += 1;
+            self.request_keyframe();
+        }
+    }
+
+    /// Count a dropped frame, entering resync if it was a compressed one
+    fn dropped(&mut self, frame: &Frame) {
+        self.stats.dropped += 1;
+        if frame.format == PixelFormat::H264 && !self.resync {
+            self.resync = true;
+            self.request_keyframe();
+        }
+    }
+
+    /// Discard the oldest queued frame, and for compressed input everything
+    /// up to the next queued keyframe
+    fn evict_oldest(&mut self) {
+        let Some(evicted) = self.queue.pop_front() else {
+            return;
+        };
+        self.stats.dropped += 1;
+        if evicted.frame.format != PixelFormat::H264 {
+            return;
+        }
+        while self.queue.front().is_some_and(|queued| !is_random_access(&queued.frame.data)) {
+            self.queue.pop_front();
+            self.stats.dropped += 1;
+        }
+        if self.queue.is_empty() {
+            self.resync = true;
+            self.request_keyframe();
+        }
+    }
+}
+
+struct State {
+    streams: Vec<Stream>,
+    next_stream: usize,
+    closed: bool,
+}
+
+struct Job {
+    stream: usize,
+    index: u64,
+    encoder: Box<dyn Encoder>,
+    queued: Queued,
+}
+
+impl State {
+    /// Take the next frame a worker can encode, visiting streams round-robin
+    fn take_job(&mut self) -> Option<Job> {
+        let count = self.streams.len();
+        for offset in 0..count {
+            let id = (self.next_stream + offset) % count;
+            let stream = &mut self.streams[id];
+            if stream.queue.is_empty() || stream.encoders.is_empty() {
+                continue;
+            }
+            if stream.limiter.poll(Instant::now()) {
+                stream.stats.forced_keyframes += 1;
+                stream.keyframe_pending = true;
+            }
+
+            let mut encoder = stream.encoders.pop()?;
+            if stream.keyframe_pending {
+                stream.keyframe_pending = false;
+                encoder.request_keyframe();
+            }
+            let queued = stream.queue.pop_front()?;
+            let index = stream.next_index;
+            stream.next_index += 1;
+            self.next_stream = id + 1;
+            return Some(Job {
+                stream: id,
+                index,
+                encoder,
+                queued,
+            });
+        }
+        None
+    }
+
+    fn drained(&self) -> bool {
+        self.streams.iter().all(|stream| stream.queue.is_empty())
+    }
+}
+
+struct Shared {
+    stage: usize,
+    state: Mutex<State>,
+    /// Signalled whenever a frame is queued, a queue slot frees up, an
+    /// encoder goes idle or the pool closes
+    changed: Condvar,
+    queue_depth: usize,
+    policy: DropPolicy,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Index of stream `id`, if it belongs to this pool; a stage hands out
+    /// ids only for streams it has
+    fn index(&self, id: StreamId) -> Result<usize, CodecError> {
+        if id.stage != self.stage {
+            return Err(CodecError::UnknownStream(id));
+        }
+        Ok(id.index)
+    }
+
+    fn submit(&self, id: StreamId, frame: Frame) -> Result<(), SubmitError> {
+        let index = self.index(id)?;
+        let mut state = self.lock();
+        if state.closed {
+            return Err(SubmitError::Rejected(frame));
+        }
+
+        let compressed = frame.format == PixelFormat::H264;
+        let stream = &mut state.streams[index];
+        if stream.resync && compressed {
+            if !is_random_access(&frame.data) {
+                stream.stats.dropped += 1;
+                return Err(SubmitError::Rejected(frame));
+            }
+            stream.resync = false;
+        }
+
+        if stream.queue.len() >= self.queue_depth {
+            match self.policy {
+                DropPolicy::DropNewest => {
+                    stream.dropped(&frame);
+                    return Err(SubmitError::Rejected(frame));
+                }
+                DropPolicy::DropOldest => {
+                    stream.evict_oldest();
+                    if stream.resync && !is_random_access(&frame.data) {
+                        stream.stats.dropped += 1;
+                        return Err(SubmitError::Rejected(frame));
+                    }
+                    stream.resync = false;
+                }
+                DropPolicy::Block => {
+                    state = self
+                        .changed
+                        .wait_while(state, |state| {
+                            !state.closed && state.streams[index].queue.len() >= self.queue_depth
+                        })
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if state.closed {
+                        return Err(SubmitError::Rejected(frame));
+                    }
+                }
+            }
+        }
+
+        let stream = &mut state.streams[index];
+        stream.queue.push_back(Queued {
+            frame,
+            queued_at: Instant::now(),
+        });
+        stream.stats.frames += 1;
+        stream.stats.max_queue_depth = stream.stats.max_queue_depth.max(stream.queue.len());
+        drop(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+}
+
+type Outcome = (usize, u64, Result<Vec<EncodedFrame>, CodecError>);
+
+/// A set of encoder streams to run on a worker pool.
+pub struct EncodeStage {
+    id: usize,
+    workers: usize,
+    queue_depth: usize,
+    policy: DropPolicy,
+    min_keyframe_interval: Duration,
+    streams: Vec<(String, Vec<Box<dyn Encoder>>)>,
+}
+
+impl Default for EncodeStage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EncodeStage {
+    /// One worker per available core, four frames of queue per stream,
+    /// [`DropPolicy::DropOldest`] and at most two forced keyframes a second
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_STAGE.fetch_add(1, Ordering::Relaxed),
+            workers: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            queue_depth: 4,
+            policy: DropPolicy::default(),
+            min_keyframe_interval: Duration::from_millis(500),
+            streams: Vec::new(),
+        }
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Frames each stream may have waiting before the drop policy applies
+    pub fn queue_depth(mut self, depth: usize) -> Self {
+        self.queue_depth = depth.max(1);
+        self
+    }
+
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Shortest time between keyframes forced for consumer requests; any
+    /// keyframe the encoder produces also restarts the interval
+    pub fn min_keyframe_interval(mut self, interval: Duration) -> Self {
+        self.min_keyframe_interval = interval;
+        self
+    }
+
+    /// Add a stream encoded by `encoder`, one frame at a time
+    pub fn add_stream<E: Encoder + 'static>(&mut self, name: &str, encoder: E) -> StreamId {
+        self.add_boxed_stream(name, Box::new(encoder))
+    }
+
+    /// Add a stream with an already boxed encoder, such as one from the
+    /// [`CodecRegistry`](crate::CodecRegistry)
+    pub fn add_boxed_stream(&mut self, name: &str, encoder: Box<dyn Encoder>) -> StreamId {
+        self.streams.push((name.to_string(), vec![encoder]));
+        StreamId { stage: self.id, index: self.streams.len() - 1 }
+    }
+
+    /// Add an intra-only stream encoded by up to `instances` encoders at once.
+    ///
+    /// Each instance keeps its own state, so rate control adapts per
+    /// instance; packets are still delivered in order and timed from the
+    /// stream's first frame.
+    ///
+    /// # Errors
+    ///
+    /// Whatever `factory` returns, or [`CodecError::Unsupported`] if it
+    /// creates encoders for a codec with inter-frame prediction.
+    pub fn add_parallel_stream<F>(
+        &mut self,
+        name: &str,
+        instances: usize,
+        mut factory: F,
+    ) -> Result<StreamId, CodecError>
+    where
+        F: FnMut() -> Result<Box<dyn Encoder>, CodecError>,
+    {
+        let encoders = (0..instances.max(1)).map(|_| factory()).collect::<Result<Vec<_>, _>>()?;
+        if encoders.iter().any(|encoder| encoder.codec() != CodecId::Mjpeg) {
+            return Err(CodecError::Unsupported("parallel encoding of inter-frame codecs"));
+        }
+        self.streams.push((name.to_string(), encoders));
+        Ok(StreamId { stage: self.id, index: self.streams.len() - 1 })
+    }
+
+    /// Start the workers.
+    ///
+    /// Every packet or encoder error is passed to `sink` on a dedicated
+    /// output thread, in submission order per stream. Dropped frames and
+    /// frames the encoder skipped are not reported to `sink`.
+    pub fn spawn<F>(self, mut sink: F) -> EncodePool
+    where
+        F: FnMut(StreamId, Result<EncodedFrame, CodecError>) + Send + 'static,
+    {
+        let parallel: Vec<bool> = self.streams.iter().map(|(_, encoders)| encoders.len() > 1).collect();
+        let streams = self
+            .streams
+            .into_iter()
+            .map(|(name, encoders)| Stream {
+                instances: encoders.len(),
+                encoders,
+                queue: VecDeque::new(),
+                next_index: 0,
+                resync: false,
+                keyframe_pending: false,
+                limiter: KeyframeLimiter::new(self.min_keyframe_interval),
+                stats: StreamStats {
+                    name,
+                    ..Default::default()
+                },
+            })
+            .collect();
+        let shared = Arc::new(Shared {
+            stage: self.id,
+            state: Mutex::new(State {
+                streams,
+                next_stream: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            queue_depth: self.queue_depth,
+            policy: self.policy,
+        });
+
+        let (output_tx, output_rx) = mpsc::channel::<Outcome>();
+        let workers = (0..self.workers)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                let output_tx = output_tx.clone();
+                std::thread::spawn(move || worker_loop(&shared, &output_tx))
+            })
+            .collect();
+
+        let output_shared = Arc::clone(&shared);
+        let stage = self.id;
+        let output = std::thread::spawn(move || {
+            // Workers finish out of order; hold results back until every
+            // earlier frame of the same stream has been delivered
+            let mut pending: Vec<BTreeMap<u64, _>> = parallel.iter().map(|_| BTreeMap::new()).collect();
+            let mut next = vec![0u64; parallel.len()];
+            let mut clocks: Vec<StreamClock> = parallel.iter().map(|_| StreamClock::default()).collect();
+
+            for (stream, index, result) in output_rx {
+                pending[stream].insert(index, result);
+                while let Some(result) = pending[stream].remove(&next[stream]) {
+                    next[stream] += 1;
+                    let packets = match result {
+                        Ok(packets) => packets,
+                        Err(e) => {
+                            sink(StreamId { stage, index: stream }, Err(e));
+                            continue;
+                        }
+                    };
+                    let mut state = output_shared.lock();
+                    state.streams[stream].stats.packets += packets.len() as u64;
+                    if packets.iter().any(|packet| packet.keyframe) {
+                        state.streams[stream].limiter.keyframe_sent(Instant::now());
+                    }
+                    drop(state);
+                    for mut packet in packets {
+                        if parallel[stream] {
+                            packet.pts = clocks[stream].pts(packet.timestamp);
+                            packet.dts = packet.pts;
+                        }
+                        sink(StreamId { stage, index: stream }, Ok(packet));
+                    }
+                }
+            }
+        });
+
+        EncodePool {
+            shared,
+            output_tx: Some(output_tx),
+            workers,
+            output: Some(output),
+        }
+    }
+}
+
+fn worker_loop(shared: &Shared, output_tx: &Sender<Outcome>) {
+    let mut state = shared.lock();
+    loop {
+        if let Some(mut job) = state.take_job() {
+            drop(state);
+            // A queue slot just freed up for blocked submitters
+            shared.changed.notify_all();
+
+            let started = Instant::now();
+            let result = job.encoder.encode(&job.queued.frame);
+            let finished = Instant::now();
+            let failed = result.is_err();
+            let _ = output_tx.send((job.stream, job.index, result));
+
+            state = shared.lock();
+            let stream = &mut state.streams[job.stream];
+            if stream.keyframe_pending && stream.instances == 1 {
+                stream.keyframe_pending = false;
+                job.encoder.request_keyframe();
+            }
+            stream.encoders.push(job.encoder);
+
+            let latency = finished - job.queued.queued_at;
+            let stats = &mut stream.stats;
+            stats.encoded += 1;
+            stats.errors += failed as u64;
+            stats.encode_time += finished - started;
+            stats.total_latency += latency;
+            stats.last_latency = latency;
+            stats.max_latency = stats.max_latency.max(latency);
+            shared.changed.notify_all();
+            continue;
+        }
+
+        if state.closed && state.drained() {
+            return;
+        }
+        state = shared.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+}
+
+/// Handle to an [`EncodeStage`] running on its worker threads.
+///
+/// Dropping the handle closes the queues, waits for frames already queued to
+/// be encoded and flushes every encoder into the sink.
+pub struct EncodePool {
+    shared: Arc<Shared>,
+    output_tx: Option<Sender<Outcome>>,
+    workers: Vec<JoinHandle<()>>,
+    output: Option<JoinHandle<()>>,
+}
+
+impl EncodePool {
+    /// Queue a frame for `stream`.
+    ///
+    /// Only blocks under [`DropPolicy::Block`].
+    ///
+    /// # Errors
+    ///
+    /// [`SubmitError::Rejected`] with the frame if it was not queued:
+    /// rejected by the drop policy, discarded while a compressed stream
+    /// waits for its next keyframe, or the pool is shut down.
+    /// [`CodecError::UnknownStream`] if `stream` belongs to a different
+    /// stage.
+    pub fn submit(&self, stream: StreamId, frame: Frame) -> Result<(), SubmitError> {
+        self.shared.submit(stream, frame)
+    }
+
+    /// Cloneable handle feeding one stream, e.g. from a capture callback
+    pub fn input(&self, stream: StreamId) -> Result<StreamInput, CodecError> {
+        self.shared.index(stream)?;
+        Ok(StreamInput {
+            shared: Arc::clone(&self.shared),
+            stream,
+        })
+    }
+
+    /// Ask for a keyframe on `stream`, subject to the rate limit
+    pub fn request_keyframe(&self, stream: StreamId) -> Result<(), CodecError> {
+        let index = self.shared.index(stream)?;
+        self.shared.lock().streams[index].consumer_request();
+        Ok(())
+    }
+
+    /// Cloneable handle for consumers of `stream` to ask for keyframes, as
+    /// [`EncodePool::request_keyframe`]; it does nothing once the pool is gone
+    pub fn keyframe_requester(&self, stream: StreamId) -> Result<KeyframeRequester, CodecError> {
+        let index = self.shared.index(stream)?;
+        let shared = Arc::downgrade(&self.shared);
+        Ok(KeyframeRequester::new(move |_| {
+            if let Some(shared) = shared.upgrade() {
+                shared.lock().streams[index].consumer_request();
+            }
+        }))
+    }
+
+    /// Run `change` on every encoder of `stream`, e.g. to adjust its bitrate.
+    ///
+    /// Waits for frames of the stream currently being encoded, so the change
+    /// applies from a frame boundary.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::UnknownStream`] if `stream` belongs to a different
+    /// stage, otherwise the first error returned by `change`.
+    pub fn configure<F>(&self, stream: StreamId, mut change: F) -> Result<(), CodecError>
+    where
+        F: FnMut(&mut dyn Encoder) -> Result<(), CodecError>,
+    {
+        let index = self.shared.index(stream)?;
+        let mut state = self
+            .shared
+            .changed
+            .wait_while(self.shared.lock(), |state| !state.streams[index].idle())
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.streams[index]
+            .encoders
+            .iter_mut()
+            .try_for_each(|encoder| change(encoder.as_mut()))
+    }
+
+    /// Snapshot of the per-stream counters, in the order streams were added
+    pub fn stats(&self) -> Vec<StreamStats> {
+        let state = self.shared.lock();
+        state
+            .streams
+            .iter()
+            .map(|stream| StreamStats {
+                queue_depth: stream.queue.len(),
+                ..stream.stats.clone()
+            })
+            .collect()
+    }
+
+    /// Frames waiting for a worker across all streams
+    pub fn queue_depth(&self) -> usize {
+        self.shared.lock().streams.iter().map(|stream| stream.queue.len()).sum()
+    }
+
+    /// Stop accepting frames, encode what is queued and flush the encoders
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        let Some(output_tx) = self.output_tx.take() else {
+            return;
+        };
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+
+        let mut state = self.shared.lock();
+        for (id, stream) in state.streams.iter_mut().enumerate() {
+            for encoder in &mut stream.encoders {
+                let _ = output_tx.send((id, stream.next_index, encoder.flush()));
+                stream.next_index += 1;
+            }
+        }
+        drop(state);
+
+        drop(output_tx);
+        if let Some(output) = self.output.take() {
+            let _ = output.join();
+        }
+    }
+}
+
+impl Drop for EncodePool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Cloneable handle submitting frames to one stream of an [`EncodePool`].
+#[derive(Clone)]
+pub struct StreamInput {
+    shared: Arc<Shared>,
+    stream: StreamId,
+}
+
+impl StreamInput {
+    /// Queue a frame, as [`EncodePool::submit`]
+    pub fn submit(&self, frame: Frame) -> Result<(), SubmitError> {
+        self.shared.submit(self.stream, frame)
+    }
+
+    pub fn stream(&self) -> StreamId {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyframe::KeyframeReason;
+    use crate::{EncoderConfig, H264Encoder, H264Passthrough, MjpegEncoder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use streaming_core::SyntheticSource;
+
+    /// Encoder that waits on a gate before every frame; dropping the
+    /// sender opens it for good
+    struct Gated<E> {
+        inner: E,
+        gate: mpsc::Receiver<()>,
+    }
+
+    fn gated<E: Encoder>(inner: E) -> (Gated<E>, mpsc::Sender<()>) {
+        let (tx, rx) = mpsc::channel();
+        (Gated { inner, gate: rx }, tx)
+    }
+
+    impl<E: Encoder> Encoder for Gated<E> {
+        fn codec(&self) -> CodecId {
+            self.inner.codec()
+        }
+
+        fn encode(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+            let _ = self.gate.recv();
+            self.inner.encode(frame)
+        }
+
+        fn request_keyframe(&mut self) {
+            self.inner.request_keyframe();
+        }
+    }
+
+    fn wait_for_worker(pool: &EncodePool) {
+        while pool.queue_depth() > 0 {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn keeps_order_per_stream_across_workers() {
+        let config = EncoderConfig::new(64, 48, 30);
+        let mut stage = EncodeStage::new().workers(4).queue_depth(64).drop_policy(DropPolicy::Block);
+        let serial = stage.add_stream("h264", H264Encoder::new(config.clone()).unwrap());
+        let parallel = stage
+            .add_parallel_stream("mjpeg", 3, || Ok(Box::new(MjpegEncoder::new(config.clone()))))
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let pool = stage.spawn(move |stream, result| tx.send((stream, result.unwrap())).unwrap());
+        let source = || SyntheticSource::new(PixelFormat::YU12, 64, 48).velocity(2, 1).take(30);
+        for (first, second) in source().zip(source()) {
+            pool.submit(serial, first).unwrap();
+            pool.submit(parallel, second).unwrap();
+        }
+        // The first stream of another stage is refused, not fed to `serial`
+        let foreign = EncodeStage::new().add_stream("other", MjpegEncoder::new(config.clone()));
+        let refused = pool.submit(foreign, source().next().unwrap()).unwrap_err();
+        assert!(matches!(refused, SubmitError::Codec(CodecError::UnknownStream(id)) if id == foreign));
+        assert!(pool.input(foreign).is_err() && pool.request_keyframe(foreign).is_err());
+        pool.shutdown();
+
+        let packets: Vec<_> = rx.iter().collect();
+        for stream in [serial, parallel] {
+            let sequences: Vec<_> = packets.iter().filter(|(id, _)| *id == stream).map(|(_, p)| p.sequence).collect();
+            assert_eq!(sequences, (1..=30).collect::<Vec<_>>(), "{}", stream);
+        }
+        let (_, tenth) = packets.iter().filter(|(id, _)| *id == parallel).nth(10).unwrap();
+        assert_eq!(tenth.pts, Duration::from_secs(10) / 30);
+    }
+
+    #[test]
+    fn drops_under_overload_and_reports_queue() {
+        let (encoder, gate) = gated(MjpegEncoder::new(EncoderConfig::new(32, 32, 30)));
+        let mut stage = EncodeStage::new().workers(1).queue_depth(2).drop_policy(DropPolicy::DropOldest);
+        let stream = stage.add_stream("gated", encoder);
+        let (tx, rx) = mpsc::channel();
+        let pool = stage.spawn(move |_, result| tx.send(result.unwrap().sequence).unwrap());
+
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 32, 32);
+        let input = pool.input(stream).unwrap();
+        input.submit(source.next_frame()).unwrap();
+        // Frame 1 is taken by the worker, which then blocks on the gate
+        wait_for_worker(&pool);
+        for _ in 2..=6 {
+            input.submit(source.next_frame()).unwrap();
+        }
+        let stats = &pool.stats()[0];
+        assert_eq!((stats.queue_depth, stats.max_queue_depth, stats.dropped), (2, 2, 3));
+
+        drop(gate);
+        pool.shutdown();
+        // The oldest frames went, the newest survived
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [1, 5, 6]);
+    }
+
+    #[test]
+    fn compressed_streams_resync_on_keyframe() {
+        let mut config = EncoderConfig::new(64, 48, 30);
+        config.keyframe_interval = 5;
+        let mut camera = H264Encoder::new(config.clone()).unwrap();
+        let camera_frames: Vec<_> = SyntheticSource::new(PixelFormat::YU12, 64, 48)
+            .velocity(2, 1)
+            .take(12)
+            .map(|frame| {
+                let packet = camera.encode(&frame).unwrap().remove(0);
+                Frame {
+                    format: PixelFormat::H264,
+                    data: packet.data.to_vec(),
+                    ..frame
+                }
+            })
+            .collect();
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let (passthrough, gate) = gated(H264Passthrough::new(config).with_keyframe_requester(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }));
+        let mut stage = EncodeStage::new().workers(1).queue_depth(1).drop_policy(DropPolicy::DropNewest);
+        let stream = stage.add_stream("camera", passthrough);
+        let (tx, rx) = mpsc::channel();
+        let pool = stage.spawn(move |_, result| tx.send(result.unwrap().sequence).unwrap());
+
+        // With the worker stuck on frame 1 and frame 2 queued, frame 3 is
+        // lost; 4 and 5 depend on it, so the stream resumes at the keyframe
+        // in frame 6
+        let mut frames = camera_frames.into_iter();
+        pool.submit(stream, frames.next().unwrap()).unwrap();
+        wait_for_worker(&pool);
+        pool.submit(stream, frames.next().unwrap()).unwrap();
+        let rejected = pool.submit(stream, frames.next().unwrap()).unwrap_err().into_frame().unwrap();
+        let mut rejected = vec![rejected.sequence];
+        drop(gate);
+        for frame in frames {
+            wait_for_worker(&pool);
+            if let Err(SubmitError::Rejected(frame)) = pool.submit(stream, frame) {
+                rejected.push(frame.sequence);
+            }
+        }
+        pool.shutdown();
+
+        assert_eq!(rejected, [3, 4, 5]);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [1, 2, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn keyframe_requests_are_rate_limited() {
+        let mut config = EncoderConfig::new(64, 48, 30);
+        config.keyframe_interval = 1000;
+        let run = |interval: Duration| {
+            let mut stage = EncodeStage::new().workers(1).min_keyframe_interval(interval);
+            let stream = stage.add_stream("h264", H264Encoder::new(config.clone()).unwrap());
+            let (tx, rx) = mpsc::channel();
+            let pool = stage.spawn(move |_, result| {
+                let packet = result.unwrap();
+                tx.send((packet.sequence, packet.keyframe)).unwrap();
+            });
+
+            let requester = pool.keyframe_requester(stream).unwrap();
+            let mut keyframes = Vec::new();
+            for (index, frame) in SyntheticSource::new(PixelFormat::YU12, 64, 48).take(5).enumerate() {
+                // Ask twice before the third frame, after the first went out
+                if index == 2 {
+                    requester.request(KeyframeReason::PacketLoss);
+                    requester.request(KeyframeReason::Join);
+                }
+                pool.submit(stream, frame).unwrap();
+                let (sequence, keyframe) = rx.recv().unwrap();
+                if keyframe {
+                    keyframes.push(sequence);
+                }
+            }
+            let stats = pool.stats().remove(0);
+            pool.shutdown();
+            // The pool is gone; this must not panic
+            requester.request(KeyframeReason::Manual);
+            (keyframes, stats.keyframe_requests, stats.forced_keyframes)
+        };
+
+        // The first keyframe restarts the hour-long interval, deferring both
+        // requests; without a limit both reach the encoder, which makes one
+        // keyframe of them
+        assert_eq!(run(Duration::from_secs(3600)), (vec![1], 2, 0));
+        assert_eq!(run(Duration::ZERO), (vec![1, 3], 2, 2));
+    }
+}
@@ -0,0 +1,53 @@
+//! Encoded packets for tests, here and in the crates built on this one.
+//!
+//! Compiled for this crate's tests and with the `test-util` feature, which
+//! dependent crates enable for their tests only.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use streaming_core::{Metadata, PixelFormat, SyntheticSource};
+
+use crate::audio::opus_head;
+use crate::{CodecId, EncodedFrame, Encoder, EncoderConfig, MjpegEncoder};
+
+/// Capture time `millis` after 2023-11-14 22:13:20 UTC
+pub fn at(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + millis)
+}
+
+/// 32x32 MJPEG packets at 10 fps captured from `start`
+pub fn mjpeg_packets(start: SystemTime, count: usize) -> Vec<EncodedFrame> {
+    let mut encoder = MjpegEncoder::new(EncoderConfig::new(32, 32, 10));
+    SyntheticSource::new(PixelFormat::RGB3, 32, 32)
+        .fps(10)
+        .start_time(start)
+        .take(count)
+        .flat_map(|frame| encoder.encode(&frame).unwrap())
+        .collect()
+}
+
+/// Mono 20 ms Opus packets captured from `start`, their payload a TOC
+/// byte and the packet's index
+pub fn opus_packets(start: SystemTime, count: usize) -> Vec<EncodedFrame> {
+    let head = opus_head(1, 48_000, 312);
+    (0..count)
+        .map(|i| {
+            let pts = Duration::from_millis(20 * i as u64);
+            EncodedFrame {
+                codec: CodecId::Opus,
+                width: 0,
+                height: 0,
+                keyframe: true,
+                pts,
+                dts: pts,
+                duration: Duration::from_millis(20),
+                timestamp: start + pts,
+                sequence: i,
+                data: Bytes::from(vec![0xF8, i as u8]),
+                extradata: Some(head.clone()),
+                metadata: Metadata::default(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,784 @@
+//! Lossless raw frame dumps: YUV4MPEG2 files and headerless raw files.
+//!
+//! [`Y4mWriter`] and [`Y4mReader`] store frames in the YUV4MPEG2 format that
+//! ffmpeg, mpv and most codec tools read, so a capture can be inspected or
+//! fed to a reference encoder. Frames reload bit-exact: YV12 and YUYV are
+//! stored planar as the format requires but tagged so they come back in
+//! their original layout, and each frame's capture timestamp and sequence
+//! number travel in frame parameters that other tools ignore.
+//!
+//! [`RawWriter`] and [`RawReader`] handle headerless dumps of frame data as
+//! is, for any raw [`PixelFormat`] including RGB; the format and size have
+//! to be known when reading them back.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_codec::y4m::{Y4mHeader, Y4mReader, Y4mWriter};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//!
+//! let header = Y4mHeader::new(PixelFormat::YUYV, 64, 48)?.frame_rate(25, 1);
+//! let mut writer = Y4mWriter::new(Vec::new(), header)?;
+//! let frame = SyntheticSource::new(PixelFormat::YUYV, 64, 48).next_frame();
+//! writer.write_frame(&frame)?;
+//!
+//! let data = writer.finish()?;
+//! let mut reader = Y4mReader::new(data.as_slice())?;
+//! assert_eq!(reader.next_frame()?.unwrap().data, frame.data);
+//! # Ok::<(), streaming_codec::CodecError>(())
+//! ```
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use streaming_core::{Frame, FrameError, Metadata, PixelFormat};
+
+use crate::CodecError;
+
+const SIGNATURE: &str = "YUV4MPEG2";
+const FRAME_MARKER: &str = "FRAME";
+
+/// Longest header or frame header line accepted when reading
+const MAX_LINE: u64 = 4096;
+
+/// Chroma layout declared by a Y4M `C` tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colorspace {
+    /// 4:2:0 with chroma centred between luma samples, the default
+    C420Jpeg,
+
+    /// 4:2:0 with chroma sited between vertically adjacent luma samples
+    C420Mpeg2,
+
+    /// 4:2:0 PAL DV siting
+    C420Paldv,
+
+    C422,
+    C444,
+
+    /// Luma only
+    Mono,
+}
+
+impl Colorspace {
+    fn tag(&self) -> &'static str {
+        match self {
+            Colorspace::C420Jpeg => "420jpeg",
+            Colorspace::C420Mpeg2 => "420mpeg2",
+            Colorspace::C420Paldv => "420paldv",
+            Colorspace::C422 => "422",
+            Colorspace::C444 => "444",
+            Colorspace::Mono => "mono",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "420" | "420jpeg" => Some(Colorspace::C420Jpeg),
+            "420mpeg2" => Some(Colorspace::C420Mpeg2),
+            "420paldv" => Some(Colorspace::C420Paldv),
+            "422" => Some(Colorspace::C422),
+            "444" => Some(Colorspace::C444),
+            "mono" => Some(Colorspace::Mono),
+            _ => None,
+        }
+    }
+}
+
+/// Field order declared by a Y4M `I` tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interlacing {
+    #[default]
+    Progressive,
+    TopFieldFirst,
+    BottomFieldFirst,
+
+    /// Signalled per frame
+    Mixed,
+}
+
+impl Interlacing {
+    fn tag(&self) -> char {
+        match self {
+            Interlacing::Progressive => 'p',
+            Interlacing::TopFieldFirst => 't',
+            Interlacing::BottomFieldFirst => 'b',
+            Interlacing::Mixed => 'm',
+        }
+    }
+}
+
+/// Stream parameters from the first line of a Y4M file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Y4mHeader {
+    pub width: u32,
+    pub height: u32,
+
+    /// Frames per second as numerator and denominator
+    pub frame_rate: (u32, u32),
+
+    pub interlacing: Interlacing,
+
+    /// Pixel aspect ratio; `(0, 0)` means unknown
+    pub aspect: (u32, u32),
+
+    pub colorspace: Colorspace,
+
+    /// Raw format frames are read into and written from
+    pub format: PixelFormat,
+
+    /// `X` tags other than the ones this module writes, without the `X`
+    pub extensions: Vec<String>,
+}
+
+impl Y4mHeader {
+    /// Header for frames of `format`, progressive at 30 fps with square
+    /// pixels.
+    ///
+    /// # Errors
+    ///
+    /// [`FrameError::UnsupportedFormat`] for formats Y4M cannot hold
+    /// losslessly (RGB and compressed), and
+    /// [`FrameError::InvalidDimensions`] if the size does not fit the chroma
+    /// grid.
+    pub fn new(format: PixelFormat, width: u32, height: u32) -> Result<Self, CodecError> {
+        let colorspace = match format {
+            PixelFormat::YU12 | PixelFormat::YV12 => Colorspace::C420Jpeg,
+            PixelFormat::YUYV => Colorspace::C422,
+            _ => return Err(FrameError::UnsupportedFormat(format).into()),
+        };
+        let (align_x, align_y) = format.alignment();
+        if width == 0 || height == 0 || !width.is_multiple_of(align_x) || !height.is_multiple_of(align_y) {
+            return Err(FrameError::InvalidDimensions { width, height, format }.into());
+        }
+
+        Ok(Self {
+            width,
+            height,
+            frame_rate: (30, 1),
+            interlacing: Interlacing::Progressive,
+            aspect: (1, 1),
+            colorspace,
+            format,
+            extensions: Vec::new(),
+        })
+    }
+
+    /// Header matching `frame`'s format and size
+    pub fn for_frame(frame: &Frame) -> Result<Self, CodecError> {
+        Self::new(frame.format, frame.width, frame.height)
+    }
+
+    pub fn frame_rate(mut self, numerator: u32, denominator: u32) -> Self {
+        self.frame_rate = (numerator.max(1), denominator.max(1));
+        self
+    }
+
+    pub fn interlacing(mut self, interlacing: Interlacing) -> Self {
+        self.interlacing = interlacing;
+        self
+    }
+
+    /// Pixel aspect ratio, e.g. `(1, 1)` for square pixels
+    pub fn aspect(mut self, numerator: u32, denominator: u32) -> Self {
+        self.aspect = (numerator, denominator);
+        self
+    }
+
+    /// Duration of one frame at the declared rate
+    pub fn frame_duration(&self) -> Duration {
+        let (numerator, denominator) = self.frame_rate;
+        Duration::from_secs(denominator as u64) / numerator.max(1)
+    }
+
+    /// Bytes of pixel data per frame
+    pub fn frame_size(&self) -> usize {
+        let luma = self.width as usize * self.height as usize;
+        match self.colorspace {
+            Colorspace::C420Jpeg | Colorspace::C420Mpeg2 | Colorspace::C420Paldv => luma * 3 / 2,
+            Colorspace::C422 => luma * 2,
+            Colorspace::C444 => luma * 3,
+            Colorspace::Mono => luma,
+        }
+    }
+
+    /// Parse a header line, without its trailing newline.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the signature, size or a tag is
+    /// malformed, or the colour space has no matching [`PixelFormat`].
+    pub fn parse(line: &str) -> Result<Self, CodecError> {
+        let mut tokens = line.split(' ').filter(|token| !token.is_empty());
+        if tokens.next() != Some(SIGNATURE) {
+            return Err(invalid("missing YUV4MPEG2 signature"));
+        }
+
+        let (mut width, mut height) = (None, None);
+        let mut header = Self {
+            width: 0,
+            height: 0,
+            frame_rate: (30, 1),
+            interlacing: Interlacing::Progressive,
+            aspect: (0, 0),
+            colorspace: Colorspace::C420Jpeg,
+            format: PixelFormat::YU12,
+            extensions: Vec::new(),
+        };
+        let mut fourcc = None;
+
+        for token in tokens {
+            let mut chars = token.chars();
+            let (tag, value) = (chars.next(), chars.as_str());
+            match tag {
+                Some('W') => width = Some(number(value)?),
+                Some('H') => height = Some(number(value)?),
+                Some('F') => header.frame_rate = ratio(value)?,
+                Some('A') => header.aspect = ratio(value)?,
+                Some('I') => {
+                    header.interlacing = match value {
+                        "p" | "?" => Interlacing::Progressive,
+                        "t" => Interlacing::TopFieldFirst,
+                        "b" => Interlacing::BottomFieldFirst,
+                        "m" => Interlacing::Mixed,
+                        _ => return Err(invalid(format!("unknown interlacing I{}", value))),
+                    }
+                }
+                Some('C') => {
+                    header.colorspace = Colorspace::from_tag(value)
+                        .ok_or_else(|| invalid(format!("unsupported colour space C{}", value)))?
+                }
+                Some('X') => match value.strip_prefix("FOURCC=") {
+                    Some(code) => fourcc = Some(code.to_string()),
+                    None if value == "YSCSS=420JPEG" || value.starts_with("COLORRANGE=") => {}
+                    None => header.extensions.push(value.to_string()),
+                },
+                _ => return Err(invalid(format!("unknown Y4M tag {}", token))),
+            }
+        }
+
+        header.width = width.ok_or_else(|| invalid("Y4M header has no width"))?;
+        header.height = height.ok_or_else(|| invalid("Y4M header has no height"))?;
+        if header.frame_rate.0 == 0 || header.frame_rate.1 == 0 {
+            header.frame_rate = (30, 1);
+        }
+
+        header.format = match (header.colorspace, fourcc.as_deref()) {
+            (Colorspace::C420Jpeg | Colorspace::C420Mpeg2 | Colorspace::C420Paldv, Some("YV12")) => PixelFormat::YV12,
+            (Colorspace::C420Jpeg | Colorspace::C420Mpeg2 | Colorspace::C420Paldv, _) => PixelFormat::YU12,
+            (Colorspace::C422, _) => PixelFormat::YUYV,
+            (colorspace, _) => {
+                return Err(invalid(format!("Y4M colour space C{} has no matching pixel format", colorspace.tag())))
+            }
+        };
+        let (align_x, align_y) = header.format.alignment();
+        if header.width == 0 || !header.width.is_multiple_of(align_x) || !header.height.is_multiple_of(align_y) {
+            return Err(invalid(format!("Y4M size {}x{} does not fit the chroma grid", header.width, header.height)));
+        }
+        Ok(header)
+    }
+}
+
+impl fmt::Display for Y4mHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} W{} H{} F{}:{} I{} A{}:{} C{}",
+            SIGNATURE,
+            self.width,
+            self.height,
+            self.frame_rate.0,
+            self.frame_rate.1,
+            self.interlacing.tag(),
+            self.aspect.0,
+            self.aspect.1,
+            self.colorspace.tag()
+        )?;
+        if self.colorspace == Colorspace::C420Jpeg {
+            f.write_str(" XYSCSS=420JPEG")?;
+        }
+        f.write_str(" XCOLORRANGE=LIMITED")?;
+        if matches!(self.format, PixelFormat::YV12 | PixelFormat::YUYV) {
+            let fourcc = self.format.to_fourcc();
+            write!(f, " XFOURCC={}", String::from_utf8_lossy(&fourcc))?;
+        }
+        for extension in &self.extensions {
+            write!(f, " X{}", extension)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes frames to a Y4M stream.
+pub struct Y4mWriter<W: Write> {
+    inner: W,
+    header: Y4mHeader,
+}
+
+impl Y4mWriter<BufWriter<File>> {
+    /// Create or truncate a file and write the header to it
+    pub fn create(path: impl AsRef<Path>, header: Y4mHeader) -> Result<Self, CodecError> {
+        Self::new(BufWriter::new(File::create(path)?), header)
+    }
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Write the header to `inner`
+    pub fn new(mut inner: W, header: Y4mHeader) -> Result<Self, CodecError> {
+        writeln!(inner, "{}", header)?;
+        Ok(Self { inner, header })
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    /// Append one frame.
+    ///
+    /// # Errors
+    ///
+    /// [`FrameError::UnsupportedFormat`] if the frame is not in the header's
+    /// format, [`CodecError::ResolutionMismatch`] if its size differs, and
+    /// [`CodecError::Io`] if writing fails.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), CodecError> {
+        if frame.format != self.header.format {
+            return Err(FrameError::UnsupportedFormat(frame.format).into());
+        }
+        if (frame.width, frame.height) != (self.header.width, self.header.height) {
+            return Err(CodecError::ResolutionMismatch {
+                width: self.header.width,
+                height: self.header.height,
+                got_width: frame.width,
+                got_height: frame.height,
+            });
+        }
+        check_size(frame)?;
+
+        let since_epoch = frame.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        writeln!(
+            self.inner,
+            "{} XTIME={}.{:09} XSEQ={}",
+            FRAME_MARKER,
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos(),
+            frame.sequence
+        )?;
+        let (w, h) = (frame.width as usize, frame.height as usize);
+        match frame.format {
+            PixelFormat::YV12 => {
+                let (y, chroma) = frame.data.split_at(w * h);
+                let (v, u) = chroma.split_at(chroma.len() / 2);
+                self.inner.write_all(y)?;
+                self.inner.write_all(u)?;
+                self.inner.write_all(v)?;
+            }
+            PixelFormat::YUYV => self.inner.write_all(&yuyv_to_planar(&frame.data))?,
+            _ => self.inner.write_all(&frame.data)?,
+        }
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn finish(mut self) -> Result<W, CodecError> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads frames from a Y4M stream.
+pub struct Y4mReader<R: BufRead> {
+    inner: R,
+    header: Y4mHeader,
+    start: SystemTime,
+    count: usize,
+}
+
+impl Y4mReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> Y4mReader<R> {
+    /// Read and parse the header from `inner`.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the header is malformed, as
+    /// [`Y4mHeader::parse`].
+    pub fn new(mut inner: R) -> Result<Self, CodecError> {
+        let line = read_line(&mut inner)?.ok_or_else(|| invalid("empty Y4M stream"))?;
+        Ok(Self {
+            header: Y4mHeader::parse(&line)?,
+            inner,
+            start: SystemTime::UNIX_EPOCH,
+            count: 0,
+        })
+    }
+
+    /// Time of the first frame, for frames written without a timestamp
+    pub fn start_time(mut self, start: SystemTime) -> Self {
+        self.start = start;
+        self
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    /// Read the next frame, or `None` at the end of the stream.
+    ///
+    /// Frames from other tools carry no timestamp or sequence number; they
+    /// are timed from the header's frame rate and numbered from 1.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] on a malformed frame header or a truncated
+    /// frame.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, CodecError> {
+        let Some(line) = read_line(&mut self.inner)? else {
+            return Ok(None);
+        };
+        let mut params = line.split(' ').filter(|token| !token.is_empty());
+        if params.next() != Some(FRAME_MARKER) {
+            return Err(invalid("missing Y4M FRAME marker"));
+        }
+
+        self.count += 1;
+        let mut timestamp = self.start + self.header.frame_duration() * (self.count - 1) as u32;
+        let mut sequence = self.count;
+        for param in params {
+            if let Some(value) = param.strip_prefix("XTIME=") {
+                timestamp = SystemTime::UNIX_EPOCH + parse_time(value)?;
+            } else if let Some(value) = param.strip_prefix("XSEQ=") {
+                sequence = value.parse().map_err(|_| invalid(format!("bad frame sequence {}", value)))?;
+            }
+        }
+
+        let mut planar = vec![0; self.header.frame_size()];
+        self.inner.read_exact(&mut planar).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("truncated Y4M frame"),
+            _ => CodecError::Io(e),
+        })?;
+
+        let (w, h) = (self.header.width as usize, self.header.height as usize);
+        let data = match self.header.format {
+            PixelFormat::YV12 => {
+                let (y, chroma) = planar.split_at(w * h);
+                let (u, v) = chroma.split_at(chroma.len() / 2);
+                [y, v, u].concat()
+            }
+            PixelFormat::YUYV => planar_to_yuyv(&planar, w, h),
+            _ => planar,
+        };
+
+        Ok(Some(Frame {
+            format: self.header.format,
+            width: self.header.width,
+            height: self.header.height,
+            timestamp,
+            sequence,
+            data,
+            metadata: Metadata::default(),
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Y4mReader<R> {
+    type Item = Result<Frame, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// Writes frame data back to back with no header.
+pub struct RawWriter<W: Write> {
+    inner: W,
+}
+
+impl RawWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> RawWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Append one raw frame exactly as it is laid out in memory.
+    ///
+    /// # Errors
+    ///
+    /// [`FrameError::UnsupportedFormat`] for compressed frames, a size error
+    /// if the data does not match the frame's dimensions, and
+    /// [`CodecError::Io`] if writing fails.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), CodecError> {
+        check_size(frame)?;
+        self.inner.write_all(&frame.data)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn finish(mut self) -> Result<W, CodecError> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a headerless dump of frames with known format and size.
+pub struct RawReader<R: Read> {
+    inner: R,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    frame_size: usize,
+    fps: u32,
+    start: SystemTime,
+    count: usize,
+}
+
+impl RawReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>, format: PixelFormat, width: u32, height: u32) -> Result<Self, CodecError> {
+        Self::new(BufReader::new(File::open(path)?), format, width, height)
+    }
+}
+
+impl<R: Read> RawReader<R> {
+    /// Read `format` frames of `width`x`height` from `inner`, at 30 fps
+    /// starting at the Unix epoch.
+    ///
+    /// # Errors
+    ///
+    /// [`FrameError::UnsupportedFormat`] for compressed formats.
+    pub fn new(inner: R, format: PixelFormat, width: u32, height: u32) -> Result<Self, CodecError> {
+        let frame_size = format.frame_size(width, height).ok_or(FrameError::UnsupportedFormat(format))?;
+        Ok(Self {
+            inner,
+            format,
+            width,
+            height,
+            frame_size,
+            fps: 30,
+            start: SystemTime::UNIX_EPOCH,
+            count: 0,
+        })
+    }
+
+    pub fn fps(mut self, fps: u32) -> Self {
+        self.fps = fps.max(1);
+        self
+    }
+
+    /// Timestamp of the first frame
+    pub fn start_time(mut self, start: SystemTime) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Read the next frame, or `None` at the end of the dump.
+    ///
+    /// # Errors
+    ///
+    /// [`CodecError::InvalidData`] if the dump ends partway through a frame.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, CodecError> {
+        let mut data = vec![0; self.frame_size];
+        let mut filled = 0;
+        while filled < data.len() {
+            match self.inner.read(&mut data[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if filled == 0 {
+            return Ok(None);
+        }
+        if filled < data.len() {
+            return Err(invalid(format!("raw dump ends {} bytes into a {}-byte frame", filled, data.len())));
+        }
+
+        let timestamp = self.start + Duration::from_secs(1) * self.count as u32 / self.fps;
+        self.count += 1;
+        Ok(Some(Frame {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            timestamp,
+            sequence: self.count,
+            data,
+            metadata: Metadata::default(),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for RawReader<R> {
+    type Item = Result<Frame, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+fn check_size(frame: &Frame) -> Result<(), CodecError> {
+    let expected = frame
+        .format
+        .frame_size(frame.width, frame.height)
+        .ok_or(FrameError::UnsupportedFormat(frame.format))?;
+    if frame.data.len() != expected {
+        return Err(FrameError::InvalidDataSize {
+            expected,
+            actual: frame.data.len(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Split packed YUYV into Y, U and V planes
+fn yuyv_to_planar(data: &[u8]) -> Vec<u8> {
+    let pairs = data.len() / 4;
+    let mut planar = vec![0; data.len()];
+    let (y, chroma) = planar.split_at_mut(pairs * 2);
+    let (u, v) = chroma.split_at_mut(pairs);
+    for (i, px) in data.chunks_exact(4).enumerate() {
+        y[2 * i] = px[0];
+        u[i] = px[1];
+        y[2 * i + 1] = px[2];
+        v[i] = px[3];
+    }
+    planar
+}
+
+fn planar_to_yuyv(planar: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let (y, chroma) = planar.split_at(width * height);
+    let (u, v) = chroma.split_at(chroma.len() / 2);
+    (0..u.len()).flat_map(|i| [y[2 * i], u[i], y[2 * i + 1], v[i]]).collect()
+}
+
+/// Read one `\n`-terminated line, or `None` at a clean end of stream
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, CodecError> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("unterminated or overlong Y4M header line"));
+    }
+    String::from_utf8(line).map(Some).map_err(|_| invalid("Y4M header is not text"))
+}
+
+fn number(value: &str) -> Result<u32, CodecError> {
+    value.parse().map_err(|_| invalid(format!("bad Y4M number {}", value)))
+}
+
+fn ratio(value: &str) -> Result<(u32, u32), CodecError> {
+    let (numerator, denominator) = value.split_once(':').ok_or_else(|| invalid(format!("bad Y4M ratio {}", value)))?;
+    Ok((number(numerator)?, number(denominator)?))
+}
+
+fn parse_time(value: &str) -> Result<Duration, CodecError> {
+    let bad = || invalid(format!("bad frame time {}", value));
+    let (secs, nanos) = value.split_once('.').ok_or_else(bad)?;
+    let secs = secs.parse().map_err(|_| bad())?;
+    let nanos = nanos.parse().map_err(|_| bad())?;
+    if nanos >= 1_000_000_000 {
+        return Err(bad());
+    }
+    Ok(Duration::new(secs, nanos))
+}
+
+fn invalid(message: impl Into<String>) -> CodecError {
+    CodecError::InvalidData(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_core::SyntheticSource;
+
+    #[test]
+    fn round_trips_bit_exact() {
+        for format in [PixelFormat::YU12, PixelFormat::YV12, PixelFormat::YUYV] {
+            let frames: Vec<_> = SyntheticSource::new(format, 64, 48).velocity(5, 3).noise(10).take(3).collect();
+            let header = Y4mHeader::for_frame(&frames[0]).unwrap().frame_rate(30000, 1001).aspect(4, 3);
+            let mut writer = Y4mWriter::new(Vec::new(), header.clone()).unwrap();
+            for frame in &frames {
+                writer.write_frame(frame).unwrap();
+            }
+            let data = writer.finish().unwrap();
+
+            let reader = Y4mReader::new(data.as_slice()).unwrap();
+            assert_eq!(reader.header(), &header);
+            let read: Vec<_> = reader.map(Result::unwrap).collect();
+            assert_eq!(read.len(), 3);
+            for (original, copy) in frames.iter().zip(&read) {
+                assert_eq!(copy.format, format);
+                assert_eq!((copy.timestamp, copy.sequence), (original.timestamp, original.sequence));
+                assert_eq!(copy.data, original.data, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_foreign_files() {
+        // As written by ffmpeg: planar 4:2:0, no frame parameters
+        let header = "YUV4MPEG2 W4 H2 F25:1 It A128:117 C420mpeg2 XYSCSS=420MPEG2\n";
+        let mut data = header.as_bytes().to_vec();
+        for value in [10u8, 20] {
+            data.extend_from_slice(b"FRAME\n");
+            data.extend(std::iter::repeat_n(value, 4 * 2 * 3 / 2));
+        }
+
+        let mut reader = Y4mReader::new(data.as_slice()).unwrap();
+        let header = reader.header().clone();
+        assert_eq!((header.width, header.height, header.format), (4, 2, PixelFormat::YU12));
+        assert_eq!((header.interlacing, header.aspect), (Interlacing::TopFieldFirst, (128, 117)));
+        assert_eq!(header.extensions, ["YSCSS=420MPEG2"]);
+
+        reader.next_frame().unwrap();
+        let second = reader.next_frame().unwrap().unwrap();
+        assert_eq!(second.sequence, 2);
+        assert_eq!(second.timestamp, SystemTime::UNIX_EPOCH + Duration::from_millis(40));
+        assert!(reader.next_frame().unwrap().is_none());
+
+        data.truncate(data.len() - 1);
+        let mut truncated = Y4mReader::new(data.as_slice()).unwrap().skip(1);
+        assert!(matches!(truncated.next(), Some(Err(CodecError::InvalidData(_)))));
+        assert!(Y4mReader::new(&b"YUV4MPEG2 W4 H2 C444\n"[..]).is_err());
+        assert!(Y4mReader::new(&b"YUV4MPEG W4 H2\n"[..]).is_err());
+        assert!(Y4mReader::new("YUV4MPEG2 W4 H2 é\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn raw_dumps_take_any_raw_format() {
+        let frames: Vec<_> = SyntheticSource::new(PixelFormat::BGR3, 16, 8).fps(10).take(2).collect();
+        let mut writer = RawWriter::new(Vec::new());
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        let mut data = writer.finish().unwrap();
+        assert_eq!(data.len(), 2 * 16 * 8 * 3);
+
+        let read: Vec<_> = RawReader::new(data.as_slice(), PixelFormat::BGR3, 16, 8)
+            .unwrap()
+            .fps(10)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read[1].data, frames[1].data);
+        assert_eq!(read[1].timestamp, SystemTime::UNIX_EPOCH + Duration::from_millis(100));
+
+        data.pop();
+        let mut reader = RawReader::new(data.as_slice(), PixelFormat::BGR3, 16, 8).unwrap();
+        reader.next_frame().unwrap();
+        assert!(matches!(reader.next_frame(), Err(CodecError::InvalidData(_))));
+        assert!(Y4mHeader::for_frame(&frames[0]).is_err());
+    }
+}
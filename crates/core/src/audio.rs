@@ -0,0 +1,217 @@
+//! Audio samples and sources.
+//!
+//! Audio travels as [`AudioFrame`]s: short runs of interleaved signed 16-bit
+//! samples, timestamped with the same wall clock as video [`Frame`]s, so the
+//! two can be lined up downstream without a separate sync signal.
+//!
+//! [`AudioSource`] is implemented by microphones in the capture crate and by
+//! the stand-ins here: [`ToneSource`] for a generated test tone and
+//! [`WavSource`](crate::wav::WavSource) for a recorded file.
+//!
+//! [`Frame`]: crate::Frame
+
+use std::f64::consts::TAU;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+/// Interleaved signed 16-bit samples from one source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFrame {
+    /// Samples per second, per channel
+    pub sample_rate: u32,
+    pub channels: u16,
+
+    /// Capture time of the first sample
+    pub timestamp: SystemTime,
+    pub sequence: usize,
+
+    /// `channels` samples per sampling instant, one after another
+    pub samples: Vec<i16>,
+}
+
+impl AudioFrame {
+    /// Sampling instants in the frame, i.e. samples per channel
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn duration(&self) -> Duration {
+        samples_duration(self.frames() as u64, self.sample_rate)
+    }
+
+    /// Capture time just after the last sample
+    pub fn end_time(&self) -> SystemTime {
+        self.timestamp + self.duration()
+    }
+}
+
+/// Time taken by `frames` sampling instants at `sample_rate`
+pub fn samples_duration(frames: u64, sample_rate: u32) -> Duration {
+    let nanos = frames as u128 * 1_000_000_000 / sample_rate.max(1) as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Anything producing [`AudioFrame`]s.
+pub trait AudioSource: Send {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> u16;
+
+    /// The next run of samples, blocking until it is available, or `None`
+    /// once the source has ended
+    fn read_frame(&mut self) -> io::Result<Option<AudioFrame>>;
+}
+
+/// Generator of a sine tone, as a stand-in for a microphone.
+///
+/// Frames are numbered from 1 and timestamped from the start time by the
+/// samples produced so far, so they never drift against a
+/// [`SyntheticSource`](crate::SyntheticSource) started at the same time.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use streaming_core::audio::ToneSource;
+///
+/// let mut source = ToneSource::new(48_000, 2).frequency(440.0);
+/// let frame = source.next_frame();
+/// assert_eq!(frame.frames(), 960);
+/// assert_eq!(frame.duration(), Duration::from_millis(20));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ToneSource {
+    sample_rate: u32,
+    channels: u16,
+    frequency: f64,
+    amplitude: f64,
+    frame_size: usize,
+    start: SystemTime,
+    position: u64,
+    sequence: usize,
+}
+
+impl ToneSource {
+    /// A 1 kHz tone at half scale in 20 ms frames
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let sample_rate = sample_rate.max(1);
+        Self {
+            sample_rate,
+            channels: channels.max(1),
+            frequency: 1000.0,
+            amplitude: 0.5,
+            frame_size: (sample_rate / 50).max(1) as usize,
+            start: SystemTime::now(),
+            position: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Tone frequency in Hz
+    pub fn frequency(mut self, frequency: f64) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Peak level as a fraction of full scale; 0 gives silence
+    pub fn amplitude(mut self, amplitude: f64) -> Self {
+        self.amplitude = amplitude.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Length of each frame
+    pub fn frame_duration(mut self, duration: Duration) -> Self {
+        let frames = duration.as_nanos() * self.sample_rate as u128 / 1_000_000_000;
+        self.frame_size = (frames as usize).max(1);
+        self
+    }
+
+    /// Timestamp of the first frame
+    pub fn start_time(mut self, start: SystemTime) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Timestamp the next frame will carry
+    pub fn next_timestamp(&self) -> SystemTime {
+        self.start + samples_duration(self.position, self.sample_rate)
+    }
+
+    /// Generate the next frame
+    pub fn next_frame(&mut self) -> AudioFrame {
+        let timestamp = self.next_timestamp();
+        self.sequence += 1;
+
+        let scale = self.amplitude * i16::MAX as f64;
+        let step = TAU * self.frequency / self.sample_rate as f64;
+        let mut samples = Vec::with_capacity(self.frame_size * self.channels as usize);
+        for instant in self.position..self.position + self.frame_size as u64 {
+            let sample = ((instant as f64 * step).sin() * scale).round() as i16;
+            samples.extend(std::iter::repeat_n(sample, self.channels as usize));
+        }
+        self.position += self.frame_size as u64;
+
+        AudioFrame {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp,
+            sequence: self.sequence,
+            samples,
+        }
+    }
+}
+
+impl Iterator for ToneSource {
+    type Item = AudioFrame;
+
+    fn next(&mut self) -> Option<AudioFrame> {
+        Some(self.next_frame())
+    }
+}
+
+impl AudioSource for ToneSource {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<AudioFrame>> {
+        Ok(Some(self.next_frame()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PixelFormat, SyntheticSource};
+
+    #[test]
+    fn tone_is_timed_by_samples() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut tone = ToneSource::new(44_100, 1).frequency(441.0).start_time(start);
+        let frames: Vec<_> = tone.by_ref().take(50).collect();
+
+        assert_eq!(frames[0].frames(), 882);
+        assert_eq!(tone.next_timestamp(), start + Duration::from_secs(1));
+        assert_eq!(frames[49].end_time(), start + Duration::from_secs(1));
+
+        // A 441 Hz period is 100 samples: peak a quarter of the way in
+        assert_eq!(frames[0].samples[0], 0);
+        assert_eq!(frames[0].samples[25], (0.5 * i16::MAX as f64).round() as i16);
+        assert_eq!(frames[0].samples[125], frames[0].samples[25]);
+    }
+
+    #[test]
+    fn tone_lines_up_with_video() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut video = SyntheticSource::new(PixelFormat::YU12, 16, 16).fps(25).start_time(start);
+        let mut audio = ToneSource::new(48_000, 2).frame_duration(Duration::from_millis(40)).start_time(start);
+        for _ in 0..100 {
+            let (frame, samples) = (video.next_frame(), audio.next_frame());
+            assert_eq!(frame.timestamp, samples.timestamp);
+            assert_eq!(samples.samples.len(), 2 * 1920);
+        }
+    }
+}
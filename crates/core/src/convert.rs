@@ -0,0 +1,242 @@
+//! Pixel format conversion between the raw formats in [`PixelFormat`].
+//!
+//! YUV data is treated as BT.601 limited range, which is what UVC webcams
+//! deliver. Conversions go through a full-resolution YUV 4:4:4 intermediate,
+//! so moving between two YUV layouts only resamples chroma and never touches luma.
+
+use crate::planes::{self, Plane};
+use crate::{Frame, FrameError, PixelFormat};
+
+/// Convert one RGB pixel to BT.601 limited-range YUV.
+pub fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y.clamp(0, 255) as u8, u.clamp(0, 255) as u8, v.clamp(0, 255) as u8)
+}
+
+/// Convert one BT.601 limited-range YUV pixel to RGB.
+pub fn yuv_to_rgb(y: u8, u: u8, v: u8) -> (u8, u8, u8) {
+    let c = y as i32 - 16;
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let r = (298 * c + 409 * e + 128) >> 8;
+    let g = (298 * c - 100 * d - 208 * e + 128) >> 8;
+    let b = (298 * c + 516 * d + 128) >> 8;
+    (r.clamp(0, 255) as u8, g.clamp(0, 255) as u8, b.clamp(0, 255) as u8)
+}
+
+/// Convert `frame` to `target`, keeping dimensions, timestamp and sequence number.
+///
+/// # Errors
+///
+/// Returns [`FrameError::UnsupportedFormat`] if either side is compressed, and
+/// a size error if the input frame is malformed or its dimensions do not fit
+/// the chroma grid of `target`.
+pub fn convert(frame: &Frame, target: PixelFormat) -> Result<Frame, FrameError> {
+    if target.is_compressed() {
+        return Err(FrameError::UnsupportedFormat(target));
+    }
+    let (align_x, align_y) = target.alignment();
+    if !frame.width.is_multiple_of(align_x) || !frame.height.is_multiple_of(align_y) {
+        return Err(FrameError::InvalidDimensions {
+            width: frame.width,
+            height: frame.height,
+            format: target,
+        });
+    }
+
+    let data = if frame.format == target {
+        planes::validate(frame)?;
+        frame.data.clone()
+    } else if matches!(
+        (frame.format, target),
+        (PixelFormat::RGB3, PixelFormat::BGR3) | (PixelFormat::BGR3, PixelFormat::RGB3)
+    ) {
+        planes::validate(frame)?;
+        frame.data.chunks_exact(3).flat_map(|px| [px[2], px[1], px[0]]).collect()
+    } else {
+        let yuv = to_yuv444(frame)?;
+        from_yuv444(&yuv, target)
+    };
+
+    Ok(Frame {
+        format: target,
+        width: frame.width,
+        height: frame.height,
+        timestamp: frame.timestamp,
+        sequence: frame.sequence,
+        data,
+        metadata: frame.metadata.clone(),
+    })
+}
+
+/// Full-resolution Y, U and V planes of a frame.
+pub(crate) struct Yuv444 {
+    pub y: Plane,
+    pub u: Plane,
+    pub v: Plane,
+}
+
+pub(crate) fn to_yuv444(frame: &Frame) -> Result<Yuv444, FrameError> {
+    let mut planes = planes::split(frame)?;
+    let (w, h) = (frame.width as usize, frame.height as usize);
+
+    match frame.format {
+        PixelFormat::RGB3 | PixelFormat::BGR3 => {
+            let (mut y, mut u, mut v) = (Plane::new(w, h, 1), Plane::new(w, h, 1), Plane::new(w, h, 1));
+            for (i, px) in planes[0].data.chunks_exact(3).enumerate() {
+                let (r, g, b) = if frame.format == PixelFormat::RGB3 {
+                    (px[0], px[1], px[2])
+                } else {
+                    (px[2], px[1], px[0])
+                };
+                (y.data[i], u.data[i], v.data[i]) = rgb_to_yuv(r, g, b);
+            }
+            Ok(Yuv444 { y, u, v })
+        }
+        _ => {
+            if frame.format == PixelFormat::YV12 {
+                planes.swap(1, 2);
+            }
+            let v = upsample(&planes.pop().unwrap(), w, h);
+            let u = upsample(&planes.pop().unwrap(), w, h);
+            let y = planes.pop().unwrap();
+            Ok(Yuv444 { y, u, v })
+        }
+    }
+}
+
+pub(crate) fn from_yuv444(yuv: &Yuv444, target: PixelFormat) -> Vec<u8> {
+    let (w, h) = (yuv.y.width, yuv.y.height);
+    match target {
+        PixelFormat::RGB3 | PixelFormat::BGR3 => {
+            let mut data = Vec::with_capacity(w * h * 3);
+            for i in 0..w * h {
+                let (r, g, b) = yuv_to_rgb(yuv.y.data[i], yuv.u.data[i], yuv.v.data[i]);
+                if target == PixelFormat::RGB3 {
+                    data.extend_from_slice(&[r, g, b]);
+                } else {
+                    data.extend_from_slice(&[b, g, r]);
+                }
+            }
+            data
+        }
+        _ => {
+            let (sx, sy) = planes::plane_shift(target, 1);
+            let u = downsample(&yuv.u, sx, sy);
+            let v = downsample(&yuv.v, sx, sy);
+            let chroma = if target == PixelFormat::YV12 { [v, u] } else { [u, v] };
+            let [first, second] = chroma;
+            planes::join(target, vec![yuv.y.clone(), first, second])
+        }
+    }
+}
+
+/// Replicate subsampled chroma up to `width`x`height`.
+fn upsample(plane: &Plane, width: usize, height: usize) -> Plane {
+    if plane.width == width && plane.height == height {
+        return plane.clone();
+    }
+    let (fx, fy) = (width / plane.width, height / plane.height);
+    let mut out = Plane::new(width, height, 1);
+    for y in 0..height {
+        for x in 0..width {
+            out.data[y * width + x] = plane.data[(y / fy) * plane.width + x / fx];
+        }
+    }
+    out
+}
+
+/// Average full-resolution chroma over blocks of `2^sx` by `2^sy` samples.
+fn downsample(plane: &Plane, sx: u32, sy: u32) -> Plane {
+    let (bw, bh) = (1usize << sx, 1usize << sy);
+    let (width, height) = (plane.width / bw, plane.height / bh);
+    let mut out = Plane::new(width, height, 1);
+    let count = (bw * bh) as u32;
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0u32;
+            for dy in 0..bh {
+                for dx in 0..bw {
+                    sum += plane.data[(y * bh + dy) * plane.width + x * bw + dx] as u32;
+                }
+            }
+            out.data[y * width + x] = ((sum + count / 2) / count) as u8;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn frame(format: PixelFormat, width: u32, height: u32, data: Vec<u8>) -> Frame {
+        Frame {
+            format,
+            width,
+            height,
+            timestamp: SystemTime::UNIX_EPOCH,
+            sequence: 1,
+            data,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn primary_colours_round_trip_through_yuv() {
+        for (r, g, b) in [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255), (0, 0, 0), (128, 64, 200)] {
+            let (y, u, v) = rgb_to_yuv(r, g, b);
+            let (r2, g2, b2) = yuv_to_rgb(y, u, v);
+            assert!((r as i32 - r2 as i32).abs() <= 2, "{:?}", (r, g, b));
+            assert!((g as i32 - g2 as i32).abs() <= 2, "{:?}", (r, g, b));
+            assert!((b as i32 - b2 as i32).abs() <= 2, "{:?}", (r, g, b));
+        }
+    }
+
+    #[test]
+    fn yuyv_to_i420_averages_chroma_rows() {
+        // 2x2 YUYV: one macropixel per row
+        let input = frame(PixelFormat::YUYV, 2, 2, vec![10, 100, 20, 200, 30, 110, 40, 210]);
+        let out = convert(&input, PixelFormat::YU12).unwrap();
+        assert_eq!(out.data, vec![10, 20, 30, 40, 105, 205]);
+
+        let yv12 = convert(&input, PixelFormat::YV12).unwrap();
+        assert_eq!(yv12.data, vec![10, 20, 30, 40, 205, 105]);
+    }
+
+    #[test]
+    fn i420_to_yuyv_replicates_chroma() {
+        let input = frame(PixelFormat::YU12, 2, 2, vec![10, 20, 30, 40, 105, 205]);
+        let out = convert(&input, PixelFormat::YUYV).unwrap();
+        assert_eq!(out.data, vec![10, 105, 20, 205, 30, 105, 40, 205]);
+    }
+
+    #[test]
+    fn rgb_bgr_swap_is_lossless() {
+        let input = frame(PixelFormat::RGB3, 2, 1, vec![1, 2, 3, 4, 5, 6]);
+        let out = convert(&input, PixelFormat::BGR3).unwrap();
+        assert_eq!(out.data, vec![3, 2, 1, 6, 5, 4]);
+    }
+
+    #[test]
+    fn every_raw_pair_produces_the_expected_size() {
+        let raw = [PixelFormat::YUYV, PixelFormat::RGB3, PixelFormat::BGR3, PixelFormat::YU12, PixelFormat::YV12];
+        for from in raw {
+            let input = frame(from, 8, 4, vec![128; from.frame_size(8, 4).unwrap()]);
+            for to in raw {
+                let out = convert(&input, to).unwrap();
+                assert_eq!(out.data.len(), to.frame_size(8, 4).unwrap(), "{:?} -> {:?}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_formats_are_rejected() {
+        let input = frame(PixelFormat::YUYV, 2, 2, vec![0; 8]);
+        assert!(matches!(convert(&input, PixelFormat::MJPG), Err(FrameError::UnsupportedFormat(_))));
+    }
+}
@@ -0,0 +1,59 @@
+//! Per-pixel drawing directly into raw frame data.
+
+use crate::convert::rgb_to_yuv;
+use crate::{Frame, PixelFormat};
+
+/// An RGB colour used for drawing onto frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    pub(crate) fn yuv(&self) -> (u8, u8, u8) {
+        rgb_to_yuv(self.r, self.g, self.b)
+    }
+}
+
+/// Set the pixel at (`x`, `y`) of a validated raw frame.
+///
+/// For subsampled YUV formats the chroma sample shared with neighbouring pixels
+/// is overwritten as well, so coloured drawing bleeds up to one pixel.
+pub(crate) fn put_pixel(frame: &mut Frame, x: usize, y: usize, color: Color) {
+    let w = frame.width as usize;
+    let h = frame.height as usize;
+    if x >= w || y >= h {
+        return;
+    }
+
+    match frame.format {
+        PixelFormat::RGB3 => frame.data[(y * w + x) * 3..(y * w + x) * 3 + 3].copy_from_slice(&[color.r, color.g, color.b]),
+        PixelFormat::BGR3 => frame.data[(y * w + x) * 3..(y * w + x) * 3 + 3].copy_from_slice(&[color.b, color.g, color.r]),
+        PixelFormat::YUYV => {
+            let (luma, u, v) = color.yuv();
+            let pair = (y * w + (x & !1)) * 2;
+            frame.data[(y * w + x) * 2] = luma;
+            frame.data[pair + 1] = u;
+            frame.data[pair + 3] = v;
+        }
+        PixelFormat::YU12 | PixelFormat::YV12 => {
+            let (luma, u, v) = color.yuv();
+            let (cw, ch) = (w / 2, h / 2);
+            let chroma = w * h + (y / 2) * cw + x / 2;
+            let (first, second) = if frame.format == PixelFormat::YU12 { (u, v) } else { (v, u) };
+            frame.data[y * w + x] = luma;
+            frame.data[chroma] = first;
+            frame.data[chroma + cw * ch] = second;
+        }
+        PixelFormat::MJPG | PixelFormat::H264 => {}
+    }
+}
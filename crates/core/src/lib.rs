@@ -1,6 +1,31 @@
 
 
 use std::time::SystemTime;
+use thiserror::Error;
+
+pub mod audio;
+pub mod convert;
+mod draw;
+mod font;
+pub mod mask;
+pub mod metadata;
+pub mod motion;
+pub mod overlay;
+mod planes;
+pub mod process;
+pub mod scale;
+pub mod synthetic;
+pub mod wav;
+
+pub use audio::{AudioFrame, AudioSource, ToneSource};
+pub use draw::Color;
+pub use mask::{MaskShape, MaskStyle, PrivacyMask, PrivacyMasker};
+pub use metadata::{Metadata, MetadataValue};
+pub use motion::{MotionConfig, MotionDetector, MotionEvent, MotionStage, MotionZone};
+pub use overlay::{Position, TextOverlay};
+pub use process::{Convert, Filter, FrameProcessor, Pipeline, PipelineWorker, StageError, StageStats};
+pub use scale::{CropScale, Rect, ScaleFilter};
+pub use synthetic::SyntheticSource;
 
 #[derive(Debug)]
 pub struct Frame {
@@ -10,6 +35,9 @@ pub struct Frame {
     pub timestamp: SystemTime,
     pub sequence: usize,
     pub data: Vec<u8>,
+
+    /// Data about the frame that is not part of the picture
+    pub metadata: Metadata,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -20,19 +48,28 @@ pub enum PixelFormat {
     BGR3,
     YU12,
     YV12,
+
+    /// H.264 Annex-B bitstream, one access unit per frame
+    H264,
 }
 
 impl PixelFormat {
     /// Convert from V4L2 fourcc bytes to PixelFormat
     pub fn from_fourcc(fourcc: &[u8; 4]) -> Self {
+        Self::try_from_fourcc(fourcc).unwrap_or(PixelFormat::YUYV)  // Default fallback
+    }
+
+    /// Convert from V4L2 fourcc bytes, or `None` for formats we do not handle
+    pub fn try_from_fourcc(fourcc: &[u8; 4]) -> Option<Self> {
         match fourcc {
-            b"MJPG" => PixelFormat::MJPG,
-            b"YUYV" => PixelFormat::YUYV,
-            b"RGB3" => PixelFormat::RGB3,
-            b"BGR3" => PixelFormat::BGR3,
-            b"YU12" => PixelFormat::YU12,
-            b"YV12" => PixelFormat::YV12,
-            _ => PixelFormat::YUYV,  // Default fallback
+            b"MJPG" => Some(PixelFormat::MJPG),
+            b"YUYV" => Some(PixelFormat::YUYV),
+            b"RGB3" => Some(PixelFormat::RGB3),
+            b"BGR3" => Some(PixelFormat::BGR3),
+            b"YU12" => Some(PixelFormat::YU12),
+            b"YV12" => Some(PixelFormat::YV12),
+            b"H264" => Some(PixelFormat::H264),
+            _ => None,
         }
     }
 
@@ -44,6 +81,33 @@ impl PixelFormat {
             PixelFormat::BGR3 => *b"BGR3",
             PixelFormat::YU12 => *b"YU12",
             PixelFormat::YV12 => *b"YV12",
+            PixelFormat::H264 => *b"H264",
+        }
+    }
+
+    /// Whether frames in this format carry a compressed bitstream rather than raw pixels
+    pub fn is_compressed(&self) -> bool {
+        matches!(self, PixelFormat::MJPG | PixelFormat::H264)
+    }
+
+    /// Size in bytes of one raw frame, or `None` for compressed formats
+    pub fn frame_size(&self, width: u32, height: u32) -> Option<usize> {
+        let (w, h) = (width as usize, height as usize);
+        match self {
+            PixelFormat::MJPG | PixelFormat::H264 => None,
+            PixelFormat::YUYV => Some(w * h * 2),
+            PixelFormat::RGB3 | PixelFormat::BGR3 => Some(w * h * 3),
+            PixelFormat::YU12 | PixelFormat::YV12 => Some(w * h + 2 * (w / 2) * (h / 2)),
+        }
+    }
+
+    /// Horizontal and vertical alignment that crop offsets and frame sizes must
+    /// respect so that chroma samples are not split
+    pub fn alignment(&self) -> (u32, u32) {
+        match self {
+            PixelFormat::YUYV => (2, 1),
+            PixelFormat::YU12 | PixelFormat::YV12 => (2, 2),
+            _ => (1, 1),
         }
     }
 }
@@ -62,4 +126,23 @@ pub struct FormatCapability {
 #[derive(Debug, Clone)]
 pub struct CameraCapabilities {
     pub formats: Vec<FormatCapability>,
-}
\ No newline at end of file
+}
+
+/// Errors produced when inspecting or transforming a [`Frame`].
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("Operation not supported for format {0:?}")]
+    UnsupportedFormat(PixelFormat),
+
+    #[error("Frame data is {actual} bytes, expected {expected}")]
+    InvalidDataSize { expected: usize, actual: usize },
+
+    #[error("Invalid dimensions {width}x{height} for format {format:?}")]
+    InvalidDimensions { width: u32, height: u32, format: PixelFormat },
+
+    #[error("Region {0:?} lies outside the frame")]
+    RegionOutOfBounds(Rect),
+
+    #[error("Region {rect:?} is not aligned to the chroma grid of {format:?}")]
+    MisalignedRegion { rect: Rect, format: PixelFormat },
+}
@@ -0,0 +1,436 @@
+//! Privacy masking of frame regions.
+//!
+//! Mask shapes use coordinates normalised to the frame size (`0.0..=1.0`), so
+//! the same mask covers the same part of the scene at every capture resolution.
+//! Chroma samples shared with a masked pixel are always treated as masked,
+//! which makes masks slightly conservative on subsampled YUV formats.
+
+use crate::planes::{self, Plane};
+use crate::{Color, Frame, FrameError, FrameProcessor, PixelFormat};
+
+/// Area of the frame covered by a mask.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaskShape {
+    /// Axis-aligned rectangle, as fractions of the frame width and height
+    Rect { x: f32, y: f32, width: f32, height: f32 },
+
+    /// Closed polygon (even-odd fill), vertices as fractions of the frame size
+    Polygon(Vec<(f32, f32)>),
+}
+
+/// How masked pixels are obscured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskStyle {
+    /// Replace with a solid colour
+    Fill(Color),
+
+    /// Replace with the average of `block`x`block` pixel cells
+    Pixelate { block: u32 },
+
+    /// Box blur of the given radius in pixels, applied three times.
+    ///
+    /// Weaker than the other styles: fine detail is removed but a blurred
+    /// region may still reveal shapes.
+    Blur { radius: u32 },
+}
+
+/// One masked region and the way it is obscured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivacyMask {
+    pub shape: MaskShape,
+    pub style: MaskStyle,
+}
+
+impl PrivacyMask {
+    pub fn new(shape: MaskShape, style: MaskStyle) -> Self {
+        Self { shape, style }
+    }
+}
+
+impl MaskShape {
+    /// Per-pixel coverage of this shape for a `width`x`height` frame
+    pub(crate) fn coverage(&self, width: usize, height: usize) -> Vec<bool> {
+        let mut covered = vec![false; width * height];
+        match self {
+            MaskShape::Rect { x, y, width: w, height: h } => {
+                // A negative size extends the rectangle left or up
+                let x0 = to_pixels(x.min(x + w), width);
+                let y0 = to_pixels(y.min(y + h), height);
+                let x1 = to_pixels(x.max(x + w), width);
+                let y1 = to_pixels(y.max(y + h), height);
+                for row in y0..y1 {
+                    covered[row * width + x0..row * width + x1].fill(true);
+                }
+            }
+            MaskShape::Polygon(points) => {
+                let points: Vec<(f32, f32)> = points
+                    .iter()
+                    .map(|&(px, py)| (px * width as f32, py * height as f32))
+                    .collect();
+                if points.len() < 3 {
+                    return covered;
+                }
+
+                let mut crossings = Vec::new();
+                for row in 0..height {
+                    let yc = row as f32 + 0.5;
+                    crossings.clear();
+                    for (i, &(ax, ay)) in points.iter().enumerate() {
+                        let (bx, by) = points[(i + 1) % points.len()];
+                        if (ay <= yc) != (by <= yc) {
+                            crossings.push(ax + (yc - ay) / (by - ay) * (bx - ax));
+                        }
+                    }
+                    crossings.sort_by(|a, b| a.total_cmp(b));
+
+                    // Pixels whose centre lies between each pair of crossings
+                    for pair in crossings.chunks_exact(2) {
+                        let start = (pair[0] - 0.5).ceil().clamp(0.0, width as f32) as usize;
+                        let end = (pair[1] - 0.5).ceil().clamp(0.0, width as f32) as usize;
+                        if start < end {
+                            covered[row * width + start..row * width + end].fill(true);
+                        }
+                    }
+                }
+            }
+        }
+        covered
+    }
+}
+
+fn to_pixels(fraction: f32, size: usize) -> usize {
+    (fraction * size as f32).round().clamp(0.0, size as f32) as usize
+}
+
+/// Frame processor applying a set of privacy masks, in order.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_core::{Color, MaskShape, MaskStyle, PrivacyMask, PrivacyMasker};
+///
+/// let masker = PrivacyMasker::new(vec![
+///     // Neighbour's window in the top-right corner
+///     PrivacyMask::new(
+///         MaskShape::Rect { x: 0.75, y: 0.0, width: 0.25, height: 0.3 },
+///         MaskStyle::Fill(Color::BLACK),
+///     ),
+///     // Footpath along the bottom edge
+///     PrivacyMask::new(
+///         MaskShape::Polygon(vec![(0.0, 0.8), (1.0, 0.9), (1.0, 1.0), (0.0, 1.0)]),
+///         MaskStyle::Pixelate { block: 16 },
+///     ),
+/// ]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PrivacyMasker {
+    masks: Vec<PrivacyMask>,
+}
+
+impl PrivacyMasker {
+    pub fn new(masks: Vec<PrivacyMask>) -> Self {
+        Self { masks }
+    }
+
+    pub fn masks(&self) -> &[PrivacyMask] {
+        &self.masks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masks.is_empty()
+    }
+
+    /// Apply every mask to `frame` in place.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for compressed or malformed frames. Callers must not
+    /// pass such frames on when masking is required.
+    pub fn apply(&self, frame: &mut Frame) -> Result<(), FrameError> {
+        if self.masks.is_empty() {
+            return Ok(());
+        }
+
+        let format = frame.format;
+        let (w, h) = (frame.width as usize, frame.height as usize);
+        let mut planes = planes::split(frame)?;
+
+        for mask in &self.masks {
+            let luma_coverage = mask.shape.coverage(w, h);
+            for (index, plane) in planes.iter_mut().enumerate() {
+                let (sx, sy) = planes::plane_shift(format, index);
+                let coverage = subsample_coverage(&luma_coverage, w, sx, sy, plane);
+                match mask.style {
+                    MaskStyle::Fill(color) => fill(plane, &coverage, &fill_value(format, index, color)),
+                    MaskStyle::Pixelate { block } => {
+                        let block = ((block.max(1) >> sx.max(sy)) as usize).max(1);
+                        pixelate(plane, &coverage, block);
+                    }
+                    MaskStyle::Blur { radius } => {
+                        let radius = (radius >> sx.max(sy)) as usize;
+                        blur(plane, &coverage, radius);
+                    }
+                }
+            }
+        }
+
+        frame.data = planes::join(format, planes);
+        Ok(())
+    }
+}
+
+impl FrameProcessor for PrivacyMasker {
+    fn name(&self) -> &str {
+        "privacy_mask"
+    }
+
+    fn process(&mut self, mut frame: Frame) -> Result<Option<Frame>, FrameError> {
+        self.apply(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+/// A plane sample is covered if any full-resolution pixel sharing it is.
+fn subsample_coverage(luma: &[bool], width: usize, sx: u32, sy: u32, plane: &Plane) -> Vec<bool> {
+    if sx == 0 && sy == 0 {
+        return luma.to_vec();
+    }
+    let (bw, bh) = (1 << sx, 1 << sy);
+    let mut covered = vec![false; plane.width * plane.height];
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            covered[y * plane.width + x] =
+                (0..bh).any(|dy| (0..bw).any(|dx| luma[(y * bh + dy) * width + x * bw + dx]));
+        }
+    }
+    covered
+}
+
+/// Sample values of `color` for the plane at `index` of `format`.
+fn fill_value(format: PixelFormat, index: usize, color: Color) -> Vec<u8> {
+    let (y, u, v) = color.yuv();
+    match (format, index) {
+        (PixelFormat::RGB3, _) => vec![color.r, color.g, color.b],
+        (PixelFormat::BGR3, _) => vec![color.b, color.g, color.r],
+        (_, 0) => vec![y],
+        (PixelFormat::YV12, 1) => vec![v],
+        (PixelFormat::YV12, _) => vec![u],
+        (_, 1) => vec![u],
+        _ => vec![v],
+    }
+}
+
+fn fill(plane: &mut Plane, coverage: &[bool], value: &[u8]) {
+    let c = plane.channels;
+    for (i, _) in coverage.iter().enumerate().filter(|(_, &covered)| covered) {
+        plane.data[i * c..(i + 1) * c].copy_from_slice(value);
+    }
+}
+
+fn pixelate(plane: &mut Plane, coverage: &[bool], block: usize) {
+    let c = plane.channels;
+    let (w, h) = (plane.width, plane.height);
+
+    for by in (0..h).step_by(block) {
+        for bx in (0..w).step_by(block) {
+            let rows = by..(by + block).min(h);
+            let cols = bx..(bx + block).min(w);
+            if !rows.clone().any(|y| cols.clone().any(|x| coverage[y * w + x])) {
+                continue;
+            }
+
+            // Average over the whole cell so the result does not depend on the mask edge
+            let count = rows.len() * cols.len();
+            let mut sums = vec![0usize; c];
+            for y in rows.clone() {
+                for x in cols.clone() {
+                    for (ch, sum) in sums.iter_mut().enumerate() {
+                        *sum += plane.data[(y * w + x) * c + ch] as usize;
+                    }
+                }
+            }
+            let average: Vec<u8> = sums.iter().map(|sum| ((sum + count / 2) / count) as u8).collect();
+
+            for y in rows.clone() {
+                for x in cols.clone() {
+                    if coverage[y * w + x] {
+                        plane.data[(y * w + x) * c..(y * w + x + 1) * c].copy_from_slice(&average);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn blur(plane: &mut Plane, coverage: &[bool], radius: usize) {
+    if radius == 0 {
+        return;
+    }
+    let c = plane.channels;
+    let (w, h) = (plane.width, plane.height);
+
+    // Three box passes approximate a Gaussian; each pass is separable
+    let mut blurred = plane.data.clone();
+    for _ in 0..3 {
+        blurred = box_pass(&blurred, w, h, c, radius, true);
+        blurred = box_pass(&blurred, w, h, c, radius, false);
+    }
+
+    for (i, _) in coverage.iter().enumerate().filter(|(_, &covered)| covered) {
+        plane.data[i * c..(i + 1) * c].copy_from_slice(&blurred[i * c..(i + 1) * c]);
+    }
+}
+
+fn box_pass(data: &[u8], w: usize, h: usize, c: usize, radius: usize, horizontal: bool) -> Vec<u8> {
+    let mut out = vec![0u8; data.len()];
+    let (lines, len) = if horizontal { (h, w) } else { (w, h) };
+    let index = |line: usize, pos: usize| if horizontal { line * w + pos } else { pos * w + line };
+
+    for line in 0..lines {
+        for ch in 0..c {
+            // Running sum over a window clamped at the plane edges
+            let sample = |pos: isize| data[index(line, pos.clamp(0, len as isize - 1) as usize) * c + ch] as u32;
+            let r = radius as isize;
+            let window = (2 * radius + 1) as u32;
+            let mut sum: u32 = (-r..=r).map(sample).sum();
+            for pos in 0..len {
+                out[index(line, pos) * c + ch] = ((sum + window / 2) / window) as u8;
+                let p = pos as isize;
+                sum = sum + sample(p + r + 1) - sample(p - r);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn frame(format: PixelFormat, width: u32, height: u32) -> Frame {
+        let size = format.frame_size(width, height).unwrap();
+        Frame {
+            format,
+            width,
+            height,
+            timestamp: SystemTime::UNIX_EPOCH,
+            sequence: 0,
+            data: (0..size).map(|i| (i * 37 % 256) as u8).collect(),
+            metadata: Default::default(),
+        }
+    }
+
+    fn left_half() -> MaskShape {
+        MaskShape::Rect { x: 0.0, y: 0.0, width: 0.5, height: 1.0 }
+    }
+
+    #[test]
+    fn fill_covers_rect_only() {
+        let mut input = frame(PixelFormat::RGB3, 8, 4);
+        let original = input.data.clone();
+        PrivacyMasker::new(vec![PrivacyMask::new(left_half(), MaskStyle::Fill(Color::rgb(1, 2, 3)))])
+            .apply(&mut input)
+            .unwrap();
+
+        for y in 0..4 {
+            for x in 0..8 {
+                let px = &input.data[(y * 8 + x) * 3..(y * 8 + x) * 3 + 3];
+                if x < 4 {
+                    assert_eq!(px, &[1, 2, 3]);
+                } else {
+                    assert_eq!(px, &original[(y * 8 + x) * 3..(y * 8 + x) * 3 + 3]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_masks_every_raw_format() {
+        for format in [PixelFormat::YUYV, PixelFormat::RGB3, PixelFormat::BGR3, PixelFormat::YU12, PixelFormat::YV12] {
+            let mut masked = frame(format, 16, 8);
+            PrivacyMasker::new(vec![PrivacyMask::new(left_half(), MaskStyle::Fill(Color::BLACK))])
+                .apply(&mut masked)
+                .unwrap();
+
+            // Every pixel of the left half must decode to black
+            let rgb = crate::convert::convert(&masked, PixelFormat::RGB3).unwrap();
+            for y in 0..8 {
+                for x in 0..8 {
+                    let px = &rgb.data[(y * 16 + x) * 3..(y * 16 + x) * 3 + 3];
+                    assert!(px.iter().all(|&v| v == 0), "{:?} at {},{}: {:?}", format, x, y, px);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn polygon_coverage_uses_pixel_centres() {
+        let triangle = PrivacyMask::new(
+            MaskShape::Polygon(vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]),
+            MaskStyle::Fill(Color::BLACK),
+        );
+        let covered = triangle.shape.coverage(4, 4);
+        let rows: Vec<usize> = covered.chunks(4).map(|row| row.iter().filter(|&&c| c).count()).collect();
+        assert_eq!(rows, vec![3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn rect_coverage_accepts_any_size() {
+        let flipped = MaskShape::Rect { x: 0.75, y: 0.5, width: -0.5, height: -0.5 };
+        let rect = MaskShape::Rect { x: 0.25, y: 0.0, width: 0.5, height: 0.5 };
+        assert_eq!(flipped.coverage(4, 4), rect.coverage(4, 4));
+
+        for (x, w) in [(2.0, -5.0), (-3.0, 1.0), (f32::MAX, f32::MAX), (0.5, f32::NAN)] {
+            let shape = MaskShape::Rect { x, y: 0.0, width: w, height: 1.0 };
+            assert_eq!(shape.coverage(4, 4).len(), 16);
+        }
+    }
+
+    #[test]
+    fn pixelate_produces_uniform_cells() {
+        let mut input = frame(PixelFormat::RGB3, 8, 8);
+        PrivacyMasker::new(vec![PrivacyMask::new(
+            MaskShape::Rect { x: 0.0, y: 0.0, width: 1.0, height: 1.0 },
+            MaskStyle::Pixelate { block: 4 },
+        )])
+        .apply(&mut input)
+        .unwrap();
+
+        let first = &input.data[0..3].to_vec();
+        for y in 0..4 {
+            for x in 0..4 {
+                assert_eq!(&input.data[(y * 8 + x) * 3..(y * 8 + x) * 3 + 3], first.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn blur_smooths_and_keeps_uniform_regions() {
+        let mut uniform = Frame {
+            data: vec![77; 32 * 16 * 2],
+            ..frame(PixelFormat::YUYV, 32, 16)
+        };
+        let masker = PrivacyMasker::new(vec![PrivacyMask::new(left_half(), MaskStyle::Blur { radius: 3 })]);
+        masker.apply(&mut uniform).unwrap();
+        assert!(uniform.data.iter().all(|&v| v == 77));
+
+        let mut noisy = frame(PixelFormat::YU12, 32, 16);
+        let before = noisy.data.clone();
+        masker.apply(&mut noisy).unwrap();
+        let variation = |data: &[u8]| data[..16].windows(2).map(|p| (p[0] as i32 - p[1] as i32).abs()).sum::<i32>();
+        assert!(variation(&noisy.data) < variation(&before) / 4);
+        assert_eq!(&noisy.data[16..32], &before[16..32]);
+    }
+
+    #[test]
+    fn compressed_frames_fail_closed() {
+        let mut input = Frame {
+            format: PixelFormat::MJPG,
+            data: vec![0xFF, 0xD8],
+            ..frame(PixelFormat::RGB3, 2, 2)
+        };
+        let masker = PrivacyMasker::new(vec![PrivacyMask::new(left_half(), MaskStyle::Fill(Color::BLACK))]);
+        assert!(matches!(masker.apply(&mut input), Err(FrameError::UnsupportedFormat(PixelFormat::MJPG))));
+    }
+}
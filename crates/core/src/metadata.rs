@@ -0,0 +1,389 @@
+//! Per-frame metadata that travels with the frame through encoding.
+//!
+//! Anything known about a frame beyond its pixels, such as motion boxes, GPS
+//! position or exposure settings, goes into its [`Metadata`] map under a
+//! string key. Encoders copy the map into their packets and H.264 embeds it
+//! in the bitstream, so it survives recording and transport; decoders put
+//! it back on the frames they produce.
+//!
+//! [`Metadata::to_bytes`] defines the binary form used everywhere the map is
+//! serialized:
+//!
+//! ```text
+//! version: u8 (1)
+//! count:   u16
+//! count times:
+//!     key:   u8 length, UTF-8
+//!     type:  u8
+//!     value: bool    u8
+//!            int     i64
+//!            float   f64
+//!            text    u32 length, UTF-8
+//!            bytes   u32 length, raw
+//!            rects   u16 count, then x, y, width, height as u32 each
+//!            floats  u16 count, then f64 each
+//! ```
+//!
+//! Integers are big-endian. Readers reject unknown types rather than skip
+//! them, so new types need a new version.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_core::metadata::{keys, Metadata, MetadataValue};
+//! use streaming_core::Rect;
+//!
+//! let mut metadata = Metadata::new();
+//! metadata.insert(keys::MOTION, vec![Rect::new(10, 20, 30, 40)]);
+//! metadata.insert(keys::GPS, vec![52.52, 13.405]);
+//! metadata.insert(keys::EXPOSURE_US, 8000i64);
+//!
+//! let restored = Metadata::from_bytes(&metadata.to_bytes())?;
+//! assert_eq!(restored, metadata);
+//! assert_eq!(restored.get(keys::EXPOSURE_US), Some(&MetadataValue::Int(8000)));
+//! # Ok::<(), streaming_core::metadata::MetadataError>(())
+//! ```
+
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::Rect;
+
+/// Keys for commonly attached data.
+pub mod keys {
+    /// Boxes around moving objects, as [`Rects`](super::MetadataValue::Rects)
+    pub const MOTION: &str = "motion";
+
+    /// Latitude, longitude and optionally altitude in metres, as
+    /// [`Floats`](super::MetadataValue::Floats)
+    pub const GPS: &str = "gps";
+
+    /// Exposure time in microseconds, as [`Int`](super::MetadataValue::Int)
+    pub const EXPOSURE_US: &str = "exposure_us";
+
+    /// Analogue gain in dB, as [`Float`](super::MetadataValue::Float)
+    pub const GAIN_DB: &str = "gain_db";
+}
+
+const VERSION: u8 = 1;
+
+const TYPE_BOOL: u8 = 0;
+const TYPE_INT: u8 = 1;
+const TYPE_FLOAT: u8 = 2;
+const TYPE_TEXT: u8 = 3;
+const TYPE_BYTES: u8 = 4;
+const TYPE_RECTS: u8 = 5;
+const TYPE_FLOATS: u8 = 6;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MetadataError {
+    #[error("Metadata ends in the middle of an entry")]
+    Truncated,
+
+    #[error("Unsupported metadata version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Unknown metadata value type {0}")]
+    UnknownType(u8),
+
+    #[error("Metadata text is not valid UTF-8")]
+    InvalidUtf8,
+
+    #[error("Metadata has {0} unused bytes at the end")]
+    TrailingBytes(usize),
+}
+
+/// A value in a [`Metadata`] map.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Rects(Vec<Rect>),
+    Floats(Vec<f64>),
+}
+
+macro_rules! from_value {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(impl From<$ty> for MetadataValue {
+            fn from(value: $ty) -> Self {
+                MetadataValue::$variant(value.into())
+            }
+        })*
+    };
+}
+
+from_value! {
+    bool => Bool,
+    i64 => Int,
+    f64 => Float,
+    String => Text,
+    &str => Text,
+    Vec<u8> => Bytes,
+    Vec<Rect> => Rects,
+    Vec<f64> => Floats,
+}
+
+/// Key-value data attached to a frame, ordered by key.
+///
+/// Keys are at most 255 bytes long; longer ones are cut at a character
+/// boundary when inserted. The map as a whole must stay under 64 Ki entries,
+/// with text and byte values under 4 GiB and lists under 64 Ki elements;
+/// [`Metadata::to_bytes`] truncates anything larger.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    entries: BTreeMap<String, MetadataValue>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Set `key` to `value`, returning the value it replaces
+    pub fn insert(&mut self, key: &str, value: impl Into<MetadataValue>) -> Option<MetadataValue> {
+        let mut end = key.len().min(u8::MAX as usize);
+        while !key.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.entries.insert(key[..end].to_string(), value.into())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.entries.get(key)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<MetadataValue> {
+        self.entries.remove(key)
+    }
+
+    /// Entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MetadataValue)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value))
+    }
+
+    /// Add every entry of `other`, replacing entries with the same key
+    pub fn extend(&mut self, other: &Metadata) {
+        self.entries.extend(other.entries.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+
+    /// Serialize in the format described in the [module docs](self)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![VERSION];
+        let count = self.entries.len().min(u16::MAX as usize);
+        out.extend_from_slice(&(count as u16).to_be_bytes());
+        for (key, value) in self.entries.iter().take(count) {
+            out.push(key.len() as u8);
+            out.extend_from_slice(key.as_bytes());
+            write_value(&mut out, value);
+        }
+        out
+    }
+
+    /// Parse data written by [`Metadata::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// [`MetadataError`] for truncated or malformed data, unknown versions
+    /// and value types, and bytes left over after the last entry.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MetadataError> {
+        let mut reader = Reader(data);
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(MetadataError::UnsupportedVersion(version));
+        }
+        let mut metadata = Metadata::new();
+        for _ in 0..reader.u16()? {
+            let len = reader.u8()? as usize;
+            let key = reader.text(len)?;
+            let value = read_value(&mut reader)?;
+            metadata.entries.insert(key, value);
+        }
+        match reader.0.len() {
+            0 => Ok(metadata),
+            extra => Err(MetadataError::TrailingBytes(extra)),
+        }
+    }
+}
+
+impl<K: AsRef<str>, V: Into<MetadataValue>> FromIterator<(K, V)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        let mut metadata = Metadata::new();
+        for (key, value) in entries {
+            metadata.insert(key.as_ref(), value);
+        }
+        metadata
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &MetadataValue) {
+    let list_len = |len: usize| len.min(u16::MAX as usize);
+    match value {
+        MetadataValue::Bool(value) => {
+            out.extend_from_slice(&[TYPE_BOOL, *value as u8]);
+        }
+        MetadataValue::Int(value) => {
+            out.push(TYPE_INT);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        MetadataValue::Float(value) => {
+            out.push(TYPE_FLOAT);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        MetadataValue::Text(text) => {
+            // Cut at a character boundary so the text stays valid
+            let mut len = text.len().min(u32::MAX as usize);
+            while !text.is_char_boundary(len) {
+                len -= 1;
+            }
+            out.push(TYPE_TEXT);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+            out.extend_from_slice(&text.as_bytes()[..len]);
+        }
+        MetadataValue::Bytes(bytes) => {
+            let len = bytes.len().min(u32::MAX as usize);
+            out.push(TYPE_BYTES);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+            out.extend_from_slice(&bytes[..len]);
+        }
+        MetadataValue::Rects(rects) => {
+            let len = list_len(rects.len());
+            out.push(TYPE_RECTS);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+            for rect in &rects[..len] {
+                for field in [rect.x, rect.y, rect.width, rect.height] {
+                    out.extend_from_slice(&field.to_be_bytes());
+                }
+            }
+        }
+        MetadataValue::Floats(values) => {
+            let len = list_len(values.len());
+            out.push(TYPE_FLOATS);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+            for value in &values[..len] {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+}
+
+fn read_value(reader: &mut Reader<'_>) -> Result<MetadataValue, MetadataError> {
+    Ok(match reader.u8()? {
+        TYPE_BOOL => MetadataValue::Bool(reader.u8()? != 0),
+        TYPE_INT => MetadataValue::Int(i64::from_be_bytes(reader.array()?)),
+        TYPE_FLOAT => MetadataValue::Float(f64::from_be_bytes(reader.array()?)),
+        TYPE_TEXT => {
+            let len = reader.u32()? as usize;
+            MetadataValue::Text(reader.text(len)?)
+        }
+        TYPE_BYTES => {
+            let len = reader.u32()? as usize;
+            MetadataValue::Bytes(reader.take(len)?.to_vec())
+        }
+        TYPE_RECTS => {
+            let len = reader.u16()? as usize;
+            let rects = (0..len)
+                .map(|_| Ok(Rect::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?)))
+                .collect::<Result<_, MetadataError>>()?;
+            MetadataValue::Rects(rects)
+        }
+        TYPE_FLOATS => {
+            let len = reader.u16()? as usize;
+            let values = (0..len)
+                .map(|_| Ok(f64::from_be_bytes(reader.array()?)))
+                .collect::<Result<_, MetadataError>>()?;
+            MetadataValue::Floats(values)
+        }
+        other => return Err(MetadataError::UnknownType(other)),
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MetadataError> {
+        if self.0.len() < len {
+            return Err(MetadataError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], MetadataError> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, MetadataError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MetadataError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, MetadataError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn text(&mut self, len: usize) -> Result<String, MetadataError> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| MetadataError::InvalidUtf8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.insert("armed", true);
+        metadata.insert("count", -3i64);
+        metadata.insert("ratio", 0.25);
+        metadata.insert("camera", "gate ✓");
+        metadata.insert("blob", vec![0u8, 1, 255]);
+        metadata.insert(keys::MOTION, vec![Rect::new(1, 2, 3, 4), Rect::new(u32::MAX, 0, 0, 7)]);
+        metadata.insert(keys::GPS, vec![-33.86, 151.21, 58.0]);
+        metadata
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let metadata = sample();
+        assert_eq!(Metadata::from_bytes(&metadata.to_bytes()), Ok(metadata.clone()));
+        assert_eq!(Metadata::from_bytes(&Metadata::new().to_bytes()), Ok(Metadata::new()));
+
+        // Overlong keys are cut to 255 bytes without splitting a character
+        let mut long = Metadata::new();
+        long.insert(&"é".repeat(200), 1i64);
+        let (key, _) = long.iter().next().unwrap();
+        assert_eq!(key.len(), 254);
+        assert_eq!(Metadata::from_bytes(&long.to_bytes()), Ok(long.clone()));
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let bytes = sample().to_bytes();
+        // Every proper prefix is truncated somewhere
+        for len in 0..bytes.len() {
+            assert_eq!(Metadata::from_bytes(&bytes[..len]), Err(MetadataError::Truncated), "{}", len);
+        }
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(Metadata::from_bytes(&extra), Err(MetadataError::TrailingBytes(1)));
+        assert_eq!(Metadata::from_bytes(&[2, 0, 0]), Err(MetadataError::UnsupportedVersion(2)));
+        assert_eq!(Metadata::from_bytes(&[1, 0, 1, 1, b'k', 9]), Err(MetadataError::UnknownType(9)));
+        assert_eq!(Metadata::from_bytes(&[1, 0, 1, 1, 0xff, 0, 1]), Err(MetadataError::InvalidUtf8));
+    }
+}
@@ -0,0 +1,520 @@
+//! Motion detection on the luma channel of captured frames.
+//!
+//! Each frame's luma is averaged down to a coarse analysis grid and compared
+//! against a running-average background model. A cell counts as moving when it
+//! differs from the background by more than the sensitivity threshold and has
+//! also changed since the previous frame, so objects that stop are no longer
+//! reported while the background slowly absorbs them. A zone is in motion while
+//! the moving fraction of its cells reaches `min_area`; motion ends once a zone
+//! has been quiet for the cooldown period.
+
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+use crate::metadata::keys;
+use crate::{planes, Frame, FrameError, FrameProcessor, MaskShape, Rect};
+
+/// A named region of the frame in which motion is tracked independently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionZone {
+    pub name: String,
+    pub shape: MaskShape,
+}
+
+impl MotionZone {
+    pub fn new(name: &str, shape: MaskShape) -> Self {
+        Self {
+            name: name.to_string(),
+            shape,
+        }
+    }
+}
+
+/// Tuning parameters for [`MotionDetector`].
+#[derive(Debug, Clone)]
+pub struct MotionConfig {
+    /// 0.0 (only large brightness changes count) to 1.0 (tiny changes count)
+    pub sensitivity: f32,
+
+    /// Fraction of a zone's area that must change to count as motion
+    pub min_area: f32,
+
+    /// How quickly the background absorbs the current frame (0.0 - 1.0)
+    pub learning_rate: f32,
+
+    /// Size in pixels of the square cells luma is averaged over before analysis
+    pub cell_size: u32,
+
+    /// Quiet time required before a zone reports `MotionEnded`
+    pub cooldown: Duration,
+
+    /// Zones tracked separately. When empty the whole frame is one zone named "frame".
+    pub zones: Vec<MotionZone>,
+
+    /// Regions ignored in every zone (trees, busy roads, timestamps)
+    pub ignore: Vec<MaskShape>,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            sensitivity: 0.5,
+            min_area: 0.01,
+            learning_rate: 0.05,
+            cell_size: 8,
+            cooldown: Duration::from_secs(2),
+            zones: Vec::new(),
+            ignore: Vec::new(),
+        }
+    }
+}
+
+impl MotionConfig {
+    /// Luma difference a cell needs to count as changed
+    fn threshold(&self) -> f32 {
+        4.0 + (1.0 - self.sensitivity.clamp(0.0, 1.0)) * 60.0
+    }
+}
+
+/// Start or end of motion in a zone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MotionEvent {
+    MotionStarted {
+        zone: String,
+        timestamp: SystemTime,
+        sequence: usize,
+
+        /// Bounding box of the changed area in the triggering frame
+        bounding_box: Rect,
+
+        /// Changed fraction of the zone in the triggering frame
+        score: f32,
+    },
+
+    MotionEnded {
+        zone: String,
+        timestamp: SystemTime,
+        sequence: usize,
+
+        /// Union of the changed areas over the whole event
+        bounding_box: Rect,
+
+        /// Highest score seen during the event
+        peak_score: f32,
+
+        /// Time from the start of motion to the last frame with motion
+        duration: Duration,
+    },
+}
+
+struct ActiveMotion {
+    started: SystemTime,
+    last_motion: SystemTime,
+    bounds: Rect,
+    peak_score: f32,
+}
+
+struct ZoneState {
+    name: String,
+    cells: Vec<bool>,
+    cell_count: usize,
+    active: Option<ActiveMotion>,
+    last_score: f32,
+}
+
+/// Background-subtraction motion detector.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_core::{MotionConfig, MotionDetector, MotionEvent, PixelFormat, SyntheticSource};
+///
+/// let mut detector = MotionDetector::new(MotionConfig::default());
+/// let mut source = SyntheticSource::new(PixelFormat::YUYV, 320, 240).velocity(6, 3);
+///
+/// for frame in source.by_ref().take(10) {
+///     for event in detector.analyze(&frame)? {
+///         if let MotionEvent::MotionStarted { bounding_box, score, .. } = event {
+///             println!("motion at {:?} ({:.1}%)", bounding_box, score * 100.0);
+///         }
+///     }
+/// }
+/// # Ok::<(), streaming_core::FrameError>(())
+/// ```
+pub struct MotionDetector {
+    config: MotionConfig,
+    background: Vec<f32>,
+    previous: Vec<f32>,
+    grid: (usize, usize),
+    frame_size: (u32, u32),
+    zones: Vec<ZoneState>,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        Self {
+            config,
+            background: Vec::new(),
+            previous: Vec::new(),
+            grid: (0, 0),
+            frame_size: (0, 0),
+            zones: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &MotionConfig {
+        &self.config
+    }
+
+    /// Whether any zone is currently in motion
+    pub fn in_motion(&self) -> bool {
+        self.zones.iter().any(|zone| zone.active.is_some())
+    }
+
+    /// Bounding boxes of the motion events in progress
+    pub fn active_regions(&self) -> Vec<Rect> {
+        self.zones.iter().filter_map(|zone| zone.active.as_ref().map(|active| active.bounds)).collect()
+    }
+
+    /// Changed fraction of each zone in the most recent frame
+    pub fn scores(&self) -> Vec<(String, f32)> {
+        self.zones.iter().map(|zone| (zone.name.clone(), zone.last_score)).collect()
+    }
+
+    /// Feed one frame and return the motion events it triggers.
+    ///
+    /// The first frame (and the first after a resolution change) only
+    /// initialises the background model.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for compressed or malformed frames.
+    pub fn analyze(&mut self, frame: &Frame) -> Result<Vec<MotionEvent>, FrameError> {
+        let luma = planes::luma(frame)?;
+        let cells = self.cells(&luma);
+
+        if self.frame_size != (frame.width, frame.height) {
+            self.reset(frame.width, frame.height, cells);
+            return Ok(Vec::new());
+        }
+
+        let threshold = self.config.threshold();
+        let rate = self.config.learning_rate.clamp(0.0, 1.0);
+        let changed: Vec<bool> = cells
+            .iter()
+            .zip(&self.previous)
+            .zip(self.background.iter_mut())
+            .map(|((&value, &previous), background)| {
+                let foreground = (value - *background).abs() > threshold;
+                let moving = foreground && (value - previous).abs() > threshold / 2.0;
+                // Moving objects must not smear into the model; still ones (a parked car) settle in
+                let alpha = if moving { rate * 0.1 } else { rate };
+                *background += alpha * (value - *background);
+                moving
+            })
+            .collect();
+        self.previous = cells;
+
+        let (cell_size, grid_w) = (self.config.cell_size.max(1), self.grid.0);
+        let (frame_w, frame_h) = self.frame_size;
+        let mut events = Vec::new();
+
+        for zone in &mut self.zones {
+            let mut count = 0;
+            let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+            for (i, _) in changed.iter().zip(&zone.cells).enumerate().filter(|(_, (&c, &z))| c && z) {
+                let (cx, cy) = (i % grid_w, i / grid_w);
+                count += 1;
+                x0 = x0.min(cx);
+                y0 = y0.min(cy);
+                x1 = x1.max(cx + 1);
+                y1 = y1.max(cy + 1);
+            }
+
+            let score = if zone.cell_count == 0 { 0.0 } else { count as f32 / zone.cell_count as f32 };
+            zone.last_score = score;
+
+            if count > 0 && score >= self.config.min_area {
+                let to_px = |cell: usize, limit: u32| ((cell as u32) * cell_size).min(limit);
+                let bounds = Rect::new(
+                    to_px(x0, frame_w),
+                    to_px(y0, frame_h),
+                    to_px(x1, frame_w) - to_px(x0, frame_w),
+                    to_px(y1, frame_h) - to_px(y0, frame_h),
+                );
+
+                match &mut zone.active {
+                    Some(active) => {
+                        active.last_motion = frame.timestamp;
+                        active.bounds = union(active.bounds, bounds);
+                        active.peak_score = active.peak_score.max(score);
+                    }
+                    None => {
+                        zone.active = Some(ActiveMotion {
+                            started: frame.timestamp,
+                            last_motion: frame.timestamp,
+                            bounds,
+                            peak_score: score,
+                        });
+                        events.push(MotionEvent::MotionStarted {
+                            zone: zone.name.clone(),
+                            timestamp: frame.timestamp,
+                            sequence: frame.sequence,
+                            bounding_box: bounds,
+                            score,
+                        });
+                    }
+                }
+            } else if let Some(active) = &zone.active {
+                let quiet = frame.timestamp.duration_since(active.last_motion).unwrap_or_default();
+                if quiet >= self.config.cooldown {
+                    events.push(MotionEvent::MotionEnded {
+                        zone: zone.name.clone(),
+                        timestamp: frame.timestamp,
+                        sequence: frame.sequence,
+                        bounding_box: active.bounds,
+                        peak_score: active.peak_score,
+                        duration: active.last_motion.duration_since(active.started).unwrap_or_default(),
+                    });
+                    zone.active = None;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Average luma over each analysis cell
+    fn cells(&self, luma: &planes::Plane) -> Vec<f32> {
+        let cell = self.config.cell_size.max(1) as usize;
+        let (gw, gh) = (luma.width.div_ceil(cell), luma.height.div_ceil(cell));
+        let mut sums = vec![0u32; gw * gh];
+        let mut counts = vec![0u32; gw * gh];
+        for y in 0..luma.height {
+            let row = &luma.data[y * luma.width..(y + 1) * luma.width];
+            for (x, &value) in row.iter().enumerate() {
+                let i = (y / cell) * gw + x / cell;
+                sums[i] += value as u32;
+                counts[i] += 1;
+            }
+        }
+        sums.iter().zip(&counts).map(|(&s, &c)| s as f32 / c as f32).collect()
+    }
+
+    fn reset(&mut self, width: u32, height: u32, cells: Vec<f32>) {
+        let cell = self.config.cell_size.max(1) as usize;
+        let grid = ((width as usize).div_ceil(cell), (height as usize).div_ceil(cell));
+        let (gw, gh) = grid;
+
+        // Zone and ignore shapes are rasterised at cell resolution
+        let ignored: Vec<bool> = self.config.ignore.iter().fold(vec![false; gw * gh], |mut acc, shape| {
+            for (a, c) in acc.iter_mut().zip(shape.coverage(gw, gh)) {
+                *a |= c;
+            }
+            acc
+        });
+
+        let whole = MotionZone::new("frame", MaskShape::Rect { x: 0.0, y: 0.0, width: 1.0, height: 1.0 });
+        let zones = if self.config.zones.is_empty() { vec![whole] } else { self.config.zones.clone() };
+
+        self.zones = zones
+            .into_iter()
+            .map(|zone| {
+                let cells: Vec<bool> = zone
+                    .shape
+                    .coverage(gw, gh)
+                    .into_iter()
+                    .zip(&ignored)
+                    .map(|(inside, &ignore)| inside && !ignore)
+                    .collect();
+                let cell_count = cells.iter().filter(|&&c| c).count();
+                ZoneState {
+                    name: zone.name,
+                    cells,
+                    cell_count,
+                    active: None,
+                    last_score: 0.0,
+                }
+            })
+            .collect();
+
+        self.background = cells.clone();
+        self.previous = cells;
+        self.grid = grid;
+        self.frame_size = (width, height);
+    }
+}
+
+fn union(a: Rect, b: Rect) -> Rect {
+    let x0 = a.x.min(b.x);
+    let y0 = a.y.min(b.y);
+    let x1 = (a.x + a.width).max(b.x + b.width);
+    let y1 = (a.y + a.height).max(b.y + b.height);
+    Rect::new(x0, y0, x1 - x0, y1 - y0)
+}
+
+/// Pipeline stage running a [`MotionDetector`] and passing frames through.
+///
+/// Events are sent to the channel given to [`MotionStage::new`]; a closed
+/// channel does not stop frames from flowing. While motion is in progress,
+/// frames carry its bounding boxes under [`keys::MOTION`] in their metadata.
+pub struct MotionStage {
+    detector: MotionDetector,
+    events: Sender<MotionEvent>,
+}
+
+impl MotionStage {
+    pub fn new(detector: MotionDetector, events: Sender<MotionEvent>) -> Self {
+        Self { detector, events }
+    }
+}
+
+impl FrameProcessor for MotionStage {
+    fn name(&self) -> &str {
+        "motion"
+    }
+
+    fn process(&mut self, mut frame: Frame) -> Result<Option<Frame>, FrameError> {
+        for event in self.detector.analyze(&frame)? {
+            let _ = self.events.send(event);
+        }
+        let regions = self.detector.active_regions();
+        if !regions.is_empty() {
+            frame.metadata.insert(keys::MOTION, regions);
+        }
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MetadataValue, PixelFormat, SyntheticSource};
+    use std::time::UNIX_EPOCH;
+
+    fn source(format: PixelFormat) -> SyntheticSource {
+        SyntheticSource::new(format, 320, 240)
+            .fps(10)
+            .start_time(UNIX_EPOCH)
+            .box_size(40)
+            .position(20, 20)
+    }
+
+    fn config() -> MotionConfig {
+        MotionConfig {
+            cooldown: Duration::from_millis(500),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn static_scene_produces_no_events() {
+        let mut detector = MotionDetector::new(config());
+        for frame in source(PixelFormat::YU12).take(30) {
+            assert!(detector.analyze(&frame).unwrap().is_empty());
+        }
+        assert!(!detector.in_motion());
+    }
+
+    #[test]
+    fn moving_box_starts_and_ends_motion() {
+        for format in [PixelFormat::YUYV, PixelFormat::YU12, PixelFormat::YV12] {
+            let mut detector = MotionDetector::new(config());
+            let mut source = source(format);
+            let mut events = Vec::new();
+
+            // Settle, move for a second, then stay still for two seconds
+            for _ in 0..5 {
+                events.extend(detector.analyze(&source.next_frame()).unwrap());
+            }
+            source.set_velocity(8, 4);
+            for _ in 0..10 {
+                events.extend(detector.analyze(&source.next_frame()).unwrap());
+            }
+            source.set_velocity(0, 0);
+            for _ in 0..20 {
+                events.extend(detector.analyze(&source.next_frame()).unwrap());
+            }
+
+            assert_eq!(events.len(), 2, "{:?}: {:?}", format, events);
+            match &events[0] {
+                MotionEvent::MotionStarted { zone, bounding_box, score, .. } => {
+                    assert_eq!(zone, "frame");
+                    assert!(*score > 0.01);
+                    // Box started at (20, 20) and is 40 pixels wide
+                    assert!(bounding_box.x <= 24 && bounding_box.x + bounding_box.width >= 60);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+            match &events[1] {
+                MotionEvent::MotionEnded { duration, bounding_box, .. } => {
+                    assert!(*duration >= Duration::from_millis(500));
+                    assert!(bounding_box.width >= 100);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn zones_and_ignore_regions_filter_motion() {
+        let left = MotionZone::new("left", MaskShape::Rect { x: 0.0, y: 0.0, width: 0.5, height: 1.0 });
+        let right = MotionZone::new("right", MaskShape::Rect { x: 0.5, y: 0.0, width: 0.5, height: 1.0 });
+
+        let mut zoned = MotionDetector::new(MotionConfig {
+            zones: vec![left, right],
+            ..config()
+        });
+        let mut ignoring = MotionDetector::new(MotionConfig {
+            ignore: vec![MaskShape::Rect { x: 0.0, y: 0.0, width: 0.5, height: 1.0 }],
+            ..config()
+        });
+
+        // Box moves vertically within the left half only
+        let mut source = source(PixelFormat::YUYV);
+        let frames: Vec<Frame> = (0..3).map(|_| source.next_frame()).collect();
+        source.set_velocity(0, 8);
+        let moving: Vec<Frame> = (0..10).map(|_| source.next_frame()).collect();
+
+        let mut zoned_events = Vec::new();
+        let mut ignored_events = Vec::new();
+        for frame in frames.iter().chain(&moving) {
+            zoned_events.extend(zoned.analyze(frame).unwrap());
+            ignored_events.extend(ignoring.analyze(frame).unwrap());
+        }
+
+        assert_eq!(zoned_events.len(), 1);
+        assert!(matches!(&zoned_events[0], MotionEvent::MotionStarted { zone, .. } if zone == "left"));
+        assert!(ignored_events.is_empty());
+    }
+
+    #[test]
+    fn stage_forwards_frames_and_events() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut stage = MotionStage::new(MotionDetector::new(config()), tx);
+        let mut source = source(PixelFormat::YU12).velocity(10, 0);
+        let first = stage.process(source.next_frame()).unwrap().unwrap();
+        assert!(first.metadata.is_empty());
+        let mut last = first;
+        for _ in 0..4 {
+            last = stage.process(source.next_frame()).unwrap().unwrap();
+        }
+        assert!(matches!(rx.try_recv(), Ok(MotionEvent::MotionStarted { .. })));
+        // Frames in motion carry the moving area
+        assert!(matches!(last.metadata.get(keys::MOTION), Some(MetadataValue::Rects(rects)) if rects.len() == 1));
+    }
+
+    #[test]
+    fn compressed_frames_are_rejected() {
+        let mut detector = MotionDetector::new(config());
+        let frame = Frame {
+            format: PixelFormat::MJPG,
+            width: 2,
+            height: 2,
+            timestamp: UNIX_EPOCH,
+            sequence: 1,
+            data: vec![0xFF, 0xD8],
+            metadata: Default::default(),
+        };
+        assert!(detector.analyze(&frame).is_err());
+    }
+}
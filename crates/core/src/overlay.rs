@@ -0,0 +1,320 @@
+//! Burning text such as capture time, device name and labels into frames.
+//!
+//! Text is rendered with the embedded 8x8 bitmap font, so no font files or
+//! system libraries are needed on the device.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::draw::{self, Color};
+use crate::font::{self, GLYPH_SIZE};
+use crate::{planes, Frame, FrameError, FrameProcessor};
+
+/// Where an overlay is placed on the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Position {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+
+    /// Top-left corner of the text block at an absolute pixel position
+    At { x: u32, y: u32 },
+}
+
+/// Text overlay burned into every frame it processes.
+///
+/// The template may span several lines and can contain these placeholders:
+///
+/// - `{time}`: capture time from [`Frame::timestamp`] as UTC, e.g. `2024-05-01 12:30:05.250Z`
+/// - `{unix}`: capture time in whole seconds since the Unix epoch
+/// - `{seq}`: [`Frame::sequence`]
+/// - `{device}`: the device name set with [`TextOverlay::device`]
+/// - `{label}`: the custom label set with [`TextOverlay::label`]
+///
+/// # Examples
+///
+/// ```
+/// use streaming_core::{Position, TextOverlay};
+///
+/// let overlay = TextOverlay::new("{device} {time}\n{label} #{seq}")
+///     .device("/dev/video0")
+///     .label("Front door")
+///     .position(Position::BottomLeft)
+///     .scale(2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TextOverlay {
+    template: String,
+    device: String,
+    label: String,
+    position: Position,
+    scale: u32,
+    margin: u32,
+    foreground: Color,
+    background: Option<Color>,
+}
+
+impl TextOverlay {
+    pub fn new(template: &str) -> Self {
+        Self {
+            template: template.to_string(),
+            device: String::new(),
+            label: String::new(),
+            position: Position::default(),
+            scale: 1,
+            margin: 8,
+            foreground: Color::WHITE,
+            background: Some(Color::BLACK),
+        }
+    }
+
+    /// Value substituted for `{device}`
+    pub fn device(mut self, name: &str) -> Self {
+        self.device = name.to_string();
+        self
+    }
+
+    /// Value substituted for `{label}`
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    pub fn position(mut self, position: Position) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Integer magnification of the 8x8 glyphs (1 = 8 pixels per character)
+    pub fn scale(mut self, scale: u32) -> Self {
+        self.scale = scale.max(1);
+        self
+    }
+
+    /// Distance from the frame edge for corner positions, in pixels
+    pub fn margin(mut self, margin: u32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    pub fn foreground(mut self, color: Color) -> Self {
+        self.foreground = color;
+        self
+    }
+
+    /// Box drawn behind the text for legibility, or `None` for transparent
+    pub fn background(mut self, color: Option<Color>) -> Self {
+        self.background = color;
+        self
+    }
+
+    /// Expand the template for `frame`
+    pub fn render_text(&self, frame: &Frame) -> String {
+        let unix = frame
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        self.template
+            .replace("{time}", &format_utc(frame.timestamp))
+            .replace("{unix}", &unix.to_string())
+            .replace("{seq}", &frame.sequence.to_string())
+            .replace("{device}", &self.device)
+            .replace("{label}", &self.label)
+    }
+
+    /// Burn the overlay into `frame` in place.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for compressed or malformed frames.
+    pub fn apply(&self, frame: &mut Frame) -> Result<(), FrameError> {
+        planes::validate(frame)?;
+
+        let text = self.render_text(frame);
+        let lines: Vec<&str> = text.lines().collect();
+        let cell = GLYPH_SIZE * self.scale as usize;
+        let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        if columns == 0 {
+            return Ok(());
+        }
+
+        // Text block plus a quarter glyph of padding for the background box
+        let pad = cell / 4;
+        let block_w = columns * cell + 2 * pad;
+        let block_h = lines.len() * cell + 2 * pad;
+        let (left, top) = self.origin(frame, block_w, block_h);
+
+        if let Some(background) = self.background {
+            for y in top..top + block_h {
+                for x in left..left + block_w {
+                    draw::put_pixel(frame, x, y, background);
+                }
+            }
+        }
+
+        for (row, line) in lines.iter().enumerate() {
+            for (column, c) in line.chars().enumerate() {
+                let glyph = font::glyph(c);
+                let glyph_x = left + pad + column * cell;
+                let glyph_y = top + pad + row * cell;
+                for y in 0..cell {
+                    let bits = glyph[y / self.scale as usize];
+                    for x in 0..cell {
+                        if bits & (1 << (x / self.scale as usize)) != 0 {
+                            draw::put_pixel(frame, glyph_x + x, glyph_y + y, self.foreground);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn origin(&self, frame: &Frame, block_w: usize, block_h: usize) -> (usize, usize) {
+        let (w, h) = (frame.width as usize, frame.height as usize);
+        let margin = self.margin as usize;
+        let right = w.saturating_sub(block_w + margin);
+        let bottom = h.saturating_sub(block_h + margin);
+        match self.position {
+            Position::TopLeft => (margin, margin),
+            Position::TopRight => (right, margin),
+            Position::BottomLeft => (margin, bottom),
+            Position::BottomRight => (right, bottom),
+            Position::At { x, y } => (x as usize, y as usize),
+        }
+    }
+}
+
+impl FrameProcessor for TextOverlay {
+    fn name(&self) -> &str {
+        "text_overlay"
+    }
+
+    fn process(&mut self, mut frame: Frame) -> Result<Option<Frame>, FrameError> {
+        self.apply(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+/// Format a timestamp as `YYYY-MM-DD HH:MM:SS.mmmZ` in UTC.
+pub fn format_utc(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Proleptic Gregorian date for a day count since 1970-01-01 (Howard Hinnant's algorithm).
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PixelFormat;
+    use std::time::Duration;
+
+    fn frame(format: PixelFormat, width: u32, height: u32) -> Frame {
+        Frame {
+            format,
+            width,
+            height,
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+            sequence: 42,
+            data: vec![0; format.frame_size(width, height).unwrap()],
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        assert_eq!(format_utc(time), "2023-11-14 22:13:20.250Z");
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01 00:00:00.000Z");
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn template_placeholders_are_expanded() {
+        let overlay = TextOverlay::new("{device}|{label}|{seq}|{unix}|{time}")
+            .device("cam0")
+            .label("Gate");
+        assert_eq!(
+            overlay.render_text(&frame(PixelFormat::RGB3, 8, 8)),
+            "cam0|Gate|42|1700000000|2023-11-14 22:13:20.250Z"
+        );
+    }
+
+    #[test]
+    fn glyph_pixels_are_burned_into_rgb() {
+        let mut input = frame(PixelFormat::RGB3, 16, 16);
+        TextOverlay::new("A")
+            .position(Position::At { x: 0, y: 0 })
+            .background(None)
+            .apply(&mut input)
+            .unwrap();
+
+        // With scale 1 the padding is 2 pixels
+        let glyph = font::glyph('A');
+        for (y, bits) in glyph.iter().enumerate() {
+            for x in 0..8 {
+                let lit = bits & (1 << x) != 0;
+                let offset = ((y + 2) * 16 + x + 2) * 3;
+                assert_eq!(input.data[offset] == 255, lit, "pixel {},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn scaled_text_on_i420_writes_luma() {
+        let mut input = frame(PixelFormat::YU12, 64, 32);
+        TextOverlay::new("#").scale(2).margin(0).apply(&mut input).unwrap();
+
+        let luma = &input.data[..64 * 32];
+        let white = luma.iter().filter(|&&y| y == 235).count();
+        let lit_bits: u32 = font::glyph('#').iter().map(|row| row.count_ones()).sum();
+        assert_eq!(white as u32, lit_bits * 4);
+        // Background box is black (Y = 16) rather than untouched zeros
+        assert_eq!(luma[0], 16);
+    }
+
+    #[test]
+    fn text_is_clipped_at_frame_edges() {
+        let mut input = frame(PixelFormat::YUYV, 8, 4);
+        TextOverlay::new("long text").position(Position::BottomRight).apply(&mut input).unwrap();
+        assert_eq!(input.data.len(), 64);
+    }
+
+    #[test]
+    fn compressed_frames_are_rejected() {
+        let mut input = Frame {
+            format: PixelFormat::MJPG,
+            data: vec![0xFF, 0xD8],
+            ..frame(PixelFormat::RGB3, 2, 2)
+        };
+        assert!(TextOverlay::new("x").apply(&mut input).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Splitting raw frames into independent sample planes and back.
+//!
+//! Packed YUYV is de-interleaved into separate Y, U and V planes so every
+//! processing stage only has to deal with one plane layout.
+
+use crate::convert::rgb_to_yuv;
+use crate::{Frame, FrameError, PixelFormat};
+
+/// A single plane of samples, optionally with several interleaved channels (RGB).
+#[derive(Debug, Clone)]
+pub(crate) struct Plane {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+}
+
+impl Plane {
+    pub fn new(width: usize, height: usize, channels: usize) -> Self {
+        Self {
+            data: vec![0; width * height * channels],
+            width,
+            height,
+            channels,
+        }
+    }
+
+    pub fn stride(&self) -> usize {
+        self.width * self.channels
+    }
+}
+
+/// Subsampling shift (horizontal, vertical) of the plane at `index` for `format`.
+pub(crate) fn plane_shift(format: PixelFormat, index: usize) -> (u32, u32) {
+    match (format, index) {
+        (_, 0) => (0, 0),
+        (PixelFormat::YUYV, _) => (1, 0),
+        (PixelFormat::YU12 | PixelFormat::YV12, _) => (1, 1),
+        _ => (0, 0),
+    }
+}
+
+/// Check that `frame` holds a raw format with the amount of data its dimensions imply.
+pub(crate) fn validate(frame: &Frame) -> Result<(), FrameError> {
+    let expected = frame
+        .format
+        .frame_size(frame.width, frame.height)
+        .ok_or(FrameError::UnsupportedFormat(frame.format))?;
+
+    let (align_x, align_y) = frame.format.alignment();
+    if frame.width == 0 || frame.height == 0 || !frame.width.is_multiple_of(align_x) || !frame.height.is_multiple_of(align_y) {
+        return Err(FrameError::InvalidDimensions {
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+        });
+    }
+
+    if frame.data.len() != expected {
+        return Err(FrameError::InvalidDataSize {
+            expected,
+            actual: frame.data.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Split a raw frame into its planes, in memory order (Y, V, U for YV12).
+pub(crate) fn split(frame: &Frame) -> Result<Vec<Plane>, FrameError> {
+    validate(frame)?;
+
+    let (w, h) = (frame.width as usize, frame.height as usize);
+    let planes = match frame.format {
+        PixelFormat::RGB3 | PixelFormat::BGR3 => vec![Plane {
+            data: frame.data.clone(),
+            width: w,
+            height: h,
+            channels: 3,
+        }],
+        PixelFormat::YUYV => {
+            let mut y = Plane::new(w, h, 1);
+            let mut u = Plane::new(w / 2, h, 1);
+            let mut v = Plane::new(w / 2, h, 1);
+            for (i, px) in frame.data.chunks_exact(4).enumerate() {
+                y.data[2 * i] = px[0];
+                u.data[i] = px[1];
+                y.data[2 * i + 1] = px[2];
+                v.data[i] = px[3];
+            }
+            vec![y, u, v]
+        }
+        PixelFormat::YU12 | PixelFormat::YV12 => {
+            let (cw, ch) = (w / 2, h / 2);
+            let (luma, chroma) = frame.data.split_at(w * h);
+            let (first, second) = chroma.split_at(cw * ch);
+            vec![
+                Plane { data: luma.to_vec(), width: w, height: h, channels: 1 },
+                Plane { data: first.to_vec(), width: cw, height: ch, channels: 1 },
+                Plane { data: second.to_vec(), width: cw, height: ch, channels: 1 },
+            ]
+        }
+        PixelFormat::MJPG | PixelFormat::H264 => return Err(FrameError::UnsupportedFormat(frame.format)),
+    };
+    Ok(planes)
+}
+
+/// Reassemble planes produced by [`split`] into frame data for `format`.
+pub(crate) fn join(format: PixelFormat, planes: Vec<Plane>) -> Vec<u8> {
+    match format {
+        PixelFormat::YUYV => {
+            let (y, u, v) = (&planes[0], &planes[1], &planes[2]);
+            let mut data = vec![0; y.width * y.height * 2];
+            for (i, px) in data.chunks_exact_mut(4).enumerate() {
+                px[0] = y.data[2 * i];
+                px[1] = u.data[i];
+                px[2] = y.data[2 * i + 1];
+                px[3] = v.data[i];
+            }
+            data
+        }
+        _ => planes.into_iter().flat_map(|plane| plane.data).collect(),
+    }
+}
+
+/// Extract the luma plane of a raw frame, computing it for RGB formats.
+pub(crate) fn luma(frame: &Frame) -> Result<Plane, FrameError> {
+    validate(frame)?;
+
+    let (w, h) = (frame.width as usize, frame.height as usize);
+    let data = match frame.format {
+        PixelFormat::YUYV => frame.data.iter().step_by(2).copied().collect(),
+        PixelFormat::YU12 | PixelFormat::YV12 => frame.data[..w * h].to_vec(),
+        PixelFormat::RGB3 => frame.data.chunks_exact(3).map(|px| rgb_to_yuv(px[0], px[1], px[2]).0).collect(),
+        PixelFormat::BGR3 => frame.data.chunks_exact(3).map(|px| rgb_to_yuv(px[2], px[1], px[0]).0).collect(),
+        PixelFormat::MJPG | PixelFormat::H264 => return Err(FrameError::UnsupportedFormat(frame.format)),
+    };
+    Ok(Plane { data, width: w, height: h, channels: 1 })
+}
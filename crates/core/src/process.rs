@@ -0,0 +1,446 @@
+//! Composable frame processing pipelines.
+//!
+//! A [`Pipeline`] is an ordered chain of [`FrameProcessor`] stages. It can be
+//! driven directly with [`Pipeline::process`], or moved onto its own worker
+//! thread with [`Pipeline::spawn`] so capture never waits on processing.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_core::{Convert, CropScale, Filter, PixelFormat, Pipeline};
+//!
+//! let pipeline = Pipeline::new()
+//!     .stage(Filter::new("every_other", |frame| frame.sequence % 2 == 0))
+//!     .stage(Convert::new(PixelFormat::YU12))
+//!     .stage(CropScale::new().resize(640, 360));
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::{convert, CropScale, Frame, FrameError, PixelFormat};
+
+/// A single step of a frame processing pipeline.
+pub trait FrameProcessor: Send {
+    /// Short name used in statistics and error messages
+    fn name(&self) -> &str;
+
+    /// Process one frame.
+    ///
+    /// Returning `Ok(None)` drops the frame; later stages will not see it.
+    fn process(&mut self, frame: Frame) -> Result<Option<Frame>, FrameError>;
+}
+
+/// Error raised by a pipeline stage, tagged with the stage name.
+#[derive(Debug, Error)]
+#[error("Stage '{stage}' failed: {source}")]
+pub struct StageError {
+    pub stage: String,
+    #[source]
+    pub source: FrameError,
+}
+
+/// Timing and throughput counters for one pipeline stage.
+#[derive(Debug, Clone, Default)]
+pub struct StageStats {
+    pub name: String,
+
+    /// Frames handed to this stage
+    pub frames: u64,
+
+    /// Frames this stage chose to drop
+    pub dropped: u64,
+
+    /// Frames this stage failed on
+    pub errors: u64,
+
+    /// Time spent in the stage across all frames
+    pub total_time: Duration,
+
+    /// Time spent on the most recent frame
+    pub last_time: Duration,
+
+    /// Slowest single frame
+    pub max_time: Duration,
+}
+
+impl StageStats {
+    /// Mean processing time per frame
+    pub fn average_time(&self) -> Duration {
+        if self.frames == 0 {
+            return Duration::ZERO;
+        }
+        self.total_time / self.frames as u32
+    }
+}
+
+struct Stage {
+    processor: Box<dyn FrameProcessor>,
+    stats: StageStats,
+}
+
+/// An ordered chain of frame processors.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a stage to the end of the pipeline
+    pub fn stage<P: FrameProcessor + 'static>(self, processor: P) -> Self {
+        self.boxed_stage(Box::new(processor))
+    }
+
+    /// Append an already boxed stage to the end of the pipeline
+    pub fn boxed_stage(mut self, processor: Box<dyn FrameProcessor>) -> Self {
+        let stats = StageStats {
+            name: processor.name().to_string(),
+            ..Default::default()
+        };
+        self.stages.push(Stage { processor, stats });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run `frame` through every stage in order.
+    ///
+    /// Returns `Ok(None)` if a stage dropped the frame.
+    pub fn process(&mut self, frame: Frame) -> Result<Option<Frame>, StageError> {
+        let mut current = frame;
+        for stage in &mut self.stages {
+            let started = Instant::now();
+            let result = stage.processor.process(current);
+            let elapsed = started.elapsed();
+
+            let stats = &mut stage.stats;
+            stats.frames += 1;
+            stats.total_time += elapsed;
+            stats.last_time = elapsed;
+            stats.max_time = stats.max_time.max(elapsed);
+
+            match result {
+                Ok(Some(frame)) => current = frame,
+                Ok(None) => {
+                    stats.dropped += 1;
+                    return Ok(None);
+                }
+                Err(source) => {
+                    stats.errors += 1;
+                    return Err(StageError {
+                        stage: stats.name.clone(),
+                        source,
+                    });
+                }
+            }
+        }
+        Ok(Some(current))
+    }
+
+    /// Snapshot of the per-stage counters, in stage order
+    pub fn stats(&self) -> Vec<StageStats> {
+        self.stages.iter().map(|stage| stage.stats.clone()).collect()
+    }
+
+    /// Move the pipeline onto a dedicated worker thread.
+    ///
+    /// Frames submitted through the returned [`PipelineWorker`] are queued (up
+    /// to `capacity`) and processed in order; every processed frame or stage
+    /// error is passed to `sink` on the worker thread. Dropped frames are not
+    /// reported to `sink`.
+    pub fn spawn<F>(mut self, capacity: usize, mut sink: F) -> PipelineWorker
+    where
+        F: FnMut(Result<Frame, StageError>) + Send + 'static,
+    {
+        let (input_tx, input_rx) = mpsc::sync_channel::<Frame>(capacity);
+        let stats = Arc::new(Mutex::new(self.stats()));
+        let worker_stats = Arc::clone(&stats);
+
+        let join_handle = std::thread::spawn(move || {
+            for frame in input_rx {
+                let result = self.process(frame);
+                if let Ok(mut shared) = worker_stats.lock() {
+                    *shared = self.stats();
+                }
+                match result {
+                    Ok(Some(frame)) => sink(Ok(frame)),
+                    Ok(None) => {}
+                    Err(e) => sink(Err(e)),
+                }
+            }
+        });
+
+        PipelineWorker {
+            input_tx: Some(input_tx),
+            stats,
+            overflowed: AtomicU64::new(0),
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+/// Handle to a pipeline running on its own thread.
+///
+/// Dropping the handle closes the input queue and waits for frames already
+/// queued to finish processing; [`PipelineWorker::detach`] does not wait.
+pub struct PipelineWorker {
+    input_tx: Option<SyncSender<Frame>>,
+    stats: Arc<Mutex<Vec<StageStats>>>,
+    overflowed: AtomicU64,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl PipelineWorker {
+    /// Queue a frame without blocking.
+    ///
+    /// If the worker is still busy with a full queue the frame is handed back
+    /// and counted in [`PipelineWorker::overflowed`].
+    pub fn submit(&self, frame: Frame) -> Result<(), Frame> {
+        let Some(input_tx) = &self.input_tx else {
+            return Err(frame);
+        };
+        match input_tx.try_send(frame) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(frame)) => {
+                self.overflowed.fetch_add(1, Ordering::Relaxed);
+                Err(frame)
+            }
+            Err(TrySendError::Disconnected(frame)) => Err(frame),
+        }
+    }
+
+    /// Snapshot of the per-stage counters, in stage order
+    pub fn stats(&self) -> Vec<StageStats> {
+        self.stats.lock().map(|stats| stats.clone()).unwrap_or_default()
+    }
+
+    /// Number of frames rejected because the input queue was full
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting frames and wait for the worker to drain its queue
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    /// Stop accepting frames and let the worker drain its queue in the
+    /// background; for callers that must not wait on a sink which may block
+    pub fn detach(mut self) {
+        self.input_tx.take();
+        self.join_handle.take();
+    }
+
+    fn stop(&mut self) {
+        self.input_tx.take();
+        if let Some(handle) = self.join_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for PipelineWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl FrameProcessor for CropScale {
+    fn name(&self) -> &str {
+        "crop_scale"
+    }
+
+    fn process(&mut self, frame: Frame) -> Result<Option<Frame>, FrameError> {
+        self.apply(&frame).map(Some)
+    }
+}
+
+/// Stage converting frames to another raw pixel format.
+#[derive(Debug, Clone)]
+pub struct Convert {
+    target: PixelFormat,
+}
+
+impl Convert {
+    pub fn new(target: PixelFormat) -> Self {
+        Self { target }
+    }
+}
+
+impl FrameProcessor for Convert {
+    fn name(&self) -> &str {
+        "convert"
+    }
+
+    fn process(&mut self, frame: Frame) -> Result<Option<Frame>, FrameError> {
+        if frame.format == self.target {
+            return Ok(Some(frame));
+        }
+        convert::convert(&frame, self.target).map(Some)
+    }
+}
+
+/// Stage that only lets through frames matching a predicate.
+pub struct Filter<F> {
+    name: String,
+    predicate: F,
+}
+
+impl<F> Filter<F>
+where
+    F: FnMut(&Frame) -> bool + Send,
+{
+    pub fn new(name: &str, predicate: F) -> Self {
+        Self {
+            name: name.to_string(),
+            predicate,
+        }
+    }
+}
+
+impl<F> FrameProcessor for Filter<F>
+where
+    F: FnMut(&Frame) -> bool + Send,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&mut self, frame: Frame) -> Result<Option<Frame>, FrameError> {
+        if (self.predicate)(&frame) {
+            Ok(Some(frame))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn frame(sequence: usize) -> Frame {
+        Frame {
+            format: PixelFormat::YUYV,
+            width: 4,
+            height: 2,
+            timestamp: SystemTime::UNIX_EPOCH,
+            sequence,
+            data: vec![128; 16],
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn stages_run_in_order() {
+        let mut pipeline = Pipeline::new()
+            .stage(Convert::new(PixelFormat::YU12))
+            .stage(CropScale::new().resize(2, 2));
+
+        let out = pipeline.process(frame(1)).unwrap().unwrap();
+        assert_eq!(out.format, PixelFormat::YU12);
+        assert_eq!((out.width, out.height), (2, 2));
+
+        let stats = pipeline.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "convert");
+        assert!(stats.iter().all(|s| s.frames == 1 && s.errors == 0));
+    }
+
+    #[test]
+    fn filter_drops_frames_and_stops_the_chain() {
+        let mut pipeline = Pipeline::new()
+            .stage(Filter::new("odd", |frame| frame.sequence % 2 == 1))
+            .stage(Convert::new(PixelFormat::RGB3));
+
+        assert!(pipeline.process(frame(2)).unwrap().is_none());
+        assert!(pipeline.process(frame(3)).unwrap().is_some());
+
+        let stats = pipeline.stats();
+        assert_eq!((stats[0].frames, stats[0].dropped), (2, 1));
+        assert_eq!(stats[1].frames, 1);
+    }
+
+    #[test]
+    fn errors_name_the_failing_stage() {
+        let mut pipeline = Pipeline::new().stage(Convert::new(PixelFormat::MJPG));
+        let err = pipeline.process(frame(1)).unwrap_err();
+        assert_eq!(err.stage, "convert");
+        assert_eq!(pipeline.stats()[0].errors, 1);
+    }
+
+    #[test]
+    fn worker_processes_frames_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let worker = Pipeline::new()
+            .stage(Convert::new(PixelFormat::RGB3))
+            .spawn(16, move |result| tx.send(result.unwrap().sequence).unwrap());
+
+        for sequence in 0..10 {
+            worker.submit(frame(sequence)).unwrap();
+        }
+        let stats_worker = worker.stats();
+        assert_eq!(stats_worker.len(), 1);
+        worker.shutdown();
+
+        let received: Vec<usize> = rx.iter().collect();
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_queue_hands_frames_back() {
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let gate_rx = Mutex::new(gate_rx);
+        let worker = Pipeline::new()
+            .stage(Filter::new("gate", move |_| gate_rx.lock().unwrap().recv().is_ok()))
+            .spawn(1, |_| {});
+
+        // First frame blocks the worker, second fills the queue
+        worker.submit(frame(0)).unwrap();
+        let mut rejected = 0;
+        for sequence in 1..4 {
+            if worker.submit(frame(sequence)).is_err() {
+                rejected += 1;
+            }
+        }
+        assert!(rejected >= 1);
+        assert_eq!(worker.overflowed(), rejected);
+
+        drop(gate_tx);
+        worker.shutdown();
+    }
+
+    #[test]
+    fn detached_worker_drains_without_waiting() {
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+        let worker = Pipeline::new().spawn(4, move |result| {
+            gate_rx.recv().unwrap();
+            tx.send(result.unwrap().sequence).unwrap();
+        });
+        worker.submit(frame(0)).unwrap();
+        worker.submit(frame(1)).unwrap();
+
+        // The sink is still blocked, so waiting for it here would hang
+        worker.detach();
+        gate_tx.send(()).unwrap();
+        gate_tx.send(()).unwrap();
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [0, 1]);
+    }
+}
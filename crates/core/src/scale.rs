@@ -0,0 +1,407 @@
+//! Software region-of-interest cropping and resizing of raw frames.
+//!
+//! Works on every uncompressed [`PixelFormat`]. Crop regions and output sizes
+//! must respect the chroma grid of the format (see [`PixelFormat::alignment`]):
+//! YUYV shares one U/V pair between two horizontal pixels, and I420/YV12 share
+//! one pair between a 2x2 block.
+
+use crate::planes::{self, Plane};
+use crate::{Frame, FrameError, Resolution};
+
+/// A rectangular region of a frame, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self { x, y, width, height }
+    }
+
+    /// Whether the region is non-empty and fits inside a `width`x`height` frame
+    pub fn fits_within(&self, width: u32, height: u32) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self.x.checked_add(self.width).is_some_and(|right| right <= width)
+            && self.y.checked_add(self.height).is_some_and(|bottom| bottom <= height)
+    }
+}
+
+/// Interpolation used when resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleFilter {
+    /// Pick the closest source sample. Fastest, blocky.
+    Nearest,
+
+    /// Linear interpolation between the four closest source samples
+    #[default]
+    Bilinear,
+
+    /// Average of all source samples covered by the output pixel. Best for downscaling.
+    Area,
+}
+
+/// Crop and/or resize stage for captured frames.
+///
+/// The crop is applied first, then the cropped region is scaled to the output
+/// size. Either step may be omitted.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_core::{CropScale, Rect, ScaleFilter};
+///
+/// // 640x360 preview of the centre of a 1080p frame
+/// let stage = CropScale::new()
+///     .crop(Rect::new(480, 270, 960, 540))
+///     .resize(640, 360)
+///     .filter(ScaleFilter::Area);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CropScale {
+    crop: Option<Rect>,
+    output: Option<Resolution>,
+    filter: ScaleFilter,
+}
+
+impl CropScale {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the given region of the input frame
+    pub fn crop(mut self, rect: Rect) -> Self {
+        self.crop = Some(rect);
+        self
+    }
+
+    /// Scale the (cropped) frame to `width`x`height`
+    pub fn resize(mut self, width: u32, height: u32) -> Self {
+        self.output = Some(Resolution { width, height });
+        self
+    }
+
+    /// Set the interpolation filter used when resizing
+    pub fn filter(mut self, filter: ScaleFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Produce a cropped and scaled copy of `frame`.
+    ///
+    /// The timestamp and sequence number of the input are preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for compressed formats, malformed frames, crop regions
+    /// outside the frame or off the chroma grid, and output sizes the format
+    /// cannot represent.
+    pub fn apply(&self, frame: &Frame) -> Result<Frame, FrameError> {
+        let format = frame.format;
+        if format.is_compressed() {
+            return Err(FrameError::UnsupportedFormat(format));
+        }
+
+        let rect = self.crop.unwrap_or(Rect::new(0, 0, frame.width, frame.height));
+        if !rect.fits_within(frame.width, frame.height) {
+            return Err(FrameError::RegionOutOfBounds(rect));
+        }
+
+        let (align_x, align_y) = format.alignment();
+        if !rect.x.is_multiple_of(align_x) || !rect.width.is_multiple_of(align_x) || !rect.y.is_multiple_of(align_y) || !rect.height.is_multiple_of(align_y) {
+            return Err(FrameError::MisalignedRegion { rect, format });
+        }
+
+        let output = self.output.unwrap_or(Resolution {
+            width: rect.width,
+            height: rect.height,
+        });
+        if output.width == 0 || output.height == 0 || !output.width.is_multiple_of(align_x) || !output.height.is_multiple_of(align_y) {
+            return Err(FrameError::InvalidDimensions {
+                width: output.width,
+                height: output.height,
+                format,
+            });
+        }
+
+        let planes = planes::split(frame)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, plane)| {
+                let (sx, sy) = planes::plane_shift(format, index);
+                let region = Rect::new(rect.x >> sx, rect.y >> sy, rect.width >> sx, rect.height >> sy);
+                let cropped = crop_plane(&plane, region);
+                scale_plane(&cropped, (output.width >> sx) as usize, (output.height >> sy) as usize, self.filter)
+            })
+            .collect();
+
+        Ok(Frame {
+            format,
+            width: output.width,
+            height: output.height,
+            timestamp: frame.timestamp,
+            sequence: frame.sequence,
+            data: planes::join(format, planes),
+            metadata: frame.metadata.clone(),
+        })
+    }
+}
+
+fn crop_plane(plane: &Plane, rect: Rect) -> Plane {
+    let (x, y) = (rect.x as usize, rect.y as usize);
+    let (w, h) = (rect.width as usize, rect.height as usize);
+    if x == 0 && y == 0 && w == plane.width && h == plane.height {
+        return plane.clone();
+    }
+
+    let c = plane.channels;
+    let mut out = Plane::new(w, h, c);
+    for row in 0..h {
+        let src = (y + row) * plane.stride() + x * c;
+        out.data[row * w * c..(row + 1) * w * c].copy_from_slice(&plane.data[src..src + w * c]);
+    }
+    out
+}
+
+pub(crate) fn scale_plane(plane: &Plane, width: usize, height: usize, filter: ScaleFilter) -> Plane {
+    if width == plane.width && height == plane.height {
+        return plane.clone();
+    }
+
+    match filter {
+        ScaleFilter::Nearest => scale_nearest(plane, width, height),
+        ScaleFilter::Bilinear => scale_bilinear(plane, width, height),
+        ScaleFilter::Area => scale_area(plane, width, height),
+    }
+}
+
+fn scale_nearest(plane: &Plane, width: usize, height: usize) -> Plane {
+    let c = plane.channels;
+    let mut out = Plane::new(width, height, c);
+
+    // Sample at pixel centres so both edges are treated symmetrically
+    let xs: Vec<usize> = (0..width)
+        .map(|x| ((2 * x + 1) * plane.width / (2 * width)).min(plane.width - 1))
+        .collect();
+
+    for y in 0..height {
+        let sy = ((2 * y + 1) * plane.height / (2 * height)).min(plane.height - 1);
+        let src_row = &plane.data[sy * plane.stride()..(sy + 1) * plane.stride()];
+        let dst_row = &mut out.data[y * width * c..(y + 1) * width * c];
+        for (x, &sx) in xs.iter().enumerate() {
+            dst_row[x * c..(x + 1) * c].copy_from_slice(&src_row[sx * c..(sx + 1) * c]);
+        }
+    }
+    out
+}
+
+/// Source index pair and weight of the second sample for each output position.
+fn linear_taps(src_len: usize, dst_len: usize) -> Vec<(usize, usize, f32)> {
+    let ratio = src_len as f32 / dst_len as f32;
+    (0..dst_len)
+        .map(|d| {
+            let pos = ((d as f32 + 0.5) * ratio - 0.5).clamp(0.0, (src_len - 1) as f32);
+            let i0 = pos.floor() as usize;
+            let i1 = (i0 + 1).min(src_len - 1);
+            (i0, i1, pos - i0 as f32)
+        })
+        .collect()
+}
+
+fn scale_bilinear(plane: &Plane, width: usize, height: usize) -> Plane {
+    let c = plane.channels;
+    let stride = plane.stride();
+    let mut out = Plane::new(width, height, c);
+
+    let x_taps = linear_taps(plane.width, width);
+    let y_taps = linear_taps(plane.height, height);
+
+    for (y, &(y0, y1, wy)) in y_taps.iter().enumerate() {
+        let row0 = &plane.data[y0 * stride..(y0 + 1) * stride];
+        let row1 = &plane.data[y1 * stride..(y1 + 1) * stride];
+        for (x, &(x0, x1, wx)) in x_taps.iter().enumerate() {
+            for ch in 0..c {
+                let top = row0[x0 * c + ch] as f32 * (1.0 - wx) + row0[x1 * c + ch] as f32 * wx;
+                let bottom = row1[x0 * c + ch] as f32 * (1.0 - wx) + row1[x1 * c + ch] as f32 * wx;
+                let value = top * (1.0 - wy) + bottom * wy;
+                out.data[(y * width + x) * c + ch] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    out
+}
+
+/// For each output position, the source samples it covers and their normalised coverage.
+fn area_weights(src_len: usize, dst_len: usize) -> Vec<Vec<(usize, f32)>> {
+    let ratio = src_len as f32 / dst_len as f32;
+    (0..dst_len)
+        .map(|d| {
+            let start = d as f32 * ratio;
+            let end = ((d + 1) as f32 * ratio).min(src_len as f32);
+            let first = start.floor() as usize;
+            let last = (end.ceil() as usize).clamp(first + 1, src_len);
+
+            let mut taps: Vec<(usize, f32)> = (first..last)
+                .map(|i| {
+                    let coverage = (end.min((i + 1) as f32) - start.max(i as f32)).max(0.0);
+                    (i, coverage)
+                })
+                .filter(|&(_, coverage)| coverage > 0.0)
+                .collect();
+            if taps.is_empty() {
+                taps.push((first.min(src_len - 1), 1.0));
+            }
+
+            let total: f32 = taps.iter().map(|&(_, w)| w).sum();
+            taps.iter_mut().for_each(|tap| tap.1 /= total);
+            taps
+        })
+        .collect()
+}
+
+fn scale_area(plane: &Plane, width: usize, height: usize) -> Plane {
+    let c = plane.channels;
+    let stride = plane.stride();
+    let x_weights = area_weights(plane.width, width);
+    let y_weights = area_weights(plane.height, height);
+
+    // Horizontal pass into a float buffer, then vertical pass into the output
+    let mut horizontal = vec![0f32; width * plane.height * c];
+    for y in 0..plane.height {
+        let src_row = &plane.data[y * stride..(y + 1) * stride];
+        for (x, taps) in x_weights.iter().enumerate() {
+            for ch in 0..c {
+                horizontal[(y * width + x) * c + ch] =
+                    taps.iter().map(|&(i, w)| src_row[i * c + ch] as f32 * w).sum();
+            }
+        }
+    }
+
+    let mut out = Plane::new(width, height, c);
+    for (y, taps) in y_weights.iter().enumerate() {
+        for x in 0..width {
+            for ch in 0..c {
+                let value: f32 = taps
+                    .iter()
+                    .map(|&(i, w)| horizontal[(i * width + x) * c + ch] * w)
+                    .sum();
+                out.data[(y * width + x) * c + ch] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PixelFormat;
+    use std::time::SystemTime;
+
+    fn frame(format: PixelFormat, width: u32, height: u32, data: Vec<u8>) -> Frame {
+        Frame {
+            format,
+            width,
+            height,
+            timestamp: SystemTime::UNIX_EPOCH,
+            sequence: 7,
+            data,
+            metadata: Default::default(),
+        }
+    }
+
+    fn gradient(format: PixelFormat, width: u32, height: u32) -> Frame {
+        let size = format.frame_size(width, height).unwrap();
+        frame(format, width, height, (0..size).map(|i| (i % 251) as u8).collect())
+    }
+
+    #[test]
+    fn crop_rgb_extracts_region() {
+        let input = gradient(PixelFormat::RGB3, 4, 4);
+        let out = CropScale::new().crop(Rect::new(1, 2, 2, 2)).apply(&input).unwrap();
+
+        assert_eq!((out.width, out.height), (2, 2));
+        assert_eq!(&out.data[0..6], &input.data[(2 * 4 + 1) * 3..(2 * 4 + 3) * 3]);
+        assert_eq!(&out.data[6..12], &input.data[(3 * 4 + 1) * 3..(3 * 4 + 3) * 3]);
+        assert_eq!(out.sequence, 7);
+    }
+
+    #[test]
+    fn crop_yuyv_keeps_macropixels() {
+        let input = gradient(PixelFormat::YUYV, 8, 2);
+        let out = CropScale::new().crop(Rect::new(2, 1, 4, 1)).apply(&input).unwrap();
+
+        // Second row, starting at the second macropixel
+        assert_eq!(out.data, input.data[16 + 4..16 + 12].to_vec());
+    }
+
+    #[test]
+    fn misaligned_crop_is_rejected() {
+        let yuyv = gradient(PixelFormat::YUYV, 8, 8);
+        assert!(matches!(
+            CropScale::new().crop(Rect::new(1, 0, 4, 4)).apply(&yuyv),
+            Err(FrameError::MisalignedRegion { .. })
+        ));
+
+        let i420 = gradient(PixelFormat::YU12, 8, 8);
+        assert!(matches!(
+            CropScale::new().crop(Rect::new(0, 1, 4, 4)).apply(&i420),
+            Err(FrameError::MisalignedRegion { .. })
+        ));
+        assert!(matches!(
+            CropScale::new().resize(5, 4).apply(&i420),
+            Err(FrameError::InvalidDimensions { .. })
+        ));
+    }
+
+    #[test]
+    fn out_of_bounds_crop_is_rejected() {
+        let input = gradient(PixelFormat::RGB3, 4, 4);
+        assert!(matches!(
+            CropScale::new().crop(Rect::new(2, 2, 4, 4)).apply(&input),
+            Err(FrameError::RegionOutOfBounds(_))
+        ));
+    }
+
+    #[test]
+    fn compressed_frames_are_rejected() {
+        let input = frame(PixelFormat::MJPG, 4, 4, vec![0xFF, 0xD8]);
+        assert!(matches!(
+            CropScale::new().resize(2, 2).apply(&input),
+            Err(FrameError::UnsupportedFormat(PixelFormat::MJPG))
+        ));
+    }
+
+    #[test]
+    fn uniform_frames_stay_uniform_under_every_filter() {
+        for format in [PixelFormat::YUYV, PixelFormat::RGB3, PixelFormat::YU12, PixelFormat::YV12] {
+            let size = format.frame_size(1920, 1080).unwrap();
+            let input = frame(format, 1920, 1080, vec![90; size]);
+            for filter in [ScaleFilter::Nearest, ScaleFilter::Bilinear, ScaleFilter::Area] {
+                let out = CropScale::new().resize(640, 360).filter(filter).apply(&input).unwrap();
+                assert_eq!(out.data.len(), format.frame_size(640, 360).unwrap());
+                assert!(out.data.iter().all(|&v| v == 90), "{:?} {:?}", format, filter);
+            }
+        }
+    }
+
+    #[test]
+    fn area_downscale_averages_blocks() {
+        let input = frame(PixelFormat::YU12, 4, 2, vec![0, 100, 50, 50, 100, 0, 50, 50, 10, 20, 30, 40]);
+        let out = CropScale::new().resize(2, 2).filter(ScaleFilter::Area).apply(&input).unwrap();
+
+        assert_eq!(&out.data[0..4], &[50, 50, 50, 50]);
+        assert_eq!(&out.data[4..], &[15, 35]);
+    }
+
+    #[test]
+    fn nearest_upscale_duplicates_samples() {
+        let input = frame(PixelFormat::BGR3, 2, 1, vec![1, 2, 3, 4, 5, 6]);
+        let out = CropScale::new().resize(4, 1).filter(ScaleFilter::Nearest).apply(&input).unwrap();
+        assert_eq!(out.data, vec![1, 2, 3, 1, 2, 3, 4, 5, 6, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,282 @@
+//! Synthetic test-pattern source.
+//!
+//! Produces frames showing a box moving over a static gradient background, in
+//! any raw [`PixelFormat`]. Useful wherever a real camera is not available:
+//! tests, benchmarks and demos of the processing and encoding stages.
+
+use std::time::{Duration, SystemTime};
+
+use crate::convert::{self, Yuv444};
+use crate::planes::Plane;
+use crate::{Frame, Metadata, PixelFormat};
+
+/// Generator of frames with a box moving over a gradient.
+///
+/// The box bounces off the frame edges and always stays inside the frame,
+/// shrinking to fit frames smaller than it. Frames are numbered from 1 like
+/// frames from the camera actor, and timestamped `1 / fps` apart from the
+/// start time.
+///
+/// # Examples
+///
+/// ```
+/// use streaming_core::{PixelFormat, SyntheticSource};
+///
+/// let mut source = SyntheticSource::new(PixelFormat::YUYV, 320, 240).velocity(4, 2);
+/// let frame = source.next_frame();
+/// assert_eq!(frame.sequence, 1);
+/// assert_eq!(frame.data.len(), 320 * 240 * 2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyntheticSource {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    fps: u32,
+    start: SystemTime,
+    box_size: u32,
+    box_luma: u8,
+    position: (i64, i64),
+    velocity: (i64, i64),
+    noise: u8,
+    sequence: usize,
+}
+
+impl SyntheticSource {
+    /// Create a 30 fps source with a stationary box in the top-left quarter
+    ///
+    /// # Panics
+    ///
+    /// If `format` is compressed; encode raw frames with `streaming_codec`
+    /// to get MJPEG or H.264.
+    pub fn new(format: PixelFormat, width: u32, height: u32) -> Self {
+        assert!(!format.is_compressed(), "synthetic frames cannot be {:?}", format);
+        let mut source = Self {
+            format,
+            width,
+            height,
+            fps: 30,
+            start: SystemTime::now(),
+            box_size: 0,
+            box_luma: 235,
+            position: (width as i64 / 8, height as i64 / 8),
+            velocity: (0, 0),
+            noise: 0,
+            sequence: 0,
+        };
+        source.set_box_size((width.min(height) / 6).max(2));
+        source
+    }
+
+    pub fn fps(mut self, fps: u32) -> Self {
+        self.fps = fps.max(1);
+        self
+    }
+
+    /// Timestamp of the first frame
+    pub fn start_time(mut self, start: SystemTime) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Side length of the box in pixels, at most the frame's smaller side
+    pub fn box_size(mut self, size: u32) -> Self {
+        self.set_box_size(size);
+        self
+    }
+
+    /// Starting position of the box's top-left corner, moved in as far as
+    /// needed to keep the box inside the frame
+    pub fn position(mut self, x: u32, y: u32) -> Self {
+        self.position = (x as i64, y as i64);
+        self.clamp_position();
+        self
+    }
+
+    /// Movement of the box in pixels per frame
+    pub fn velocity(mut self, dx: i32, dy: i32) -> Self {
+        self.set_velocity(dx, dy);
+        self
+    }
+
+    /// Add up to `amplitude` of pseudo-random luma noise per pixel, different
+    /// in every frame but reproducible, to mimic sensor noise
+    pub fn noise(mut self, amplitude: u8) -> Self {
+        self.noise = amplitude;
+        self
+    }
+
+    /// Change the box movement mid-stream (e.g. to stop it)
+    pub fn set_velocity(&mut self, dx: i32, dy: i32) {
+        self.velocity = (dx as i64, dy as i64);
+    }
+
+    /// Current top-left corner and size of the box, in pixels
+    pub fn box_rect(&self) -> crate::Rect {
+        crate::Rect::new(self.position.0 as u32, self.position.1 as u32, self.box_size, self.box_size)
+    }
+
+    /// Timestamp the next frame will carry
+    pub fn next_timestamp(&self) -> SystemTime {
+        self.start + Duration::from_secs(1) * self.sequence as u32 / self.fps
+    }
+
+    /// Render the next frame and advance the box
+    pub fn next_frame(&mut self) -> Frame {
+        let timestamp = self.next_timestamp();
+        self.sequence += 1;
+
+        let (w, h) = (self.width as usize, self.height as usize);
+        let mut yuv = Yuv444 {
+            y: Plane::new(w, h, 1),
+            u: Plane::new(w, h, 1),
+            v: Plane::new(w, h, 1),
+        };
+
+        // Diagonal luma gradient with slowly varying chroma as background
+        for y in 0..h {
+            for x in 0..w {
+                let i = y * w + x;
+                yuv.y.data[i] = (16 + (x + y) * 160 / (w + h)) as u8;
+                yuv.u.data[i] = (96 + x * 64 / w) as u8;
+                yuv.v.data[i] = (96 + y * 64 / h) as u8;
+            }
+        }
+
+        let rect = self.box_rect();
+        for y in rect.y as usize..(rect.y + rect.height) as usize {
+            for x in rect.x as usize..(rect.x + rect.width) as usize {
+                let i = y * w + x;
+                yuv.y.data[i] = self.box_luma;
+                yuv.u.data[i] = 128;
+                yuv.v.data[i] = 128;
+            }
+        }
+        self.advance();
+
+        if self.noise > 0 {
+            let span = 2 * self.noise as u64 + 1;
+            let mut state = (self.sequence as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+            for luma in yuv.y.data.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let offset = (state % span) as i32 - self.noise as i32;
+                *luma = (*luma as i32 + offset).clamp(16, 235) as u8;
+            }
+        }
+
+        Frame {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            timestamp,
+            sequence: self.sequence,
+            data: convert::from_yuv444(&yuv, self.format),
+            metadata: Metadata::default(),
+        }
+    }
+
+    fn set_box_size(&mut self, size: u32) {
+        self.box_size = size.max(1).min(self.width.min(self.height));
+        self.clamp_position();
+    }
+
+    /// Largest top-left corner that keeps the box inside the frame
+    fn max_position(&self) -> (i64, i64) {
+        (self.width.saturating_sub(self.box_size) as i64, self.height.saturating_sub(self.box_size) as i64)
+    }
+
+    fn clamp_position(&mut self) {
+        let (max_x, max_y) = self.max_position();
+        self.position = (self.position.0.clamp(0, max_x), self.position.1.clamp(0, max_y));
+    }
+
+    fn advance(&mut self) {
+        let (max_x, max_y) = self.max_position();
+
+        let (mut x, mut y) = (self.position.0 + self.velocity.0, self.position.1 + self.velocity.1);
+        if x < 0 || x > max_x {
+            self.velocity.0 = -self.velocity.0;
+            x = x.clamp(0, max_x);
+        }
+        if y < 0 || y > max_y {
+            self.velocity.1 = -self.velocity.1;
+            y = y.clamp(0, max_y);
+        }
+        self.position = (x, y);
+    }
+}
+
+impl Iterator for SyntheticSource {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        Some(self.next_frame())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_sized_and_timed() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut source = SyntheticSource::new(PixelFormat::YU12, 64, 48).fps(25).start_time(start);
+        let first = source.next_frame();
+        let second = source.next_frame();
+
+        assert_eq!(first.data.len(), PixelFormat::YU12.frame_size(64, 48).unwrap());
+        assert_eq!((first.sequence, second.sequence), (1, 2));
+        assert_eq!(second.timestamp.duration_since(first.timestamp).unwrap(), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn noise_is_reproducible() {
+        let noisy = |seed_frames: usize| SyntheticSource::new(PixelFormat::YU12, 32, 32).noise(8).nth(seed_frames).unwrap();
+        let clean = SyntheticSource::new(PixelFormat::YU12, 32, 32).next_frame();
+        assert_eq!(noisy(0).data, noisy(0).data);
+        assert_ne!(noisy(0).data, noisy(1).data);
+        assert_ne!(noisy(0).data, clean.data);
+    }
+
+    #[test]
+    fn box_moves_and_bounces() {
+        let mut source = SyntheticSource::new(PixelFormat::RGB3, 40, 20).box_size(10).position(28, 0).velocity(4, 0);
+        source.next_frame();
+        assert_eq!(source.box_rect().x, 30);
+        source.next_frame();
+        assert_eq!(source.box_rect().x, 26);
+    }
+
+    #[test]
+    fn box_stays_inside_the_frame() {
+        // Placed past the right and bottom edges, then moving into them
+        let mut source =
+            SyntheticSource::new(PixelFormat::YU12, 40, 20).box_size(10).position(35, 18).velocity(3, 3);
+        assert_eq!(source.box_rect(), crate::Rect::new(30, 10, 10, 10));
+        let frame = source.next_frame();
+        assert_eq!(frame.data[19 * 40 + 39], 235);
+        assert_eq!(source.box_rect(), crate::Rect::new(30, 10, 10, 10));
+        source.next_frame();
+        assert_eq!(source.box_rect(), crate::Rect::new(27, 7, 10, 10));
+
+        // A frame smaller than the box shrinks it rather than underflowing
+        let mut tiny = SyntheticSource::new(PixelFormat::RGB3, 1, 1).velocity(1, 1);
+        assert_eq!(tiny.box_rect(), crate::Rect::new(0, 0, 1, 1));
+        let mut narrow = SyntheticSource::new(PixelFormat::RGB3, 3, 40).box_size(8).velocity(2, 2);
+        for _ in 0..30 {
+            assert_eq!(tiny.next_frame().data.len(), 3);
+            narrow.next_frame();
+            let rect = narrow.box_rect();
+            assert!(rect.x + rect.width <= 3 && rect.y + rect.height <= 40);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "synthetic frames cannot be MJPG")]
+    fn rejects_compressed_formats() {
+        SyntheticSource::new(PixelFormat::MJPG, 64, 48);
+    }
+}
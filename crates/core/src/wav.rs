@@ -0,0 +1,323 @@
+//! Reading and writing 16-bit PCM WAV files.
+//!
+//! [`WavSource`] plays a recording back as an [`AudioSource`], standing in
+//! for a microphone in tests and demos; [`WavWriter`] stores captured audio
+//! for later inspection. Only uncompressed 16-bit PCM is handled.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::audio::{samples_duration, AudioFrame, AudioSource};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Audio frames read from a 16-bit PCM WAV file.
+///
+/// Frames are timestamped from the start time by the samples read so far,
+/// like [`ToneSource`](crate::audio::ToneSource). The source ends with the
+/// file unless it is set to loop.
+pub struct WavSource<R = BufReader<File>> {
+    reader: R,
+    sample_rate: u32,
+    channels: u16,
+    data_start: u64,
+    data_len: u64,
+    remaining: u64,
+    frame_size: usize,
+    looping: bool,
+    start: SystemTime,
+    position: u64,
+    sequence: usize,
+}
+
+impl WavSource {
+    /// Open the WAV file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// I/O errors, and [`io::ErrorKind::InvalidData`] for files that are not
+    /// 16-bit PCM WAV.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> WavSource<R> {
+    /// Read WAV data from `reader`, positioned at the RIFF header
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(invalid("not a RIFF WAVE file"));
+        }
+
+        let mut format = None;
+        loop {
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk)?;
+            let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+            match &chunk[0..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0u8; len.clamp(16, 64) as usize];
+                    reader.read_exact(&mut fmt)?;
+                    reader.seek(SeekFrom::Current(len as i64 - fmt.len() as i64 + (len & 1) as i64))?;
+                    let tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+                    let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+                    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+                    if !matches!(tag, WAVE_FORMAT_PCM | WAVE_FORMAT_EXTENSIBLE) || bits != 16 {
+                        return Err(invalid("only 16-bit PCM WAV files are supported"));
+                    }
+                    if channels == 0 || sample_rate == 0 {
+                        return Err(invalid("WAV file without channels or sample rate"));
+                    }
+                    format = Some((sample_rate, channels));
+                }
+                b"data" => {
+                    let (sample_rate, channels) = format.ok_or_else(|| invalid("WAV data before format"))?;
+                    let data_start = reader.stream_position()?;
+                    // Writers that never finished leave the length at 0 or
+                    // a placeholder; the data then runs to the end of file
+                    let end = reader.seek(SeekFrom::End(0))?;
+                    let data_len = if len == 0 { end - data_start } else { len.min(end - data_start) };
+                    reader.seek(SeekFrom::Start(data_start))?;
+                    return Ok(Self {
+                        reader,
+                        sample_rate,
+                        channels,
+                        data_start,
+                        data_len,
+                        remaining: data_len,
+                        frame_size: (sample_rate / 50).max(1) as usize,
+                        looping: false,
+                        start: SystemTime::now(),
+                        position: 0,
+                        sequence: 0,
+                    });
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current(len as i64 + (len & 1) as i64))?;
+                }
+            }
+        }
+    }
+
+    /// Length of each frame; 20 ms by default
+    pub fn frame_duration(mut self, duration: Duration) -> Self {
+        let frames = duration.as_nanos() * self.sample_rate as u128 / 1_000_000_000;
+        self.frame_size = (frames as usize).max(1);
+        self
+    }
+
+    /// Start over at the end of the file instead of ending
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Timestamp of the first frame
+    pub fn start_time(mut self, start: SystemTime) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Length of the recording
+    pub fn duration(&self) -> Duration {
+        let frame_bytes = 2 * self.channels as u64;
+        samples_duration(self.data_len / frame_bytes, self.sample_rate)
+    }
+
+    /// Read the next frame, shorter than the rest at the end of the file
+    pub fn next_frame(&mut self) -> io::Result<Option<AudioFrame>> {
+        let frame_bytes = 2 * self.channels as u64;
+        if self.remaining < frame_bytes && self.looping && self.data_len >= frame_bytes {
+            self.reader.seek(SeekFrom::Start(self.data_start))?;
+            self.remaining = self.data_len;
+        }
+        let len = (self.frame_size as u64 * frame_bytes).min(self.remaining / frame_bytes * frame_bytes);
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let mut bytes = vec![0u8; len as usize];
+        self.reader.read_exact(&mut bytes)?;
+        self.remaining -= len;
+
+        let timestamp = self.start + samples_duration(self.position, self.sample_rate);
+        self.position += len / frame_bytes;
+        self.sequence += 1;
+        Ok(Some(AudioFrame {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp,
+            sequence: self.sequence,
+            samples: bytes.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect(),
+        }))
+    }
+}
+
+impl<R: Read + Seek + Send> AudioSource for WavSource<R> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<AudioFrame>> {
+        self.next_frame()
+    }
+}
+
+/// Writes audio frames to a 16-bit PCM WAV file.
+///
+/// The header carries a placeholder length until [`WavWriter::finish`]
+/// writes the final one; [`WavSource`] also reads files whose writer never
+/// got that far.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Cursor;
+/// use streaming_core::audio::ToneSource;
+/// use streaming_core::wav::{WavSource, WavWriter};
+///
+/// let mut writer = WavWriter::new(Cursor::new(Vec::new()), 16_000, 1)?;
+/// for frame in ToneSource::new(16_000, 1).take(5) {
+///     writer.write_frame(&frame)?;
+/// }
+/// let mut file = writer.finish()?;
+/// file.set_position(0);
+/// let source = WavSource::new(file)?;
+/// assert_eq!(source.duration().as_millis(), 100);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+    channels: u16,
+    data_len: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Create a WAV file at `path`
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Write the header for `channels` at `sample_rate` to `writer`
+    pub fn new(mut writer: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        writer.write_all(&header(sample_rate, channels, None))?;
+        Ok(Self { writer, sample_rate, channels, data_len: 0 })
+    }
+
+    /// Append the samples of `frame`.
+    ///
+    /// # Errors
+    ///
+    /// [`io::ErrorKind::InvalidInput`] if the frame's sample rate or channel
+    /// count differs from the file's, or the file would exceed 4 GiB.
+    pub fn write_frame(&mut self, frame: &AudioFrame) -> io::Result<()> {
+        if (frame.sample_rate, frame.channels) != (self.sample_rate, self.channels) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "audio format differs from the WAV file"));
+        }
+        let len = u32::try_from(frame.samples.len() * 2)
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len).filter(|total| *total <= u32::MAX - 36))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "WAV file would exceed 4 GiB"))?;
+        let bytes: Vec<u8> = frame.samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.writer.write_all(&bytes)?;
+        self.data_len = len;
+        Ok(())
+    }
+
+    /// Fill in the final length and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header(self.sample_rate, self.channels, Some(self.data_len)))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Header for `data_len` bytes of samples, or for a stream of unknown length
+fn header(sample_rate: u32, channels: u16, data_len: Option<u32>) -> Vec<u8> {
+    let block_align = 2 * channels;
+    let riff_len = data_len.map_or(u32::MAX, |len| 36 + len);
+    let data_len = data_len.unwrap_or(u32::MAX);
+    let mut out = Vec::with_capacity(44);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&riff_len.to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::ToneSource;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trips_and_loops() {
+        let start = SystemTime::UNIX_EPOCH;
+        let frames: Vec<_> = ToneSource::new(8000, 2).start_time(start).take(3).collect();
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 8000, 2).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        let mut file = writer.finish().unwrap();
+        assert_eq!(file.get_ref().len(), 44 + 3 * 160 * 2 * 2);
+
+        // Frames of 25 ms: three whole ones and a 10 ms tail, then the loop
+        file.set_position(0);
+        let mut source = WavSource::new(file)
+            .unwrap()
+            .frame_duration(Duration::from_millis(25))
+            .start_time(start)
+            .looping(true);
+        let read: Vec<_> = (0..5).map(|_| source.next_frame().unwrap().unwrap()).collect();
+        assert_eq!(read.iter().map(AudioFrame::frames).collect::<Vec<_>>(), [200, 200, 80, 200, 200]);
+        assert_eq!(read[3].timestamp, start + Duration::from_millis(60));
+
+        let samples: Vec<i16> = read[..3].iter().flat_map(|frame| frame.samples.clone()).collect();
+        let written: Vec<i16> = frames.iter().flat_map(|frame| frame.samples.clone()).collect();
+        assert_eq!(samples, written);
+        assert_eq!(read[3].samples, written[..400]);
+    }
+
+    #[test]
+    fn reads_unfinished_files_and_rejects_other_formats() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 8000, 1).unwrap();
+        writer.write_frame(&ToneSource::new(8000, 1).next_frame()).unwrap();
+        // Never finished, so the header still has the placeholder length
+        let mut data = writer.writer.into_inner();
+        let mut source = WavSource::new(Cursor::new(data.clone())).unwrap();
+        assert_eq!(source.next_frame().unwrap().unwrap().frames(), 160);
+        assert!(source.next_frame().unwrap().is_none());
+
+        data[34] = 8;
+        let err = WavSource::new(Cursor::new(data)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,166 @@
+//! X25519 recipient keys.
+//!
+//! Keys are stored as a single line of hex so they can be copied between
+//! machines and provisioning scripts without a binary-safe channel.
+
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use rand_core::OsRng;
+use x25519_dalek::StaticSecret;
+
+use crate::CryptoError;
+
+/// Key that recordings are encrypted to; safe to deploy on cameras.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(x25519_dalek::PublicKey);
+
+/// Key that decrypts recordings; keep it off the cameras.
+#[derive(Clone)]
+pub struct SecretKey(StaticSecret);
+
+impl SecretKey {
+    /// Generate a key from the operating system's random source
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(StaticSecret::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0))
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, CryptoError> {
+        decode_hex(text).map(Self::from_bytes)
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CryptoError> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// Write the key readable by its owner only, on Unix
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CryptoError> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        // The mode only applies to new files
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all((self.to_hex() + "\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// X25519 shared secret with `public`
+    pub(crate) fn agree(&self, public: &PublicKey) -> [u8; 32] {
+        self.0.diffie_hellman(&public.0).to_bytes()
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecretKey").field(&self.public_key()).finish()
+    }
+}
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(x25519_dalek::PublicKey::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, CryptoError> {
+        decode_hex(text).map(Self::from_bytes)
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CryptoError> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CryptoError> {
+        Ok(fs::write(path, self.to_hex() + "\n")?)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self.to_hex())
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Result<[u8; 32], CryptoError> {
+    let text = text.trim();
+    if text.len() != 64 || !text.is_ascii() {
+        return Err(CryptoError::InvalidKey(format!("expected 64 hex digits, got {} characters", text.len())));
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).unwrap_or_default();
+        *byte =
+            u8::from_str_radix(pair, 16).map_err(|_| CryptoError::InvalidKey(format!("bad hex digits {:?}", pair)))?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        let secret = SecretKey::generate();
+        let parsed = SecretKey::from_hex(&format!("  {}\n", secret.to_hex())).unwrap();
+        assert_eq!(parsed.to_bytes(), secret.to_bytes());
+        assert_eq!(PublicKey::from_hex(&secret.public_key().to_hex()).unwrap(), secret.public_key());
+
+        assert!(matches!(PublicKey::from_hex("abcd"), Err(CryptoError::InvalidKey(_))));
+        assert!(matches!(PublicKey::from_hex(&"zz".repeat(32)), Err(CryptoError::InvalidKey(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("secret-key-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let secret = SecretKey::generate();
+        secret.save(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(SecretKey::load(&path).unwrap().to_bytes(), secret.to_bytes());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn agreement_is_symmetric() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        assert_eq!(alice.agree(&bob.public_key()), bob.agree(&alice.public_key()));
+        assert!(!format!("{:?}", alice).contains(&alice.to_hex()));
+    }
+}
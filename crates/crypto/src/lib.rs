@@ -1 +1,34 @@
-// Crypto library placeholder
+//! Encryption for the streaming pipeline.
+//!
+//! [`recording`] defines an encrypted-at-rest container for recordings:
+//! each file gets a random data key, wrapped for a recipient's X25519
+//! public key, and its contents are sealed in AES-256-GCM chunks that can be
+//! decrypted independently. A device only needs the public key, so a stolen
+//! SD card reveals nothing without the recipient's secret key.
+
+use thiserror::Error;
+
+pub mod keys;
+pub mod recording;
+
+pub use keys::{PublicKey, SecretKey};
+pub use recording::{EncryptedReader, EncryptedWriter, VerifyReport};
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("Invalid encrypted file: {0}")]
+    InvalidFormat(String),
+
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u16),
+
+    /// A tag did not verify: wrong key, or tampered or corrupt data
+    #[error("Authentication failed for {0}")]
+    Authentication(String),
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,99 @@
+//! Command-line tool for encrypted recordings.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+use streaming_crypto::{EncryptedReader, EncryptedWriter, PublicKey, SecretKey};
+
+const USAGE: &str = "\
+usage: streaming-crypto keygen <secret-key-out> <public-key-out>
+       streaming-crypto encrypt <public-key> <input> <output>
+       streaming-crypto decrypt <secret-key> <input> <output>
+       streaming-crypto verify [--allow-unfinished] <secret-key> <input>...";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["keygen", secret_path, public_path] => keygen(secret_path, public_path),
+        ["encrypt", key, input, output] => encrypt(key, input, output),
+        ["decrypt", key, input, output] => decrypt(key, input, output),
+        ["verify", "--allow-unfinished", key, inputs @ ..] if !inputs.is_empty() => verify(key, inputs, true),
+        ["verify", key, inputs @ ..] if !inputs.is_empty() => verify(key, inputs, false),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn keygen(secret_path: &str, public_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = SecretKey::generate();
+    secret.save(secret_path)?;
+    secret.public_key().save(public_path)?;
+    println!("{}", secret.public_key().to_hex());
+    Ok(())
+}
+
+fn encrypt(key: &str, input: &str, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let recipient = PublicKey::load(key)?;
+    let mut input = BufReader::new(File::open(input)?);
+    let output = BufWriter::new(File::create(output)?);
+    let mut writer = EncryptedWriter::new(output, &recipient)?;
+    io::copy(&mut input, &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+fn decrypt(key: &str, input: &str, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = SecretKey::load(key)?;
+    let mut reader = EncryptedReader::open(BufReader::new(File::open(input)?), &secret)?;
+    if !reader.is_complete() {
+        eprintln!("Warning: {} was not finished; decrypting the complete chunks", input);
+    }
+    let mut output = BufWriter::new(File::create(output)?);
+    io::copy(&mut reader, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Unfinished files fail unless `allow_unfinished`: a file cut short by
+/// whoever holds it decrypts as cleanly as one cut short by a crash
+fn verify(key: &str, inputs: &[&str], allow_unfinished: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = SecretKey::load(key)?;
+    let mut failed = 0;
+    for input in inputs {
+        let report = File::open(input)
+            .map_err(Into::into)
+            .and_then(|file| EncryptedReader::open(BufReader::new(file), &secret)?.verify());
+        match report {
+            Ok(report) if !report.complete && !allow_unfinished => {
+                println!("{}: FAILED: unfinished after {} chunks, see --allow-unfinished", input, report.chunks);
+                failed += 1;
+            }
+            Ok(report) => println!(
+                "{}: OK, {} chunks, {} bytes{}",
+                input,
+                report.chunks,
+                report.bytes,
+                if report.complete { "" } else { " (unfinished)" }
+            ),
+            Err(err) => {
+                println!("{}: FAILED: {}", input, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files failed verification", failed, inputs.len()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,647 @@
+//! Encrypted-at-rest container for recordings.
+//!
+//! Layout, all integers big-endian:
+//!
+//! ```text
+//! header   "SVSENC" | version u16 | ephemeral X25519 public key (32)
+//!          | wrap nonce (12) | wrapped data key (32 + 16 tag)
+//! chunk*   length u32 | AES-256-GCM ciphertext and tag, the last one marked
+//!          final
+//! index    length u32 | sealed list of (chunk offset u64, length u32)
+//! footer   index offset u64 | "SVSIDX\0\x01"
+//! ```
+//!
+//! The data key is wrapped with a key derived by HKDF-SHA256 from an
+//! ephemeral X25519 exchange with the recipient. Every chunk is sealed with
+//! the SHA-256 of the header and a final-chunk flag as associated data and a
+//! nonce made of its kind and number, so tampering with the header,
+//! reordering chunks or splicing chunks from another file all fail
+//! authentication, and a file cut short passes for complete only if the cut
+//! falls after its final chunk. The index makes any chunk reachable with one
+//! seek; a file without one is read by walking the chunks instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io::{Cursor, Read, Write};
+//! use streaming_crypto::{EncryptedReader, EncryptedWriter, SecretKey};
+//!
+//! let secret = SecretKey::generate();
+//! let mut writer = EncryptedWriter::new(Cursor::new(Vec::new()), &secret.public_key())?;
+//! writer.write_all(b"segment data")?;
+//! let file = writer.finish()?.into_inner();
+//!
+//! let mut reader = EncryptedReader::open(Cursor::new(file), &secret)?;
+//! let mut plain = String::new();
+//! reader.read_to_string(&mut plain)?;
+//! assert_eq!(plain, "segment data");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::keys::{PublicKey, SecretKey};
+use crate::CryptoError;
+
+const MAGIC: &[u8; 6] = b"SVSENC";
+const FOOTER_MAGIC: &[u8; 8] = b"SVSIDX\0\x01";
+const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 100;
+/// Header bytes covered by the key wrap tag: everything before the wrapped key
+const WRAP_AAD_LEN: usize = 52;
+const FOOTER_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const INDEX_ENTRY_LEN: usize = 12;
+
+const KEY_WRAP_INFO: &[u8] = b"streaming-crypto recording key wrap v1";
+
+const KIND_DATA: u8 = 0;
+const KIND_INDEX: u8 = 1;
+
+/// Associated data of a chunk: the header digest and the final-chunk flag
+fn chunk_aad(digest: &[u8; 32], last: bool) -> [u8; 33] {
+    let mut aad = [0u8; 33];
+    aad[..32].copy_from_slice(digest);
+    aad[32] = last as u8;
+    aad
+}
+
+/// Plaintext size of the chunks [`EncryptedWriter`] cuts by default
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk accepted when reading, to reject corrupt lengths before
+/// allocating
+const MAX_CHUNK_LEN: u32 = 64 * 1024 * 1024;
+
+fn nonce(kind: u8, number: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0] = kind;
+    nonce[4..].copy_from_slice(&number.to_be_bytes());
+    nonce
+}
+
+fn key_wrap_cipher(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> Result<Aes256Gcm, CryptoError> {
+    if shared.iter().all(|&byte| byte == 0) {
+        return Err(CryptoError::InvalidKey("low-order public key".to_string()));
+    }
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(&ephemeral.to_bytes());
+    salt[32..].copy_from_slice(&recipient.to_bytes());
+    let mut kek = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(KEY_WRAP_INFO, &mut kek)
+        .map_err(|_| CryptoError::InvalidKey("key derivation failed".to_string()))?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&kek)))
+}
+
+/// Location of one chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Chunk {
+    /// File offset of the chunk's length prefix
+    offset: u64,
+    /// Offset of the chunk's first byte in the plaintext
+    start: u64,
+    len: u32,
+}
+
+/// Encrypts a stream into the recording container.
+///
+/// Data written through [`Write`] is cut into chunks of the configured
+/// size; [`EncryptedWriter::write_chunk`] seals a chunk of its own, e.g.
+/// one media fragment. [`Write::flush`] seals whatever is buffered, so data
+/// reaches the disk promptly at the cost of a short chunk. Dropping the
+/// writer without [`EncryptedWriter::finish`] loses buffered data but
+/// leaves earlier chunks readable.
+pub struct EncryptedWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    digest: [u8; 32],
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    chunks: Vec<Chunk>,
+    position: u64,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    /// Generate a data key for `recipient` and write the header
+    pub fn new(mut inner: W, recipient: &PublicKey) -> Result<Self, CryptoError> {
+        let ephemeral = SecretKey::generate();
+        let ephemeral_public = ephemeral.public_key();
+        let wrap = key_wrap_cipher(&ephemeral.agree(recipient), &ephemeral_public, recipient)?;
+
+        let mut data_key = [0u8; 32];
+        OsRng.fill_bytes(&mut data_key);
+        let mut wrap_nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut wrap_nonce);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_be_bytes());
+        header.extend_from_slice(&ephemeral_public.to_bytes());
+        header.extend_from_slice(&wrap_nonce);
+        let wrapped = wrap
+            .encrypt(Nonce::from_slice(&wrap_nonce), Payload { msg: &data_key, aad: &header })
+            .map_err(|_| CryptoError::InvalidKey("key wrap failed".to_string()))?;
+        header.extend_from_slice(&wrapped);
+        inner.write_all(&header)?;
+
+        Ok(Self {
+            inner,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)),
+            digest: Sha256::digest(&header).into(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buffer: Vec::new(),
+            chunks: Vec::new(),
+            position: HEADER_LEN as u64,
+        })
+    }
+
+    /// Plaintext size of chunks cut from data written through [`Write`]
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size.max(1);
+        self
+    }
+
+    /// Seal buffered data, then `data` as a chunk of its own
+    pub fn write_chunk(&mut self, data: &[u8]) -> Result<(), CryptoError> {
+        self.seal_buffer()?;
+        self.seal(data, false)
+    }
+
+    /// Number of chunks written so far
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Seal buffered data as the final chunk, which may be empty, write the
+    /// index and footer and return the writer
+    pub fn finish(mut self) -> Result<W, CryptoError> {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.seal(&buffer, true)?;
+
+        let mut index = Vec::with_capacity(self.chunks.len() * INDEX_ENTRY_LEN);
+        for chunk in &self.chunks {
+            index.extend_from_slice(&chunk.offset.to_be_bytes());
+            index.extend_from_slice(&chunk.len.to_be_bytes());
+        }
+        let index_offset = self.position;
+        let sealed = self.encrypt(KIND_INDEX, self.chunks.len() as u64, &index, true)?;
+        self.inner.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&sealed)?;
+        self.inner.write_all(&index_offset.to_be_bytes())?;
+        self.inner.write_all(FOOTER_MAGIC)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn seal_buffer(&mut self) -> Result<(), CryptoError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let buffer = std::mem::take(&mut self.buffer);
+        self.seal(&buffer, false)
+    }
+
+    fn seal(&mut self, data: &[u8], last: bool) -> Result<(), CryptoError> {
+        if data.is_empty() && !last {
+            return Ok(());
+        }
+        let len = u32::try_from(data.len())
+            .ok()
+            .filter(|&len| len <= MAX_CHUNK_LEN - TAG_LEN as u32)
+            .ok_or_else(|| CryptoError::InvalidFormat(format!("chunk of {} bytes is too large", data.len())))?;
+        let sealed = self.encrypt(KIND_DATA, self.chunks.len() as u64, data, last)?;
+        self.inner.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&sealed)?;
+
+        let start = self.chunks.last().map_or(0, |chunk| chunk.start + chunk.len as u64);
+        self.chunks.push(Chunk { offset: self.position, start, len });
+        self.position += 4 + sealed.len() as u64;
+        Ok(())
+    }
+
+    fn encrypt(&self, kind: u8, number: u64, data: &[u8], last: bool) -> Result<Vec<u8>, CryptoError> {
+        let nonce = nonce(kind, number);
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &chunk_aad(&self.digest, last) })
+            .map_err(|_| CryptoError::InvalidFormat("encryption failed".to_string()))
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() >= self.chunk_size {
+            let rest = self.buffer.split_off(self.chunk_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            self.seal(&chunk, false).map_err(into_io)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.seal_buffer().map_err(into_io)?;
+        self.inner.flush()
+    }
+}
+
+/// Result of [`EncryptedReader::verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub chunks: usize,
+
+    /// Plaintext bytes
+    pub bytes: u64,
+
+    /// Whether the file ends with its final chunk
+    pub complete: bool,
+}
+
+/// Decrypts a recording container, with random access by chunk or through
+/// [`Read`] and [`Seek`] on the plaintext.
+pub struct EncryptedReader<R: Read + Seek> {
+    inner: R,
+    cipher: Aes256Gcm,
+    digest: [u8; 32],
+    chunks: Vec<Chunk>,
+    complete: bool,
+    position: u64,
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> EncryptedReader<R> {
+    /// Unwrap the data key with `secret` and load the chunk index.
+    ///
+    /// # Errors
+    ///
+    /// [`CryptoError::Authentication`] if `secret` is not the recipient or
+    /// the header or index was modified, and
+    /// [`CryptoError::InvalidFormat`] if the file is not a recording
+    /// container.
+    pub fn open(mut inner: R, secret: &SecretKey) -> Result<Self, CryptoError> {
+        inner.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => CryptoError::InvalidFormat("truncated header".to_string()),
+            _ => err.into(),
+        })?;
+        if &header[..6] != MAGIC {
+            return Err(CryptoError::InvalidFormat("not an encrypted recording".to_string()));
+        }
+        let version = u16::from_be_bytes([header[6], header[7]]);
+        if version != VERSION {
+            return Err(CryptoError::UnsupportedVersion(version));
+        }
+
+        let ephemeral = PublicKey::from_bytes(header[8..40].try_into().unwrap_or_default());
+        let wrap = key_wrap_cipher(&secret.agree(&ephemeral), &ephemeral, &secret.public_key())?;
+        let data_key = wrap
+            .decrypt(
+                Nonce::from_slice(&header[40..52]),
+                Payload { msg: &header[WRAP_AAD_LEN..], aad: &header[..WRAP_AAD_LEN] },
+            )
+            .map_err(|_| CryptoError::Authentication("data key; wrong secret key?".to_string()))?;
+
+        let mut reader = Self {
+            inner,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)),
+            digest: Sha256::digest(header).into(),
+            chunks: Vec::new(),
+            complete: false,
+            position: 0,
+            cached: None,
+        };
+        match reader.read_footer()? {
+            Some(index_offset) => {
+                reader.load_index(index_offset)?;
+                reader.complete = true;
+            }
+            None => {
+                reader.scan()?;
+                // A cut inside the footer leaves the whole index behind the
+                // final chunk
+                let last = reader.chunks.len().checked_sub(1);
+                if last.is_some_and(|last| reader.seals_last(KIND_INDEX, last)) {
+                    reader.chunks.pop();
+                }
+                // The index may be lost after the final chunk was written
+                let last = reader.chunks.len().checked_sub(1);
+                reader.complete = last.is_some_and(|last| reader.seals_last(KIND_DATA, last));
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Whether the file ends with its final chunk; an unfinished one still
+    /// yields the chunks written before it was cut off
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Plaintext length
+    pub fn len(&self) -> u64 {
+        self.chunks.last().map_or(0, |chunk| chunk.start + chunk.len as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decrypt chunk `number`
+    pub fn read_chunk(&mut self, number: usize) -> Result<Vec<u8>, CryptoError> {
+        let chunk = *self
+            .chunks
+            .get(number)
+            .ok_or_else(|| CryptoError::InvalidFormat(format!("no chunk {}", number)))?;
+        let sealed = self.read_record(chunk.offset)?;
+        if sealed.len() != chunk.len as usize + TAG_LEN {
+            return Err(CryptoError::InvalidFormat(format!("chunk {} does not match the index", number)));
+        }
+        let last = self.complete && number + 1 == self.chunks.len();
+        self.decrypt(KIND_DATA, number as u64, &sealed, last)
+            .map_err(|_| CryptoError::Authentication(format!("chunk {}", number)))
+    }
+
+    /// Decrypt every chunk, failing on the first that does not authenticate
+    pub fn verify(&mut self) -> Result<VerifyReport, CryptoError> {
+        for number in 0..self.chunks.len() {
+            self.read_chunk(number)?;
+        }
+        Ok(VerifyReport {
+            chunks: self.chunks.len(),
+            bytes: self.len(),
+            complete: self.complete,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Offset of the index, if the file ends with a footer
+    fn read_footer(&mut self) -> Result<Option<u64>, CryptoError> {
+        let end = self.inner.seek(SeekFrom::End(0))?;
+        if end < (HEADER_LEN + FOOTER_LEN) as u64 {
+            return Ok(None);
+        }
+        self.inner.seek(SeekFrom::Start(end - FOOTER_LEN as u64))?;
+        let mut footer = [0u8; FOOTER_LEN];
+        self.inner.read_exact(&mut footer)?;
+        if &footer[8..] != FOOTER_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u64::from_be_bytes(footer[..8].try_into().unwrap_or_default())))
+    }
+
+    fn load_index(&mut self, offset: u64) -> Result<(), CryptoError> {
+        let sealed = self.read_record(offset)?;
+        let count = (sealed.len().saturating_sub(TAG_LEN) / INDEX_ENTRY_LEN) as u64;
+        let index = self
+            .decrypt(KIND_INDEX, count, &sealed, true)
+            .map_err(|_| CryptoError::Authentication("chunk index".to_string()))?;
+
+        let mut start = 0;
+        for entry in index.chunks_exact(INDEX_ENTRY_LEN) {
+            let offset = u64::from_be_bytes(entry[..8].try_into().unwrap_or_default());
+            let len = u32::from_be_bytes(entry[8..].try_into().unwrap_or_default());
+            self.chunks.push(Chunk { offset, start, len });
+            start += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the index of an unfinished file from the chunk lengths,
+    /// stopping at the first incomplete chunk
+    fn scan(&mut self) -> Result<(), CryptoError> {
+        let end = self.inner.seek(SeekFrom::End(0))?;
+        let mut offset = HEADER_LEN as u64;
+        let mut start = 0;
+        while offset + 4 <= end {
+            self.inner.seek(SeekFrom::Start(offset))?;
+            let mut length = [0u8; 4];
+            self.inner.read_exact(&mut length)?;
+            let length = u32::from_be_bytes(length);
+            if length < TAG_LEN as u32 || length > MAX_CHUNK_LEN || offset + 4 + length as u64 > end {
+                break;
+            }
+            let len = length - TAG_LEN as u32;
+            self.chunks.push(Chunk { offset, start, len });
+            start += len as u64;
+            offset += 4 + length as u64;
+        }
+        Ok(())
+    }
+
+    /// Whether scanned record `number` authenticates as the final one of
+    /// `kind`
+    fn seals_last(&mut self, kind: u8, number: usize) -> bool {
+        let Some(chunk) = self.chunks.get(number).copied() else {
+            return false;
+        };
+        self.read_record(chunk.offset)
+            .is_ok_and(|sealed| self.decrypt(kind, number as u64, &sealed, true).is_ok())
+    }
+
+    fn read_record(&mut self, offset: u64) -> Result<Vec<u8>, CryptoError> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut length = [0u8; 4];
+        self.inner.read_exact(&mut length)?;
+        let length = u32::from_be_bytes(length);
+        if !(TAG_LEN as u32..=MAX_CHUNK_LEN).contains(&length) {
+            return Err(CryptoError::InvalidFormat(format!("chunk length {} at offset {}", length, offset)));
+        }
+        let mut sealed = vec![0u8; length as usize];
+        self.inner.read_exact(&mut sealed)?;
+        Ok(sealed)
+    }
+
+    fn decrypt(&self, kind: u8, number: u64, sealed: &[u8], last: bool) -> Result<Vec<u8>, aes_gcm::Error> {
+        let nonce = nonce(kind, number);
+        self.cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: sealed, aad: &chunk_aad(&self.digest, last) })
+    }
+}
+
+impl<R: Read + Seek> Read for EncryptedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len() {
+            return Ok(0);
+        }
+        let number = self.chunks.partition_point(|chunk| chunk.start + chunk.len as u64 <= self.position);
+        if self.cached.as_ref().map(|(cached, _)| *cached) != Some(number) {
+            let data = self.read_chunk(number).map_err(into_io)?;
+            self.cached = Some((number, data));
+        }
+        let Some((_, data)) = &self.cached else {
+            return Ok(0);
+        };
+        let skip = (self.position - self.chunks[number].start) as usize;
+        let count = buf.len().min(data.len() - skip);
+        buf[..count].copy_from_slice(&data[skip..skip + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<R: Read + Seek> Seek for EncryptedReader<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position =
+            target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the stream"))?;
+        Ok(self.position)
+    }
+}
+
+fn into_io(err: CryptoError) -> io::Error {
+    match err {
+        CryptoError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn encrypt(data: &[u8], recipient: &PublicKey) -> Vec<u8> {
+        let mut writer = EncryptedWriter::new(Cursor::new(Vec::new()), recipient).unwrap().chunk_size(4096);
+        writer.write_all(&data[..10_000]).unwrap();
+        writer.write_chunk(&data[10_000..10_500]).unwrap();
+        writer.write_all(&data[10_500..]).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn round_trip_with_random_access() {
+        let secret = SecretKey::generate();
+        let data = payload(20_000);
+        let file = encrypt(&data, &secret.public_key());
+
+        let mut reader = EncryptedReader::open(Cursor::new(file), &secret).unwrap();
+        // 4096 + 4096 + 1808 buffered, the explicit chunk, then
+        // 4096 + 4096 + 1308 from the rest
+        assert_eq!(reader.chunk_count(), 7);
+        assert_eq!(reader.read_chunk(3).unwrap(), data[10_000..10_500]);
+        assert_eq!(reader.verify().unwrap(), VerifyReport { chunks: 7, bytes: 20_000, complete: true });
+
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, data);
+
+        let mut middle = [0u8; 300];
+        reader.seek(SeekFrom::Start(8_000)).unwrap();
+        reader.read_exact(&mut middle).unwrap();
+        assert_eq!(middle, data[8_000..8_300]);
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        plain.clear();
+        reader.read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, data[19_990..]);
+    }
+
+    #[test]
+    fn tampering_and_wrong_keys_are_detected() {
+        let secret = SecretKey::generate();
+        let file = encrypt(&payload(20_000), &secret.public_key());
+
+        let wrong = SecretKey::generate();
+        assert!(matches!(
+            EncryptedReader::open(Cursor::new(file.clone()), &wrong),
+            Err(CryptoError::Authentication(_))
+        ));
+
+        // A flipped bit in the header breaks the key wrap
+        let mut header = file.clone();
+        header[20] ^= 1;
+        assert!(EncryptedReader::open(Cursor::new(header), &secret).is_err());
+
+        // ... and in a chunk, that chunk only
+        let mut body = file.clone();
+        body[HEADER_LEN + 4 + 100] ^= 1;
+        let mut reader = EncryptedReader::open(Cursor::new(body), &secret).unwrap();
+        assert!(matches!(reader.read_chunk(0), Err(CryptoError::Authentication(_))));
+        assert!(reader.read_chunk(1).is_ok());
+        assert!(reader.verify().is_err());
+
+        // Swapping two equal-sized chunks fails on the nonce
+        let chunk = 4 + 4096 + TAG_LEN;
+        let mut swapped = file.clone();
+        let (first, second) = swapped[HEADER_LEN..HEADER_LEN + 2 * chunk].split_at_mut(chunk);
+        first.swap_with_slice(second);
+        let mut reader = EncryptedReader::open(Cursor::new(swapped), &secret).unwrap();
+        assert!(reader.read_chunk(0).is_err());
+        assert!(reader.read_chunk(1).is_err());
+        assert!(reader.read_chunk(2).is_ok());
+    }
+
+    #[test]
+    fn unfinished_file_is_readable_up_to_the_cut() {
+        let secret = SecretKey::generate();
+        let data = payload(20_000);
+        let mut file = Vec::new();
+        let mut writer = EncryptedWriter::new(Cursor::new(&mut file), &secret.public_key()).unwrap().chunk_size(4096);
+        writer.write_all(&data).unwrap();
+        // Power lost: four chunks sealed, the rest still buffered
+        drop(writer);
+        file.truncate(file.len() - 10);
+
+        let mut reader = EncryptedReader::open(Cursor::new(&file), &secret).unwrap();
+        assert!(!reader.is_complete());
+        assert_eq!(reader.chunk_count(), 3);
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, data[..3 * 4096]);
+        assert_eq!(reader.verify().unwrap(), VerifyReport { chunks: 3, bytes: 3 * 4096, complete: false });
+    }
+
+    #[test]
+    fn cut_files_are_complete_only_with_the_final_chunk() {
+        let secret = SecretKey::generate();
+        let data = payload(20_000);
+        let file = encrypt(&data, &secret.public_key());
+        let index_offset = u64::from_be_bytes(file[file.len() - FOOTER_LEN..][..8].try_into().unwrap()) as usize;
+
+        // Losing only the index keeps the file complete
+        let mut reader = EncryptedReader::open(Cursor::new(&file[..index_offset]), &secret).unwrap();
+        assert_eq!(reader.verify().unwrap(), VerifyReport { chunks: 7, bytes: 20_000, complete: true });
+
+        // ... as does a cut inside the footer
+        for cut in [1, 8, FOOTER_LEN] {
+            let mut reader = EncryptedReader::open(Cursor::new(&file[..file.len() - cut]), &secret).unwrap();
+            assert_eq!(reader.verify().unwrap(), VerifyReport { chunks: 7, bytes: 20_000, complete: true });
+            let mut plain = Vec::new();
+            reader.read_to_end(&mut plain).unwrap();
+            assert_eq!(plain, data);
+        }
+
+        // Cutting off the final chunk as well does not
+        let last = reader.chunks[6].offset as usize;
+        let mut reader = EncryptedReader::open(Cursor::new(&file[..last]), &secret).unwrap();
+        assert_eq!(reader.verify().unwrap(), VerifyReport { chunks: 6, bytes: 20_000 - 1308, complete: false });
+
+        // An explicit chunk at the end is followed by an empty final one
+        let mut writer = EncryptedWriter::new(Cursor::new(Vec::new()), &secret.public_key()).unwrap();
+        writer.write_chunk(b"fragment").unwrap();
+        let file = writer.finish().unwrap().into_inner();
+        let mut reader = EncryptedReader::open(Cursor::new(file), &secret).unwrap();
+        assert_eq!(reader.verify().unwrap(), VerifyReport { chunks: 2, bytes: 8, complete: true });
+    }
+}
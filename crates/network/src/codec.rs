@@ -0,0 +1,173 @@
+//! [`Message`] framing for tokio streams.
+//!
+//! [`WireCodec`] plugs the wire format into `tokio_util::codec`, so a TCP or
+//! Unix socket becomes a `Stream` and `Sink` of messages through
+//! `Framed::new(socket, WireCodec::new())`. It caps the frame length it
+//! accepts, so a corrupt or hostile length prefix cannot make it buffer
+//! gigabytes before failing.
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::wire::{Message, WireError, LENGTH_SIZE};
+
+/// Frame length [`WireCodec::new`] accepts: room for a 4K keyframe
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Encodes and decodes length-prefixed [`Message`] frames.
+///
+/// A decode error leaves the stream out of step with the framing, so the
+/// connection should be closed; `Framed` ends the stream after one anyway.
+#[derive(Debug, Clone)]
+pub struct WireCodec {
+    max_frame_len: usize,
+}
+
+impl WireCodec {
+    pub fn new() -> Self {
+        Self { max_frame_len: DEFAULT_MAX_FRAME_LEN }
+    }
+
+    /// Longest frame to send or accept, length prefix excluded
+    pub fn max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+}
+
+impl Default for WireCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for WireCodec {
+    type Item = Message;
+    type Error = WireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, WireError> {
+        let Some(prefix) = src.get(..LENGTH_SIZE) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(prefix.try_into().expect("prefix is four bytes")) as usize;
+        if length > self.max_frame_len {
+            return Err(WireError::FrameTooLarge { length, limit: self.max_frame_len });
+        }
+        if src.len() < LENGTH_SIZE + length {
+            src.reserve(LENGTH_SIZE + length - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_SIZE);
+        Message::parse(src.split_to(length).freeze()).map(Some)
+    }
+}
+
+impl Encoder<Message> for WireCodec {
+    type Error = WireError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), WireError> {
+        Encoder::<&Message>::encode(self, &message, dst)
+    }
+}
+
+impl Encoder<&Message> for WireCodec {
+    type Error = WireError;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), WireError> {
+        let start = dst.len();
+        message.encode(dst)?;
+        let length = dst.len() - start - LENGTH_SIZE;
+        if length > self.max_frame_len {
+            dst.truncate(start);
+            return Err(WireError::FrameTooLarge { length, limit: self.max_frame_len });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::tests::{noise, samples};
+    use crate::wire::MediaPacket;
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    #[test]
+    fn decodes_frames_split_anywhere() {
+        let messages = samples();
+        let mut codec = WireCodec::new();
+        let mut stream = BytesMut::new();
+        for message in &messages {
+            codec.encode(message, &mut stream).unwrap();
+        }
+
+        // Fed a byte at a time, then in uneven chunks
+        for chunk in [1, 7, 64, stream.len()] {
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            for piece in stream.chunks(chunk) {
+                src.extend_from_slice(piece);
+                while let Some(message) = codec.decode(&mut src).unwrap() {
+                    decoded.push(message);
+                }
+            }
+            assert_eq!(decoded, messages);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn enforces_frame_limit() {
+        let mut codec = WireCodec::new().max_frame_len(1024);
+        let large = Message::Media(MediaPacket::new(1, 0, Bytes::from(vec![0; 2000])));
+        let mut dst = BytesMut::from(&b"kept"[..]);
+        assert!(matches!(codec.encode(&large, &mut dst), Err(WireError::FrameTooLarge { .. })));
+        assert_eq!(&dst[..], b"kept");
+
+        // A huge length prefix fails before anything is buffered
+        let mut src = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 1][..]);
+        let err = codec.decode(&mut src).unwrap_err();
+        assert!(matches!(err, WireError::FrameTooLarge { length: 0xffff_ffff, limit: 1024 }));
+    }
+
+    #[test]
+    fn survives_fuzzed_streams() {
+        let mut state = 0x853c_49e6_748f_ea9b;
+        let mut valid = BytesMut::new();
+        for message in samples() {
+            WireCodec::new().encode(message, &mut valid).unwrap();
+        }
+        for _ in 0..2000 {
+            let mut data = valid.clone();
+            for _ in 0..1 + noise(&mut state) % 8 {
+                let at = (noise(&mut state) % data.len() as u64) as usize;
+                data[at] = noise(&mut state) as u8;
+            }
+            // Decode until the data runs out or fails; neither may panic
+            let mut codec = WireCodec::new().max_frame_len(4096);
+            while let Ok(Some(_)) = codec.decode(&mut data) {}
+        }
+    }
+
+    #[tokio::test]
+    async fn carries_messages_over_a_socket() {
+        let (client, server) = tokio::io::duplex(256);
+        let messages = samples();
+        let sent = messages.clone();
+        let writer = tokio::spawn(async move {
+            let mut framed = FramedWrite::new(client, WireCodec::new());
+            for message in sent {
+                framed.send(message).await.unwrap();
+            }
+        });
+
+        let received: Vec<_> = FramedRead::new(server, WireCodec::new())
+            .map(|message| message.unwrap())
+            .collect()
+            .await;
+        writer.await.unwrap();
+        assert_eq!(received, messages);
+    }
+}
@@ -0,0 +1,213 @@
+//! Splitting access units across media messages and putting them back.
+//!
+//! Keyframes easily run to hundreds of kilobytes, more than a datagram or a
+//! fair share of a multiplexed connection. [`MediaPacket::split`] cuts one
+//! into fragments numbered within its sequence, and a [`Reassembler`] on the
+//! receiving side joins them once all have arrived. Fragments may arrive in
+//! any order, but a stream's access units are expected in sequence: a
+//! fragment of a newer access unit abandons the incomplete older one.
+
+use std::collections::HashMap;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::wire::{MediaPacket, WireError};
+
+impl MediaPacket {
+    /// Cut the payload into fragments of at most `max_payload` bytes.
+    ///
+    /// The first fragment keeps the metadata; every fragment keeps the
+    /// stream, sequence, timing and keyframe flag. An empty payload still
+    /// makes one fragment.
+    ///
+    /// # Errors
+    ///
+    /// [`WireError::TooManyFragments`] if that needs more than 65535
+    /// fragments.
+    pub fn split(self, max_payload: usize) -> Result<Vec<MediaPacket>, WireError> {
+        let max_payload = max_payload.max(1);
+        let count = self.payload.len().div_ceil(max_payload).max(1);
+        let count = u16::try_from(count).map_err(|_| WireError::TooManyFragments(count))?;
+
+        let mut fragments = Vec::with_capacity(count as usize);
+        let mut rest = self.payload.clone();
+        for index in 0..count {
+            let payload = rest.split_to(rest.len().min(max_payload));
+            let metadata = if index == 0 { self.metadata.clone() } else { Default::default() };
+            fragments.push(MediaPacket { fragment: index, fragment_count: count, metadata, payload, ..self.clone() });
+        }
+        Ok(fragments)
+    }
+}
+
+struct Partial {
+    sequence: u64,
+    first: Option<MediaPacket>,
+    parts: Vec<Option<Bytes>>,
+    received: usize,
+}
+
+/// Joins fragmented [`MediaPacket`]s back into whole access units.
+///
+/// # Examples
+///
+/// ```
+/// use bytes::Bytes;
+/// use streaming_network::wire::MediaPacket;
+/// use streaming_network::Reassembler;
+///
+/// let packet = MediaPacket::new(1, 0, Bytes::from(vec![7; 3000]));
+/// let mut reassembler = Reassembler::new();
+/// let mut whole = None;
+/// for fragment in packet.clone().split(1200)? {
+///     whole = reassembler.push(fragment);
+/// }
+/// assert_eq!(whole, Some(packet));
+/// # Ok::<(), streaming_network::WireError>(())
+/// ```
+#[derive(Default)]
+pub struct Reassembler {
+    /// Access unit being collected, per stream
+    partial: HashMap<u32, Partial>,
+    /// Newest sequence seen, per stream
+    newest: HashMap<u32, u64>,
+    dropped: u64,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add one fragment, returning the whole access unit once it completes.
+    ///
+    /// Whole packets pass straight through. Duplicates, fragments of older
+    /// access units and fragments disagreeing with their siblings on the
+    /// fragment count are ignored.
+    pub fn push(&mut self, packet: MediaPacket) -> Option<MediaPacket> {
+        let stream = packet.stream_id;
+        let newest = self.newest.get(&stream).copied();
+        if newest.is_none_or(|newest| packet.sequence > newest) {
+            self.newest.insert(stream, packet.sequence);
+            if self.partial.remove(&stream).is_some() {
+                self.dropped += 1;
+            }
+            if packet.fragment_count > 1 {
+                self.partial.insert(stream, Partial {
+                    sequence: packet.sequence,
+                    first: None,
+                    parts: vec![None; packet.fragment_count as usize],
+                    received: 0,
+                });
+            }
+        }
+        if packet.fragment_count <= 1 {
+            return Some(packet);
+        }
+
+        let partial = self.partial.get_mut(&stream).filter(|partial| partial.sequence == packet.sequence)?;
+        let index = packet.fragment as usize;
+        if partial.parts.len() != packet.fragment_count as usize || partial.parts.get(index)?.is_some() {
+            return None;
+        }
+        partial.parts[index] = Some(packet.payload.clone());
+        partial.received += 1;
+        if index == 0 {
+            partial.first = Some(packet);
+        }
+        if partial.received < partial.parts.len() {
+            return None;
+        }
+
+        let partial = self.partial.remove(&stream)?;
+        let mut payload = BytesMut::new();
+        for part in partial.parts.into_iter().flatten() {
+            payload.extend_from_slice(&part);
+        }
+        Some(MediaPacket { fragment: 0, fragment_count: 1, payload: payload.freeze(), ..partial.first? })
+    }
+
+    /// Access units abandoned incomplete so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::tests::noise;
+    use crate::wire::Message;
+    use std::time::Duration;
+    use streaming_core::Metadata;
+
+    fn packet(stream_id: u32, sequence: u64, len: usize) -> MediaPacket {
+        let payload: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut metadata = Metadata::new();
+        metadata.insert("sequence", sequence as i64);
+        MediaPacket::new(stream_id, sequence, Bytes::from(payload))
+            .pts(Duration::from_millis(40 * sequence))
+            .keyframe(sequence == 0)
+            .metadata(metadata)
+    }
+
+    #[test]
+    fn splits_and_reassembles_out_of_order() {
+        let whole = packet(3, 0, 2500);
+        let mut fragments = whole.clone().split(1000).unwrap();
+        assert_eq!(fragments.iter().map(|f| f.payload.len()).collect::<Vec<_>>(), [1000, 1000, 500]);
+        assert!(fragments.iter().all(|f| f.fragment_count == 3 && f.keyframe && f.sequence == 0));
+        assert!(!fragments[0].metadata.is_empty() && fragments[1].metadata.is_empty());
+
+        // Fragments of another stream interleave freely
+        let other = packet(4, 9, 10);
+        let mut reassembler = Reassembler::new();
+        fragments.swap(0, 2);
+        assert_eq!(reassembler.push(fragments[0].clone()), None);
+        assert_eq!(reassembler.push(other.clone()), Some(other));
+        assert_eq!(reassembler.push(fragments[1].clone()), None);
+        assert_eq!(reassembler.push(fragments[1].clone()), None);
+        assert_eq!(reassembler.push(fragments[2].clone()), Some(whole));
+
+        let empty = MediaPacket::new(1, 0, Bytes::new()).split(100).unwrap();
+        assert_eq!(empty.len(), 1);
+        assert!(MediaPacket::new(1, 0, Bytes::from(vec![0; 70_000])).split(1).is_err());
+    }
+
+    #[test]
+    fn abandons_incomplete_access_units() {
+        let mut reassembler = Reassembler::new();
+        let first = packet(1, 1, 300).split(100).unwrap();
+        let second = packet(1, 2, 300).split(100).unwrap();
+        reassembler.push(first[0].clone());
+        reassembler.push(first[1].clone());
+        for fragment in &second[..2] {
+            assert_eq!(reassembler.push(fragment.clone()), None);
+        }
+        // The straggler of the abandoned unit cannot revive it
+        assert_eq!(reassembler.push(first[2].clone()), None);
+        assert_eq!(reassembler.push(second[2].clone()), Some(packet(1, 2, 300)));
+        assert_eq!(reassembler.dropped(), 1);
+    }
+
+    #[test]
+    fn survives_fuzzed_fragments() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        let mut reassembler = Reassembler::new();
+        for round in 0..5000 {
+            let count = 1 + (noise(&mut state) % 5) as u16;
+            let packet = MediaPacket {
+                stream_id: (noise(&mut state) % 3) as u32,
+                sequence: round / 4 + noise(&mut state) % 4,
+                fragment: (noise(&mut state) % (count as u64 + 1)) as u16,
+                fragment_count: count,
+                ..packet(0, 0, (noise(&mut state) % 64) as usize)
+            };
+            // Whatever comes out is whole and encodes
+            if let Some(whole) = reassembler.push(packet) {
+                assert_eq!(whole.fragment_count, 1);
+                Message::Media(whole).to_bytes().unwrap();
+            }
+        }
+    }
+}
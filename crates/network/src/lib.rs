@@ -1 +1,16 @@
-// Network library placeholder
+//! Transport of media and control messages between processes.
+//!
+//! [`wire`] defines the versioned, length-prefixed binary format: media
+//! packets carrying encoded access units with their metadata, and the
+//! control messages peers negotiate with (hello, capabilities, configure,
+//! keyframe requests and statistics). [`WireCodec`] frames those messages
+//! on tokio streams, and [`Reassembler`] joins access units that were
+//! split with [`MediaPacket::split`] to fit a transport.
+
+pub mod codec;
+pub mod fragment;
+pub mod wire;
+
+pub use codec::WireCodec;
+pub use fragment::Reassembler;
+pub use wire::{Capabilities, Configure, Hello, MediaPacket, Message, Stats, WireError};
@@ -0,0 +1,644 @@
+//! Binary framing of the messages exchanged between processes.
+//!
+//! Every message travels as one frame:
+//!
+//! ```text
+//! length:  u32   bytes after this field
+//! version: u8    (2)
+//! kind:    u8
+//! body:    length - 2 bytes, laid out per kind
+//! ```
+//!
+//! Bodies by kind:
+//!
+//! ```text
+//! 0 hello:            version u8, name str
+//! 1 capabilities:     codec count u8, codec str each,
+//!                     max width u32, max height u32, max fps u32, max bitrate u32
+//! 2 configure:        stream u32, codec str, width u32, height u32, fps u32,
+//!                     bitrate u32, keyframe interval u32
+//! 3 keyframe request: stream u32
+//! 4 stats:            stream u32, packets received u64, packets lost u64,
+//!                     bytes received u64, jitter u32 µs, round trip u32 µs
+//! 5 media:            stream u32, sequence u64, codec str, pts u64 µs,
+//!                     dts u64 µs, duration u32 µs, capture time u64 µs,
+//!                     flags u8, fragment u16, fragment count u16,
+//!                     if flags & 2: metadata length u32, metadata,
+//!                     payload (the rest)
+//! ```
+//!
+//! Integers are big-endian and `str` is a u8 length followed by UTF-8.
+//! Capture times count from the Unix epoch.
+//! Media flags are 1 for a keyframe and 2 when [`Metadata`] follows, in the
+//! form [`Metadata::to_bytes`] writes. Readers reject unknown versions,
+//! kinds and flags, so any extension needs a new version.
+//!
+//! # Examples
+//!
+//! ```
+//! use streaming_network::wire::{Message, MediaPacket};
+//!
+//! let packet = MediaPacket::new(1, 42, bytes::Bytes::from_static(b"access unit")).keyframe(true);
+//! let bytes = Message::Media(packet.clone()).to_bytes()?;
+//! assert_eq!(Message::from_bytes(&bytes)?, Message::Media(packet));
+//! # Ok::<(), streaming_network::WireError>(())
+//! ```
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use streaming_core::metadata::MetadataError;
+use streaming_core::Metadata;
+use thiserror::Error;
+
+/// Version written in, and the only one accepted from, frame headers
+pub const VERSION: u8 = 2;
+
+/// Bytes of the length prefix
+pub const LENGTH_SIZE: usize = 4;
+
+const KIND_HELLO: u8 = 0;
+const KIND_CAPABILITIES: u8 = 1;
+const KIND_CONFIGURE: u8 = 2;
+const KIND_KEYFRAME_REQUEST: u8 = 3;
+const KIND_STATS: u8 = 4;
+const KIND_MEDIA: u8 = 5;
+
+const FLAG_KEYFRAME: u8 = 1;
+const FLAG_METADATA: u8 = 2;
+
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error("Message ends before its last field")]
+    Truncated,
+
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Unknown message kind {0}")]
+    UnknownKind(u8),
+
+    #[error("Unknown media flags {0:#04x}")]
+    UnknownFlags(u8),
+
+    #[error("Fragment {index} of a packet in {count} fragments")]
+    InvalidFragment { index: u16, count: u16 },
+
+    #[error("Payload needs {0} fragments, more than 65535")]
+    TooManyFragments(usize),
+
+    #[error("Message text is not valid UTF-8")]
+    InvalidUtf8,
+
+    #[error("Message has {0} unused bytes at the end")]
+    TrailingBytes(usize),
+
+    #[error("Message of {length} bytes exceeds the {limit}-byte limit")]
+    FrameTooLarge { length: usize, limit: usize },
+
+    #[error("Metadata error: {0}")]
+    Metadata(#[from] MetadataError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// First message each side sends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// Highest protocol version the sender speaks
+    pub version: u8,
+    /// Name of the sending process, for logs
+    pub name: String,
+}
+
+/// What a sender can produce or a receiver can take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Codec names such as `"h264"`, in order of preference
+    pub codecs: Vec<String>,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_fps: u32,
+    pub max_bitrate: u32,
+}
+
+/// Settings for one stream, announced by its sender before its media.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Configure {
+    pub stream_id: u32,
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub bitrate: u32,
+    pub keyframe_interval: u32,
+}
+
+/// Reception statistics a receiver reports back for one stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub stream_id: u32,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub bytes_received: u64,
+    /// Travels in whole microseconds, saturating at about 71 minutes
+    pub jitter: Duration,
+    /// Travels in whole microseconds, saturating at about 71 minutes
+    pub round_trip: Duration,
+}
+
+/// One encoded access unit, or a fragment of one.
+///
+/// Large access units are split with [`MediaPacket::split`] and put back
+/// together by a [`Reassembler`](crate::Reassembler); only the first
+/// fragment carries the metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaPacket {
+    pub stream_id: u32,
+    /// Access unit number within the stream, shared by all its fragments
+    pub sequence: u64,
+    /// Codec name as in [`Configure::codec`], such as `"h264"`
+    pub codec: String,
+    /// Presentation time, travelling in whole microseconds
+    pub pts: Duration,
+    /// Decode time, travelling in whole microseconds
+    pub dts: Duration,
+    /// Travels in whole microseconds, saturating at about 71 minutes
+    pub duration: Duration,
+    /// Capture time of the source frame, travelling in whole microseconds
+    pub timestamp: SystemTime,
+    pub keyframe: bool,
+    /// Index of this fragment, below `fragment_count`
+    pub fragment: u16,
+    pub fragment_count: u16,
+    pub metadata: Metadata,
+    pub payload: Bytes,
+}
+
+impl MediaPacket {
+    /// A whole access unit with no codec, timing, metadata or keyframe flag
+    /// set
+    pub fn new(stream_id: u32, sequence: u64, payload: Bytes) -> Self {
+        Self {
+            stream_id,
+            sequence,
+            codec: String::new(),
+            pts: Duration::ZERO,
+            dts: Duration::ZERO,
+            duration: Duration::ZERO,
+            timestamp: UNIX_EPOCH,
+            keyframe: false,
+            fragment: 0,
+            fragment_count: 1,
+            metadata: Metadata::new(),
+            payload,
+        }
+    }
+
+    pub fn codec(mut self, codec: impl Into<String>) -> Self {
+        self.codec = codec.into();
+        self
+    }
+
+    pub fn pts(mut self, pts: Duration) -> Self {
+        self.pts = pts;
+        self
+    }
+
+    pub fn dts(mut self, dts: Duration) -> Self {
+        self.dts = dts;
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: SystemTime) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn keyframe(mut self, keyframe: bool) -> Self {
+        self.keyframe = keyframe;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+/// A protocol message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Hello(Hello),
+    Capabilities(Capabilities),
+    Configure(Configure),
+    /// Ask the sender of a stream for a keyframe, e.g. after loss
+    KeyframeRequest { stream_id: u32 },
+    Stats(Stats),
+    Media(MediaPacket),
+}
+
+impl Message {
+    /// Append the framed message to `out`.
+    ///
+    /// Strings are cut to 255 bytes at a character boundary, and codec
+    /// lists to 255 entries.
+    ///
+    /// # Errors
+    ///
+    /// [`WireError::FrameTooLarge`] if the frame would not fit the u32
+    /// length prefix; `out` is left as it was.
+    pub fn encode(&self, out: &mut BytesMut) -> Result<(), WireError> {
+        let start = out.len();
+        out.put_u32(0);
+        out.put_u8(VERSION);
+        match self {
+            Message::Hello(hello) => {
+                out.put_u8(KIND_HELLO);
+                out.put_u8(hello.version);
+                put_str(out, &hello.name);
+            }
+            Message::Capabilities(caps) => {
+                out.put_u8(KIND_CAPABILITIES);
+                let count = caps.codecs.len().min(u8::MAX as usize);
+                out.put_u8(count as u8);
+                for codec in &caps.codecs[..count] {
+                    put_str(out, codec);
+                }
+                out.put_u32(caps.max_width);
+                out.put_u32(caps.max_height);
+                out.put_u32(caps.max_fps);
+                out.put_u32(caps.max_bitrate);
+            }
+            Message::Configure(config) => {
+                out.put_u8(KIND_CONFIGURE);
+                out.put_u32(config.stream_id);
+                put_str(out, &config.codec);
+                out.put_u32(config.width);
+                out.put_u32(config.height);
+                out.put_u32(config.fps);
+                out.put_u32(config.bitrate);
+                out.put_u32(config.keyframe_interval);
+            }
+            Message::KeyframeRequest { stream_id } => {
+                out.put_u8(KIND_KEYFRAME_REQUEST);
+                out.put_u32(*stream_id);
+            }
+            Message::Stats(stats) => {
+                out.put_u8(KIND_STATS);
+                out.put_u32(stats.stream_id);
+                out.put_u64(stats.packets_received);
+                out.put_u64(stats.packets_lost);
+                out.put_u64(stats.bytes_received);
+                out.put_u32(stats.jitter.as_micros().min(u32::MAX as u128) as u32);
+                out.put_u32(stats.round_trip.as_micros().min(u32::MAX as u128) as u32);
+            }
+            Message::Media(packet) => {
+                out.put_u8(KIND_MEDIA);
+                out.put_u32(packet.stream_id);
+                out.put_u64(packet.sequence);
+                put_str(out, &packet.codec);
+                out.put_u64(packet.pts.as_micros().min(u64::MAX as u128) as u64);
+                out.put_u64(packet.dts.as_micros().min(u64::MAX as u128) as u64);
+                out.put_u32(packet.duration.as_micros().min(u32::MAX as u128) as u32);
+                let capture = packet.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+                out.put_u64(capture.as_micros().min(u64::MAX as u128) as u64);
+                let mut flags = 0;
+                if packet.keyframe {
+                    flags |= FLAG_KEYFRAME;
+                }
+                if !packet.metadata.is_empty() {
+                    flags |= FLAG_METADATA;
+                }
+                out.put_u8(flags);
+                out.put_u16(packet.fragment);
+                out.put_u16(packet.fragment_count);
+                if !packet.metadata.is_empty() {
+                    let metadata = packet.metadata.to_bytes();
+                    out.put_u32(metadata.len() as u32);
+                    out.put_slice(&metadata);
+                }
+                out.put_slice(&packet.payload);
+            }
+        }
+
+        let length = out.len() - start - LENGTH_SIZE;
+        let Ok(prefix) = u32::try_from(length) else {
+            out.truncate(start);
+            return Err(WireError::FrameTooLarge { length, limit: u32::MAX as usize });
+        };
+        out[start..start + LENGTH_SIZE].copy_from_slice(&prefix.to_be_bytes());
+        Ok(())
+    }
+
+    /// The framed message
+    pub fn to_bytes(&self) -> Result<Bytes, WireError> {
+        let mut out = BytesMut::new();
+        self.encode(&mut out)?;
+        Ok(out.freeze())
+    }
+
+    /// Parse exactly one frame, length prefix included.
+    ///
+    /// # Errors
+    ///
+    /// [`WireError::Truncated`] if `data` is shorter than the frame,
+    /// [`WireError::TrailingBytes`] if it is longer, and the other
+    /// [`WireError`]s for malformed frames.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, WireError> {
+        let mut reader = Reader(Bytes::copy_from_slice(data));
+        let length = reader.u32()? as usize;
+        match reader.0.len() {
+            len if len < length => Err(WireError::Truncated),
+            len if len > length => Err(WireError::TrailingBytes(len - length)),
+            _ => Self::parse(reader.0),
+        }
+    }
+
+    /// Parse a frame body: everything after the length prefix
+    pub(crate) fn parse(frame: Bytes) -> Result<Self, WireError> {
+        let mut reader = Reader(frame);
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(WireError::UnsupportedVersion(version));
+        }
+        let message = match reader.u8()? {
+            KIND_HELLO => Message::Hello(Hello { version: reader.u8()?, name: reader.str()? }),
+            KIND_CAPABILITIES => {
+                let count = reader.u8()?;
+                let codecs = (0..count).map(|_| reader.str()).collect::<Result<_, _>>()?;
+                Message::Capabilities(Capabilities {
+                    codecs,
+                    max_width: reader.u32()?,
+                    max_height: reader.u32()?,
+                    max_fps: reader.u32()?,
+                    max_bitrate: reader.u32()?,
+                })
+            }
+            KIND_CONFIGURE => Message::Configure(Configure {
+                stream_id: reader.u32()?,
+                codec: reader.str()?,
+                width: reader.u32()?,
+                height: reader.u32()?,
+                fps: reader.u32()?,
+                bitrate: reader.u32()?,
+                keyframe_interval: reader.u32()?,
+            }),
+            KIND_KEYFRAME_REQUEST => Message::KeyframeRequest { stream_id: reader.u32()? },
+            KIND_STATS => Message::Stats(Stats {
+                stream_id: reader.u32()?,
+                packets_received: reader.u64()?,
+                packets_lost: reader.u64()?,
+                bytes_received: reader.u64()?,
+                jitter: Duration::from_micros(reader.u32()? as u64),
+                round_trip: Duration::from_micros(reader.u32()? as u64),
+            }),
+            KIND_MEDIA => {
+                let stream_id = reader.u32()?;
+                let sequence = reader.u64()?;
+                let codec = reader.str()?;
+                let pts = Duration::from_micros(reader.u64()?);
+                let dts = Duration::from_micros(reader.u64()?);
+                let duration = Duration::from_micros(reader.u32()? as u64);
+                let timestamp = UNIX_EPOCH + Duration::from_micros(reader.u64()?);
+                let flags = reader.u8()?;
+                if flags & !(FLAG_KEYFRAME | FLAG_METADATA) != 0 {
+                    return Err(WireError::UnknownFlags(flags));
+                }
+                let (fragment, fragment_count) = (reader.u16()?, reader.u16()?);
+                if fragment >= fragment_count {
+                    return Err(WireError::InvalidFragment { index: fragment, count: fragment_count });
+                }
+                let metadata = match flags & FLAG_METADATA {
+                    0 => Metadata::new(),
+                    _ => {
+                        let len = reader.u32()? as usize;
+                        Metadata::from_bytes(&reader.take(len)?)?
+                    }
+                };
+                let payload = std::mem::take(&mut reader.0);
+                Message::Media(MediaPacket {
+                    stream_id,
+                    sequence,
+                    codec,
+                    pts,
+                    dts,
+                    duration,
+                    timestamp,
+                    keyframe: flags & FLAG_KEYFRAME != 0,
+                    fragment,
+                    fragment_count,
+                    metadata,
+                    payload,
+                })
+            }
+            kind => return Err(WireError::UnknownKind(kind)),
+        };
+        match reader.0.len() {
+            0 => Ok(message),
+            extra => Err(WireError::TrailingBytes(extra)),
+        }
+    }
+}
+
+fn put_str(out: &mut BytesMut, text: &str) {
+    let mut end = text.len().min(u8::MAX as usize);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    out.put_u8(end as u8);
+    out.put_slice(&text.as_bytes()[..end]);
+}
+
+/// Cursor over a frame that hands out payloads without copying
+struct Reader(Bytes);
+
+impl Reader {
+    fn take(&mut self, len: usize) -> Result<Bytes, WireError> {
+        if self.0.len() < len {
+            return Err(WireError::Truncated);
+        }
+        Ok(self.0.split_to(len))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        Ok(self.take(N)?[..].try_into().expect("took N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, WireError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, WireError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, WireError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, WireError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn str(&mut self) -> Result<String, WireError> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| WireError::InvalidUtf8)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use streaming_core::metadata::keys;
+    use streaming_core::Rect;
+
+    /// One of each message kind
+    pub(crate) fn samples() -> Vec<Message> {
+        let mut metadata = Metadata::new();
+        metadata.insert(keys::MOTION, vec![Rect::new(4, 8, 16, 32)]);
+        vec![
+            Message::Hello(Hello { version: 1, name: "camera ✓".to_string() }),
+            Message::Capabilities(Capabilities {
+                codecs: vec!["h264".to_string(), "mjpeg".to_string()],
+                max_width: 1920,
+                max_height: 1080,
+                max_fps: 30,
+                max_bitrate: 8_000_000,
+            }),
+            Message::Configure(Configure {
+                stream_id: 7,
+                codec: "h264".to_string(),
+                width: 1280,
+                height: 720,
+                fps: 25,
+                bitrate: 2_000_000,
+                keyframe_interval: 50,
+            }),
+            Message::KeyframeRequest { stream_id: 7 },
+            Message::Stats(Stats {
+                stream_id: 7,
+                packets_received: 1000,
+                packets_lost: 3,
+                bytes_received: u64::MAX,
+                jitter: Duration::from_micros(1500),
+                round_trip: Duration::from_millis(40),
+            }),
+            Message::Media(
+                MediaPacket::new(7, u64::MAX, Bytes::from_static(&[0, 0, 0, 1, 0x65, 0x88]))
+                    .codec("h264")
+                    .pts(Duration::from_micros(33_367))
+                    .dts(Duration::from_micros(0))
+                    .duration(Duration::from_micros(33_333))
+                    .timestamp(UNIX_EPOCH + Duration::from_micros(1_700_000_000_033_367))
+                    .keyframe(true)
+                    .metadata(metadata),
+            ),
+            Message::Media(MediaPacket::new(7, 0, Bytes::new())),
+        ]
+    }
+
+    /// Deterministic xorshift noise for the fuzz tests
+    pub(crate) fn noise(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        for message in samples() {
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize, bytes.len() - 4);
+            assert_eq!(bytes[4], VERSION);
+            assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
+        }
+
+        // Overlong names are cut without splitting a character
+        let hello = Message::Hello(Hello { version: 1, name: "é".repeat(200) });
+        let Message::Hello(restored) = Message::from_bytes(&hello.to_bytes().unwrap()).unwrap() else {
+            panic!("not a hello");
+        };
+        assert_eq!(restored.name.len(), 254);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let media = samples()[5].to_bytes().unwrap();
+        for len in 0..media.len() {
+            assert!(Message::from_bytes(&media[..len]).is_err(), "{}", len);
+        }
+        let mut extra = media.to_vec();
+        extra.push(0);
+        assert!(matches!(Message::from_bytes(&extra), Err(WireError::TrailingBytes(1))));
+
+        let frame = |body: &[u8]| [&(body.len() as u32).to_be_bytes(), body].concat();
+        let unsupported = Message::from_bytes(&frame(&[1, KIND_HELLO, 1, 0]));
+        assert!(matches!(unsupported, Err(WireError::UnsupportedVersion(1))));
+        assert!(matches!(Message::from_bytes(&frame(&[2, 99])), Err(WireError::UnknownKind(99))));
+        // A body longer than its kind needs
+        let request = Message::from_bytes(&frame(&[2, KIND_KEYFRAME_REQUEST, 0, 0, 0, 7, 0]));
+        assert!(matches!(request, Err(WireError::TrailingBytes(1))));
+        assert!(matches!(Message::from_bytes(&frame(&[2, KIND_HELLO, 1, 1, 0xff])), Err(WireError::InvalidUtf8)));
+
+        // Flags follow the stream, sequence, "h264" and timing
+        let flags = 2 + 4 + 8 + 5 + 8 + 8 + 4 + 8;
+        let mut media_body = media[4..].to_vec();
+        media_body[flags] |= 0x80;
+        assert!(matches!(Message::from_bytes(&frame(&media_body)), Err(WireError::UnknownFlags(0x83))));
+        let mut media_body = media[4..].to_vec();
+        media_body[flags + 1..flags + 5].copy_from_slice(&[0, 2, 0, 2]);
+        let fragment = Message::from_bytes(&frame(&media_body));
+        assert!(matches!(fragment, Err(WireError::InvalidFragment { index: 2, count: 2 })));
+        let mut media_body = media[4..].to_vec();
+        media_body[flags + 9] = 9;
+        assert!(matches!(Message::from_bytes(&frame(&media_body)), Err(WireError::Metadata(_))));
+    }
+
+    #[test]
+    fn survives_fuzzed_frames() {
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        let valid: Vec<_> = samples().iter().map(|message| message.to_bytes().unwrap().to_vec()).collect();
+
+        for round in 0..20_000 {
+            let mut data = valid[round % valid.len()].clone();
+            // Flip, overwrite or drop a few bytes of a valid frame, keeping
+            // the length prefix consistent half of the time
+            for _ in 0..1 + noise(&mut state) % 4 {
+                let at = (noise(&mut state) % data.len() as u64) as usize;
+                match noise(&mut state) % 3 {
+                    0 => data[at] ^= 1 << (noise(&mut state) % 8),
+                    1 => data[at] = noise(&mut state) as u8,
+                    _ if data.len() > 4 => {
+                        data.remove(at.max(4));
+                    }
+                    _ => {}
+                }
+            }
+            if round % 2 == 0 && data.len() >= 4 {
+                let length = (data.len() - 4) as u32;
+                data[..4].copy_from_slice(&length.to_be_bytes());
+            }
+            if let Ok(message) = Message::from_bytes(&data) {
+                // Whatever parses must survive another round trip; compared
+                // as bytes as fuzzed floats may be NaN
+                let bytes = message.to_bytes().unwrap();
+                assert_eq!(Message::from_bytes(&bytes).unwrap().to_bytes().unwrap(), bytes);
+            }
+        }
+
+        // Pure noise of every short length
+        for len in 0..512 {
+            let data: Vec<u8> = (0..len).map(|_| noise(&mut state) as u8).collect();
+            let _ = Message::from_bytes(&data);
+        }
+    }
+}
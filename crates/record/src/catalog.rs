@@ -0,0 +1,540 @@
+//! Catalogue of recorded segments and events across cameras.
+//!
+//! The catalogue is an append-only text file with one tab-separated record
+//! per line, so an update is a single small write and a crash can at worst
+//! leave a torn last line, which is ignored when reading:
+//!
+//! ```text
+//! S <camera> <container> <start> <end> <bytes> <path> [incomplete]   segment added or updated
+//! R <path>                                                           segment removed
+//! E <camera> <start> <end> <label>                                   event
+//! ```
+//!
+//! Times are seconds since the Unix epoch with nanoseconds. Segments whose
+//! file was never closed carry a trailing `incomplete`. Later records
+//! for a path replace earlier ones; [`Catalog::compact`] rewrites the file
+//! without the superseded ones.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use streaming_codec::{Encoder, EncoderConfig, MjpegEncoder};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//! use streaming_record::{Catalog, Recorder, RecorderConfig};
+//!
+//! let dir = std::env::temp_dir().join(format!("catalog-doc-{}", std::process::id()));
+//! let mut config = RecorderConfig::new(&dir, "porch");
+//! config.segment_duration = Duration::from_secs(1);
+//! let mut recorder = Recorder::open(config)?;
+//! let mut catalog = Catalog::open(dir.join("catalog.tsv"))?;
+//!
+//! let mut encoder = MjpegEncoder::new(EncoderConfig::new(64, 48, 10));
+//! let frames: Vec<_> = SyntheticSource::new(PixelFormat::RGB3, 64, 48).fps(10).take(25).collect();
+//! let start = frames[0].timestamp;
+//! for frame in frames {
+//!     for packet in encoder.encode(&frame)? {
+//!         if recorder.write(&packet)?.is_some() {
+//!             catalog.sync("porch", recorder.segments())?;
+//!         }
+//!     }
+//! }
+//! recorder.finish()?;
+//!
+//! // Half a second either side of the first segment boundary
+//! let (from, to) = (start + Duration::from_millis(500), start + Duration::from_millis(1500));
+//! assert_eq!(catalog.segments("porch", from, to).len(), 2);
+//! let clip = catalog.export("porch", from, to, &dir.join("clip.mkv"))?.unwrap();
+//! assert_eq!(clip.duration(), Duration::from_secs(1));
+//! # std::fs::remove_dir_all(&dir)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use streaming_codec::mkv::MkvReader;
+use streaming_codec::mp4::Mp4Reader;
+use streaming_codec::{CodecError, CodecId, EncodedFrame, Muxer};
+
+use crate::segment::Segment;
+use crate::{Container, RecordError};
+
+/// Something that happened on a camera, e.g. motion in a zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub camera: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+
+    /// Free-form description, e.g. `"motion: driveway"`
+    pub label: String,
+}
+
+/// A segment and the camera that recorded it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    camera: String,
+    segment: Segment,
+}
+
+/// Index of recordings, queried by camera and time range.
+///
+/// The catalogue does not watch the file system: it lists what it was told
+/// about through [`Catalog::add_segment`] or [`Catalog::sync`]. Call
+/// `sync` with [`Recorder::segments`](crate::Recorder::segments) after each
+/// rotation so segments deleted by retention drop out too.
+pub struct Catalog {
+    path: PathBuf,
+    file: File,
+    entries: Vec<Entry>,
+    events: Vec<Event>,
+}
+
+impl Catalog {
+    /// Open the catalogue at `path`, creating it if missing
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, RecordError> {
+        let path = path.into();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+
+        let mut catalog = Self { path, file, entries: Vec::new(), events: Vec::new() };
+        for line in text.lines() {
+            catalog.apply(line);
+        }
+        // Keep whatever follows a torn line on a line of its own
+        if !text.is_empty() && !text.ends_with('\n') {
+            catalog.file.write_all(b"\n")?;
+        }
+        Ok(catalog)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record a segment of `camera`, replacing any earlier record of its path
+    pub fn add_segment(&mut self, camera: &str, segment: &Segment) -> Result<(), RecordError> {
+        let line = segment_record(camera, segment)?;
+        self.append(&line)
+    }
+
+    /// Forget the segment at `path`
+    pub fn remove_segment(&mut self, path: &Path) -> Result<(), RecordError> {
+        if !self.entries.iter().any(|entry| entry.segment.path == path) {
+            return Ok(());
+        }
+        let line = format!("R\t{}", field(&path_text(path)?)?);
+        self.append(&line)
+    }
+
+    /// Make the catalogue list exactly `segments` for `camera`
+    pub fn sync(&mut self, camera: &str, segments: &[Segment]) -> Result<(), RecordError> {
+        let stale: Vec<PathBuf> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.camera == camera && !segments.iter().any(|s| s.path == entry.segment.path))
+            .map(|entry| entry.segment.path.clone())
+            .collect();
+        for path in stale {
+            self.remove_segment(&path)?;
+        }
+        for segment in segments {
+            let known = self.entries.iter().any(|entry| entry.camera == camera && entry.segment == *segment);
+            if !known {
+                self.add_segment(camera, segment)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_event(&mut self, event: &Event) -> Result<(), RecordError> {
+        let line = format!(
+            "E\t{}\t{}\t{}\t{}",
+            field(&event.camera)?,
+            format_time(event.start),
+            format_time(event.end),
+            field(&event.label)?
+        );
+        self.append(&line)
+    }
+
+    /// Cameras with segments or events, sorted
+    pub fn cameras(&self) -> Vec<&str> {
+        let segments = self.entries.iter().map(|entry| entry.camera.as_str());
+        let mut cameras: Vec<&str> = segments.chain(self.events.iter().map(|event| event.camera.as_str())).collect();
+        cameras.sort_unstable();
+        cameras.dedup();
+        cameras
+    }
+
+    /// Segments of `camera` covering any part of `from..to`, oldest first
+    pub fn segments(&self, camera: &str, from: SystemTime, to: SystemTime) -> Vec<&Segment> {
+        let mut segments: Vec<&Segment> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.camera == camera && entry.segment.overlaps(from, to))
+            .map(|entry| &entry.segment)
+            .collect();
+        segments.sort_by_key(|segment| segment.start);
+        segments
+    }
+
+    /// Events of `camera` overlapping `from..to`, oldest first; an instant
+    /// event at `from` counts
+    pub fn events(&self, camera: &str, from: SystemTime, to: SystemTime) -> Vec<&Event> {
+        let mut events: Vec<&Event> = self
+            .events
+            .iter()
+            .filter(|event| event.camera == camera && event.start < to && event.end >= from)
+            .collect();
+        events.sort_by_key(|event| event.start);
+        events
+    }
+
+    /// Write `camera`'s footage from `from` to `to` into one file at
+    /// `output`, whose extension picks the container.
+    ///
+    /// The clip starts at the keyframe at or before `from`, so it decodes
+    /// from its first frame, and follows the stream across segment
+    /// boundaries. It ends early where the codec or resolution changes, as
+    /// one file holds a single track. Returns `None` when nothing was
+    /// recorded in the range.
+    pub fn export(
+        &self,
+        camera: &str,
+        from: SystemTime,
+        to: SystemTime,
+        output: &Path,
+    ) -> Result<Option<Segment>, RecordError> {
+        let container = output
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Container::from_extension)
+            .ok_or_else(|| RecordError::Unsupported(format!("output format of {}", output.display())))?;
+
+        let mut clip = ClipExport { output, container, camera, muxer: None, segment: None, format: None };
+        // The latest GOP starting before `from`
+        let mut lead_in: Vec<EncodedFrame> = Vec::new();
+        'segments: for segment in self.segments(camera, from, to) {
+            let packets: Box<dyn Iterator<Item = Result<EncodedFrame, CodecError>>> = match segment.container {
+                Container::Matroska => Box::new(MkvReader::open(&segment.path)?),
+                Container::Mp4 => Box::new(Mp4Reader::open(&segment.path)?),
+            };
+            for packet in packets {
+                let packet = packet?;
+                if packet.timestamp >= to {
+                    break 'segments;
+                }
+                if clip.muxer.is_none() && packet.timestamp < from {
+                    if packet.keyframe {
+                        lead_in.clear();
+                    }
+                    if packet.keyframe || !lead_in.is_empty() {
+                        lead_in.push(packet);
+                    }
+                    continue;
+                }
+                if packet.keyframe {
+                    lead_in.clear();
+                }
+                for packet in lead_in.drain(..).chain(Some(packet)) {
+                    if !clip.write(packet)? {
+                        break 'segments;
+                    }
+                }
+            }
+        }
+        clip.finish()
+    }
+
+    /// Rewrite the file with only the current records
+    pub fn compact(&mut self) -> Result<(), RecordError> {
+        let temporary = self.path.with_extension("compacting");
+        let mut output = BufWriter::new(File::create(&temporary)?);
+        for entry in &self.entries {
+            writeln!(output, "{}", segment_record(&entry.camera, &entry.segment)?)?;
+        }
+        for event in &self.events {
+            let (start, end) = (format_time(event.start), format_time(event.end));
+            writeln!(output, "E\t{}\t{}\t{}\t{}", event.camera, start, end, event.label)?;
+        }
+        output.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), RecordError> {
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.apply(line);
+        Ok(())
+    }
+
+    /// Update the in-memory index from one record, skipping malformed ones
+    fn apply(&mut self, line: &str) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["S", camera, container, start, end, bytes, path, state @ ..] => {
+                let complete = match state {
+                    [] => true,
+                    ["incomplete"] => false,
+                    _ => return,
+                };
+                let (Some(container), Some(start), Some(end), Ok(bytes)) =
+                    (Container::from_extension(container), parse_time(start), parse_time(end), bytes.parse())
+                else {
+                    return;
+                };
+                let path = PathBuf::from(path);
+                self.entries.retain(|entry| entry.segment.path != path);
+                let segment = Segment { path, container, start, end, bytes, complete };
+                self.entries.push(Entry { camera: camera.to_string(), segment });
+            }
+            ["R", path] => self.entries.retain(|entry| entry.segment.path != Path::new(path)),
+            ["E", camera, start, end, label] => {
+                let (Some(start), Some(end)) = (parse_time(start), parse_time(end)) else {
+                    return;
+                };
+                self.events.push(Event { camera: camera.to_string(), start, end, label: label.to_string() });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The file being exported and the stream properties it was started with.
+struct ClipExport<'a> {
+    output: &'a Path,
+    container: Container,
+    camera: &'a str,
+    muxer: Option<Box<dyn Muxer>>,
+    segment: Option<Segment>,
+    format: Option<(CodecId, u32, u32)>,
+}
+
+impl ClipExport<'_> {
+    /// Write one packet, returning false once the stream changes format
+    fn write(&mut self, mut packet: EncodedFrame) -> Result<bool, RecordError> {
+        let format = (packet.codec, packet.width, packet.height);
+        if self.muxer.is_none() {
+            if !packet.keyframe {
+                return Ok(true);
+            }
+            self.muxer = Some(self.container.create(self.output, self.camera, None)?);
+            self.format = Some(format);
+            self.segment = Some(Segment {
+                path: self.output.to_path_buf(),
+                container: self.container,
+                start: packet.timestamp,
+                end: packet.timestamp,
+                bytes: 0,
+                complete: false,
+            });
+        }
+        if self.format != Some(format) {
+            return Ok(false);
+        }
+        let (Some(muxer), Some(segment)) = (&mut self.muxer, &mut self.segment) else {
+            return Ok(false);
+        };
+        // Stream time restarts in every segment; capture time does not
+        packet.pts = packet.timestamp.duration_since(segment.start).unwrap_or_default();
+        packet.dts = packet.pts;
+        muxer.write_packet(&packet)?;
+        segment.end = segment.end.max(packet.timestamp + packet.duration);
+        Ok(true)
+    }
+
+    fn finish(self) -> Result<Option<Segment>, RecordError> {
+        let (Some(muxer), Some(mut segment)) = (self.muxer, self.segment) else {
+            return Ok(None);
+        };
+        muxer.finish()?;
+        segment.bytes = fs::metadata(&segment.path)?.len();
+        segment.complete = true;
+        Ok(Some(segment))
+    }
+}
+
+/// The `S` record of a segment of `camera`
+fn segment_record(camera: &str, segment: &Segment) -> Result<String, RecordError> {
+    Ok(format!(
+        "S\t{}\t{}\t{}\t{}\t{}\t{}{}",
+        field(camera)?,
+        segment.container.extension(),
+        format_time(segment.start),
+        format_time(segment.end),
+        segment.bytes,
+        field(&path_text(&segment.path)?)?,
+        if segment.complete { "" } else { "\tincomplete" }
+    ))
+}
+
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:09}", since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn parse_time(text: &str) -> Option<SystemTime> {
+    let (seconds, nanos) = text.split_once('.')?;
+    if nanos.len() != 9 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(seconds.parse().ok()?, nanos.parse().ok()?))
+}
+
+fn path_text(path: &Path) -> io::Result<String> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("non-UTF-8 path {}", path.display())))
+}
+
+/// `text` if it can be stored as one field of a record
+fn field(text: &str) -> io::Result<&str> {
+    if text.contains(['\t', '\n', '\r']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} contains a tab or line break", text)));
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::{at, mjpeg_packets, temp_dir};
+    use crate::{Recorder, RecorderConfig};
+
+    fn segment(dir: &Path, start: u64, end: u64) -> Segment {
+        Segment {
+            path: dir.join(format!("{}.mkv", start)),
+            container: Container::Matroska,
+            start: at(start),
+            end: at(end),
+            bytes: end - start,
+            complete: true,
+        }
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = temp_dir("reopen");
+        let path = dir.join("catalog.tsv");
+        let mut catalog = Catalog::open(&path).unwrap();
+        let segments: Vec<_> = (0..4).map(|i| segment(&dir, i * 1000, i * 1000 + 1000)).collect();
+        for segment in &segments {
+            catalog.add_segment("cam", segment).unwrap();
+        }
+        let incomplete = Segment { complete: false, ..segment(&dir, 500, 900) };
+        catalog.add_segment("other", &incomplete).unwrap();
+        catalog.remove_segment(&segments[1].path).unwrap();
+        let event = Event { camera: "cam".into(), start: at(2500), end: at(2500), label: "motion: gate".into() };
+        catalog.add_event(&event).unwrap();
+        assert!(catalog.add_event(&Event { label: "two\nlines".into(), ..event.clone() }).is_err());
+        drop(catalog);
+
+        // A record torn by a crash is skipped, and appending carries on
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"S\tcam\tmkv\t17000").unwrap();
+        let mut catalog = Catalog::open(&path).unwrap();
+        catalog.add_segment("cam", &segments[1]).unwrap();
+        let catalog = Catalog::open(&path).unwrap();
+
+        assert_eq!(catalog.cameras(), ["cam", "other"]);
+        assert_eq!(catalog.segments("other", at(0), at(1000)), [&incomplete]);
+        let found = catalog.segments("cam", at(500), at(2000));
+        assert_eq!(found, [&segments[0], &segments[1]]);
+        assert_eq!(catalog.segments("cam", at(0), at(10_000)).len(), 4);
+        assert_eq!(catalog.events("cam", at(2500), at(3000)), [&event]);
+        assert!(catalog.events("cam", at(0), at(2500)).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sync_follows_retention_and_compacts() {
+        let dir = temp_dir("sync");
+        let path = dir.join("catalog.tsv");
+        let mut catalog = Catalog::open(&path).unwrap();
+        let segments: Vec<_> = (0..5).map(|i| segment(&dir, i * 1000, i * 1000 + 1000)).collect();
+        catalog.add_segment("other", &segment(&dir, 100, 5000)).unwrap();
+        catalog.sync("cam", &segments[..3]).unwrap();
+        catalog.sync("cam", &segments[2..]).unwrap();
+
+        let all = catalog.segments("cam", at(0), at(10_000));
+        assert_eq!(all, segments[2..].iter().collect::<Vec<_>>());
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 1 + 3 + 2 + 2);
+
+        catalog.compact().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+        catalog.sync("cam", &segments[3..]).unwrap();
+        let catalog = Catalog::open(&path).unwrap();
+        assert_eq!(catalog.segments("cam", at(0), at(10_000)), segments[3..].iter().collect::<Vec<_>>());
+        assert_eq!(catalog.segments("other", at(0), at(10_000)).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_spans_segments_from_a_keyframe() {
+        let dir = temp_dir("export");
+        let mut config = RecorderConfig::new(&dir, "cam");
+        config.segment_duration = Duration::from_secs(1);
+        let mut recorder = Recorder::open(config).unwrap();
+
+        // 10 fps with a keyframe every half second
+        for (i, packet) in mjpeg_packets(at(0), 30).into_iter().enumerate() {
+            recorder.write(&EncodedFrame { keyframe: i % 5 == 0, ..packet }).unwrap();
+        }
+        recorder.finish().unwrap();
+        let mut catalog = Catalog::open(dir.join("catalog.tsv")).unwrap();
+        catalog.sync("cam", Recorder::open(RecorderConfig::new(&dir, "cam")).unwrap().segments()).unwrap();
+
+        let output = dir.join("clip.mkv");
+        let clip = catalog.export("cam", at(750), at(1750), &output).unwrap().unwrap();
+        assert_eq!((clip.start, clip.end), (at(500), at(1800)));
+
+        let packets: Vec<_> = MkvReader::open(&output).unwrap().map(Result::unwrap).collect();
+        assert_eq!(packets.len(), 13);
+        assert!(packets[0].keyframe);
+        assert_eq!(packets[0].timestamp, at(500));
+        assert_eq!(packets[12].pts, Duration::from_millis(1200));
+
+        assert!(catalog.export("cam", at(10_000), at(11_000), &output).unwrap().is_none());
+        assert!(matches!(
+            catalog.export("cam", at(0), at(1000), &dir.join("clip.avi")),
+            Err(RecordError::Unsupported(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_reads_mp4_segments() {
+        let dir = temp_dir("export-mp4");
+        let mut config = RecorderConfig::new(&dir, "cam");
+        config.segment_duration = Duration::from_secs(1);
+        config.container = Container::Mp4;
+        let mut recorder = Recorder::open(config.clone()).unwrap();
+        for packet in mjpeg_packets(at(0), 30) {
+            recorder.write(&packet).unwrap();
+        }
+        recorder.finish().unwrap();
+        let mut catalog = Catalog::open(dir.join("catalog.tsv")).unwrap();
+        catalog.sync("cam", Recorder::open(config).unwrap().segments()).unwrap();
+
+        // Every MJPEG frame is a keyframe, so the clip starts right at `from`
+        for output in [dir.join("clip.mkv"), dir.join("clip.mp4")] {
+            let clip = catalog.export("cam", at(700), at(1750), &output).unwrap().unwrap();
+            assert_eq!((clip.start, clip.end), (at(700), at(1800)));
+        }
+        let packets: Vec<_> = Mp4Reader::open(dir.join("clip.mp4")).unwrap().map(Result::unwrap).collect();
+        assert_eq!(packets.len(), 11);
+        assert_eq!(packets[10].timestamp, at(1700));
+        assert_eq!(packets[10].pts, Duration::from_millis(1000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+//! Event clips with pre-event footage.
+//!
+//! [`PreEventBuffer`] keeps the last few seconds of an encoded stream in
+//! memory, starting on a keyframe so it can always be decoded.
+//! [`ClipRecorder`] feeds every packet through one and, when an event such
+//! as [`streaming_core::MotionEvent`] fires, writes the buffered footage
+//! plus the following seconds into a clip file.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use streaming_codec::{Encoder, EncoderConfig, MjpegEncoder};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//! use streaming_record::{ClipConfig, ClipRecorder};
+//!
+//! let dir = std::env::temp_dir().join(format!("clip-doc-{}", std::process::id()));
+//! let mut config = ClipConfig::new(&dir, "porch");
+//! config.pre_event = Duration::from_secs(1);
+//! config.post_event = Duration::from_secs(1);
+//!
+//! let mut clips = ClipRecorder::new(config)?;
+//! let mut encoder = MjpegEncoder::new(EncoderConfig::new(64, 48, 10));
+//! for (i, frame) in SyntheticSource::new(PixelFormat::RGB3, 64, 48).fps(10).take(50).enumerate() {
+//!     if i == 20 {
+//!         clips.trigger(frame.timestamp)?;
+//!     }
+//!     for packet in encoder.encode(&frame)? {
+//!         clips.write(&packet)?;
+//!     }
+//! }
+//! assert!(clips.finish()?.is_none());
+//! assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+//! # std::fs::remove_dir_all(&dir)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use streaming_codec::{EncodedFrame, Muxer};
+
+use crate::segment::{file_name, Segment};
+use crate::{Container, RecordError};
+
+/// In-memory ring of recent packets, trimmed a GOP at a time.
+///
+/// `max_duration` is the pre-roll to keep: the oldest GOP is only dropped
+/// once the rest still covers that much, so the buffer usually holds a bit
+/// more. `max_bytes` is a hard cap; if a single GOP exceeds it the buffer
+/// empties and waits for the next keyframe.
+#[derive(Debug, Clone)]
+pub struct PreEventBuffer {
+    max_duration: Duration,
+    max_bytes: usize,
+    packets: VecDeque<EncodedFrame>,
+    bytes: usize,
+}
+
+impl PreEventBuffer {
+    pub fn new(max_duration: Duration, max_bytes: usize) -> Self {
+        Self {
+            max_duration,
+            max_bytes,
+            packets: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Add a packet, dropping old GOPs that are no longer needed.
+    ///
+    /// Packets that do not follow a keyframe in the buffer are ignored, as
+    /// nothing could decode them.
+    pub fn push(&mut self, packet: &EncodedFrame) {
+        if self.packets.is_empty() && !packet.keyframe {
+            return;
+        }
+        self.packets.push_back(packet.clone());
+        self.bytes += packet.data.len();
+
+        let newest = packet.timestamp;
+        while let Some(next_gop) = self.packets.iter().skip(1).position(|packet| packet.keyframe) {
+            let next_gop = next_gop + 1;
+            let remaining = newest.duration_since(self.packets[next_gop].timestamp).unwrap_or_default();
+            if remaining < self.max_duration && self.bytes <= self.max_bytes {
+                break;
+            }
+            for packet in self.packets.drain(..next_gop) {
+                self.bytes -= packet.data.len();
+            }
+        }
+        if self.bytes > self.max_bytes {
+            self.clear();
+        }
+    }
+
+    /// Remove and return the buffered packets, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = EncodedFrame> + '_ {
+        self.bytes = 0;
+        self.packets.drain(..)
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.bytes = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &EncodedFrame> {
+        self.packets.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Payload bytes held
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Capture time covered, from the first packet to the end of the last
+    pub fn duration(&self) -> Duration {
+        match (self.packets.front(), self.packets.back()) {
+            (Some(first), Some(last)) => {
+                (last.timestamp + last.duration).duration_since(first.timestamp).unwrap_or_default()
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Settings for a [`ClipRecorder`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipConfig {
+    /// Directory for clip files; created if missing
+    pub dir: PathBuf,
+
+    /// Camera name, used as the file name prefix and device tag
+    pub name: String,
+
+    pub container: Container,
+
+    /// Footage to keep from before an event
+    pub pre_event: Duration,
+
+    /// Footage to record after the last event of a clip
+    pub post_event: Duration,
+
+    /// Memory limit of the pre-event buffer
+    pub max_buffer_bytes: usize,
+}
+
+impl ClipConfig {
+    /// Ten seconds either side of an event, buffering at most 64 MiB
+    pub fn new(dir: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            name: name.into(),
+            container: Container::Matroska,
+            pre_event: Duration::from_secs(10),
+            post_event: Duration::from_secs(10),
+            max_buffer_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// The clip being written.
+struct ActiveClip {
+    muxer: Box<dyn Muxer>,
+    segment: Segment,
+    base: Duration,
+    /// Capture time at which the clip stops
+    until: SystemTime,
+}
+
+/// Records clips around events from a continuous stream.
+///
+/// Events while a clip is being written extend it rather than starting a
+/// new one.
+pub struct ClipRecorder {
+    config: ClipConfig,
+    buffer: PreEventBuffer,
+    active: Option<ActiveClip>,
+    /// End of a clip triggered before any keyframe arrived
+    pending: Option<SystemTime>,
+}
+
+impl ClipRecorder {
+    pub fn new(config: ClipConfig) -> Result<Self, RecordError> {
+        fs::create_dir_all(&config.dir)?;
+        Ok(Self {
+            buffer: PreEventBuffer::new(config.pre_event, config.max_buffer_bytes),
+            config,
+            active: None,
+            pending: None,
+        })
+    }
+
+    pub fn config(&self) -> &ClipConfig {
+        &self.config
+    }
+
+    pub fn buffer(&self) -> &PreEventBuffer {
+        &self.buffer
+    }
+
+    /// The clip being written, with its end as of the last packet
+    pub fn active(&self) -> Option<&Segment> {
+        self.active.as_ref().map(|clip| &clip.segment)
+    }
+
+    /// Record an event at capture time `at`: start a clip with the buffered
+    /// footage, or extend the current one.
+    ///
+    /// Nothing is written until a keyframe has been buffered; a trigger
+    /// before then only sets the end time of the clip that the next
+    /// keyframe starts.
+    pub fn trigger(&mut self, at: SystemTime) -> Result<(), RecordError> {
+        let until = at + self.config.post_event;
+        if let Some(clip) = &mut self.active {
+            clip.until = clip.until.max(until);
+            return Ok(());
+        }
+
+        let mut packets = self.buffer.drain().collect::<Vec<_>>().into_iter();
+        let Some(first) = packets.next() else {
+            self.pending = Some(self.pending.map_or(until, |pending| pending.max(until)));
+            return Ok(());
+        };
+        let mut clip = self.start_clip(&first, until)?;
+        for packet in std::iter::once(first).chain(packets) {
+            write_to(&mut clip, &packet)?;
+        }
+        self.active = Some(clip);
+        Ok(())
+    }
+
+    /// Add a packet to the pre-event buffer and any active clip, returning
+    /// the clip it completed, if any
+    pub fn write(&mut self, packet: &EncodedFrame) -> Result<Option<Segment>, RecordError> {
+        if self.active.is_none() {
+            if let Some(until) = self.pending.take_if(|_| packet.keyframe) {
+                if packet.timestamp < until {
+                    self.active = Some(self.start_clip(packet, until)?);
+                }
+            }
+        }
+
+        let Some(clip) = &mut self.active else {
+            self.buffer.push(packet);
+            return Ok(None);
+        };
+        if packet.timestamp >= clip.until {
+            let closed = self.close()?;
+            self.buffer.push(packet);
+            return Ok(closed);
+        }
+        write_to(clip, packet)?;
+        Ok(None)
+    }
+
+    /// Close the active clip, returning it
+    pub fn finish(mut self) -> Result<Option<Segment>, RecordError> {
+        self.close()
+    }
+
+    fn start_clip(&self, first: &EncodedFrame, until: SystemTime) -> Result<ActiveClip, RecordError> {
+        let container = self.config.container;
+        let path = self.config.dir.join(file_name(&self.config.name, first.timestamp, container));
+        Ok(ActiveClip {
+            muxer: container.create(&path, &self.config.name, None)?,
+            segment: Segment {
+                path,
+                container,
+                start: first.timestamp,
+                end: first.timestamp,
+                bytes: 0,
+                complete: false,
+            },
+            base: first.pts,
+            until,
+        })
+    }
+
+    fn close(&mut self) -> Result<Option<Segment>, RecordError> {
+        let Some(clip) = self.active.take() else {
+            return Ok(None);
+        };
+        let mut segment = clip.segment;
+        clip.muxer.finish()?;
+        segment.bytes = fs::metadata(&segment.path)?.len();
+        segment.complete = true;
+        Ok(Some(segment))
+    }
+}
+
+/// Write a packet with its times rebased to the start of the clip
+fn write_to(clip: &mut ActiveClip, packet: &EncodedFrame) -> Result<(), RecordError> {
+    let mut packet = packet.clone();
+    packet.pts = packet.pts.saturating_sub(clip.base);
+    packet.dts = packet.dts.saturating_sub(clip.base);
+    clip.muxer.write_packet(&packet)?;
+    clip.segment.end = clip.segment.end.max(packet.timestamp + packet.duration);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use streaming_codec::mkv::MkvReader;
+    use streaming_codec::{Encoder, EncoderConfig, H264Encoder};
+    use streaming_core::{PixelFormat, SyntheticSource};
+
+    use crate::test_util::{at, mjpeg_packets, temp_dir};
+
+    #[test]
+    fn buffer_keeps_whole_gops() {
+        let mut config = EncoderConfig::new(64, 48, 30);
+        config.keyframe_interval = 10;
+        let mut encoder = H264Encoder::new(config).unwrap();
+        let mut buffer = PreEventBuffer::new(Duration::from_millis(500), usize::MAX);
+        let source = SyntheticSource::new(PixelFormat::YU12, 64, 48).velocity(2, 1);
+        for frame in source.take(40) {
+            for packet in encoder.encode(&frame).unwrap() {
+                buffer.push(&packet);
+            }
+        }
+        // The GOP at frame 30 alone covers only 333 ms, so the one at 20 stays
+        assert_eq!(buffer.len(), 20);
+        assert!(buffer.iter().next().unwrap().keyframe);
+        assert_eq!(buffer.iter().filter(|packet| packet.keyframe).count(), 2);
+        assert!(buffer.duration() >= Duration::from_millis(500));
+
+        // A byte cap below one GOP empties the buffer until the next keyframe
+        let cap = buffer.iter().take(10).map(|packet| packet.data.len()).sum::<usize>() - 1;
+        let mut capped = PreEventBuffer::new(Duration::from_secs(10), cap);
+        for packet in buffer.iter() {
+            capped.push(packet);
+            assert!(capped.bytes() <= cap);
+        }
+        // The first GOP never fit, so anything left is from the second
+        let second_gop = buffer.iter().nth(10).unwrap().timestamp;
+        assert!(capped.iter().all(|packet| packet.timestamp >= second_gop));
+        assert!(capped.is_empty() || capped.iter().next().unwrap().keyframe);
+    }
+
+    #[test]
+    fn clip_covers_pre_and_post_event() {
+        let dir = temp_dir("events");
+        let mut config = ClipConfig::new(&dir, "cam");
+        config.pre_event = Duration::from_secs(1);
+        config.post_event = Duration::from_secs(2);
+        let mut clips = ClipRecorder::new(config).unwrap();
+
+        let packets = mjpeg_packets(at(0), 80);
+        let mut closed = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            closed.extend(clips.write(packet).unwrap());
+            // Events at 2.0 s and, extending the clip, 3.5 s
+            if i == 20 || i == 35 {
+                clips.trigger(packet.timestamp).unwrap();
+            }
+        }
+        assert!(clips.finish().unwrap().is_none());
+
+        // 1 s before the first event up to 2 s after the second
+        assert_eq!(closed.len(), 1);
+        let clip = &closed[0];
+        assert_eq!(clip.start, at(1000));
+        assert_eq!(clip.duration(), Duration::from_millis(4500));
+        assert_eq!(clip.path.file_name().unwrap(), "cam_2023-11-14_22-13-21.000.mkv");
+
+        let mut reader = MkvReader::open(&clip.path).unwrap();
+        let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 45);
+        assert_eq!(read[0].pts, Duration::ZERO);
+        assert_eq!(read[0].data, packets[10].data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trigger_before_keyframe_waits_for_one() {
+        let dir = temp_dir("wait");
+        let mut config = ClipConfig::new(&dir, "cam");
+        config.post_event = Duration::from_secs(1);
+        let mut clips = ClipRecorder::new(config).unwrap();
+
+        let mut packets = mjpeg_packets(at(0), 20);
+        packets[..5].iter_mut().for_each(|packet| packet.keyframe = false);
+        clips.trigger(packets[0].timestamp).unwrap();
+        assert!(clips.active().is_none());
+        for packet in &packets[..5] {
+            clips.write(packet).unwrap();
+        }
+        assert!(clips.active().is_none());
+
+        let mut closed = Vec::new();
+        for packet in &packets[5..] {
+            closed.extend(clips.write(packet).unwrap());
+        }
+        // The clip starts at the first keyframe and still ends 1 s after the event
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].start, packets[5].timestamp);
+        assert_eq!(MkvReader::open(&closed[0].path).unwrap().count(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
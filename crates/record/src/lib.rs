@@ -0,0 +1,99 @@
+//! Recording of encoded streams to disk.
+//!
+//! [`Recorder`] writes a continuous stream into time-based segment files,
+//! named after the capture time of their first frame, and deletes old
+//! segments to stay within an age and disk quota. It indexes the segments
+//! already in its directory when opened, so recording resumes cleanly after
+//! a restart or crash. [`ClipRecorder`] instead records only around
+//! events, using a [`PreEventBuffer`] to include footage from before them.
+//! [`Catalog`] indexes segments and events across cameras for time-range
+//! queries and exports clips that span segments.
+
+use std::path::Path;
+use std::time::Duration;
+
+use streaming_codec::mkv::MkvWriter;
+use streaming_codec::mp4::{Mp4Layout, Mp4Writer};
+use streaming_codec::{AudioTrack, CodecError, Muxer};
+use thiserror::Error;
+
+pub mod catalog;
+pub mod clip;
+pub mod recorder;
+pub mod segment;
+#[cfg(test)]
+mod test_util;
+
+pub use catalog::{Catalog, Event};
+pub use clip::{ClipConfig, ClipRecorder, PreEventBuffer};
+pub use recorder::{Recorder, RecorderConfig};
+pub use segment::Segment;
+
+#[derive(Debug, Error)]
+pub enum RecordError {
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// File format of recorded segments.
+///
+/// Both keep everything up to the last complete frame or fragment readable
+/// if recording stops without closing the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Container {
+    #[default]
+    Matroska,
+
+    /// Fragmented MP4
+    Mp4,
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Matroska => "mkv",
+            Container::Mp4 => "mp4",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "mkv" => Some(Container::Matroska),
+            "mp4" => Some(Container::Mp4),
+            _ => None,
+        }
+    }
+
+    /// Create a file at `path` and a muxer writing to it, tagged with the
+    /// device name where the format allows, with an audio track if given
+    pub fn create(
+        &self,
+        path: &Path,
+        device: &str,
+        audio: Option<AudioTrack>,
+    ) -> Result<Box<dyn Muxer>, RecordError> {
+        Ok(match self {
+            Container::Matroska => {
+                let writer = MkvWriter::create(path)?.device_name(device);
+                Box::new(match audio {
+                    Some(audio) => writer.audio(audio),
+                    None => writer,
+                })
+            }
+            Container::Mp4 => {
+                let layout = Mp4Layout::Fragmented { fragment_duration: Duration::from_secs(2) };
+                let writer = Mp4Writer::create(path, layout)?;
+                Box::new(match audio {
+                    Some(audio) => writer.audio(audio),
+                    None => writer,
+                })
+            }
+        })
+    }
+}
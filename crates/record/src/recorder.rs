@@ -0,0 +1,542 @@
+//! Continuous recording into rotating segments.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use streaming_codec::{Encoder, EncoderConfig, MjpegEncoder};
+//! use streaming_core::{PixelFormat, SyntheticSource};
+//! use streaming_record::{Recorder, RecorderConfig};
+//!
+//! let dir = std::env::temp_dir().join(format!("recorder-doc-{}", std::process::id()));
+//! let mut config = RecorderConfig::new(&dir, "garage");
+//! config.segment_duration = Duration::from_secs(1);
+//! config.max_bytes = Some(50 * 1024 * 1024);
+//!
+//! let mut recorder = Recorder::open(config)?;
+//! let mut encoder = MjpegEncoder::new(EncoderConfig::new(64, 48, 10));
+//! for frame in SyntheticSource::new(PixelFormat::RGB3, 64, 48).fps(10).take(25) {
+//!     for packet in encoder.encode(&frame)? {
+//!         recorder.write(&packet)?;
+//!     }
+//! }
+//! recorder.finish()?;
+//! assert_eq!(Recorder::open(RecorderConfig::new(&dir, "garage"))?.segments().len(), 3);
+//! # std::fs::remove_dir_all(&dir)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use streaming_codec::mkv::{self, MkvReader};
+use streaming_codec::mp4::Mp4Reader;
+use streaming_codec::{AudioTrack, CodecId, EncodedFrame, Muxer};
+
+use crate::segment::{file_name, parse_file_name, Segment};
+use crate::{Container, RecordError};
+
+/// Longest stretch of one stream held back waiting for the other before it
+/// is written anyway
+const INTERLEAVE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Settings for a [`Recorder`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecorderConfig {
+    /// Directory holding the segments; created if missing
+    pub dir: PathBuf,
+
+    /// Camera name, used as the file name prefix and device tag
+    pub name: String,
+
+    pub container: Container,
+
+    /// Length after which the next keyframe starts a new segment
+    pub segment_duration: Duration,
+
+    /// Delete segments that ended longer ago than this
+    pub max_age: Option<Duration>,
+
+    /// Delete the oldest segments while finished ones take more than this
+    /// many bytes; the segment being written comes on top
+    pub max_bytes: Option<u64>,
+
+    /// Audio recorded next to the video; audio packets are refused without
+    pub audio: Option<AudioTrack>,
+}
+
+impl RecorderConfig {
+    /// Five-minute Matroska segments kept forever
+    pub fn new(dir: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            name: name.into(),
+            container: Container::Matroska,
+            segment_duration: Duration::from_secs(300),
+            max_age: None,
+            max_bytes: None,
+            audio: None,
+        }
+    }
+}
+
+/// The segment being written.
+struct Current {
+    muxer: Box<dyn Muxer>,
+    segment: Segment,
+    codec: CodecId,
+    resolution: (u32, u32),
+    /// Stream time of the first frame, subtracted so each file starts at zero
+    base: Duration,
+}
+
+/// Records one encoded stream into segment files.
+///
+/// Segments start on keyframes, so each file plays on its own; frames
+/// before the first keyframe are skipped. Besides the configured duration,
+/// a change of codec or resolution also starts a new segment.
+///
+/// With [`RecorderConfig::audio`] set, audio packets go into the same
+/// segments. Audio and video come from separate encoders, so packets are
+/// held back until the other stream catches up, for at most half a second,
+/// and written in capture order.
+///
+/// Retention runs when opening and after each segment is closed. Ages are
+/// measured against the capture time of the latest frame, or the system
+/// clock when opening.
+pub struct Recorder {
+    config: RecorderConfig,
+    segments: Vec<Segment>,
+    current: Option<Current>,
+    /// Packets held back for interleaving, in capture order
+    interleave: VecDeque<EncodedFrame>,
+}
+
+impl Recorder {
+    /// Index the segments already in the directory and apply retention.
+    ///
+    /// Segment ends are read from the files: the duration of a finished
+    /// Matroska segment, otherwise the end of its last whole frame. An
+    /// unfinished Matroska segment left by a crash is rewritten with
+    /// [`mkv::recover`] so it becomes seekable; files that do not match
+    /// the naming scheme are left alone.
+    pub fn open(config: RecorderConfig) -> Result<Self, RecordError> {
+        fs::create_dir_all(&config.dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            let Some((start, container)) = parse_file_name(&config.name, &path) else {
+                continue;
+            };
+            let metadata = fs::metadata(&path)?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let mut segment = Segment { path, container, start, end: start, bytes: metadata.len(), complete: false };
+            scan(&mut segment);
+            segments.push(segment);
+        }
+        segments.sort_by_key(|segment| segment.start);
+
+        if let Some(last) = segments.last_mut() {
+            if last.container == Container::Matroska && !last.complete {
+                repair(last)?;
+            }
+        }
+
+        let mut recorder = Self { config, segments, current: None, interleave: VecDeque::new() };
+        recorder.apply_retention(SystemTime::now())?;
+        Ok(recorder)
+    }
+
+    pub fn config(&self) -> &RecorderConfig {
+        &self.config
+    }
+
+    /// Finished segments, oldest first
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The segment being written, with its end as of the last frame
+    pub fn current(&self) -> Option<&Segment> {
+        self.current.as_ref().map(|current| &current.segment)
+    }
+
+    /// Record one packet, returning the segment it closed, if any; when
+    /// interleaving with audio, the latest one closed by the packets it let
+    /// through.
+    ///
+    /// # Errors
+    ///
+    /// [`RecordError::Unsupported`] for an audio packet without an audio
+    /// track configured, and errors from the muxer or the file system. The
+    /// recorder stays usable; a failed segment is closed as far as possible
+    /// on the next rotation.
+    pub fn write(&mut self, packet: &EncodedFrame) -> Result<Option<Segment>, RecordError> {
+        if self.config.audio.is_none() {
+            if packet.codec.is_audio() {
+                return Err(RecordError::Unsupported(format!("{} audio without an audio track", packet.codec)));
+            }
+            return self.write_video(packet);
+        }
+
+        let at = self.interleave.iter().rposition(|held| held.timestamp <= packet.timestamp).map_or(0, |i| i + 1);
+        self.interleave.insert(at, packet.clone());
+        let mut closed = None;
+        while let Some(packet) = self.next_interleaved() {
+            closed = self.write_now(&packet)?.or(closed);
+        }
+        Ok(closed)
+    }
+
+    /// The oldest held-back packet, once nothing older can still arrive
+    fn next_interleaved(&mut self) -> Option<EncodedFrame> {
+        let oldest = self.interleave.front()?;
+        // Each stream arrives in order, so a later packet of the other one
+        // means it has caught up
+        let caught_up = self.interleave.iter().any(|held| held.codec.is_audio() != oldest.codec.is_audio());
+        let newest = self.interleave.back()?.timestamp;
+        let stalled = newest.duration_since(oldest.timestamp).unwrap_or_default() > INTERLEAVE_WINDOW;
+        if caught_up || stalled {
+            self.interleave.pop_front()
+        } else {
+            None
+        }
+    }
+
+    fn write_now(&mut self, packet: &EncodedFrame) -> Result<Option<Segment>, RecordError> {
+        if !packet.codec.is_audio() {
+            return self.write_video(packet);
+        }
+        // Audio never starts a segment; it is placed by capture time
+        let Some(current) = &mut self.current else {
+            return Ok(None);
+        };
+        let Ok(pts) = packet.timestamp.duration_since(current.segment.start) else {
+            return Ok(None);
+        };
+        current.muxer.write_packet(&EncodedFrame { pts, dts: pts, ..packet.clone() })?;
+        current.segment.end = current.segment.end.max(packet.timestamp + packet.duration);
+        Ok(None)
+    }
+
+    fn write_video(&mut self, packet: &EncodedFrame) -> Result<Option<Segment>, RecordError> {
+        let rotate = match &self.current {
+            None => true,
+            Some(current) => {
+                let elapsed = packet.timestamp.duration_since(current.segment.start).unwrap_or_default();
+                packet.keyframe
+                    && (elapsed >= self.config.segment_duration
+                        || current.codec != packet.codec
+                        || current.resolution != (packet.width, packet.height))
+            }
+        };
+
+        let mut closed = None;
+        if rotate {
+            if !packet.keyframe {
+                return Ok(None);
+            }
+            closed = self.close(packet.timestamp)?;
+            self.current = Some(self.start_segment(packet)?);
+        }
+
+        let Some(current) = &mut self.current else {
+            return Ok(closed);
+        };
+        let mut packet = packet.clone();
+        packet.pts = packet.pts.saturating_sub(current.base);
+        packet.dts = packet.dts.saturating_sub(current.base);
+        current.muxer.write_packet(&packet)?;
+        current.segment.end = current.segment.end.max(packet.timestamp + packet.duration);
+        Ok(closed)
+    }
+
+    /// Write any held-back packets and close the current segment,
+    /// returning it
+    pub fn finish(mut self) -> Result<Option<Segment>, RecordError> {
+        while let Some(packet) = self.interleave.pop_front() {
+            self.write_now(&packet)?;
+        }
+        let now = self.current.as_ref().map_or_else(SystemTime::now, |current| current.segment.end);
+        self.close(now)
+    }
+
+    fn start_segment(&self, packet: &EncodedFrame) -> Result<Current, RecordError> {
+        let container = self.config.container;
+        let path = self.config.dir.join(file_name(&self.config.name, packet.timestamp, container));
+        Ok(Current {
+            muxer: container.create(&path, &self.config.name, self.config.audio)?,
+            segment: Segment {
+                path,
+                container,
+                start: packet.timestamp,
+                end: packet.timestamp,
+                bytes: 0,
+                complete: false,
+            },
+            codec: packet.codec,
+            resolution: (packet.width, packet.height),
+            base: packet.pts,
+        })
+    }
+
+    /// Finish the current segment and apply retention as of `now`
+    fn close(&mut self, now: SystemTime) -> Result<Option<Segment>, RecordError> {
+        let Some(current) = self.current.take() else {
+            return Ok(None);
+        };
+        let mut segment = current.segment;
+        // A segment that failed to finish still holds its frames, so it is
+        // indexed as incomplete rather than lost
+        let finished = current.muxer.finish();
+        segment.complete = finished.is_ok();
+        if let Ok(metadata) = fs::metadata(&segment.path) {
+            segment.bytes = metadata.len();
+        }
+        self.segments.push(segment.clone());
+        finished?;
+        self.apply_retention(now)?;
+        Ok(Some(segment))
+    }
+
+    fn apply_retention(&mut self, now: SystemTime) -> Result<(), RecordError> {
+        let mut total: u64 = self.segments.iter().map(|segment| segment.bytes).sum();
+        while let Some(oldest) = self.segments.first() {
+            let age = now.duration_since(oldest.end).unwrap_or_default();
+            let expired = self.config.max_age.is_some_and(|max| age > max);
+            let over_quota = self.config.max_bytes.is_some_and(|max| total > max);
+            if !expired && !over_quota {
+                break;
+            }
+            match fs::remove_file(&oldest.path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            total -= oldest.bytes;
+            self.segments.remove(0);
+        }
+        Ok(())
+    }
+}
+
+/// Set the end and state of a segment found on disk from its frames; a
+/// file that cannot be read at all is left covering no time
+fn scan(segment: &mut Segment) {
+    let start = segment.start;
+    let frame_end = |packet: Result<EncodedFrame, _>| packet.ok().map(|packet| packet.timestamp + packet.duration);
+    match segment.container {
+        Container::Matroska => {
+            let Ok(reader) = MkvReader::open(&segment.path) else {
+                return;
+            };
+            if let Some(duration) = reader.info().duration {
+                segment.end = start + duration;
+                segment.complete = true;
+                return;
+            }
+            segment.end = reader.with_audio().map_while(frame_end).fold(start, SystemTime::max);
+        }
+        Container::Mp4 => {
+            let Ok(mut reader) = Mp4Reader::open(&segment.path) else {
+                return;
+            };
+            segment.end = reader.by_ref().map_while(frame_end).fold(start, SystemTime::max);
+            // Fragments stand alone, so a file is whole unless one is cut off
+            segment.complete = !reader.is_truncated();
+        }
+    }
+}
+
+/// Rewrite a Matroska segment that was never finished, updating its size
+/// and end from the frames that survived
+fn repair(segment: &mut Segment) -> Result<(), RecordError> {
+    // Cut off inside the header: nothing to salvage
+    if MkvReader::open(&segment.path).is_err() {
+        return Ok(());
+    }
+
+    let temporary = segment.path.with_extension("mkv.recovering");
+    let recovery = fs::File::open(&segment.path)
+        .map_err(RecordError::from)
+        .and_then(|input| {
+            let output = io::BufWriter::new(fs::File::create(&temporary)?);
+            Ok(mkv::recover(io::BufReader::new(input), output)?)
+        });
+    match recovery {
+        Ok(recovery) => {
+            fs::rename(&temporary, &segment.path)?;
+            segment.end = segment.start + recovery.duration;
+            segment.bytes = fs::metadata(&segment.path)?.len();
+            segment.complete = true;
+            Ok(())
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&temporary);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+    use streaming_codec::audio::opus_head;
+
+    use crate::test_util::{at, mjpeg_packets, opus_packets, temp_dir};
+
+    fn names(segments: &[Segment]) -> Vec<String> {
+        segments.iter().map(|segment| segment.path.file_name().unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    fn frame_count(path: &Path) -> usize {
+        MkvReader::open(path).unwrap().count()
+    }
+
+    #[test]
+    fn rotates_segments_named_by_capture_time() {
+        let dir = temp_dir("rotate");
+        let mut config = RecorderConfig::new(&dir, "cam");
+        config.segment_duration = Duration::from_secs(1);
+        let mut recorder = Recorder::open(config).unwrap();
+
+        let mut closed = Vec::new();
+        for packet in mjpeg_packets(at(0), 35) {
+            closed.extend(recorder.write(&packet).unwrap());
+        }
+        assert_eq!(closed.len(), 3);
+        closed.extend(recorder.finish().unwrap());
+
+        assert_eq!(
+            names(&closed),
+            [
+                "cam_2023-11-14_22-13-20.000.mkv",
+                "cam_2023-11-14_22-13-21.000.mkv",
+                "cam_2023-11-14_22-13-22.000.mkv",
+                "cam_2023-11-14_22-13-23.000.mkv",
+            ]
+        );
+        let counts: Vec<_> = closed.iter().map(|segment| frame_count(&segment.path)).collect();
+        assert_eq!(counts, [10, 10, 10, 5]);
+        assert_eq!(closed[3].duration(), Duration::from_millis(500));
+
+        // Each file starts at zero, with its own capture start time
+        let mut reader = MkvReader::open(&closed[1].path).unwrap();
+        assert_eq!(reader.info().start, Some(closed[1].start));
+        assert_eq!(reader.next_packet().unwrap().unwrap().pts, Duration::ZERO);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retention_by_age_and_quota() {
+        let dir = temp_dir("retention");
+        let mut config = RecorderConfig::new(&dir, "cam");
+        config.segment_duration = Duration::from_secs(1);
+        config.max_age = Some(Duration::from_millis(2500));
+        let mut recorder = Recorder::open(config.clone()).unwrap();
+        for packet in mjpeg_packets(at(0), 60) {
+            recorder.write(&packet).unwrap();
+        }
+        // When the segment at 5 s starts, those ending at 1 and 2 s are over
+        // 2.5 s old
+        let starts: Vec<_> = recorder.segments().iter().map(|s| s.start.duration_since(UNIX_EPOCH).unwrap()).collect();
+        assert_eq!(starts, [2, 3, 4].map(|s| Duration::from_secs(1_700_000_000 + s)));
+        recorder.finish().unwrap();
+
+        // Reopening with a quota of two segments' worth drops the oldest
+        config.max_age = None;
+        let sizes: Vec<_> = Recorder::open(config.clone()).unwrap().segments().iter().map(|s| s.bytes).collect();
+        assert_eq!(sizes.len(), 3);
+        config.max_bytes = Some(sizes[1] + sizes[2]);
+        let recorder = Recorder::open(config).unwrap();
+        assert_eq!(recorder.segments().len(), 2);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_indexes_and_repairs_segments() {
+        let dir = temp_dir("restart");
+        let mut config = RecorderConfig::new(&dir, "cam");
+        config.segment_duration = Duration::from_secs(1);
+        let mut recorder = Recorder::open(config.clone()).unwrap();
+        for packet in mjpeg_packets(at(0), 25) {
+            recorder.write(&packet).unwrap();
+        }
+        let unfinished = recorder.current().unwrap().path.clone();
+        // Crash: the last segment is never finished
+        drop(recorder);
+        fs::write(dir.join("notes.txt"), "not a segment").unwrap();
+        assert_eq!(MkvReader::open(&unfinished).unwrap().info().duration, None);
+        // Ends come from the files, whatever their modification times
+        let touched = fs::File::options().append(true).open(dir.join("cam_2023-11-14_22-13-20.000.mkv")).unwrap();
+        touched.set_modified(UNIX_EPOCH + Duration::from_secs(1_800_000_000)).unwrap();
+        drop(touched);
+
+        let mut recorder = Recorder::open(config).unwrap();
+        assert_eq!(recorder.segments().len(), 3);
+        let first = &recorder.segments()[0];
+        assert_eq!(first.end, first.start + Duration::from_secs(1));
+        assert!(recorder.segments().iter().all(|segment| segment.complete));
+        let last = &recorder.segments()[2];
+        assert_eq!(last.path, unfinished);
+        assert_eq!(last.duration(), Duration::from_millis(500));
+        assert_eq!(MkvReader::open(&unfinished).unwrap().info().duration, Some(Duration::from_millis(500)));
+        assert_eq!(frame_count(&unfinished), 5);
+
+        // Recording carries on into a new segment
+        recorder.write(&mjpeg_packets(at(60_000), 1)[0]).unwrap();
+        assert_eq!(recorder.finish().unwrap().unwrap().path, dir.join("cam_2023-11-14_22-14-20.000.mkv"));
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_audio_interleaved_with_video() {
+        let dir = temp_dir("audio");
+        let video = mjpeg_packets(at(0), 15);
+        let audio = opus_packets(video[0].timestamp + Duration::from_millis(5), 75);
+        let mut config = RecorderConfig::new(&dir, "cam");
+        config.segment_duration = Duration::from_secs(1);
+        config.audio = Some(AudioTrack::from_opus_head(audio[0].extradata.as_ref().unwrap()).unwrap());
+        let mut recorder = Recorder::open(config).unwrap();
+
+        // The audio encoder delivers a frame's worth ahead of the video
+        let mut pending_audio = audio.iter();
+        for packet in &video {
+            for audio in pending_audio.by_ref().take(5) {
+                recorder.write(audio).unwrap();
+            }
+            recorder.write(packet).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let recorder = Recorder::open(RecorderConfig::new(&dir, "cam")).unwrap();
+        let mut read_audio = Vec::new();
+        for segment in recorder.segments() {
+            let reader = MkvReader::open(&segment.path).unwrap().with_audio();
+            assert_eq!(reader.info().audio, Some(AudioTrack::from_opus_head(&opus_head(1, 48_000, 312)).unwrap()));
+            let packets: Vec<_> = reader.map(Result::unwrap).collect();
+            assert!(packets.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+            let audio: Vec<_> = packets.into_iter().filter(|packet| packet.codec.is_audio()).collect();
+            assert!(audio.iter().all(|packet| packet.timestamp >= segment.start));
+            read_audio.push(audio);
+        }
+        assert_eq!(read_audio.iter().map(Vec::len).collect::<Vec<_>>(), [50, 25]);
+        for (read, original) in read_audio.concat().iter().zip(&audio) {
+            assert_eq!(read.data, original.data);
+            assert!(read.timestamp.duration_since(original.timestamp).unwrap() <= Duration::from_millis(1));
+        }
+
+        let mut plain = Recorder::open(RecorderConfig::new(&dir, "other")).unwrap();
+        assert!(matches!(plain.write(&audio[0]), Err(RecordError::Unsupported(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Segment files and their names.
+//!
+//! Segments are named `<name>_<YYYY-MM-DD>_<HH-MM-SS.mmm>.<ext>` after the
+//! UTC capture time of their first frame, so a directory listing sorts
+//! chronologically and the index can be rebuilt from names alone.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use streaming_core::overlay::format_utc;
+
+use crate::Container;
+
+/// A recorded file and the span of capture time it covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub path: PathBuf,
+    pub container: Container,
+
+    /// Capture time of the first frame
+    pub start: SystemTime,
+
+    /// Capture time at which the last frame ends
+    pub end: SystemTime,
+
+    /// File size
+    pub bytes: u64,
+
+    /// Whether the file was closed cleanly; an incomplete one is readable
+    /// up to its last whole frame
+    pub complete: bool,
+}
+
+impl Segment {
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+
+    /// Whether the segment covers any part of `from..to`
+    pub fn overlaps(&self, from: SystemTime, to: SystemTime) -> bool {
+        self.start < to && self.end > from
+    }
+}
+
+/// File name for a segment of camera `name` starting at `start`
+pub fn file_name(name: &str, start: SystemTime, container: Container) -> String {
+    // "2024-01-02 03:04:05.678Z" -> "2024-01-02_03-04-05.678"
+    let time = format_utc(start).trim_end_matches('Z').replace(' ', "_").replace(':', "-");
+    format!("{}_{}.{}", name, time, container.extension())
+}
+
+/// Start time and container of a segment file of camera `name`, or `None`
+/// if the file is not one
+pub fn parse_file_name(name: &str, path: &Path) -> Option<(SystemTime, Container)> {
+    let container = Container::from_extension(path.extension()?.to_str()?)?;
+    let stem = path.file_stem()?.to_str()?;
+    let time = stem.strip_prefix(name)?.strip_prefix('_')?;
+
+    // YYYY-MM-DD_HH-MM-SS.mmm
+    let bytes = time.as_bytes();
+    if bytes.len() != 23 || [4, 7, 13, 16].iter().any(|&i| bytes[i] != b'-') || bytes[10] != b'_' || bytes[19] != b'.' {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| -> Option<u64> {
+        let digits = time.get(range)?;
+        digits.bytes().all(|b| b.is_ascii_digit()).then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second, millis) = (field(11..13)?, field(14..16)?, field(17..19)?, field(20..23)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month as u32, day as u32);
+    let seconds = u64::try_from(days).ok()? * 86_400 + hour * 3600 + minute * 60 + second;
+    Some((UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis), container))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's
+/// algorithm)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        let start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let name = file_name("front_door", start, Container::Matroska);
+        assert_eq!(name, "front_door_2023-11-14_22-13-20.123.mkv");
+        assert_eq!(parse_file_name("front_door", Path::new(&name)), Some((start, Container::Matroska)));
+
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        let name = file_name("cam", leap, Container::Mp4);
+        assert_eq!(name, "cam_2000-02-29_00-00-00.000.mp4");
+        assert_eq!(parse_file_name("cam", Path::new(&name)), Some((leap, Container::Mp4)));
+    }
+
+    #[test]
+    fn foreign_files_are_ignored() {
+        for name in [
+            "cam_2023-11-14_22-13-20.123.txt",
+            "other_2023-11-14_22-13-20.123.mkv",
+            "cam_2023-11-14_22-13-20.mkv",
+            "cam_2023-13-14_22-13-20.123.mkv",
+            "cam_2023-11-14 22:13:20.123.mkv",
+            "cam_+023-11-14_22-13-20.123.mkv",
+        ] {
+            assert_eq!(parse_file_name("cam", Path::new(name)), None, "{}", name);
+        }
+    }
+}
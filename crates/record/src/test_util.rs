@@ -0,0 +1,14 @@
+//! Fixtures shared by the tests of this crate.
+
+use std::fs;
+use std::path::PathBuf;
+
+pub(crate) use streaming_codec::test_util::{at, mjpeg_packets, opus_packets};
+
+/// An empty directory for `test`, named after it and this process
+pub(crate) fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("record-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}